@@ -0,0 +1,252 @@
+//! Parameter automation.
+//!
+//! While recording, every parameter change the pedals get from the prompt,
+//! a script, MIDI, a scene or a morph is stamped with the sample it landed
+//! on, counted from the start of the take. `on` stands for switching a
+//! pedal on (1) or off (0). A take is saved as one lane per parameter:
+//!
+//! ```text
+//! # rasta automation
+//! rate 48000
+//! lane delay mix
+//! 0 0.3
+//! 24000 0.5
+//! lane aw filter
+//! 12000 bandpass
+//! ```
+//!
+//! Played back, the pedals split their blocks wherever a change is due so
+//! it takes effect on its exact sample, live as well as when rendering.
+
+use effects::Param;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::sync::Arc;
+
+/// Changes preallocated for a take, more only cost a reallocation
+const CAPACITY: usize = 4096;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Event {
+    /// samples since the start of the take
+    pub time: u64,
+    /// shared with the other events of the take
+    pub pedal: Arc<str>,
+    pub param: Arc<str>,
+    pub value: Param,
+}
+
+/// A recorded take, events in time order
+#[derive(Clone, Debug, PartialEq)]
+pub struct Automation {
+    pub sample_rate: usize,
+    pub events: Vec<Event>,
+    /// every pedal and parameter name of the take once, so recording a
+    /// change only allocates for a name the take has not seen
+    names: Vec<Arc<str>>,
+}
+
+impl Automation {
+
+    pub fn new(sample_rate: usize) -> Self {
+        Automation {
+            sample_rate,
+            events: Vec::with_capacity(CAPACITY),
+            names: vec![],
+        }
+    }
+
+    pub fn record(&mut self, time: u64, pedal: &str, param: &str, value: Param) {
+        let (pedal, param) = (self.intern(pedal), self.intern(param));
+        self.events.push(Event { time, pedal, param, value });
+    }
+
+    fn intern(&mut self, name: &str) -> Arc<str> {
+        if let Some(known) = self.names.iter().find(|n| &***n == name) {
+            return known.clone();
+        }
+        let name: Arc<str> = Arc::from(name);
+        self.names.push(name.clone());
+        name
+    }
+
+    /// Length of the take in seconds, up to its last change
+    pub fn duration(&self) -> f32 {
+        self.events.last().map_or(0., |e| e.time as f32 / self.sample_rate as f32)
+    }
+
+    /// Reads a take and moves its changes to `sample_rate`
+    pub fn load(path: &str, sample_rate: usize) -> Result<Automation, String> {
+        let mut text = String::new();
+        File::open(path)
+            .and_then(|mut f| f.read_to_string(&mut text))
+            .map_err(|e| format!("{}: {}", path, e))?;
+        let mut take = Automation::parse(&text).map_err(|(line, e)| format!("{}:{}: {}", path, line, e))?;
+        take.resample(sample_rate);
+        Ok(take)
+    }
+
+    fn parse(text: &str) -> Result<Automation, (usize, String)> {
+        let mut take = Automation::new(0);
+        let mut lane = None;
+        for (i, line) in text.lines().enumerate() {
+            let line = match line.find('#') {
+                Some(idx) => &line[..idx],
+                None => line,
+            };
+            let words: Vec<&str> = line.split_whitespace().collect();
+            match words.as_slice() {
+                [] => (),
+                ["rate", rate] => match rate.parse::<usize>() {
+                    Ok(rate) if rate > 0 => take.sample_rate = rate,
+                    _ => return Err((i + 1, format!("{} is not a sample rate", rate))),
+                },
+                ["lane", pedal, param] => lane = Some((pedal.to_string(), param.to_string())),
+                [time, value] => {
+                    let (pedal, param) = lane.as_ref().ok_or((i + 1, "change before any lane".to_owned()))?;
+                    let time = time.parse::<u64>().map_err(|_| (i + 1, format!("{} is not a sample", time)))?;
+                    let value = match value.parse::<f32>() {
+                        Ok(v) => Param::Float(v),
+                        Err(_) => Param::Name(value.to_string()),
+                    };
+                    take.record(time, pedal, param, value);
+                },
+                _ => return Err((i + 1, format!("expected `lane <pedal> <param>` or `<sample> <value>`, got {}", line.trim()))),
+            }
+        }
+        if take.sample_rate == 0 {
+            return Err((1, "no `rate` line".to_owned()));
+        }
+        // lanes one after the other, stable so equal times keep file order
+        take.events.sort_by_key(|e| e.time);
+        Ok(take)
+    }
+
+    fn resample(&mut self, sample_rate: usize) {
+        if sample_rate == self.sample_rate {
+            return;
+        }
+        for event in self.events.iter_mut() {
+            event.time = (event.time as f64 * sample_rate as f64 / self.sample_rate as f64).round() as u64;
+        }
+        self.sample_rate = sample_rate;
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), String> {
+        File::create(path)
+            .and_then(|mut f| f.write_all(self.to_string().as_bytes()))
+            .map_err(|e| format!("{}: {}", path, e))
+    }
+
+    /// The file contents, lanes in the order they were first touched
+    fn to_string(&self) -> String {
+        let mut lanes: Vec<(&str, &str)> = vec![];
+        for event in self.events.iter() {
+            if !lanes.contains(&(&event.pedal, &event.param)) {
+                lanes.push((&event.pedal, &event.param));
+            }
+        }
+        let mut text = format!("# rasta automation\nrate {}\n", self.sample_rate);
+        for (pedal, param) in lanes {
+            text.push_str(&format!("lane {} {}\n", pedal, param));
+            for event in self.events.iter().filter(|e| &*e.pedal == pedal && &*e.param == param) {
+                text.push_str(&format!("{} {}\n", event.time, event.value));
+            }
+        }
+        text
+    }
+}
+
+/// Plays a take from the sample it was started on
+pub struct Player {
+    take: Arc<Automation>,
+    next: usize,
+    start: u64,
+}
+
+impl Player {
+
+    pub fn new(take: Arc<Automation>, start: u64) -> Self {
+        Player { take, next: 0, start }
+    }
+
+    /// Calls `play` with every change due by sample `now`
+    pub fn due<F: FnMut(&Event)>(&mut self, now: u64, mut play: F) {
+        while let Some(event) = self.take.events.get(self.next) {
+            if self.start + event.time > now {
+                break;
+            }
+            play(event);
+            self.next += 1;
+        }
+    }
+
+    /// Samples from `now` to the next change, None once all are played
+    pub fn until_next(&self, now: u64) -> Option<u64> {
+        self.take.events.get(self.next).map(|e| (self.start + e.time).saturating_sub(now))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Automation, Event, Player};
+    use effects::Param;
+    use std::env;
+    use std::sync::Arc;
+
+    fn take() -> Automation {
+        let mut take = Automation::new(48000);
+        take.record(0, "delay", "mix", Param::Float(0.3));
+        take.record(12000, "aw", "filter", Param::Name("bandpass".to_owned()));
+        take.record(24000, "delay", "mix", Param::Float(0.5));
+        take.record(24000, "od", "on", Param::Int(0));
+        take
+    }
+
+    #[test]
+    fn test_save_load() {
+        let path = env::temp_dir().join("rasta_test_take.txt");
+        let path = path.to_str().unwrap();
+        take().save(path).unwrap();
+        let text = take().to_string();
+        assert_eq!(text, "# rasta automation\nrate 48000\nlane delay mix\n0 0.3\n24000 0.5\n\
+                          lane aw filter\n12000 bandpass\nlane od on\n24000 0\n");
+
+        let loaded = Automation::load(path, 48000).unwrap();
+        let times: Vec<_> = loaded.events.iter().map(|e| (e.time, &*e.param)).collect();
+        assert_eq!(times, vec![(0, "mix"), (12000, "filter"), (24000, "mix"), (24000, "on")]);
+        assert_eq!(loaded.to_string(), text);
+
+        let loaded = Automation::load(path, 44100).unwrap();
+        assert_eq!(loaded.events[1].time, 11025);
+        assert_eq!(loaded.duration(), 24000. / 48000.);
+
+        assert_eq!(Automation::parse("rate 48000\n0 0.5").unwrap_err(), (2, "change before any lane".to_owned()));
+        assert_eq!(Automation::parse("lane a b\n0 1").unwrap_err(), (1, "no `rate` line".to_owned()));
+    }
+
+    #[test]
+    fn test_player() {
+        let mut player = Player::new(Arc::new(take()), 1000);
+        let due = |player: &mut Player, now: u64| {
+            let mut played = vec![];
+            player.due(now, |e: &Event| played.push(format!("{} {} {}", e.pedal, e.param, e.value)));
+            played
+        };
+        assert!(due(&mut player, 999).is_empty());
+        assert_eq!(player.until_next(999), Some(1));
+        assert_eq!(due(&mut player, 1000), vec!["delay mix 0.3"]);
+        assert_eq!(player.until_next(1128), Some(11872));
+        assert_eq!(due(&mut player, 13000).len(), 1);
+        assert_eq!(due(&mut player, 25000), vec!["delay mix 0.5", "od on 0"]);
+        assert_eq!(player.until_next(25000), None);
+    }
+
+    #[test]
+    fn test_names_are_shared() {
+        let take = take();
+        assert!(Arc::ptr_eq(&take.events[0].pedal, &take.events[2].pedal));
+        assert!(Arc::ptr_eq(&take.events[0].param, &take.events[2].param));
+        assert_eq!(take.names.len(), 6);
+    }
+}
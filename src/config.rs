@@ -0,0 +1,756 @@
+//! Startup configuration.
+//!
+//! The config is a TOML file given with `--config` or found at
+//! `$XDG_CONFIG_HOME/rasta/config.toml` (`~/.config/rasta/config.toml`)
+//! or in `$XDG_CONFIG_DIRS` (`/etc/xdg`). Without one the supplied
+//! `config.toml` is used. It sets up the pedals through the same control
+//! messages the REPL sends:
+//!
+//! ```toml
+//! chain = ["in", "gate", "od", "delay", "out"]
+//! bypass = ["delay"]
+//!
+//! [jack]
+//! name = "rasta"
+//! capture = ["system:capture_1"]
+//!
+//! [control]
+//! tempo = 120
+//!
+//! [pedals]
+//! gate = "gate"
+//! od = "overdrive"
+//! delay = "delay"
+//!
+//! [params.delay]
+//! sync = 0.75
+//! feedback = 0.4
+//!
+//! [sidechains]
+//! gate = "in"
+//!
+//! [[midi]]
+//! cc = 11
+//! pedal = "delay"
+//! param = "mix"
+//! ```
+//!
+//! Only the part of TOML these need is read: tables, arrays of tables,
+//! strings, numbers, booleans and arrays. Every problem found is reported
+//! as `path:line: message`.
+
+use effects::{self, CtrlMsg};
+use options::{self, Options};
+use std::env;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+const BUILTIN: &str = include_str!("../config.toml");
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Str(String),
+    Num(f64),
+    Bool(bool),
+    Array(Vec<Value>),
+}
+
+impl Value {
+
+    fn describe(&self) -> &'static str {
+        match *self {
+            Value::Str(_) => "a string",
+            Value::Num(_) => "a number",
+            Value::Bool(_) => "a boolean",
+            Value::Array(_) => "an array",
+        }
+    }
+}
+
+pub struct Entry {
+    pub key: String,
+    pub value: Value,
+    pub line: usize,
+}
+
+pub struct Table {
+    /// dotted name of the header, empty for the keys before the first one,
+    /// in brackets for an array of tables
+    pub name: String,
+    pub line: usize,
+    pub entries: Vec<Entry>,
+}
+
+/// Drops a `#` comment, unless it is inside a string
+fn strip_comment(line: &str) -> &str {
+    let mut quote = None;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match quote {
+            Some(q) => {
+                if escaped {
+                    escaped = false;
+                } else if c == '\\' && q == '"' {
+                    escaped = true;
+                } else if c == q {
+                    quote = None;
+                }
+            },
+            None if c == '"' || c == '\'' => quote = Some(c),
+            None if c == '#' => return &line[..i],
+            None => (),
+        }
+    }
+    line
+}
+
+/// How many more `[` than `]` the line has outside of strings
+fn open_brackets(line: &str) -> i32 {
+    let mut depth = 0;
+    let mut quote = None;
+    let mut escaped = false;
+    for c in line.chars() {
+        match quote {
+            Some(q) => {
+                if escaped {
+                    escaped = false;
+                } else if c == '\\' && q == '"' {
+                    escaped = true;
+                } else if c == q {
+                    quote = None;
+                }
+            },
+            None if c == '"' || c == '\'' => quote = Some(c),
+            None if c == '[' => depth += 1,
+            None if c == ']' => depth -= 1,
+            None => (),
+        }
+    }
+    depth
+}
+
+fn is_bare(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.'
+}
+
+struct Cursor<'a> {
+    text: &'a str,
+    pos: usize,
+}
+
+impl<'a> Cursor<'a> {
+
+    fn peek(&self) -> Option<char> {
+        self.text[self.pos..].chars().next()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += c.len_utf8();
+        Some(c)
+    }
+
+    fn skip_space(&mut self) {
+        while self.peek().map_or(false, |c| c.is_whitespace()) {
+            self.bump();
+        }
+    }
+
+    fn key(&mut self) -> Result<String, String> {
+        match self.peek() {
+            Some(q) if q == '"' || q == '\'' => self.string(),
+            _ => {
+                let start = self.pos;
+                while self.peek().map_or(false, is_bare) {
+                    self.bump();
+                }
+                if self.pos == start {
+                    return Err("expected a key".to_owned());
+                }
+                Ok(self.text[start..self.pos].to_owned())
+            },
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        let quote = self.bump().unwrap();
+        let mut s = String::new();
+        loop {
+            match self.bump() {
+                None => return Err("unterminated string".to_owned()),
+                Some(c) if c == quote => return Ok(s),
+                Some('\\') if quote == '"' => {
+                    match self.bump() {
+                        Some('n') => s.push('\n'),
+                        Some('t') => s.push('\t'),
+                        Some('"') => s.push('"'),
+                        Some('\\') => s.push('\\'),
+                        Some(c) => return Err(format!("unknown escape \\{}", c)),
+                        None => return Err("unterminated string".to_owned()),
+                    }
+                },
+                Some(c) => s.push(c),
+            }
+        }
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        self.skip_space();
+        match self.peek() {
+            None => Err("expected a value".to_owned()),
+            Some('"') | Some('\'') => Ok(Value::Str(self.string()?)),
+            Some('[') => {
+                self.bump();
+                let mut items = vec![];
+                loop {
+                    self.skip_space();
+                    if self.peek() == Some(']') {
+                        self.bump();
+                        return Ok(Value::Array(items));
+                    }
+                    items.push(self.value()?);
+                    self.skip_space();
+                    match self.bump() {
+                        Some(',') => (),
+                        Some(']') => return Ok(Value::Array(items)),
+                        _ => return Err("expected , or ] in array".to_owned()),
+                    }
+                }
+            },
+            Some(_) => {
+                let start = self.pos;
+                while self.peek().map_or(false, |c| c != ',' && c != ']' && !c.is_whitespace()) {
+                    self.bump();
+                }
+                let word = &self.text[start..self.pos];
+                match word {
+                    "true" => Ok(Value::Bool(true)),
+                    "false" => Ok(Value::Bool(false)),
+                    _ => word.replace('_', "").parse::<f64>()
+                        .map(Value::Num)
+                        .map_err(|_| format!("{} is not a value, strings need quotes", word)),
+                }
+            },
+        }
+    }
+}
+
+/// Splits TOML text into its tables, errors carry the line number
+pub fn parse_tables(text: &str) -> Result<Vec<Table>, (usize, String)> {
+    let mut tables = vec![Table { name: String::new(), line: 1, entries: vec![] }];
+    let mut lines = text.lines().enumerate();
+    while let Some((i, line)) = lines.next() {
+        let line_no = i + 1;
+        let mut line = strip_comment(line).trim().to_owned();
+        if line.is_empty() {
+            continue;
+        }
+
+        if line.starts_with('[') {
+            let array = line.starts_with("[[");
+            let (open, close) = if array { ("[[", "]]") } else { ("[", "]") };
+            if !line.ends_with(close) {
+                return Err((line_no, format!("expected {} to close the table header", close)));
+            }
+            let name = line[open.len()..line.len() - close.len()].trim();
+            if name.is_empty() || !name.chars().all(is_bare) {
+                return Err((line_no, format!("bad table name {}", name)));
+            }
+            let name = if array { format!("[{}]", name) } else { name.to_owned() };
+            if !array && tables.iter().any(|t| t.name == name) {
+                return Err((line_no, format!("table [{}] appears twice", name)));
+            }
+            tables.push(Table { name, line: line_no, entries: vec![] });
+            continue;
+        }
+
+        // arrays may span lines
+        while open_brackets(&line) > 0 {
+            match lines.next() {
+                Some((_, next)) => {
+                    line.push(' ');
+                    line.push_str(strip_comment(next).trim());
+                },
+                None => return Err((line_no, "unterminated array".to_owned())),
+            }
+        }
+
+        let mut cursor = Cursor { text: &line, pos: 0 };
+        let key = cursor.key().map_err(|e| (line_no, e))?;
+        cursor.skip_space();
+        if cursor.bump() != Some('=') {
+            return Err((line_no, format!("expected = after {}", key)));
+        }
+        let value = cursor.value().map_err(|e| (line_no, e))?;
+        cursor.skip_space();
+        if cursor.peek().is_some() {
+            return Err((line_no, format!("unexpected {} after the value", &line[cursor.pos..])));
+        }
+        let table = tables.last_mut().unwrap();
+        if table.entries.iter().any(|e| e.key == key) {
+            return Err((line_no, format!("{} is set twice", key)));
+        }
+        table.entries.push(Entry { key, value, line: line_no });
+    }
+    Ok(tables)
+}
+
+/// A MIDI controller mapped to a parameter, as `m cc pedal param min max`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Mapping {
+    pub cc: u8,
+    pub pedal: String,
+    pub param: String,
+    pub min: f32,
+    pub max: f32,
+}
+
+/// Everything the config sets
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Config {
+    /// where it was read from
+    pub path: String,
+    pub client_name: Option<String>,
+    pub inputs: Option<Vec<String>>,
+    pub outputs: Option<Vec<String>>,
+    pub capture: Vec<String>,
+    pub playback: Vec<String>,
+    pub patchbay: Option<String>,
+    /// beats per minute, 0 leaves it unset
+    pub tempo: f32,
+    /// seconds between stats lines, 0 is off
+    pub stats_log: f32,
+    /// name and type of every pedal in the order they are listed
+    pub pedals: Vec<(String, String)>,
+    pub chain: Vec<String>,
+    pub bypass: Vec<String>,
+    /// pedal, parameter and value
+    pub params: Vec<(String, String, Value)>,
+    /// pedal and the node keying it
+    pub sidechains: Vec<(String, String)>,
+    pub midi: Vec<Mapping>,
+    /// footswitches stepping through the setlist, controller and step
+    pub steps: Vec<(u8, i32)>,
+}
+
+pub fn string(value: &Value) -> Result<String, String> {
+    match *value {
+        Value::Str(ref s) => Ok(s.clone()),
+        ref v => Err(format!("expected a string, found {}", v.describe())),
+    }
+}
+
+pub fn strings(value: &Value) -> Result<Vec<String>, String> {
+    match *value {
+        Value::Array(ref items) => items.iter().map(string).collect(),
+        ref v => Err(format!("expected an array of strings, found {}", v.describe())),
+    }
+}
+
+pub fn number(value: &Value) -> Result<f32, String> {
+    match *value {
+        Value::Num(n) => Ok(n as f32),
+        ref v => Err(format!("expected a number, found {}", v.describe())),
+    }
+}
+
+impl Config {
+
+    /// The supplied `config.toml`
+    pub fn builtin() -> Config {
+        Config::parse(BUILTIN, "config.toml").unwrap()
+    }
+
+    pub fn load(path: &str) -> Result<Config, String> {
+        let mut text = String::new();
+        File::open(path)
+            .and_then(|mut f| f.read_to_string(&mut text))
+            .map_err(|e| format!("{}: {}", path, e))?;
+        Config::parse(&text, path)
+    }
+
+    /// Reads and checks the text of a config, `path` only labels the
+    /// errors. All problems are reported at once, one per line.
+    pub fn parse(text: &str, path: &str) -> Result<Config, String> {
+        let tables = parse_tables(text)
+            .map_err(|(line, e)| format!("{}:{}: {}", path, line, e))?;
+
+        let mut config = Config { path: path.to_owned(), ..Config::default() };
+        let mut errors = vec![];
+        {
+            let mut error = |line: usize, msg: String| errors.push(format!("{}:{}: {}", path, line, msg));
+
+            // pedals first, the other tables refer to them
+            for table in tables.iter().filter(|t| t.name == "pedals") {
+                for entry in table.entries.iter() {
+                    let eff_type = match string(&entry.value) {
+                        Ok(t) => t,
+                        Err(e) => { error(entry.line, format!("pedal {}: {}", entry.key, e)); continue; },
+                    };
+                    if entry.key == "in" || entry.key == "out" {
+                        error(entry.line, format!("{} is the name of a chain end", entry.key));
+                    } else if entry.key == "ab" {
+                        error(entry.line, "ab is the name of the A/B morph control".to_owned());
+                    } else if !effects::TYPES.contains(&eff_type.as_str()) {
+                        error(entry.line, format!("pedal {}: unknown type {}, expected one of {}",
+                            entry.key, eff_type, effects::TYPES.join(", ")));
+                    } else {
+                        config.pedals.push((entry.key.clone(), eff_type));
+                    }
+                }
+            }
+            let pedals: Vec<String> = config.pedals.iter().map(|p| p.0.clone()).collect();
+            let is_pedal = |name: &str| pedals.iter().any(|p| p == name);
+
+            for table in tables.iter() {
+                let name = table.name.as_str();
+                match name {
+                    "" | "jack" | "control" | "sidechains" | "[midi]" => (),
+                    "pedals" => continue,
+                    _ if name.starts_with("params.") => {
+                        let pedal = &name[7..];
+                        if !is_pedal(pedal) {
+                            error(table.line, format!("[{}]: no pedal named {}", name, pedal));
+                            continue;
+                        }
+                        let eff_type = &config.pedals.iter().find(|p| p.0 == pedal).unwrap().1;
+                        let known = effects::param_names(eff_type);
+                        for entry in table.entries.iter() {
+                            if !known.contains(&entry.key) {
+                                if known.is_empty() {
+                                    error(entry.line, format!("{}: {} {} has no parameters", entry.key, eff_type, pedal));
+                                } else {
+                                    error(entry.line, format!("{}: no parameter of {} {}, expected one of {}",
+                                        entry.key, eff_type, pedal, known.join(", ")));
+                                }
+                                continue;
+                            }
+                            config.params.push((pedal.to_owned(), entry.key.clone(), entry.value.clone()));
+                            if let Value::Array(_) = entry.value {
+                                error(entry.line, format!("{}: expected a number, string or boolean", entry.key));
+                            }
+                        }
+                        continue;
+                    },
+                    _ => {
+                        error(table.line, format!("unknown table [{}]", name));
+                        continue;
+                    },
+                }
+
+                let mut mapping = Mapping { cc: 0, pedal: String::new(), param: String::new(), min: 0., max: 1. };
+                let mut has_cc = false;
+                let mut step = None;
+                for entry in table.entries.iter() {
+                    let value = &entry.value;
+                    let result = match (name, entry.key.as_str()) {
+                        ("", "chain") => strings(value).and_then(|chain| {
+                            if chain.is_empty() {
+                                Ok(())
+                            } else if chain[0] != "in" || chain[chain.len() - 1] != "out" {
+                                Err("chain must run from in to out".to_owned())
+                            } else if let Some(p) = chain[1..chain.len() - 1].iter().find(|p| !is_pedal(p)) {
+                                Err(format!("no pedal named {}", p))
+                            } else if let Some((i, _)) = chain.iter().enumerate()
+                                .find(|&(i, p)| chain[..i].contains(p)) {
+                                Err(format!("{} appears twice", chain[i]))
+                            } else {
+                                config.chain = chain;
+                                Ok(())
+                            }
+                        }),
+                        ("", "bypass") => strings(value).and_then(|names| {
+                            match names.iter().find(|p| !is_pedal(p)) {
+                                Some(p) => Err(format!("no pedal named {}", p)),
+                                None => { config.bypass = names; Ok(()) },
+                            }
+                        }),
+                        ("jack", "name") => string(value).map(|n| config.client_name = Some(n)),
+                        ("jack", "inputs") => match *value {
+                            Value::Num(n) if n >= 1. && n.fract() == 0. => {
+                                config.inputs = Some(options::input_names(n as usize));
+                                Ok(())
+                            },
+                            Value::Array(ref a) if !a.is_empty() => strings(value).map(|n| config.inputs = Some(n)),
+                            _ => Err("expected a number of inputs or their names".to_owned()),
+                        },
+                        ("jack", "outputs") => strings(value).and_then(|names| {
+                            if names.len() == 2 {
+                                config.outputs = Some(names);
+                                Ok(())
+                            } else {
+                                Err("expected two names, left and right".to_owned())
+                            }
+                        }),
+                        ("jack", "capture") => strings(value).map(|p| config.capture = p),
+                        ("jack", "playback") => strings(value).map(|p| config.playback = p),
+                        ("jack", "patchbay") => string(value).map(|p| config.patchbay = Some(p)),
+                        ("control", "tempo") => number(value).map(|t| config.tempo = t),
+                        ("control", "stats_log") => number(value).map(|s| config.stats_log = s),
+                        ("sidechains", pedal) => string(value).and_then(|source| {
+                            if !is_pedal(pedal) {
+                                Err(format!("no pedal named {}", pedal))
+                            } else if source != "in" && !is_pedal(&source) {
+                                Err(format!("no node named {}", source))
+                            } else {
+                                config.sidechains.push((pedal.to_owned(), source));
+                                Ok(())
+                            }
+                        }),
+                        ("[midi]", "cc") => match *value {
+                            Value::Num(n) if n >= 0. && n <= 127. && n.fract() == 0. => {
+                                mapping.cc = n as u8;
+                                has_cc = true;
+                                Ok(())
+                            },
+                            _ => Err("expected a controller number 0..127".to_owned()),
+                        },
+                        ("[midi]", "pedal") => string(value).and_then(|p| {
+                            // "ab" is the morph between the A/B slots
+                            if is_pedal(&p) || p == "ab" {
+                                mapping.pedal = p;
+                                Ok(())
+                            } else {
+                                Err(format!("no pedal named {}", p))
+                            }
+                        }),
+                        ("[midi]", "param") => string(value).map(|p| mapping.param = p),
+                        ("[midi]", "action") => string(value).and_then(|a| {
+                            match a.as_str() {
+                                "next" => { step = Some(1); Ok(()) },
+                                "prev" => { step = Some(-1); Ok(()) },
+                                _ => Err(format!("unknown action {}, expected next or prev", a)),
+                            }
+                        }),
+                        ("[midi]", "min") => number(value).map(|v| mapping.min = v),
+                        ("[midi]", "max") => number(value).map(|v| mapping.max = v),
+                        (_, key) => Err(format!("unknown setting {}", key)),
+                    };
+                    if let Err(e) = result {
+                        error(entry.line, format!("{}: {}", entry.key, e));
+                    }
+                }
+
+                if name == "[midi]" {
+                    if has_cc && step.is_some() {
+                        config.steps.push((mapping.cc, step.unwrap()));
+                    } else if !has_cc || mapping.pedal.is_empty() || mapping.param.is_empty() {
+                        error(table.line, "[[midi]] needs cc, pedal and param or cc and action".to_owned());
+                    } else {
+                        config.midi.push(mapping);
+                    }
+                }
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(config)
+        } else {
+            Err(errors.join("\n"))
+        }
+    }
+
+    /// The backend options of the config, command line flags go on top
+    pub fn options(&self) -> Options {
+        let mut opts = Options::default();
+        if let Some(ref name) = self.client_name {
+            opts.client_name = name.clone();
+        }
+        if let Some(ref inputs) = self.inputs {
+            opts.inputs = inputs.clone();
+        }
+        if let Some(ref outputs) = self.outputs {
+            opts.outputs = outputs.clone();
+        }
+        opts.capture = self.capture.clone();
+        opts.playback = self.playback.clone();
+        opts.patchbay = self.patchbay.clone();
+        opts
+    }
+
+    /// The control messages that set up the pedals
+    pub fn commands(&self) -> Vec<CtrlMsg> {
+        use self::CtrlMsg::*;
+        let mut msgs = vec![];
+        if self.tempo > 0. {
+            msgs.push(Tempo(self.tempo));
+        }
+        for &(ref name, ref eff_type) in self.pedals.iter() {
+            msgs.push(Add(name.clone(), eff_type.clone()));
+        }
+        for (from, to) in self.chain.iter().zip(self.chain.iter().skip(1)) {
+            msgs.push(Connect(from.clone(), to.clone()));
+        }
+        for &(ref pedal, ref source) in self.sidechains.iter() {
+            msgs.push(Sidechain(pedal.clone(), source.clone()));
+        }
+        for &(ref pedal, ref param, ref value) in self.params.iter() {
+            let (pedal, param) = (pedal.clone(), param.clone());
+            msgs.push(match *value {
+                Value::Num(n) => Set(pedal, param, n as f32),
+                Value::Bool(b) => Set(pedal, param, if b { 1. } else { 0. }),
+                Value::Str(ref s) => Select(pedal, param, s.clone()),
+                Value::Array(_) => continue,
+            });
+        }
+        for m in self.midi.iter() {
+            msgs.push(MidiMap(m.cc, m.pedal.clone(), m.param.clone(), m.min, m.max));
+        }
+        for &(cc, step) in self.steps.iter() {
+            msgs.push(MidiStep(cc, step));
+        }
+        for name in self.bypass.iter() {
+            msgs.push(Enable(name.clone(), false));
+        }
+        msgs
+    }
+}
+
+/// The user's config directory, `$XDG_CONFIG_HOME/rasta`
+pub fn dir() -> Option<PathBuf> {
+    match env::var("XDG_CONFIG_HOME") {
+        Ok(ref dir) if !dir.is_empty() => Some(PathBuf::from(dir).join("rasta")),
+        _ => env::var("HOME").ok().map(|home| Path::new(&home).join(".config").join("rasta")),
+    }
+}
+
+/// The first config on the XDG search path
+pub fn find() -> Option<PathBuf> {
+    let mut dirs: Vec<_> = dir().into_iter().collect();
+    let system = match env::var("XDG_CONFIG_DIRS") {
+        Ok(ref d) if !d.is_empty() => d.clone(),
+        _ => "/etc/xdg".to_owned(),
+    };
+    dirs.extend(system.split(':').filter(|d| !d.is_empty()).map(|d| Path::new(d).join("rasta")));
+    dirs.into_iter()
+        .map(|dir| dir.join("config.toml"))
+        .find(|path| path.is_file())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Config, Mapping, Value};
+    use effects::CtrlMsg;
+
+    #[test]
+    fn test_builtin() {
+        let config = Config::builtin();
+        let names: Vec<_> = config.pedals.iter().map(|p| p.0.as_str()).collect();
+        assert_eq!(names, vec!["tuner", "overdrive", "delay", "aw", "trem"]);
+        assert_eq!(config.chain, vec!["in", "tuner", "overdrive", "delay", "out"]);
+        assert_eq!(config.options().client_name, "rasta");
+    }
+
+    #[test]
+    fn test_parse() {
+        let text = r#"
+            # comments anywhere
+            chain = [
+                "in", "gate",  # the gate first
+                "eq", "out",
+            ]
+            bypass = ["eq"]
+
+            [jack]
+            name = "amp"
+            inputs = 2
+            outputs = ['l', 'r']
+
+            [pedals]
+            gate = "gate"
+            eq = "eq"
+
+            [params.eq]
+            b2.freq = 800
+            "b6.type" = "lowpass"
+            b1.gain = 3
+
+            [sidechains]
+            gate = "in"
+
+            [[midi]]
+            cc = 11
+            pedal = "eq"
+            param = "b2.gain"
+            min = -12
+            max = 12
+
+            [[midi]]
+            cc = 64
+            pedal = "gate"
+            param = "threshold"
+
+            [[midi]]
+            cc = 80
+            action = "next"
+        "#;
+        let config = Config::parse(text, "test.toml").unwrap();
+        assert_eq!(config.chain, vec!["in", "gate", "eq", "out"]);
+        assert_eq!(config.params[0], ("eq".to_owned(), "b2.freq".to_owned(), Value::Num(800.)));
+        assert_eq!(config.midi[0], Mapping { cc: 11, pedal: "eq".to_owned(), param: "b2.gain".to_owned(), min: -12., max: 12. });
+        assert_eq!((config.midi[1].min, config.midi[1].max), (0., 1.));
+
+        let opts = config.options();
+        assert_eq!(opts.client_name, "amp");
+        assert_eq!(opts.inputs, vec!["guitar_in", "guitar_in_2"]);
+        assert_eq!(opts.outputs, vec!["l", "r"]);
+
+        let msgs = config.commands();
+        assert_eq!(config.steps, vec![(80, 1)]);
+        assert_eq!(msgs.len(), 2 + 3 + 1 + 3 + 2 + 1 + 1);
+        match msgs[1] {
+            CtrlMsg::Add(ref name, ref eff_type) => assert_eq!((name.as_str(), eff_type.as_str()), ("eq", "eq")),
+            _ => panic!("expected the pedals first"),
+        }
+        match msgs[7] {
+            CtrlMsg::Select(_, ref param, ref val) => assert_eq!((param.as_str(), val.as_str()), ("b6.type", "lowpass")),
+            _ => panic!("expected a select"),
+        }
+        match msgs[msgs.len() - 1] {
+            CtrlMsg::Enable(ref name, on) => assert_eq!((name.as_str(), on), ("eq", false)),
+            _ => panic!("expected the bypassed pedal switched off"),
+        }
+    }
+
+    #[test]
+    fn test_errors() {
+        let err = Config::parse("chain = [\"in\", \"od\"\n", "a.toml").unwrap_err();
+        assert_eq!(err, "a.toml:1: unterminated array");
+        let err = Config::parse("[pedals]\nod = overdrive", "a.toml").unwrap_err();
+        assert_eq!(err, "a.toml:2: overdrive is not a value, strings need quotes");
+
+        let text = "chain = [\"in\", \"od\", \"fuzz\", \"out\"]\n\
+                    [pedals]\n\
+                    od = \"overdrive\"\n\
+                    wah = \"wahwah\"\n\
+                    [params.fuzz]\n\
+                    gain = 1\n\
+                    [[midi]]\n\
+                    cc = 200\n\
+                    [lfo]\n";
+        let err = Config::parse(text, "b.toml").unwrap_err();
+        let lines: Vec<_> = err.lines().collect();
+        assert!(lines[0].starts_with("b.toml:4: pedal wah: unknown type wahwah, expected one of delay"), "{}", err);
+        assert_eq!(&lines[1..], &[
+            "b.toml:1: chain: no pedal named fuzz",
+            "b.toml:5: [params.fuzz]: no pedal named fuzz",
+            "b.toml:8: cc: expected a controller number 0..127",
+            "b.toml:7: [[midi]] needs cc, pedal and param or cc and action",
+            "b.toml:9: unknown table [lfo]",
+        ]);
+
+        let text = "[pedals]\n\
+                    eq = \"eq\"\n\
+                    od = \"overdrive\"\n\
+                    [params.eq]\n\
+                    b2.freq = 800\n\
+                    b2.frq = 800\n\
+                    [params.od]\n\
+                    drive = 1\n";
+        let err = Config::parse(text, "c.toml").unwrap_err();
+        let lines: Vec<_> = err.lines().collect();
+        assert!(lines[0].starts_with("c.toml:6: b2.frq: no parameter of eq eq, expected one of gain, b1.type"), "{}", err);
+        assert_eq!(lines[1], "c.toml:8: drive: overdrive od has no parameters");
+    }
+}
@@ -0,0 +1,205 @@
+//! Automatic JACK connections.
+//!
+//! A rule connects the output ports matching one regular expression to
+//! the input ports matching another, pairwise in the order JACK lists
+//! them. A single port on either side is connected to every port on the
+//! other, so a mono capture feeds all inputs.
+//!
+//! Rules can be derived from a QjackCtl patchbay such as the supplied
+//! `patchbay.xml`: every cable becomes one rule per pair of plugs.
+
+use jack;
+use std::fs::File;
+use std::io::Read;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Rule {
+    /// pattern of the output ports the signal comes from
+    pub from: String,
+    /// pattern of the input ports it goes to
+    pub to: String,
+}
+
+/// Escapes `name` so it only matches itself in a JACK port pattern
+pub fn escape(name: &str) -> String {
+    let mut escaped = String::new();
+    for c in name.chars() {
+        if "\\.^$|?*+()[]{}".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Pattern matching exactly the full port names in `names`
+pub fn exact(names: &[String]) -> String {
+    let alternatives: Vec<_> = names.iter().map(|n| escape(n)).collect();
+    format!("^({})$", alternatives.join("|"))
+}
+
+/// The connections a rule makes between the matched ports
+pub fn pairs<'a>(from: &'a [String], to: &'a [String]) -> Vec<(&'a str, &'a str)> {
+    if from.len() == 1 {
+        to.iter().map(|t| (from[0].as_str(), t.as_str())).collect()
+    } else if to.len() == 1 {
+        from.iter().map(|f| (f.as_str(), to[0].as_str())).collect()
+    } else {
+        from.iter().zip(to.iter()).map(|(f, t)| (f.as_str(), t.as_str())).collect()
+    }
+}
+
+/// Makes the connections of every rule, reporting the ones that fail
+pub fn apply(client: &jack::Client, rules: &[Rule]) {
+    for rule in rules.iter() {
+        let from = client.ports(Some(&rule.from), None, jack::PortFlags::IS_OUTPUT);
+        let to = client.ports(Some(&rule.to), None, jack::PortFlags::IS_INPUT);
+        if from.is_empty() || to.is_empty() {
+            println!("No ports to connect for {} -> {}", rule.from, rule.to);
+            continue;
+        }
+        for (src, dst) in pairs(&from, &to) {
+            match client.connect_ports_by_name(src, dst) {
+                Ok(()) => println!("Connected {} -> {}", src, dst),
+                Err(e) => println!("Could not connect {} -> {}: {:?}", src, dst, e),
+            }
+        }
+    }
+}
+
+struct Socket {
+    name: String,
+    client: String,
+    plugs: Vec<String>,
+}
+
+/// Value of `key="..."` in the attributes of a tag
+fn attribute(tag: &str, key: &str) -> Option<String> {
+    let start = tag.find(&format!(" {}=\"", key))? + key.len() + 3;
+    let len = tag[start..].find('"')?;
+    Some(unescape_xml(&tag[start..start + len]))
+}
+
+fn unescape_xml(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+/// Derives the rules from the text of a QjackCtl patchbay.
+///
+/// Socket clients and plugs are patterns already. Sockets of the client
+/// called `own` in the patchbay are renamed to `client`, the name JACK
+/// gave this instance. ALSA MIDI cables are skipped.
+pub fn parse_patchbay(xml: &str, own: &str, client: &str) -> Result<Vec<Rule>, String> {
+    let mut outputs: Vec<Socket> = vec![];
+    let mut inputs: Vec<Socket> = vec![];
+    let mut in_outputs = false;
+    let mut socket: Option<Socket> = None;
+    let mut cables = vec![];
+
+    let mut rest = xml;
+    while let Some(start) = rest.find('<') {
+        let end = rest[start..].find('>').ok_or("unterminated tag")? + start;
+        let tag = &rest[start + 1..end];
+        let after = &rest[end + 1..];
+        rest = after;
+
+        let name = tag.split_whitespace().next().unwrap_or("");
+        match name {
+            "output-sockets" => in_outputs = true,
+            "input-sockets" => in_outputs = false,
+            "socket" => {
+                let client_name = attribute(tag, "client").ok_or("socket without client")?;
+                let client_name = if client_name == own { escape(client) } else { client_name };
+                socket = Some(Socket {
+                    name: attribute(tag, "name").ok_or("socket without name")?,
+                    client: client_name,
+                    plugs: vec![],
+                });
+            },
+            "plug" => {
+                let len = after.find('<').ok_or("unterminated plug")?;
+                if let Some(ref mut socket) = socket {
+                    socket.plugs.push(unescape_xml(after[..len].trim()));
+                }
+            },
+            "/socket" => {
+                if let Some(socket) = socket.take() {
+                    if in_outputs { outputs.push(socket) } else { inputs.push(socket) }
+                }
+            },
+            "cable" | "cable/" => {
+                let typ = attribute(tag, "type").unwrap_or_default();
+                if typ != "alsa-midi" {
+                    let output = attribute(tag, "output").ok_or("cable without output")?;
+                    let input = attribute(tag, "input").ok_or("cable without input")?;
+                    cables.push((output, input));
+                }
+            },
+            _ => (),
+        }
+    }
+
+    let mut rules = vec![];
+    for (output, input) in cables {
+        let from = outputs.iter().find(|s| s.name == output)
+            .ok_or_else(|| format!("no output socket {}", output))?;
+        let to = inputs.iter().find(|s| s.name == input)
+            .ok_or_else(|| format!("no input socket {}", input))?;
+        let plug = |socket: &Socket, i: usize| {
+            let plug = &socket.plugs[i.min(socket.plugs.len() - 1)];
+            format!("^{}:{}$", socket.client, plug)
+        };
+        if from.plugs.is_empty() || to.plugs.is_empty() {
+            continue;
+        }
+        for i in 0..from.plugs.len().max(to.plugs.len()) {
+            rules.push(Rule { from: plug(from, i), to: plug(to, i) });
+        }
+    }
+    Ok(rules)
+}
+
+/// Reads the patchbay at `path`, see `parse_patchbay`
+pub fn load_patchbay(path: &str, own: &str, client: &str) -> Result<Vec<Rule>, String> {
+    let mut xml = String::new();
+    File::open(path)
+        .and_then(|mut f| f.read_to_string(&mut xml))
+        .map_err(|e| format!("{}: {}", path, e))?;
+    parse_patchbay(&xml, own, client).map_err(|e| format!("{}: {}", path, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_patchbay, pairs, exact, Rule};
+
+    #[test]
+    fn test_supplied_patchbay() {
+        let xml = include_str!("../patchbay.xml");
+        let rules = parse_patchbay(xml, "rasta", "rasta-01").unwrap();
+        let rule = |from: &str, to: &str| Rule { from: from.to_owned(), to: to.to_owned() };
+        assert_eq!(rules, vec![
+            rule("^rasta-01:rasta_out_l$", "^system:playback_1$"),
+            rule("^rasta-01:rasta_out_r$", "^system:playback_2$"),
+            rule("^system:capture_2$", "^rasta-01:guitar_in$"),
+        ]);
+    }
+
+    #[test]
+    fn test_pairs() {
+        let names = |n: &[&str]| n.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        let capture = names(&["system:capture_1"]);
+        let inputs = names(&["rasta:in_1", "rasta:in_2"]);
+        assert_eq!(pairs(&capture, &inputs),
+            vec![("system:capture_1", "rasta:in_1"), ("system:capture_1", "rasta:in_2")]);
+
+        let playback = names(&["system:playback_1", "system:playback_2", "system:playback_3"]);
+        assert_eq!(pairs(&inputs, &playback),
+            vec![("rasta:in_1", "system:playback_1"), ("rasta:in_2", "system:playback_2")]);
+
+        assert_eq!(exact(&names(&["my.rig:out (L)"])), "^(my\\.rig:out \\(L\\))$");
+    }
+}
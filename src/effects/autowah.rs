@@ -1,4 +1,4 @@
-use effects::{CtrlMsg, Effect};
+use effects::{CtrlMsg, Effect, Param, params};
 use std::default::Default;
 use std::f32::consts::PI as pi;
 
@@ -26,10 +26,21 @@ fn precisionTan(x: f32) -> f32 {
     x * (1. + tanConst3*x2 + tanConst5*x4)
 }
 
+/// Envelope-controlled wah.
+///
+/// The center frequency of a state variable filter follows the level of
+/// the input (times `sensitivity`) between `min` and `max` Hz, upwards or
+/// downwards. In manual mode it follows the `pedal` position instead,
+/// which an expression pedal can drive through a MIDI mapping.
 #[derive(Default)]
 pub struct AutoWah {
     bypassing: bool,
-    frame_size: u32,
+
+    // Sweep control
+    sensitivity: f32,
+    direction: Direction,
+    manual: bool,
+    pedal: f32,
 
     // Level Detector parameters
     alphaA: f32,
@@ -59,11 +70,10 @@ pub struct AutoWah {
 }
 
 impl Effect for AutoWah {
-    fn new(sample_rate: usize, frame_size: u32) -> Self {
+    fn new(sample_rate: usize, _frame_size: u32) -> Self {
         let mut aw = AutoWah {
             bypassing: false,
             sample_rate: sample_rate as f32,
-            frame_size,
             ..Default::default()
         };
 
@@ -72,6 +82,7 @@ impl Effect for AutoWah {
         aw.set_min_maxFreq(20., 3000.);
         aw.set_quality_factor(1. / 5.);
         aw.set_mixing(0.8);
+        aw.set_sensitivity(1.);
 
         aw
     }
@@ -81,7 +92,14 @@ impl Effect for AutoWah {
     }
 
     fn process_samples(&mut self, input: &[f32], output_l: &mut [f32], output_r: &mut [f32]) {
-        for i in 0..self.frame_size as usize {
+
+        if self.bypassing {
+            output_l.clone_from_slice(input);
+            output_r.clone_from_slice(input);
+            return;
+        }
+
+        for i in 0..input.len() {
             let x = input[i] * 1.;
             let mut y = self.run_effect(x) * 2.;
 
@@ -94,6 +112,36 @@ impl Effect for AutoWah {
         }
     }
 
+    fn sample_rate_changed(&mut self, sample_rate: usize) {
+        self.set_sample_rate(sample_rate as f32);
+    }
+
+    fn params(&self) -> Vec<(String, Param)> {
+        use self::Param::*;
+        let tau = |alpha: f32| -1. / (alpha.ln() * self.sample_rate);
+        params(&[
+            ("attack", Float(tau(self.alphaA))),
+            ("release", Float(tau(self.alphaR))),
+            // min first, it may push max up
+            ("min", Float(self.min_frequency())),
+            ("max", Float(self.max_frequency())),
+            ("q", Float(self.q)),
+            ("mix", Float(self.alphaMix)),
+            ("sens", Float(self.sensitivity)),
+            ("pedal", Float(self.pedal)),
+            ("filter", Name(match self.filter {
+                FilterType::Lowpass => "lowpass",
+                FilterType::Bandpass => "bandpass",
+                FilterType::Highpass => "highpass",
+            }.to_owned())),
+            ("direction", Name(match self.direction {
+                Direction::Up => "up",
+                Direction::Down => "down",
+            }.to_owned())),
+            ("mode", Name(if self.manual { "manual" } else { "auto" }.to_owned())),
+        ])
+    }
+
     fn bypass(&mut self) {
         self.bypassing = !self.bypassing;
     }
@@ -102,8 +150,43 @@ impl Effect for AutoWah {
         self.bypassing
     }
 
+    fn set(&mut self, param: &str, val: f32) {
+        match param {
+            "attack" => self.set_attack(val),
+            "release" => self.set_release(val),
+            "min" => {
+                let maxFreq = self.max_frequency();
+                self.set_min_maxFreq(val, maxFreq);
+            },
+            "max" => {
+                let minFreq = self.min_frequency();
+                self.set_min_maxFreq(minFreq, val);
+            },
+            "q" => self.set_quality_factor(val),
+            "mix" => self.set_mixing(val),
+            "sens" => self.set_sensitivity(val),
+            "pedal" => self.set_pedal(val),
+            _ => (),
+        }
+    }
+
     fn ctrl(&mut self, msg: CtrlMsg) {
+        use self::CtrlMsg::*;
         match msg {
+            Bypass => self.bypass(),
+            Set(_pedal_name, conf_name, val) => self.set(&conf_name, val),
+            Select(_pedal_name, conf_name, val) => {
+                match (conf_name.as_str(), val.as_str()) {
+                    ("filter", "lowpass") => self.set_filter_type(FilterType::Lowpass),
+                    ("filter", "bandpass") => self.set_filter_type(FilterType::Bandpass),
+                    ("filter", "highpass") => self.set_filter_type(FilterType::Highpass),
+                    ("direction", "up") => self.set_direction(Direction::Up),
+                    ("direction", "down") => self.set_direction(Direction::Down),
+                    ("mode", "auto") => self.set_manual(false),
+                    ("mode", "manual") => self.set_manual(true),
+                    _ => println!("Unknown {}: {}", conf_name, val),
+                }
+            },
             _ => (),
         }
     }
@@ -115,8 +198,18 @@ impl AutoWah {
 
         let yL = self.level_detector(xL);
 
-        //fc = yL * (maxFreq - minFreq) + minFreq;
-        self.centerFreq = yL * self.freqBandwidth + self.minFreq;
+        let sweep = if self.manual {
+            self.pedal
+        } else {
+            (yL * self.sensitivity).min(1.)
+        };
+        let sweep = match self.direction {
+            Direction::Up => sweep,
+            Direction::Down => 1. - sweep,
+        };
+
+        //fc = sweep * (maxFreq - minFreq) + minFreq;
+        self.centerFreq = sweep * self.freqBandwidth + self.minFreq;
 
         //float xF = x;
         let xF = self.low_pass_filter(x);
@@ -136,14 +229,47 @@ impl AutoWah {
     }
     pub fn set_release(&mut self, tauR: f32) {
         self.alphaR = (-1. / tauR / self.sample_rate ).exp();
-        self.betaR = 1. - self.alphaA;
+        self.betaR = 1. - self.alphaR;
     }
     pub fn set_min_maxFreq(&mut self, minFreq: f32, maxFreq: f32) {
-        self.freqBandwidth = pi * (2. * maxFreq - minFreq) / self.sample_rate;
+        let maxFreq = maxFreq.max(minFreq).min(0.3 * self.sample_rate);
+        self.freqBandwidth = pi * (maxFreq - minFreq) / self.sample_rate;
         self.minFreq = pi * minFreq / self.sample_rate;
     }
+    pub fn min_frequency(&self) -> f32 {
+        self.minFreq * self.sample_rate / pi
+    }
+    pub fn max_frequency(&self) -> f32 {
+        (self.minFreq + self.freqBandwidth) * self.sample_rate / pi
+    }
+    /// current center frequency of the filter in Hz
+    pub fn center_frequency(&self) -> f32 {
+        self.centerFreq * self.sample_rate / pi
+    }
+    /// gain applied to the envelope before it moves the filter
+    pub fn set_sensitivity(&mut self, sensitivity: f32) {
+        self.sensitivity = sensitivity.max(0.);
+    }
+    pub fn set_direction(&mut self, direction: Direction) {
+        self.direction = direction;
+    }
+    pub fn set_manual(&mut self, manual: bool) {
+        self.manual = manual;
+    }
+    /// position of the expression pedal, 0 (heel) to 1 (toe)
+    pub fn set_pedal(&mut self, position: f32) {
+        self.pedal = position.max(0.).min(1.);
+    }
+    /// keeps the envelope times and the sweep range in seconds and Hz
     pub fn set_sample_rate(&mut self, sample_rate: f32) {
+        let (minFreq, maxFreq) = (self.min_frequency(), self.max_frequency());
+        let ratio = self.sample_rate / sample_rate;
+        self.alphaA = self.alphaA.powf(ratio);
+        self.betaA = 1. - self.alphaA;
+        self.alphaR = self.alphaR.powf(ratio);
+        self.betaR = 1. - self.alphaR;
         self.sample_rate = sample_rate;
+        self.set_min_maxFreq(minFreq, maxFreq);
     }
     pub fn set_quality_factor(&mut self, Q: f32) {
         self.q = Q;
@@ -191,7 +317,7 @@ impl AutoWah {
     }
 }
 
-enum FilterType {
+pub enum FilterType {
     Lowpass,
     Bandpass,
     Highpass
@@ -201,4 +327,79 @@ impl Default for FilterType {
     fn default() -> Self {
         FilterType::Highpass
     }
+}
+
+/// Whether a louder signal opens (up) or closes (down) the filter
+pub enum Direction {
+    Up,
+    Down,
+}
+
+impl Default for Direction {
+    fn default() -> Self {
+        Direction::Up
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AutoWah, Direction};
+    use effects::Effect;
+
+    /// center frequency after a block of constant level
+    fn settle(aw: &mut AutoWah, level: f32) -> f32 {
+        for _ in 0..48000 {
+            aw.run_effect(level);
+        }
+        aw.center_frequency()
+    }
+
+    #[test]
+    fn test_center_tracks_envelope() {
+        let mut aw = AutoWah::new(48000, 256);
+        aw.set_min_maxFreq(200., 2000.);
+
+        let quiet = settle(&mut aw, 0.05);
+        let loud = settle(&mut aw, 0.5);
+        assert!(quiet > 200. && quiet < loud);
+        assert!((loud - (200. + 0.5 * 1800.)).abs() < 20.);
+
+        let silent = settle(&mut aw, 0.);
+        assert!((silent - 200.).abs() < 1.);
+    }
+
+    #[test]
+    fn test_sample_rate_change() {
+        let mut aw = AutoWah::new(48000, 256);
+        aw.set_min_maxFreq(200., 2000.);
+        let before = settle(&mut aw, 0.5);
+
+        aw.sample_rate_changed(96000);
+        assert!((aw.min_frequency() - 200.).abs() < 0.1);
+        assert!((aw.max_frequency() - 2000.).abs() < 0.1);
+        assert!((settle(&mut aw, 0.5) - before).abs() < 1.);
+
+        // blocks shorter and longer than the one it was made for
+        for &len in [64, 1024].iter() {
+            let input = vec![0.1; len];
+            let mut l = vec![0.; len];
+            let mut r = vec![0.; len];
+            aw.process_samples(&input, &mut l, &mut r);
+            assert!(l[len - 1] != 0.);
+        }
+    }
+
+    #[test]
+    fn test_direction_and_manual() {
+        let mut aw = AutoWah::new(48000, 256);
+        aw.set_min_maxFreq(200., 2000.);
+
+        aw.set_direction(Direction::Down);
+        assert!((settle(&mut aw, 0.) - 2000.).abs() < 1.);
+
+        aw.set_direction(Direction::Up);
+        aw.set_manual(true);
+        aw.set_pedal(0.25);
+        assert!((settle(&mut aw, 0.5) - 650.).abs() < 1.);
+    }
 }
\ No newline at end of file
@@ -0,0 +1,176 @@
+//! Second-order IIR sections with coefficients from the RBJ
+//! Audio EQ Cookbook.
+
+use std::f32::consts::PI;
+
+/// Time in seconds for the coefficients to glide to new values
+const SMOOTHING_TIME: f32 = 0.01;
+
+/// Normalized coefficients, a0 = 1
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Coeffs {
+    pub b0: f32,
+    pub b1: f32,
+    pub b2: f32,
+    pub a1: f32,
+    pub a2: f32,
+}
+
+impl Coeffs {
+
+    /// passes the signal unchanged
+    pub fn identity() -> Self {
+        Coeffs { b0: 1., b1: 0., b2: 0., a1: 0., a2: 0. }
+    }
+
+    fn normalize(b0: f32, b1: f32, b2: f32, a0: f32, a1: f32, a2: f32) -> Self {
+        Coeffs {
+            b0: b0 / a0,
+            b1: b1 / a0,
+            b2: b2 / a0,
+            a1: a1 / a0,
+            a2: a2 / a0,
+        }
+    }
+
+    /// (cos w0, alpha) of the cookbook formulas
+    fn prewarp(sample_rate: f32, freq: f32, q: f32) -> (f32, f32) {
+        let w0 = 2. * PI * freq / sample_rate;
+        (w0.cos(), w0.sin() / (2. * q))
+    }
+
+    pub fn lowpass(sample_rate: f32, freq: f32, q: f32) -> Self {
+        let (cos, alpha) = Self::prewarp(sample_rate, freq, q);
+        Self::normalize(
+            (1. - cos) / 2., 1. - cos, (1. - cos) / 2.,
+            1. + alpha, -2. * cos, 1. - alpha)
+    }
+
+    pub fn highpass(sample_rate: f32, freq: f32, q: f32) -> Self {
+        let (cos, alpha) = Self::prewarp(sample_rate, freq, q);
+        Self::normalize(
+            (1. + cos) / 2., -(1. + cos), (1. + cos) / 2.,
+            1. + alpha, -2. * cos, 1. - alpha)
+    }
+
+    /// constant 0 dB peak gain
+    pub fn bandpass(sample_rate: f32, freq: f32, q: f32) -> Self {
+        let (cos, alpha) = Self::prewarp(sample_rate, freq, q);
+        Self::normalize(
+            alpha, 0., -alpha,
+            1. + alpha, -2. * cos, 1. - alpha)
+    }
+
+    pub fn allpass(sample_rate: f32, freq: f32, q: f32) -> Self {
+        let (cos, alpha) = Self::prewarp(sample_rate, freq, q);
+        Self::normalize(
+            1. - alpha, -2. * cos, 1. + alpha,
+            1. + alpha, -2. * cos, 1. - alpha)
+    }
+
+    pub fn peaking(sample_rate: f32, freq: f32, q: f32, gain_db: f32) -> Self {
+        let a = 10f32.powf(gain_db / 40.);
+        let (cos, alpha) = Self::prewarp(sample_rate, freq, q);
+        Self::normalize(
+            1. + alpha * a, -2. * cos, 1. - alpha * a,
+            1. + alpha / a, -2. * cos, 1. - alpha / a)
+    }
+
+    pub fn low_shelf(sample_rate: f32, freq: f32, q: f32, gain_db: f32) -> Self {
+        let a = 10f32.powf(gain_db / 40.);
+        let (cos, alpha) = Self::prewarp(sample_rate, freq, q);
+        let sq = 2. * a.sqrt() * alpha;
+        Self::normalize(
+            a * ((a + 1.) - (a - 1.) * cos + sq),
+            2. * a * ((a - 1.) - (a + 1.) * cos),
+            a * ((a + 1.) - (a - 1.) * cos - sq),
+            (a + 1.) + (a - 1.) * cos + sq,
+            -2. * ((a - 1.) + (a + 1.) * cos),
+            (a + 1.) + (a - 1.) * cos - sq)
+    }
+
+    pub fn high_shelf(sample_rate: f32, freq: f32, q: f32, gain_db: f32) -> Self {
+        let a = 10f32.powf(gain_db / 40.);
+        let (cos, alpha) = Self::prewarp(sample_rate, freq, q);
+        let sq = 2. * a.sqrt() * alpha;
+        Self::normalize(
+            a * ((a + 1.) + (a - 1.) * cos + sq),
+            -2. * a * ((a - 1.) + (a + 1.) * cos),
+            a * ((a + 1.) + (a - 1.) * cos - sq),
+            (a + 1.) - (a - 1.) * cos + sq,
+            2. * ((a - 1.) - (a + 1.) * cos),
+            (a + 1.) - (a - 1.) * cos - sq)
+    }
+}
+
+/// Transposed direct form II biquad. New coefficients are approached
+/// with a one-pole glide so parameter changes do not click.
+pub struct Biquad {
+    current: Coeffs,
+    target: Coeffs,
+    glide: f32,
+    z1: f32,
+    z2: f32,
+}
+
+impl Biquad {
+
+    pub fn new(sample_rate: f32) -> Self {
+        Biquad {
+            current: Coeffs::identity(),
+            target: Coeffs::identity(),
+            glide: (-1. / (SMOOTHING_TIME * sample_rate)).exp(),
+            z1: 0.,
+            z2: 0.,
+        }
+    }
+
+    /// only changes the glide, the caller recomputes the coefficients
+    pub fn set_sample_rate(&mut self, sample_rate: f32) {
+        self.glide = (-1. / (SMOOTHING_TIME * sample_rate)).exp();
+    }
+
+    /// glides to `coeffs` over a few milliseconds
+    pub fn set_coeffs(&mut self, coeffs: Coeffs) {
+        self.target = coeffs;
+    }
+
+    /// switches to `coeffs` immediately
+    pub fn snap_coeffs(&mut self, coeffs: Coeffs) {
+        self.target = coeffs;
+        self.current = coeffs;
+    }
+
+    pub fn reset(&mut self) {
+        self.z1 = 0.;
+        self.z2 = 0.;
+    }
+
+    pub fn process(&mut self, x: f32) -> f32 {
+        if self.current != self.target {
+            self.step_coeffs();
+        }
+        let c = self.current;
+        let y = c.b0 * x + self.z1;
+        self.z1 = c.b1 * x - c.a1 * y + self.z2;
+        self.z2 = c.b2 * x - c.a2 * y;
+        y
+    }
+
+    fn step_coeffs(&mut self) {
+        let g = self.glide;
+        let (c, t) = (&mut self.current, &self.target);
+        c.b0 = t.b0 + g * (c.b0 - t.b0);
+        c.b1 = t.b1 + g * (c.b1 - t.b1);
+        c.b2 = t.b2 + g * (c.b2 - t.b2);
+        c.a1 = t.a1 + g * (c.a1 - t.a1);
+        c.a2 = t.a2 + g * (c.a2 - t.a2);
+
+        // stop gliding once the difference is inaudible
+        let close = |a: f32, b: f32| (a - b).abs() < 1e-6;
+        if close(c.b0, t.b0) && close(c.b1, t.b1) && close(c.b2, t.b2)
+            && close(c.a1, t.a1) && close(c.a2, t.a2) {
+            *c = *t;
+        }
+    }
+}
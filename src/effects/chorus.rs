@@ -0,0 +1,185 @@
+use effects::{Effect, CtrlMsg, Param, params};
+use effects::delay_line::DelayLine;
+use effects::lfo::{Lfo, Waveform};
+
+const MAX_VOICES: usize = 4;
+/// Longest delay + depth in seconds
+const MAX_DELAY: f32 = 0.05;
+
+/// Multi-voice chorus. Every voice reads the same delay line with its
+/// own LFO phase; voices are panned alternately left and right by
+/// `spread` to widen the stereo image.
+pub struct Chorus {
+    pub bypassing: bool,
+    sample_rate: f32,
+    line: DelayLine,
+    lfo: Lfo,
+    voices: usize,
+    /// base delay and modulation depth in seconds
+    delay: f32,
+    depth: f32,
+    spread: f32,
+    mix: f32,
+}
+
+impl Chorus {
+
+    pub fn set_voices(&mut self, voices: usize) {
+        self.voices = voices.max(1).min(MAX_VOICES);
+    }
+
+    /// delay and depth are in milliseconds
+    pub fn set_delay(&mut self, ms: f32) {
+        self.delay = (ms / 1000.).max(0.001).min(MAX_DELAY / 2.);
+    }
+
+    pub fn set_depth(&mut self, ms: f32) {
+        self.depth = (ms / 1000.).max(0.).min(MAX_DELAY / 2.);
+    }
+
+    pub fn set_spread(&mut self, spread: f32) {
+        self.spread = spread.max(0.).min(1.);
+    }
+
+    pub fn set_mix(&mut self, mix: f32) {
+        self.mix = mix.max(0.).min(1.);
+    }
+}
+
+impl Effect for Chorus {
+
+    fn new(sample_rate: usize, _frame_size: u32) -> Self {
+        let sample_rate = sample_rate as f32;
+        Chorus {
+            bypassing: false,
+            sample_rate,
+            line: DelayLine::new((MAX_DELAY * sample_rate) as usize),
+            lfo: Lfo::new(sample_rate, 0.8),
+            voices: 2,
+            delay: 0.015,
+            depth: 0.003,
+            spread: 0.8,
+            mix: 0.5,
+        }
+    }
+
+    fn name(&self) -> &str {
+        "chorus"
+    }
+
+    fn process_samples(&mut self, input: &[f32], output_l: &mut [f32], output_r: &mut [f32]) {
+
+        if self.bypassing {
+            output_l.clone_from_slice(input);
+            output_r.clone_from_slice(input);
+            return;
+        }
+
+        let gain = 1. / self.voices as f32;
+        for (i, x) in input.iter().enumerate() {
+            self.line.push(*x);
+
+            let (mut wet_l, mut wet_r) = (0., 0.);
+            for v in 0..self.voices {
+                let offset = v as f32 / self.voices as f32;
+                let delay = self.delay + self.depth * self.lfo.value_at(offset);
+                let y = self.line.read_cubic(delay * self.sample_rate) * gain;
+
+                // odd voices lean right, even voices lean left
+                let pan = if self.voices == 1 {
+                    0.
+                } else if v % 2 == 0 {
+                    -self.spread
+                } else {
+                    self.spread
+                };
+                wet_l += y * (1. - pan) / 2.;
+                wet_r += y * (1. + pan) / 2.;
+            }
+            self.lfo.advance();
+
+            // the pan law above halves a centered voice
+            output_l[i] = (1. - self.mix) * x + self.mix * 2. * wet_l;
+            output_r[i] = (1. - self.mix) * x + self.mix * 2. * wet_r;
+        }
+    }
+
+    fn sample_rate_changed(&mut self, sample_rate: usize) {
+        self.sample_rate = sample_rate as f32;
+        self.line = DelayLine::new((MAX_DELAY * self.sample_rate) as usize);
+        self.lfo.set_sample_rate(self.sample_rate);
+    }
+
+    fn params(&self) -> Vec<(String, Param)> {
+        use self::Param::*;
+        params(&[
+            ("rate", Float(self.lfo.rate())),
+            ("depth", Float(self.depth * 1000.)),
+            ("delay", Float(self.delay * 1000.)),
+            ("voices", Int(self.voices as i32)),
+            ("spread", Float(self.spread)),
+            ("mix", Float(self.mix)),
+            ("wave", Name(self.lfo.waveform.name().to_owned())),
+        ])
+    }
+
+    fn bypass(&mut self) {
+        self.bypassing = !self.bypassing;
+    }
+
+    fn is_bypassing(&self) -> bool {
+        self.bypassing
+    }
+
+    fn set(&mut self, param: &str, val: f32) {
+        match param {
+            "rate" => self.lfo.set_rate(val),
+            "depth" => self.set_depth(val),
+            "delay" => self.set_delay(val),
+            "voices" => self.set_voices(val as usize),
+            "spread" => self.set_spread(val),
+            "mix" => self.set_mix(val),
+            _ => (),
+        }
+    }
+
+    fn ctrl(&mut self, msg: CtrlMsg) {
+        use self::CtrlMsg::*;
+        match msg {
+            Bypass => self.bypass(),
+            Set(_pedal_name, conf_name, val) => self.set(&conf_name, val),
+            Select(_pedal_name, conf_name, val) => {
+                if &conf_name == "wave" {
+                    match Waveform::from_name(&val) {
+                        Some(w) => self.lfo.waveform = w,
+                        None => println!("Unknown waveform: {}", val),
+                    }
+                }
+            },
+            _ => (),
+        }
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Chorus;
+    use effects::Effect;
+
+    #[test]
+    fn test_voice_at_delay() {
+        // one unswept voice is the input 15 ms late, mixed half and half
+        let mut chorus = Chorus::new(48000, 1024);
+        chorus.set_voices(1);
+        chorus.set_depth(0.);
+        let mut input = vec![0.; 1024];
+        input[0] = 1.;
+        let (mut l, mut r) = (vec![0.; 1024], vec![0.; 1024]);
+        chorus.process_samples(&input, &mut l, &mut r);
+        assert_eq!(l[0], 0.5);
+        assert!((l[720] - 0.5).abs() < 1e-3);
+        assert!(l[1..719].iter().all(|y| y.abs() < 1e-3));
+        assert_eq!(l, r);
+    }
+}
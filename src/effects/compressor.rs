@@ -0,0 +1,301 @@
+use effects::{Effect, CtrlMsg, Param, params};
+use effects::dynamics::{db_to_gain, gain_to_db, time_coeff, Lookahead};
+
+/// Longest lookahead in seconds, allocated up front
+const MAX_LOOKAHEAD: f32 = 0.01;
+/// Averaging time of the RMS detector in seconds
+const RMS_WINDOW: f32 = 0.01;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Detection {
+    Peak,
+    Rms,
+}
+
+/// Feed-forward compressor with a soft knee.
+///
+/// The gain computer works in dB on the detected level; the resulting
+/// gain reduction is smoothed with separate attack and release times.
+/// With lookahead the audio path is delayed so the gain can come down
+/// before a transient passes.
+pub struct Compressor {
+    pub bypassing: bool,
+    sample_rate: f32,
+
+    threshold: f32,
+    ratio: f32,
+    knee: f32,
+    /// attack, release and lookahead in seconds
+    attack: f32,
+    release: f32,
+    lookahead_time: f32,
+    attack_coeff: f32,
+    release_coeff: f32,
+    makeup: f32,
+    mix: f32,
+    detection: Detection,
+
+    rms_coeff: f32,
+    mean_square: f32,
+    lookahead: Lookahead,
+
+    /// smoothed gain reduction in dB, <= 0
+    reduction: f32,
+    /// largest gain reduction since the last meter reading
+    max_reduction: f32,
+}
+
+impl Compressor {
+
+    /// threshold in dBFS
+    pub fn set_threshold(&mut self, db: f32) {
+        self.threshold = db.min(0.);
+    }
+
+    pub fn set_ratio(&mut self, ratio: f32) {
+        self.ratio = ratio.max(1.);
+    }
+
+    /// knee width in dB, 0 for a hard knee
+    pub fn set_knee(&mut self, db: f32) {
+        self.knee = db.max(0.);
+    }
+
+    /// attack and release are in seconds
+    pub fn set_attack(&mut self, t: f32) {
+        self.attack = t;
+        self.attack_coeff = time_coeff(t, self.sample_rate);
+    }
+
+    pub fn set_release(&mut self, t: f32) {
+        self.release = t;
+        self.release_coeff = time_coeff(t, self.sample_rate);
+    }
+
+    /// makeup gain in dB
+    pub fn set_makeup(&mut self, db: f32) {
+        self.makeup = db;
+    }
+
+    /// 1 is fully compressed, 0 is dry (parallel compression in between)
+    pub fn set_mix(&mut self, mix: f32) {
+        self.mix = mix.max(0.).min(1.);
+    }
+
+    pub fn set_detection(&mut self, detection: Detection) {
+        self.detection = detection;
+        self.mean_square = 0.;
+    }
+
+    /// lookahead in seconds, up to 10 ms
+    pub fn set_lookahead(&mut self, t: f32) {
+        self.lookahead_time = t;
+        self.lookahead.set_len((t.max(0.) * self.sample_rate) as usize);
+    }
+
+    /// Static curve: output level in dB for an input level in dB
+    pub fn gain_computer(&self, x: f32) -> f32 {
+        let over = x - self.threshold;
+        if 2. * over < -self.knee {
+            x
+        } else if self.knee > 0. && 2. * over.abs() <= self.knee {
+            let t = over + self.knee / 2.;
+            x + (1. / self.ratio - 1.) * t * t / (2. * self.knee)
+        } else {
+            self.threshold + over / self.ratio
+        }
+    }
+
+    /// current gain reduction in dB
+    pub fn gain_reduction(&self) -> f32 {
+        self.reduction
+    }
+
+    fn detect(&mut self, x: f32) -> f32 {
+        match self.detection {
+            Detection::Peak => x.abs(),
+            Detection::Rms => {
+                self.mean_square = self.rms_coeff * self.mean_square
+                    + (1. - self.rms_coeff) * x * x;
+                self.mean_square.sqrt()
+            }
+        }
+    }
+
+    fn print_meter(&mut self) {
+        println!("{}: gain reduction {:.1} dB (max {:.1} dB)",
+            self.name(), self.reduction, self.max_reduction);
+        self.max_reduction = 0.;
+    }
+}
+
+impl Effect for Compressor {
+
+    fn new(sample_rate: usize, _frame_size: u32) -> Self {
+        let sample_rate = sample_rate as f32;
+        let mut comp = Compressor {
+            bypassing: false,
+            sample_rate,
+            threshold: -20.,
+            ratio: 4.,
+            knee: 6.,
+            attack: 0.,
+            release: 0.,
+            lookahead_time: 0.,
+            attack_coeff: 0.,
+            release_coeff: 0.,
+            makeup: 0.,
+            mix: 1.,
+            detection: Detection::Peak,
+            rms_coeff: time_coeff(RMS_WINDOW, sample_rate),
+            mean_square: 0.,
+            lookahead: Lookahead::new((MAX_LOOKAHEAD * sample_rate) as usize),
+            reduction: 0.,
+            max_reduction: 0.,
+        };
+        comp.set_attack(0.01);
+        comp.set_release(0.1);
+        comp
+    }
+
+    fn name(&self) -> &str {
+        "compressor"
+    }
+
+    fn process_samples(&mut self, input: &[f32], output_l: &mut [f32], output_r: &mut [f32]) {
+
+        if self.bypassing {
+            output_l.clone_from_slice(input);
+            output_r.clone_from_slice(input);
+            return;
+        }
+
+        for (i, x) in input.iter().enumerate() {
+            let level = gain_to_db(self.detect(*x));
+            let target = self.gain_computer(level) - level;
+
+            let coeff = if target < self.reduction {
+                self.attack_coeff
+            } else {
+                self.release_coeff
+            };
+            self.reduction = target + coeff * (self.reduction - target);
+            self.max_reduction = self.max_reduction.min(self.reduction);
+
+            let dry = self.lookahead.process(*x);
+            let wet = dry * db_to_gain(self.reduction + self.makeup);
+            let y = self.mix * wet + (1. - self.mix) * dry;
+            output_l[i] = y;
+            output_r[i] = y;
+        }
+    }
+
+    fn sample_rate_changed(&mut self, sample_rate: usize) {
+        self.sample_rate = sample_rate as f32;
+        self.rms_coeff = time_coeff(RMS_WINDOW, self.sample_rate);
+        self.lookahead = Lookahead::new((MAX_LOOKAHEAD * self.sample_rate) as usize);
+        let (attack, release, lookahead) = (self.attack, self.release, self.lookahead_time);
+        self.set_attack(attack);
+        self.set_release(release);
+        self.set_lookahead(lookahead);
+    }
+
+    fn params(&self) -> Vec<(String, Param)> {
+        use self::Param::*;
+        params(&[
+            ("threshold", Float(self.threshold)),
+            ("ratio", Float(self.ratio)),
+            ("knee", Float(self.knee)),
+            ("attack", Float(self.attack)),
+            ("release", Float(self.release)),
+            ("makeup", Float(self.makeup)),
+            ("mix", Float(self.mix)),
+            ("lookahead", Float(self.lookahead_time)),
+            ("detect", Name(match self.detection {
+                Detection::Peak => "peak",
+                Detection::Rms => "rms",
+            }.to_owned())),
+        ])
+    }
+
+    fn bypass(&mut self) {
+        self.bypassing = !self.bypassing;
+    }
+
+    fn is_bypassing(&self) -> bool {
+        self.bypassing
+    }
+
+    fn set(&mut self, param: &str, val: f32) {
+        match param {
+            "threshold" => self.set_threshold(val),
+            "ratio" => self.set_ratio(val),
+            "knee" => self.set_knee(val),
+            "attack" => self.set_attack(val),
+            "release" => self.set_release(val),
+            "makeup" => self.set_makeup(val),
+            "mix" => self.set_mix(val),
+            "lookahead" => self.set_lookahead(val),
+            _ => (),
+        }
+    }
+
+    fn ctrl(&mut self, msg: CtrlMsg) {
+        use self::CtrlMsg::*;
+        match msg {
+            Bypass => self.bypass(),
+            Set(_pedal_name, conf_name, val) => self.set(&conf_name, val),
+            Select(_pedal_name, conf_name, val) => {
+                if &conf_name == "detect" {
+                    match val.as_str() {
+                        "peak" => self.set_detection(Detection::Peak),
+                        "rms" => self.set_detection(Detection::Rms),
+                        _ => println!("Unknown detection: {} (peak, rms)", val),
+                    }
+                }
+            },
+            Info(_) => self.print_meter(),
+            _ => (),
+        }
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Compressor;
+    use effects::Effect;
+
+    #[test]
+    fn test_gain_computer() {
+        let mut comp = Compressor::new(48000, 256);
+        comp.set_threshold(-20.);
+        comp.set_ratio(4.);
+        comp.set_knee(0.);
+
+        assert_eq!(comp.gain_computer(-30.), -30.);
+        assert_eq!(comp.gain_computer(-20.), -20.);
+        assert_eq!(comp.gain_computer(0.), -15.);
+
+        // the soft knee stays between the two straight segments
+        comp.set_knee(10.);
+        let y = comp.gain_computer(-20.);
+        assert!(y < -20. && y > -20. - 10. * 0.75 / 2.);
+    }
+
+    #[test]
+    fn test_steady_state_reduction() {
+        let mut comp = Compressor::new(48000, 256);
+        comp.set_threshold(-20.);
+        comp.set_ratio(4.);
+        comp.set_knee(0.);
+
+        let input = vec![0.5; 48000];
+        let mut l = vec![0.; 48000];
+        let mut r = vec![0.; 48000];
+        comp.process_samples(&input, &mut l, &mut r);
+
+        // -6 dB in, 14 dB over the threshold, reduced by 3/4
+        assert!((comp.gain_reduction() + 10.5).abs() < 0.1);
+    }
+}
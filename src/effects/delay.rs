@@ -1,49 +1,169 @@
-use effects::{Effect, CtrlMsg};
+use effects::{Effect, CtrlMsg, Param, params};
+use effects::biquad::{Biquad, Coeffs};
+use effects::delay_line::DelayLine;
+use effects::dynamics::time_coeff;
+use effects::lfo::Lfo;
 
+/// Longest delay time in seconds, allocated up front
+const MAX_DELAY: f32 = 5.;
+/// Largest feedback that still dies out with saturation off
+const MAX_FEEDBACK: f32 = 0.98;
+/// Time in seconds for the read head to glide to a new delay time
+const GLIDE_TIME: f32 = 0.05;
+/// Rates of the slow and fast tape speed variations in Hz
+const WOW_RATE: f32 = 0.6;
+const FLUTTER_RATE: f32 = 7.;
+
+/// Delay with an interpolated read head.
+///
+/// The repeats go through a low-cut and high-cut filter and an optional
+/// saturator before being fed back. Wow and flutter wobble the read head
+/// like the capstan of a tape echo. Changing the delay time glides the
+/// head instead of jumping, which bends the pitch of the repeats.
 pub struct Delay {
     pub bypassing: bool,
-    delay_buffer: Vec<f32>,
-    delay_buffer_size: usize,
+    sample_rate: f32,
+    line: DelayLine,
+
+    /// delay time in seconds, the head glides towards it
+    delay: f32,
+    head: f32,
+    glide: f32,
+    /// length of the delay in beats when synced to the tempo, 0 is free
+    sync: f32,
+    tempo: Option<f32>,
+
     feedback: f32,
-    i_idx: usize,
-    o_idx: usize,
-    sample_rate: usize,
-    delay_time: usize,
-    frame_size: u32,
+    mix: f32,
+    drive: f32,
+    low_cut: Biquad,
+    high_cut: Biquad,
+    /// filter cutoffs in Hz
+    low_cut_freq: f32,
+    high_cut_freq: f32,
+
+    wow: Lfo,
+    flutter: Lfo,
+    /// depth of the speed variations in seconds
+    wow_depth: f32,
+    flutter_depth: f32,
 }
 
 impl Delay {
 
     /// t is in seconds
     pub fn set_delay(&mut self, t: f32) {
-        let delay_time = (t * self.sample_rate as f32) as usize;
-        assert!(delay_time < self.delay_buffer_size);
-        self.delay_time = delay_time;
+        self.delay = clamp("delay", t, 0.001, MAX_DELAY);
     }
 
     pub fn set_feedback(&mut self, f: f32) {
-        assert!(f < 1.); // multiplying by > 1 would be too loud
-        self.feedback = f;
+        // multiplying by > 1 would be too loud
+        self.feedback = clamp("feedback", f, 0., MAX_FEEDBACK);
+    }
+
+    pub fn set_mix(&mut self, mix: f32) {
+        self.mix = clamp("mix", mix, 0., 1.);
+    }
+
+    /// delay in beats, e.g. 0.75 for a dotted eighth; 0 to unsync
+    pub fn set_sync(&mut self, beats: f32) {
+        self.sync = beats.max(0.);
+        self.update_sync();
+    }
+
+    pub fn set_tempo(&mut self, bpm: f32) {
+        self.tempo = if bpm > 0. { Some(bpm) } else { None };
+        self.update_sync();
     }
 
+    /// cutoffs of the filters in the feedback path, in Hz
+    pub fn set_low_cut(&mut self, freq: f32) {
+        let freq = clamp("lowcut", freq, 20., 2000.);
+        self.low_cut_freq = freq;
+        self.low_cut.set_coeffs(Coeffs::highpass(self.sample_rate, freq, 0.707));
+    }
 
+    pub fn set_high_cut(&mut self, freq: f32) {
+        let freq = clamp("highcut", freq, 500., 0.45 * self.sample_rate);
+        self.high_cut_freq = freq;
+        self.high_cut.set_coeffs(Coeffs::lowpass(self.sample_rate, freq, 0.707));
+    }
+
+    /// saturation of the repeats, 0 is clean
+    pub fn set_drive(&mut self, drive: f32) {
+        self.drive = clamp("drive", drive, 0., 1.);
+    }
+
+    /// depths of the speed variations in milliseconds
+    pub fn set_wow(&mut self, ms: f32) {
+        self.wow_depth = clamp("wow", ms, 0., 10.) / 1000.;
+    }
+
+    pub fn set_flutter(&mut self, ms: f32) {
+        self.flutter_depth = clamp("flutter", ms, 0., 2.) / 1000.;
+    }
+
+    fn update_sync(&mut self) {
+        if let Some(bpm) = self.tempo {
+            if self.sync > 0. {
+                let t = 60. / bpm * self.sync;
+                self.set_delay(t);
+            }
+        }
+    }
+
+    fn snap_filters(&mut self) {
+        let sr = self.sample_rate;
+        self.low_cut.snap_coeffs(Coeffs::highpass(sr, self.low_cut_freq, 0.707));
+        self.high_cut.snap_coeffs(Coeffs::lowpass(sr, self.high_cut_freq.min(0.45 * sr), 0.707));
+    }
+
+    fn saturate(&self, x: f32) -> f32 {
+        if self.drive == 0. {
+            return x;
+        }
+        // unity gain for small signals, rounds off the loud repeats
+        let k = 1. + 4. * self.drive;
+        (k * x).tanh() / k
+    }
+}
+
+/// Clamps `val` to `min..max`, telling the user when it had to
+fn clamp(name: &str, val: f32, min: f32, max: f32) -> f32 {
+    let clamped = val.max(min).min(max);
+    if clamped != val {
+        println!("delay: {} {} is out of range, using {}", name, val, clamped);
+    }
+    clamped
 }
 
 impl Effect for Delay {
 
-    fn new(sample_rate: usize, frame_size: u32) -> Self {
-        let dbs = sample_rate;
-        Delay {
+    fn new(sample_rate: usize, _frame_size: u32) -> Self {
+        let sr = sample_rate as f32;
+        let mut delay = Delay {
             bypassing: false,
-            delay_buffer_size: dbs,
-            delay_buffer: vec![0.; dbs],
+            sample_rate: sr,
+            line: DelayLine::new((MAX_DELAY * sr) as usize + 2),
+            delay: 0.2,
+            head: 0.2,
+            glide: time_coeff(GLIDE_TIME, sr),
+            sync: 0.,
+            tempo: None,
             feedback: 0.3,
-            i_idx: 0,
-            o_idx: 0,
-            delay_time: 8820,
-            sample_rate,
-            frame_size
-        }
+            mix: 0.35,
+            drive: 0.,
+            low_cut: Biquad::new(sr),
+            high_cut: Biquad::new(sr),
+            low_cut_freq: 80.,
+            high_cut_freq: 6000.,
+            wow: Lfo::new(sr, WOW_RATE),
+            flutter: Lfo::new(sr, FLUTTER_RATE),
+            wow_depth: 0.,
+            flutter_depth: 0.,
+        };
+        delay.snap_filters();
+        delay
     }
 
     fn name(&self) -> &str {
@@ -58,29 +178,51 @@ impl Effect for Delay {
             return;
         }
 
-        for bufptr in 0..self.frame_size as usize {
-            if self.i_idx >= self.delay_buffer_size {
-                self.i_idx = 0;
-            }
-            
-            self.o_idx = if self.i_idx >= self.delay_time {
-                self.i_idx - self.delay_time
-            } else {
-                self.delay_buffer_size as usize + self.i_idx - self.delay_time
-            };
+        for (bufptr, x) in input.iter().enumerate() {
+            self.head = self.delay + self.glide * (self.head - self.delay);
+            let modulation = self.wow_depth * (1. + self.wow.next())
+                + self.flutter_depth * (1. + self.flutter.next());
+
+            // read before writing, so one sample of the delay has passed
+            let t = (self.head + modulation) * self.sample_rate - 1.;
+            let wet = self.line.read_cubic(t);
 
-            let y = input[bufptr] + (self.delay_buffer[self.o_idx] * self.feedback);
-            self.delay_buffer[self.i_idx] = y;
-            let out = (y + 0.5).cos();
+            let repeat = self.saturate(wet);
+            let repeat = self.high_cut.process(self.low_cut.process(repeat));
+            self.line.push(x + repeat * self.feedback);
 
+            let out = (1. - self.mix) * x + self.mix * wet;
             output_r[bufptr] = out;
             output_l[bufptr] = out;
-            
-            self.i_idx += 1;
         }
+    }
 
-        // output_l.clone_from_slice(&slice);
-        // output_r.clone_from_slice(&slice);
+    fn sample_rate_changed(&mut self, sample_rate: usize) {
+        let sr = sample_rate as f32;
+        self.sample_rate = sr;
+        self.line = DelayLine::new((MAX_DELAY * sr) as usize + 2);
+        self.glide = time_coeff(GLIDE_TIME, sr);
+        self.low_cut.set_sample_rate(sr);
+        self.high_cut.set_sample_rate(sr);
+        self.snap_filters();
+        self.wow.set_sample_rate(sr);
+        self.flutter.set_sample_rate(sr);
+    }
+
+    fn params(&self) -> Vec<(String, Param)> {
+        use self::Param::*;
+        params(&[
+            // before sync, setting the time unsyncs
+            ("delay", Float(self.delay)),
+            ("sync", Float(self.sync)),
+            ("feedback", Float(self.feedback)),
+            ("mix", Float(self.mix)),
+            ("lowcut", Float(self.low_cut_freq)),
+            ("highcut", Float(self.high_cut_freq)),
+            ("drive", Float(self.drive)),
+            ("wow", Float(self.wow_depth * 1000.)),
+            ("flutter", Float(self.flutter_depth * 1000.)),
+        ])
     }
 
     fn bypass(&mut self) {
@@ -91,19 +233,74 @@ impl Effect for Delay {
         self.bypassing
     }
 
+    fn set(&mut self, param: &str, val: f32) {
+        match param {
+            "feedback" => self.set_feedback(val),
+            "delay" => {
+                self.sync = 0.;
+                self.set_delay(val);
+            },
+            "mix" => self.set_mix(val),
+            "sync" => self.set_sync(val),
+            "lowcut" => self.set_low_cut(val),
+            "highcut" => self.set_high_cut(val),
+            "drive" => self.set_drive(val),
+            "wow" => self.set_wow(val),
+            "flutter" => self.set_flutter(val),
+            _ => (),
+        }
+    }
+
     fn ctrl(&mut self, msg: CtrlMsg) {
         use self::CtrlMsg::*;
         match msg {
             Bypass => self.bypass(),
-            Set(_pedal_name, conf_name, val) => {
-                if &conf_name == "feedback" {
-                    self.set_feedback(val);
-                } else if &conf_name == "delay" {
-                    self.set_delay(val);
-                }
-            },
+            Set(_pedal_name, conf_name, val) => self.set(&conf_name, val),
+            Tempo(bpm) => self.set_tempo(bpm),
             _ => (),
         }
     }
 
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Delay;
+    use effects::Effect;
+
+    #[test]
+    fn test_echo_time_and_mix() {
+        let mut delay = Delay::new(48000, 256);
+        delay.set_mix(0.5);
+        delay.set_feedback(0.);
+
+        let mut input = vec![0.; 24000];
+        input[0] = 1.;
+        let mut l = vec![0.; 24000];
+        let mut r = vec![0.; 24000];
+        delay.process_samples(&input, &mut l, &mut r);
+
+        assert_eq!(l[0], 0.5);
+        // the echo lands 200 ms later
+        let echo = l[1..].iter().cloned().fold(0., f32::max);
+        let at = l[1..].iter().position(|&y| y == echo).unwrap() + 1;
+        assert_eq!(at, 9600);
+        assert!((echo - 0.5).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_out_of_range_values_are_clamped() {
+        let mut delay = Delay::new(48000, 256);
+        delay.set_feedback(1.5);
+        delay.set_delay(60.);
+
+        // full scale noise-free input must not blow up
+        let input = vec![1.; 48000];
+        let mut l = vec![0.; 48000];
+        let mut r = vec![0.; 48000];
+        for _ in 0..10 {
+            delay.process_samples(&input, &mut l, &mut r);
+        }
+        assert!(l.iter().all(|y| y.is_finite() && y.abs() < 100.));
+    }
+}
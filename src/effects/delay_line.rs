@@ -0,0 +1,88 @@
+//! Circular buffer read at fractional delays, shared by the
+//! modulation and delay effects.
+
+/// The buffer is allocated once in `new`, so reading and writing never
+/// allocates on the audio thread.
+pub struct DelayLine {
+    buffer: Vec<f32>,
+    /// position of the most recent sample
+    idx: usize,
+}
+
+impl DelayLine {
+
+    /// `max_delay` in samples
+    pub fn new(max_delay: usize) -> Self {
+        DelayLine {
+            // room for the extra points of the cubic interpolation
+            buffer: vec![0.; max_delay + 4],
+            idx: 0,
+        }
+    }
+
+    /// longest delay that can be read, in samples
+    pub fn max_delay(&self) -> usize {
+        self.buffer.len() - 4
+    }
+
+    pub fn clear(&mut self) {
+        for x in self.buffer.iter_mut() {
+            *x = 0.;
+        }
+    }
+
+    pub fn push(&mut self, x: f32) {
+        self.idx = (self.idx + 1) % self.buffer.len();
+        self.buffer[self.idx] = x;
+    }
+
+    /// sample pushed `delay` samples ago, 0 being the latest
+    pub fn tap(&self, delay: usize) -> f32 {
+        let len = self.buffer.len();
+        self.buffer[(self.idx + len - delay % len) % len]
+    }
+
+    /// linear interpolation between the two neighbouring samples
+    pub fn read_linear(&self, delay: f32) -> f32 {
+        let delay = delay.max(0.).min(self.max_delay() as f32);
+        let i = delay.floor();
+        let frac = delay - i;
+        let i = i as usize;
+        let a = self.tap(i);
+        let b = self.tap(i + 1);
+        a + frac * (b - a)
+    }
+
+    /// 4-point Hermite interpolation, smoother for modulated reads
+    pub fn read_cubic(&self, delay: f32) -> f32 {
+        let delay = delay.max(1.).min(self.max_delay() as f32);
+        let i = delay.floor();
+        let t = delay - i;
+        let i = i as usize;
+        let (y0, y1, y2, y3) = (self.tap(i - 1), self.tap(i), self.tap(i + 1), self.tap(i + 2));
+
+        let c1 = 0.5 * (y2 - y0);
+        let c2 = y0 - 2.5 * y1 + 2. * y2 - 0.5 * y3;
+        let c3 = 0.5 * (y3 - y0) + 1.5 * (y1 - y2);
+        ((c3 * t + c2) * t + c1) * t + y1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DelayLine;
+
+    #[test]
+    fn test_fractional_read() {
+        let mut line = DelayLine::new(16);
+        for i in 0..32 {
+            line.push(i as f32);
+        }
+        // a ramp is reproduced exactly by both interpolations
+        assert_eq!(line.tap(0), 31.);
+        assert_eq!(line.read_linear(2.5), 28.5);
+        assert!((line.read_cubic(3.25) - 27.75).abs() < 1e-5);
+        // reads past the end are clamped to the longest delay
+        assert_eq!(line.read_linear(100.), 31. - 16.);
+    }
+}
@@ -0,0 +1,54 @@
+//! Helpers shared by the level-dependent effects (gate, compressor, ...)
+
+/// Converts decibels to a linear gain factor
+pub fn db_to_gain(db: f32) -> f32 {
+    10f32.powf(db / 20.)
+}
+
+/// Converts a linear gain factor to decibels, floored at -180 dB
+pub fn gain_to_db(gain: f32) -> f32 {
+    20. * gain.max(1e-9).log10()
+}
+
+/// Coefficient of a one-pole smoother reaching ~63% of a step
+/// after `t` seconds
+pub fn time_coeff(t: f32, sample_rate: f32) -> f32 {
+    if t <= 0. {
+        0.
+    } else {
+        (-1. / (t * sample_rate)).exp()
+    }
+}
+
+/// Fixed-size delay used to look ahead of the signal being processed.
+/// The buffer is allocated once for `max_len` samples.
+pub struct Lookahead {
+    buffer: Vec<f32>,
+    idx: usize,
+    len: usize,
+}
+
+impl Lookahead {
+
+    pub fn new(max_len: usize) -> Self {
+        Lookahead {
+            buffer: vec![0.; max_len + 1],
+            idx: 0,
+            len: 0,
+        }
+    }
+
+    /// delay in samples, clamped to the allocated size
+    pub fn set_len(&mut self, len: usize) {
+        self.len = len.min(self.buffer.len() - 1);
+    }
+
+    /// pushes a sample and returns the one delayed by `len` samples
+    pub fn process(&mut self, x: f32) -> f32 {
+        let size = self.buffer.len();
+        self.buffer[self.idx] = x;
+        let out = self.buffer[(self.idx + size - self.len) % size];
+        self.idx = (self.idx + 1) % size;
+        out
+    }
+}
@@ -0,0 +1,364 @@
+use effects::{Effect, CtrlMsg, Param, params};
+use effects::biquad::{Biquad, Coeffs};
+use effects::dynamics::db_to_gain;
+use std::f32::consts::PI;
+
+/// Highest slope of the pass filters is 4 * 12 dB/oct
+const MAX_STAGES: usize = 4;
+
+/// Center frequencies of the graphic EQ, one per octave
+const GRAPHIC_FREQS: [f32; 10] = [
+    31., 62., 125., 250., 500., 1000., 2000., 4000., 8000., 16000.
+];
+/// Q of a one octave wide band
+const GRAPHIC_Q: f32 = 1.41;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BandType {
+    Off,
+    Peak,
+    LowShelf,
+    HighShelf,
+    LowPass,
+    HighPass,
+}
+
+impl BandType {
+    fn from_name(name: &str) -> Option<BandType> {
+        use self::BandType::*;
+        match name {
+            "off" => Some(Off),
+            "peak" => Some(Peak),
+            "lowshelf" => Some(LowShelf),
+            "highshelf" => Some(HighShelf),
+            "lowpass" => Some(LowPass),
+            "highpass" => Some(HighPass),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        use self::BandType::*;
+        match *self {
+            Off => "off",
+            Peak => "peak",
+            LowShelf => "lowshelf",
+            HighShelf => "highshelf",
+            LowPass => "lowpass",
+            HighPass => "highpass",
+        }
+    }
+}
+
+/// One band of the equalizer. Pass filters cascade up to four
+/// Butterworth sections for slopes of 12 to 48 dB/oct.
+pub struct Band {
+    typ: BandType,
+    freq: f32,
+    gain: f32,
+    q: f32,
+    /// number of 12 dB/oct sections of the pass filters
+    order: usize,
+    stages: Vec<Biquad>,
+}
+
+impl Band {
+
+    fn new(sample_rate: f32, typ: BandType, freq: f32, q: f32) -> Self {
+        Band {
+            typ,
+            freq,
+            gain: 0.,
+            q,
+            order: 1,
+            stages: (0..MAX_STAGES).map(|_| Biquad::new(sample_rate)).collect(),
+        }
+    }
+
+    fn active_stages(&self) -> usize {
+        match self.typ {
+            BandType::Off => 0,
+            BandType::LowPass | BandType::HighPass => self.order,
+            _ => 1,
+        }
+    }
+
+    /// recomputes the sections, gliding to the new response unless `snap`
+    fn update(&mut self, sample_rate: f32, snap: bool) {
+        use self::BandType::*;
+        let freq = self.freq.max(10.).min(0.45 * sample_rate);
+        let n = self.order;
+        let active = self.active_stages();
+        for (k, stage) in self.stages.iter_mut().enumerate() {
+            // a single pass section uses the band's Q, cascades are Butterworth
+            let q = if n == 1 {
+                self.q
+            } else {
+                1. / (2. * ((2 * k + 1) as f32 * PI / (4 * n) as f32).sin())
+            };
+            let coeffs = match self.typ {
+                Peak => Coeffs::peaking(sample_rate, freq, self.q, self.gain),
+                LowShelf => Coeffs::low_shelf(sample_rate, freq, self.q, self.gain),
+                HighShelf => Coeffs::high_shelf(sample_rate, freq, self.q, self.gain),
+                LowPass => Coeffs::lowpass(sample_rate, freq, q),
+                HighPass => Coeffs::highpass(sample_rate, freq, q),
+                Off => Coeffs::identity(),
+            };
+            if k < active && snap {
+                stage.snap_coeffs(coeffs);
+            } else if k < active {
+                stage.set_coeffs(coeffs);
+            } else {
+                // idle sections restart from silence when enabled again
+                stage.snap_coeffs(Coeffs::identity());
+                stage.reset();
+            }
+        }
+    }
+
+    fn process(&mut self, x: f32) -> f32 {
+        let n = self.active_stages();
+        self.stages[..n].iter_mut().fold(x, |y, stage| stage.process(y))
+    }
+}
+
+/// Multi-band equalizer. Bands are addressed as `b1`, `b2`, ... and
+/// configured with `s eq b2.freq 800`, `s eq b2.gain -3`, `s eq b2.q 2`,
+/// `s eq b2.slope 24` and `s eq b2.type peak`.
+pub struct Equalizer {
+    pub bypassing: bool,
+    sample_rate: f32,
+    bands: Vec<Band>,
+    /// output gain in dB
+    gain: f32,
+}
+
+impl Equalizer {
+
+    /// Ten peaking bands an octave apart, adjusted with `s geq b3.gain 4`
+    pub fn graphic(sample_rate: usize, _frame_size: u32) -> Self {
+        let sample_rate = sample_rate as f32;
+        let bands = GRAPHIC_FREQS.iter()
+            .map(|&f| Band::new(sample_rate, BandType::Peak, f, GRAPHIC_Q))
+            .collect();
+        Self::with_bands(sample_rate, bands)
+    }
+
+    fn with_bands(sample_rate: f32, bands: Vec<Band>) -> Self {
+        let mut eq = Equalizer {
+            bypassing: false,
+            sample_rate,
+            bands,
+            gain: 0.,
+        };
+        for band in eq.bands.iter_mut() {
+            band.update(sample_rate, true);
+        }
+        eq
+    }
+
+    pub fn set_band(&mut self, band: usize, param: &str, val: f32) {
+        let sample_rate = self.sample_rate;
+        let band = match self.bands.get_mut(band) {
+            Some(band) => band,
+            None => {
+                println!("No band {}", band + 1);
+                return;
+            }
+        };
+        match param {
+            "freq" => band.freq = val,
+            "gain" => band.gain = val,
+            "q" => band.q = val.max(0.1),
+            "slope" => {
+                let order = (val / 12.).round() as usize;
+                band.order = order.max(1).min(MAX_STAGES);
+                if band.order * 12 != val as usize {
+                    println!("Slope set to {} dB/oct", band.order * 12);
+                }
+            },
+            _ => {
+                println!("Unknown band parameter: {}", param);
+                return;
+            }
+        }
+        band.update(sample_rate, false);
+    }
+
+    pub fn set_band_type(&mut self, band: usize, typ: BandType) {
+        let sample_rate = self.sample_rate;
+        if let Some(band) = self.bands.get_mut(band) {
+            band.typ = typ;
+            band.update(sample_rate, false);
+        }
+    }
+
+    fn print_bands(&self) {
+        for (i, band) in self.bands.iter().enumerate() {
+            println!("b{}: {:?} {} Hz {:+.1} dB q {:.2} slope {} dB/oct",
+                i + 1, band.typ, band.freq, band.gain, band.q, band.order * 12);
+        }
+        println!("gain: {:+.1} dB", self.gain);
+    }
+}
+
+/// Splits `b2.freq` into the band index and parameter name
+fn parse_band(conf_name: &str) -> Option<(usize, &str)> {
+    if !conf_name.starts_with("b") {
+        return None;
+    }
+    let mut parts = conf_name[1..].splitn(2, '.');
+    let idx = parts.next()?.parse::<usize>().ok()?;
+    let param = parts.next()?;
+    if idx == 0 {
+        return None;
+    }
+    Some((idx - 1, param))
+}
+
+impl Effect for Equalizer {
+
+    /// low shelf, four peaking bands and a high shelf, all flat
+    fn new(sample_rate: usize, _frame_size: u32) -> Self {
+        let sample_rate = sample_rate as f32;
+        let bands = vec![
+            Band::new(sample_rate, BandType::LowShelf, 100., 0.707),
+            Band::new(sample_rate, BandType::Peak, 250., 1.),
+            Band::new(sample_rate, BandType::Peak, 800., 1.),
+            Band::new(sample_rate, BandType::Peak, 2500., 1.),
+            Band::new(sample_rate, BandType::Peak, 5000., 1.),
+            Band::new(sample_rate, BandType::HighShelf, 8000., 0.707),
+        ];
+        Self::with_bands(sample_rate, bands)
+    }
+
+    fn name(&self) -> &str {
+        "eq"
+    }
+
+    fn process_samples(&mut self, input: &[f32], output_l: &mut [f32], output_r: &mut [f32]) {
+
+        if self.bypassing {
+            output_l.clone_from_slice(input);
+            output_r.clone_from_slice(input);
+            return;
+        }
+
+        let gain = db_to_gain(self.gain);
+        for (i, x) in input.iter().enumerate() {
+            let y = self.bands.iter_mut().fold(*x, |y, band| band.process(y)) * gain;
+            output_l[i] = y;
+            output_r[i] = y;
+        }
+    }
+
+    fn sample_rate_changed(&mut self, sample_rate: usize) {
+        let sample_rate = sample_rate as f32;
+        self.sample_rate = sample_rate;
+        for band in self.bands.iter_mut() {
+            for stage in band.stages.iter_mut() {
+                stage.set_sample_rate(sample_rate);
+            }
+            band.update(sample_rate, true);
+        }
+    }
+
+    fn params(&self) -> Vec<(String, Param)> {
+        use self::Param::*;
+        let mut list = params(&[("gain", Float(self.gain))]);
+        for (i, band) in self.bands.iter().enumerate() {
+            let name = |param: &str| format!("b{}.{}", i + 1, param);
+            list.push((name("type"), Name(band.typ.name().to_owned())));
+            list.push((name("freq"), Float(band.freq)));
+            list.push((name("gain"), Float(band.gain)));
+            list.push((name("q"), Float(band.q)));
+            list.push((name("slope"), Int(band.order as i32 * 12)));
+        }
+        list
+    }
+
+    fn bypass(&mut self) {
+        self.bypassing = !self.bypassing;
+    }
+
+    fn is_bypassing(&self) -> bool {
+        self.bypassing
+    }
+
+    fn set(&mut self, param: &str, val: f32) {
+        if param == "gain" {
+            self.gain = val;
+        } else if let Some((band, param)) = parse_band(param) {
+            self.set_band(band, param, val);
+        }
+    }
+
+    fn ctrl(&mut self, msg: CtrlMsg) {
+        use self::CtrlMsg::*;
+        match msg {
+            Bypass => self.bypass(),
+            Set(_pedal_name, conf_name, val) => self.set(&conf_name, val),
+            Select(_pedal_name, conf_name, val) => {
+                match (parse_band(&conf_name), BandType::from_name(&val)) {
+                    (Some((band, "type")), Some(typ)) => self.set_band_type(band, typ),
+                    (Some((_, "type")), None) => println!(
+                        "Unknown band type: {} (off, peak, lowshelf, highshelf, lowpass, highpass)", val),
+                    _ => (),
+                }
+            },
+            Info(_) => self.print_bands(),
+            _ => (),
+        }
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Equalizer, BandType};
+    use effects::Effect;
+    use std::f32::consts::PI;
+
+    /// peak amplitude of the second half of the response to a sine
+    fn response(eq: &mut Equalizer, freq: f32) -> f32 {
+        let len = 48000;
+        let input: Vec<f32> = (0..len)
+            .map(|i| (2. * PI * freq * i as f32 / 48000.).sin())
+            .collect();
+        let mut l = vec![0.; len];
+        let mut r = vec![0.; len];
+        eq.process_samples(&input, &mut l, &mut r);
+        l[len / 2..].iter().fold(0., |m, y| y.abs().max(m))
+    }
+
+    #[test]
+    fn test_flat_by_default() {
+        let mut eq = Equalizer::new(48000, 256);
+        assert!((response(&mut eq, 1000.) - 1.).abs() < 0.01);
+        let mut geq = Equalizer::graphic(48000, 256);
+        assert!((response(&mut geq, 440.) - 1.).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_peaking_band() {
+        let mut eq = Equalizer::new(48000, 256);
+        eq.set_band(2, "freq", 1000.);
+        eq.set_band(2, "gain", 6.);
+        let y = response(&mut eq, 1000.);
+        assert!((y - 2.).abs() < 0.05);
+    }
+
+    #[test]
+    fn test_lowpass_slope() {
+        let mut eq = Equalizer::new(48000, 256);
+        eq.set_band_type(5, BandType::LowPass);
+        eq.set_band(5, "freq", 1000.);
+        eq.set_band(5, "slope", 12.);
+        let gentle = response(&mut eq, 4000.);
+        eq.set_band(5, "slope", 48.);
+        let steep = response(&mut eq, 4000.);
+        // two octaves above the cutoff: about -24 dB and -96 dB
+        assert!(gentle < 0.1 && gentle > 0.03);
+        assert!(steep < 0.001);
+    }
+}
@@ -0,0 +1,210 @@
+use effects::{Effect, CtrlMsg, Param, params};
+use effects::delay_line::DelayLine;
+use effects::lfo::{Lfo, Waveform};
+
+/// Longest delay + sweep in seconds
+const MAX_DELAY: f32 = 0.02;
+
+/// Flanger with feedback and an optional through-zero mode.
+///
+/// In through-zero mode the dry signal is delayed by the center of the
+/// sweep, so the modulated tap passes it and the comb filter notches
+/// collapse to zero delay. The right channel's LFO is shifted by `phase`
+/// degrees.
+pub struct Flanger {
+    pub bypassing: bool,
+    sample_rate: f32,
+    lines: [DelayLine; 2],
+    dry_line: DelayLine,
+    lfo: Lfo,
+    /// shortest delay and sweep width in seconds
+    delay: f32,
+    depth: f32,
+    feedback: f32,
+    mix: f32,
+    phase: f32,
+    through_zero: bool,
+}
+
+impl Flanger {
+
+    /// delay and depth are in milliseconds
+    pub fn set_delay(&mut self, ms: f32) {
+        self.delay = (ms / 1000.).max(0.).min(MAX_DELAY / 2.);
+    }
+
+    pub fn set_depth(&mut self, ms: f32) {
+        self.depth = (ms / 1000.).max(0.).min(MAX_DELAY / 4.);
+    }
+
+    /// negative feedback gives hollower notches
+    pub fn set_feedback(&mut self, fb: f32) {
+        self.feedback = fb.max(-0.95).min(0.95);
+    }
+
+    pub fn set_mix(&mut self, mix: f32) {
+        self.mix = mix.max(0.).min(1.);
+    }
+
+    /// offset of the right channel's sweep in degrees
+    pub fn set_phase(&mut self, degrees: f32) {
+        self.phase = degrees / 360.;
+    }
+
+    pub fn set_through_zero(&mut self, on: bool) {
+        self.through_zero = on;
+    }
+
+    fn channel(&mut self, ch: usize, x: f32, dry: f32) -> f32 {
+        let lfo = self.lfo.value_at(ch as f32 * self.phase);
+        let delay = if self.through_zero {
+            // sweeps around the delay of the dry path
+            self.delay + self.depth * (1. + lfo)
+        } else {
+            self.delay + self.depth * (1. + lfo) / 2.
+        };
+
+        // read before the push so the feedback comes round after exactly
+        // the delay, not a sample later
+        let line = &mut self.lines[ch];
+        let wet = line.read_linear(delay * self.sample_rate - 1.);
+        line.push(x + self.feedback * wet);
+
+        (1. - self.mix) * dry + self.mix * wet
+    }
+}
+
+impl Effect for Flanger {
+
+    fn new(sample_rate: usize, _frame_size: u32) -> Self {
+        let sample_rate = sample_rate as f32;
+        let max = (MAX_DELAY * sample_rate) as usize;
+        Flanger {
+            bypassing: false,
+            sample_rate,
+            lines: [DelayLine::new(max), DelayLine::new(max)],
+            dry_line: DelayLine::new(max),
+            lfo: Lfo::new(sample_rate, 0.25),
+            delay: 0.001,
+            depth: 0.002,
+            feedback: 0.5,
+            mix: 0.5,
+            phase: 0.25,
+            through_zero: false,
+        }
+    }
+
+    fn name(&self) -> &str {
+        "flanger"
+    }
+
+    fn process_samples(&mut self, input: &[f32], output_l: &mut [f32], output_r: &mut [f32]) {
+
+        if self.bypassing {
+            output_l.clone_from_slice(input);
+            output_r.clone_from_slice(input);
+            return;
+        }
+
+        for (i, x) in input.iter().enumerate() {
+            self.dry_line.push(*x);
+            let dry = if self.through_zero {
+                self.dry_line.read_linear((self.delay + self.depth) * self.sample_rate)
+            } else {
+                *x
+            };
+
+            output_l[i] = self.channel(0, *x, dry);
+            output_r[i] = self.channel(1, *x, dry);
+            self.lfo.advance();
+        }
+    }
+
+    fn sample_rate_changed(&mut self, sample_rate: usize) {
+        self.sample_rate = sample_rate as f32;
+        let max = (MAX_DELAY * self.sample_rate) as usize;
+        self.lines = [DelayLine::new(max), DelayLine::new(max)];
+        self.dry_line = DelayLine::new(max);
+        self.lfo.set_sample_rate(self.sample_rate);
+    }
+
+    fn params(&self) -> Vec<(String, Param)> {
+        use self::Param::*;
+        params(&[
+            ("rate", Float(self.lfo.rate())),
+            ("depth", Float(self.depth * 1000.)),
+            ("delay", Float(self.delay * 1000.)),
+            ("feedback", Float(self.feedback)),
+            ("mix", Float(self.mix)),
+            ("phase", Float(self.phase * 360.)),
+            ("tz", Int(self.through_zero as i32)),
+            ("wave", Name(self.lfo.waveform.name().to_owned())),
+        ])
+    }
+
+    fn bypass(&mut self) {
+        self.bypassing = !self.bypassing;
+    }
+
+    fn is_bypassing(&self) -> bool {
+        self.bypassing
+    }
+
+    fn set(&mut self, param: &str, val: f32) {
+        match param {
+            "rate" => self.lfo.set_rate(val),
+            "depth" => self.set_depth(val),
+            "delay" => self.set_delay(val),
+            "feedback" => self.set_feedback(val),
+            "mix" => self.set_mix(val),
+            "phase" => self.set_phase(val),
+            "tz" => self.set_through_zero(val >= 0.5),
+            _ => (),
+        }
+    }
+
+    fn ctrl(&mut self, msg: CtrlMsg) {
+        use self::CtrlMsg::*;
+        match msg {
+            Bypass => self.bypass(),
+            Set(_pedal_name, conf_name, val) => self.set(&conf_name, val),
+            Select(_pedal_name, conf_name, val) => {
+                if &conf_name == "wave" {
+                    match Waveform::from_name(&val) {
+                        Some(w) => self.lfo.waveform = w,
+                        None => println!("Unknown waveform: {}", val),
+                    }
+                }
+            },
+            _ => (),
+        }
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Flanger;
+    use effects::Effect;
+
+    #[test]
+    fn test_comb_at_delay() {
+        // 1 ms at 48 kHz, no sweep: the impulse comes back every 48
+        // samples, halved by the mix and again by the feedback
+        let mut flanger = Flanger::new(48000, 256);
+        flanger.set_delay(1.);
+        flanger.set_depth(0.);
+        flanger.set_feedback(0.5);
+        flanger.set_mix(0.5);
+
+        let mut input = vec![0.; 256];
+        input[0] = 1.;
+        let (mut l, mut r) = (vec![0.; 256], vec![0.; 256]);
+        flanger.process_samples(&input, &mut l, &mut r);
+        for &(i, expected) in [(0, 0.5), (48, 0.5), (96, 0.25), (144, 0.125)].iter() {
+            assert!((l[i] - expected).abs() < 1e-3, "{}: {}", i, l[i]);
+        }
+        assert!(l[1..48].iter().chain(l[49..96].iter()).all(|y| y.abs() < 1e-3));
+        assert_eq!(l, r);
+    }
+}
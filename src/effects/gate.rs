@@ -0,0 +1,322 @@
+use effects::{Effect, CtrlMsg, Param, params};
+use effects::dynamics::{db_to_gain, gain_to_db, time_coeff};
+use std::f32::consts::PI;
+
+/// Release time of the peak detector feeding the gate
+const DETECTOR_RELEASE: f32 = 0.01;
+
+/// Noise gate with hysteresis and an optional sidechain key.
+///
+/// The gate opens when the detector rises above `threshold` and
+/// closes once it has stayed below `threshold - hysteresis` for
+/// `hold` seconds. While closed the signal is attenuated by `range` dB.
+/// The detector runs on the key signal, which is the gate input unless
+/// a sidechain tap has been assigned with `sc <gate> <node>`.
+pub struct NoiseGate {
+    pub bypassing: bool,
+    sample_rate: f32,
+
+    threshold: f32,
+    hysteresis: f32,
+    attack: f32,
+    hold: f32,
+    release: f32,
+    range: f32,
+
+    // key filter, cutoffs in Hz; 0 disables a stage
+    key_hp: f32,
+    key_lp: f32,
+    hp_coeff: f32,
+    lp_coeff: f32,
+    hp_state: (f32, f32),
+    lp_state: f32,
+
+    /// allocated at the frame size, the first `key_len` samples are the
+    /// key of the next block
+    key: Vec<f32>,
+    key_len: usize,
+    use_key: bool,
+
+    envelope: f32,
+    detector_coeff: f32,
+    open: bool,
+    hold_counter: usize,
+    gain: f32,
+}
+
+impl NoiseGate {
+
+    /// threshold in dBFS
+    pub fn set_threshold(&mut self, db: f32) {
+        self.threshold = db.min(0.);
+    }
+
+    /// distance in dB between the open and close thresholds
+    pub fn set_hysteresis(&mut self, db: f32) {
+        self.hysteresis = db.max(0.);
+    }
+
+    /// attack, hold and release are in seconds
+    pub fn set_attack(&mut self, t: f32) {
+        self.attack = t.max(0.);
+    }
+
+    pub fn set_hold(&mut self, t: f32) {
+        self.hold = t.max(0.);
+    }
+
+    pub fn set_release(&mut self, t: f32) {
+        self.release = t.max(0.);
+    }
+
+    /// attenuation in dB applied while the gate is closed
+    pub fn set_range(&mut self, db: f32) {
+        self.range = -db.abs();
+    }
+
+    pub fn set_key_highpass(&mut self, freq: f32) {
+        self.key_hp = freq.max(0.);
+        self.hp_coeff = one_pole_coeff(self.key_hp, self.sample_rate);
+    }
+
+    pub fn set_key_lowpass(&mut self, freq: f32) {
+        self.key_lp = freq.max(0.);
+        self.lp_coeff = one_pole_coeff(self.key_lp, self.sample_rate);
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.open
+    }
+
+    fn filter_key(&mut self, x: f32) -> f32 {
+        let mut y = x;
+        if self.key_hp > 0. {
+            // one-pole highpass: y[n] = a * (y[n-1] + x[n] - x[n-1])
+            let (x1, y1) = self.hp_state;
+            y = self.hp_coeff * (y1 + x - x1);
+            self.hp_state = (x, y);
+        }
+        if self.key_lp > 0. {
+            self.lp_state += (1. - self.lp_coeff) * (y - self.lp_state);
+            y = self.lp_state;
+        }
+        y
+    }
+
+    fn detect(&mut self, key: f32) {
+        let level = self.filter_key(key).abs();
+        self.envelope = if level > self.envelope {
+            level
+        } else {
+            self.detector_coeff * self.envelope
+        };
+
+        let level_db = gain_to_db(self.envelope);
+        if level_db > self.threshold {
+            self.open = true;
+            self.hold_counter = (self.hold * self.sample_rate) as usize;
+        } else if self.open && level_db < self.threshold - self.hysteresis {
+            if self.hold_counter == 0 {
+                self.open = false;
+            } else {
+                self.hold_counter -= 1;
+            }
+        }
+    }
+
+    fn next_gain(&mut self) -> f32 {
+        let target = if self.open { 1. } else { db_to_gain(self.range) };
+        let coeff = if target > self.gain {
+            time_coeff(self.attack, self.sample_rate)
+        } else {
+            time_coeff(self.release, self.sample_rate)
+        };
+        self.gain = target + coeff * (self.gain - target);
+        self.gain
+    }
+}
+
+/// Smoothing coefficient of a one-pole filter with the given cutoff
+fn one_pole_coeff(freq: f32, sample_rate: f32) -> f32 {
+    (-2. * PI * freq / sample_rate).exp()
+}
+
+impl Effect for NoiseGate {
+
+    fn new(sample_rate: usize, frame_size: u32) -> Self {
+        let sample_rate = sample_rate as f32;
+        NoiseGate {
+            bypassing: false,
+            sample_rate,
+            threshold: -50.,
+            hysteresis: 6.,
+            attack: 0.001,
+            hold: 0.05,
+            release: 0.1,
+            range: -80.,
+            key_hp: 0.,
+            key_lp: 0.,
+            hp_coeff: 0.,
+            lp_coeff: 0.,
+            hp_state: (0., 0.),
+            lp_state: 0.,
+            key: vec![0.; frame_size as usize],
+            key_len: 0,
+            use_key: false,
+            envelope: 0.,
+            detector_coeff: time_coeff(DETECTOR_RELEASE, sample_rate),
+            open: false,
+            hold_counter: 0,
+            gain: 0.,
+        }
+    }
+
+    fn name(&self) -> &str {
+        "gate"
+    }
+
+    fn process_samples(&mut self, input: &[f32], output_l: &mut [f32], output_r: &mut [f32]) {
+
+        if self.bypassing {
+            output_l.clone_from_slice(input);
+            output_r.clone_from_slice(input);
+            return;
+        }
+
+        let use_key = self.use_key && self.key_len == input.len();
+        for (i, x) in input.iter().enumerate() {
+            let key = if use_key { self.key[i] } else { *x };
+            self.detect(key);
+            let y = x * self.next_gain();
+            output_l[i] = y;
+            output_r[i] = y;
+        }
+    }
+
+    fn sample_rate_changed(&mut self, sample_rate: usize) {
+        self.sample_rate = sample_rate as f32;
+        self.detector_coeff = time_coeff(DETECTOR_RELEASE, self.sample_rate);
+        let (hp, lp) = (self.key_hp, self.key_lp);
+        self.set_key_highpass(hp);
+        self.set_key_lowpass(lp);
+    }
+
+    fn frame_size_changed(&mut self, frame_size: u32) {
+        self.key = vec![0.; frame_size as usize];
+    }
+
+    fn sidechain(&mut self, key: &[f32]) {
+        self.key_len = key.len().min(self.key.len());
+        self.key[..self.key_len].clone_from_slice(&key[..self.key_len]);
+        self.use_key = true;
+    }
+
+    fn params(&self) -> Vec<(String, Param)> {
+        use self::Param::*;
+        params(&[
+            ("threshold", Float(self.threshold)),
+            ("hysteresis", Float(self.hysteresis)),
+            ("attack", Float(self.attack)),
+            ("hold", Float(self.hold)),
+            ("release", Float(self.release)),
+            ("range", Float(self.range)),
+            ("keyhp", Float(self.key_hp)),
+            ("keylp", Float(self.key_lp)),
+        ])
+    }
+
+    fn bypass(&mut self) {
+        self.bypassing = !self.bypassing;
+    }
+
+    fn is_bypassing(&self) -> bool {
+        self.bypassing
+    }
+
+    fn set(&mut self, param: &str, val: f32) {
+        match param {
+            "threshold" => self.set_threshold(val),
+            "hysteresis" => self.set_hysteresis(val),
+            "attack" => self.set_attack(val),
+            "hold" => self.set_hold(val),
+            "release" => self.set_release(val),
+            "range" => self.set_range(val),
+            "keyhp" => self.set_key_highpass(val),
+            "keylp" => self.set_key_lowpass(val),
+            _ => (),
+        }
+    }
+
+    fn ctrl(&mut self, msg: CtrlMsg) {
+        use self::CtrlMsg::*;
+        match msg {
+            Bypass => self.bypass(),
+            Set(_pedal_name, conf_name, val) => self.set(&conf_name, val),
+            Sidechain(_pedal_name, _source) => {
+                // Pedals stops feeding the key once the tap is removed
+                self.use_key = false;
+            },
+            _ => (),
+        }
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::NoiseGate;
+    use effects::Effect;
+
+    fn run(gate: &mut NoiseGate, input: &[f32]) -> Vec<f32> {
+        let mut l = vec![0.; input.len()];
+        let mut r = vec![0.; input.len()];
+        gate.process_samples(input, &mut l, &mut r);
+        l
+    }
+
+    #[test]
+    fn test_gate_opens_and_closes() {
+        let mut gate = NoiseGate::new(48000, 256);
+        gate.set_hold(0.);
+        gate.set_release(0.001);
+
+        let loud: Vec<f32> = (0..4800).map(|i| (i as f32 * 0.05).sin() * 0.5).collect();
+        let out = run(&mut gate, &loud);
+        assert!(gate.is_open());
+        assert!((out[4799] - loud[4799]).abs() < 1e-3);
+
+        let hiss = vec![1e-4; 4800];
+        let out = run(&mut gate, &hiss);
+        assert!(!gate.is_open());
+        assert!(out[4799].abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_gate_hysteresis() {
+        let mut gate = NoiseGate::new(48000, 256);
+        gate.set_threshold(-40.);
+        gate.set_hysteresis(10.);
+        gate.set_hold(0.);
+
+        run(&mut gate, &vec![0.1; 480]);
+        assert!(gate.is_open());
+
+        // -46 dB sits between the close (-50 dB) and open (-40 dB) thresholds
+        run(&mut gate, &vec![0.005; 4800]);
+        assert!(gate.is_open());
+    }
+
+    #[test]
+    fn test_gate_sidechain_key() {
+        let mut gate = NoiseGate::new(48000, 480);
+        gate.set_hold(0.);
+        gate.set_release(0.001);
+
+        // loud key keeps the gate open on a quiet input
+        let quiet = vec![1e-4; 480];
+        gate.sidechain(&vec![0.5; 480]);
+        let out = run(&mut gate, &quiet);
+        assert!(gate.is_open());
+        assert!((out[479] - 1e-4).abs() < 1e-6);
+    }
+}
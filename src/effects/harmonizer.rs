@@ -0,0 +1,226 @@
+use effects::{Effect, CtrlMsg, Param, params};
+use effects::pitch::{PitchTracker, freq_to_midi, parse_key};
+use effects::pitch_shift::Shifter;
+use effects::tuner::note_name;
+
+/// Detected pitches below this confidence keep the previous interval
+const MIN_CONFIDENCE: f32 = 0.8;
+
+const SCALES: &[(&str, &[i32])] = &[
+    ("major", &[0, 2, 4, 5, 7, 9, 11]),
+    ("minor", &[0, 2, 3, 5, 7, 8, 10]),
+    ("dorian", &[0, 2, 3, 5, 7, 9, 10]),
+    ("mixolydian", &[0, 2, 4, 5, 7, 9, 10]),
+    ("harmonicminor", &[0, 2, 3, 5, 7, 8, 11]),
+    ("pentatonic", &[0, 2, 4, 7, 9]),
+    ("minorpentatonic", &[0, 3, 5, 7, 10]),
+];
+
+/// Semitones from `note` (a MIDI note number) to the note `steps`
+/// scale degrees away in the scale starting on pitch class `key`.
+///
+/// Notes outside the scale count from the scale degree below them, so
+/// the harmony always lands on a note of the scale.
+pub fn diatonic_shift(note: i32, key: i32, scale: &[i32], steps: i32) -> i32 {
+    let len = scale.len() as i32;
+    let pc = ((note - key) % 12 + 12) % 12;
+    let degree = scale.iter().rposition(|&s| s <= pc).unwrap_or(0) as i32;
+
+    let target = degree + steps;
+    let octave = if target < 0 { (target + 1) / len - 1 } else { target / len };
+    let target_pc = scale[(target - octave * len) as usize] + 12 * octave;
+    target_pc - pc
+}
+
+/// Intelligent harmonizer adding up to two voices a number of scale
+/// degrees above or below the played note, e.g. `s hm interval 2` for
+/// a third in the selected key and scale. An interval of 0 mutes the
+/// voice.
+pub struct Harmonizer {
+    pub bypassing: bool,
+    tracker: PitchTracker,
+    voices: [Shifter; 2],
+    intervals: [i32; 2],
+    key: i32,
+    scale: &'static [i32],
+    /// last detected note, the voices hold their interval in silence
+    note: Option<i32>,
+    dry: f32,
+    level: f32,
+}
+
+impl Harmonizer {
+
+    pub fn set_key(&mut self, key: i32) {
+        self.key = key;
+        self.update();
+    }
+
+    pub fn set_scale(&mut self, name: &str) -> bool {
+        match SCALES.iter().find(|&&(n, _)| n == name) {
+            Some(&(_, scale)) => {
+                self.scale = scale;
+                self.update();
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// interval of voice 0 or 1 in scale degrees
+    pub fn set_interval(&mut self, voice: usize, steps: i32) {
+        self.intervals[voice] = steps.max(-14).min(14);
+        self.update();
+    }
+
+    fn update(&mut self) {
+        if let Some(note) = self.note {
+            for (voice, &steps) in self.voices.iter_mut().zip(self.intervals.iter()) {
+                voice.set_semitones(diatonic_shift(note, self.key, self.scale, steps) as f32);
+            }
+        }
+    }
+}
+
+impl Effect for Harmonizer {
+
+    fn new(sample_rate: usize, _frame_size: u32) -> Self {
+        let sr = sample_rate as f32;
+        Harmonizer {
+            bypassing: false,
+            tracker: PitchTracker::new(sr),
+            voices: [Shifter::new(sr), Shifter::new(sr)],
+            intervals: [2, 0],
+            key: 0,
+            scale: SCALES[0].1,
+            note: None,
+            dry: 1.,
+            level: 0.7,
+        }
+    }
+
+    fn name(&self) -> &str {
+        "harmonizer"
+    }
+
+    fn process_samples(&mut self, input: &[f32], output_l: &mut [f32], output_r: &mut [f32]) {
+
+        if self.bypassing {
+            output_l.clone_from_slice(input);
+            output_r.clone_from_slice(input);
+            return;
+        }
+
+        for (i, x) in input.iter().enumerate() {
+            if let Some((freq, confidence)) = self.tracker.process(*x) {
+                let note = freq_to_midi(freq).round() as i32;
+                if confidence >= MIN_CONFIDENCE && self.note != Some(note) {
+                    self.note = Some(note);
+                    self.update();
+                }
+            }
+
+            let mut y = self.dry * x;
+            for (voice, &steps) in self.voices.iter_mut().zip(self.intervals.iter()) {
+                let wet = voice.process(*x);
+                if steps != 0 && self.note.is_some() {
+                    y += self.level * wet;
+                }
+            }
+            output_l[i] = y;
+            output_r[i] = y;
+        }
+    }
+
+    fn sample_rate_changed(&mut self, sample_rate: usize) {
+        let sr = sample_rate as f32;
+        self.tracker = PitchTracker::new(sr);
+        for voice in self.voices.iter_mut() {
+            voice.set_sample_rate(sr);
+        }
+    }
+
+    fn params(&self) -> Vec<(String, Param)> {
+        use self::Param::*;
+        let key = note_name(self.key + 60);
+        let scale = SCALES.iter().find(|&&(_, s)| s == self.scale).map_or("major", |&(n, _)| n);
+        params(&[
+            ("interval", Int(self.intervals[0])),
+            ("interval2", Int(self.intervals[1])),
+            ("dry", Float(self.dry)),
+            ("level", Float(self.level)),
+            ("window", Float(self.voices[0].window() * 1000.)),
+            ("key", Name(key.trim_end_matches(|c: char| c.is_ascii_digit()).to_owned())),
+            ("scale", Name(scale.to_owned())),
+        ])
+    }
+
+    fn bypass(&mut self) {
+        self.bypassing = !self.bypassing;
+    }
+
+    fn is_bypassing(&self) -> bool {
+        self.bypassing
+    }
+
+    fn set(&mut self, param: &str, val: f32) {
+        match param {
+            "interval" => self.set_interval(0, val.round() as i32),
+            "interval2" => self.set_interval(1, val.round() as i32),
+            "dry" => self.dry = val.max(0.).min(1.),
+            "level" => self.level = val.max(0.).min(1.),
+            "window" => {
+                for voice in self.voices.iter_mut() {
+                    voice.set_window(val / 1000.);
+                }
+            },
+            _ => (),
+        }
+    }
+
+    fn ctrl(&mut self, msg: CtrlMsg) {
+        use self::CtrlMsg::*;
+        match msg {
+            Bypass => self.bypass(),
+            Set(_pedal_name, conf_name, val) => self.set(&conf_name, val),
+            Select(_pedal_name, conf_name, val) => {
+                match conf_name.as_str() {
+                    "key" => match parse_key(&val) {
+                        Some(key) => self.set_key(key),
+                        None => println!("Unknown key: {}", val),
+                    },
+                    "scale" => if !self.set_scale(&val) {
+                        let names: Vec<_> = SCALES.iter().map(|&(n, _)| n).collect();
+                        println!("Unknown scale: {} ({})", val, names.join(", "));
+                    },
+                    _ => (),
+                }
+            },
+            _ => (),
+        }
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{diatonic_shift, SCALES};
+    use effects::pitch::parse_key;
+
+    #[test]
+    fn test_diatonic_shift() {
+        let major = SCALES[0].1;
+        // a third up in C major is major on C and minor on E
+        assert_eq!(diatonic_shift(60, 0, major, 2), 4);
+        assert_eq!(diatonic_shift(64, 0, major, 2), 3);
+        // B up a third wraps into the next octave
+        assert_eq!(diatonic_shift(71, 0, major, 2), 3);
+        // a third down from C is A below
+        assert_eq!(diatonic_shift(60, 0, major, -2), -3);
+        // G in E minor, a fifth up is D
+        let minor = SCALES[1].1;
+        assert_eq!(diatonic_shift(67, parse_key("E").unwrap(), minor, 4), 7);
+        assert_eq!(diatonic_shift(60, 0, major, 7), 12);
+        assert_eq!(diatonic_shift(60, 0, major, -7), -12);
+    }
+}
@@ -0,0 +1,146 @@
+//! Low frequency oscillator shared by the modulation effects.
+
+use std::f32::consts::PI;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Waveform {
+    Sine,
+    Triangle,
+    /// hard edges, smooth them where they would click
+    Square,
+}
+
+impl Waveform {
+    pub fn from_name(name: &str) -> Option<Waveform> {
+        match name {
+            "sine" => Some(Waveform::Sine),
+            "triangle" => Some(Waveform::Triangle),
+            "square" => Some(Waveform::Square),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Waveform::Sine => "sine",
+            Waveform::Triangle => "triangle",
+            Waveform::Square => "square",
+        }
+    }
+}
+
+/// Oscillator in -1..1 advanced once per sample, so its rate does not
+/// depend on the block size.
+pub struct Lfo {
+    sample_rate: f32,
+    rate: f32,
+    /// position in the cycle, 0..1
+    phase: f32,
+    pub waveform: Waveform,
+}
+
+impl Lfo {
+
+    pub fn new(sample_rate: f32, rate: f32) -> Self {
+        Lfo {
+            sample_rate,
+            rate,
+            phase: 0.,
+            waveform: Waveform::Sine,
+        }
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: f32) {
+        self.sample_rate = sample_rate;
+    }
+
+    /// rate in Hz
+    pub fn set_rate(&mut self, rate: f32) {
+        self.rate = rate.max(0.);
+    }
+
+    pub fn rate(&self) -> f32 {
+        self.rate
+    }
+
+    pub fn set_phase(&mut self, phase: f32) {
+        self.phase = phase - phase.floor();
+    }
+
+    pub fn phase(&self) -> f32 {
+        self.phase
+    }
+
+    /// value at the current phase shifted by `offset` cycles
+    pub fn value_at(&self, offset: f32) -> f32 {
+        let p = self.phase + offset;
+        let p = p - p.floor();
+        match self.waveform {
+            Waveform::Sine => (2. * PI * p).sin(),
+            Waveform::Triangle => {
+                // starts at 0 rising, like the sine
+                if p < 0.25 {
+                    4. * p
+                } else if p < 0.75 {
+                    2. - 4. * p
+                } else {
+                    4. * p - 4.
+                }
+            },
+            Waveform::Square => if p < 0.5 { 1. } else { -1. },
+        }
+    }
+
+    /// moves one sample forward
+    pub fn advance(&mut self) {
+        self.phase += self.rate / self.sample_rate;
+        if self.phase >= 1. {
+            self.phase -= self.phase.floor();
+        }
+    }
+
+    /// current value, then moves one sample forward
+    pub fn next(&mut self) -> f32 {
+        let y = self.value_at(0.);
+        self.advance();
+        y
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Lfo, Waveform};
+
+    #[test]
+    fn test_rate() {
+        // 1 Hz at 100 samples a second is a cycle every 100 samples
+        let mut lfo = Lfo::new(100., 1.);
+        let sine: Vec<f32> = (0..100).map(|_| lfo.next()).collect();
+        assert_eq!(sine[0], 0.);
+        assert!((sine[25] - 1.).abs() < 1e-4);
+        assert!((sine[75] + 1.).abs() < 1e-4);
+        let phase = lfo.phase();
+        assert!(phase < 1e-4 || phase > 1. - 1e-4);
+
+        lfo.set_phase(0.);
+        lfo.set_rate(2.);
+        for _ in 0..25 {
+            lfo.advance();
+        }
+        assert!((lfo.phase() - 0.5).abs() < 1e-4);
+    }
+
+    #[test]
+    fn test_shapes() {
+        let mut lfo = Lfo::new(100., 1.);
+        lfo.waveform = Waveform::Triangle;
+        let points: Vec<f32> = [0., 0.125, 0.25, 0.5, 0.75, 0.875].iter().map(|&p| lfo.value_at(p)).collect();
+        assert_eq!(points, vec![0., 0.5, 1., 0., -1., -0.5]);
+
+        lfo.waveform = Waveform::Square;
+        assert_eq!((lfo.value_at(0.1), lfo.value_at(0.6)), (1., -1.));
+        // offsets wrap around the cycle
+        lfo.set_phase(0.75);
+        assert_eq!(lfo.value_at(0.5), 1.);
+    }
+}
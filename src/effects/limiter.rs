@@ -0,0 +1,220 @@
+use effects::{Effect, CtrlMsg, Param, params};
+use effects::dynamics::{db_to_gain, gain_to_db, time_coeff, Lookahead};
+
+/// Lookahead of the limiter in seconds
+const LOOKAHEAD: f32 = 0.0015;
+
+/// Brickwall limiter working on an estimate of the true peak.
+///
+/// Inter-sample peaks are estimated with a cubic interpolation at
+/// three points between each pair of samples. The gain needed to keep
+/// every peak under the ceiling is held over the lookahead window and
+/// averaged over the same length, so the gain has fully come down by
+/// the time the peak leaves the delay line.
+pub struct Limiter {
+    pub bypassing: bool,
+    sample_rate: f32,
+
+    ceiling: f32,
+    /// release in seconds
+    release: f32,
+    release_coeff: f32,
+
+    history: [f32; 4],
+    lookahead: Lookahead,
+    required: Vec<f32>,
+    smoothing: Vec<f32>,
+    window_idx: usize,
+    window_sum: f32,
+    envelope: f32,
+
+    /// current gain reduction in dB, <= 0
+    reduction: f32,
+    max_reduction: f32,
+}
+
+impl Limiter {
+
+    /// ceiling in dBFS
+    pub fn set_ceiling(&mut self, db: f32) {
+        self.ceiling = db.min(0.);
+    }
+
+    /// release in seconds
+    pub fn set_release(&mut self, t: f32) {
+        self.release = t;
+        self.release_coeff = time_coeff(t, self.sample_rate);
+    }
+
+    pub fn gain_reduction(&self) -> f32 {
+        self.reduction
+    }
+
+    /// Largest absolute value between the two middle samples of `history`
+    fn true_peak(&self) -> f32 {
+        let [y0, y1, y2, y3] = self.history;
+        let mut peak = y1.abs().max(y2.abs());
+        for k in 1..4 {
+            let t = k as f32 / 4.;
+            // Catmull-Rom spline through y1..y2
+            let y = 0.5 * (2. * y1
+                + (y2 - y0) * t
+                + (2. * y0 - 5. * y1 + 4. * y2 - y3) * t * t
+                + (3. * y1 - y0 - 3. * y2 + y3) * t * t * t);
+            peak = peak.max(y.abs());
+        }
+        peak
+    }
+
+    fn next_gain(&mut self, x: f32) -> f32 {
+        self.history = [self.history[1], self.history[2], self.history[3], x];
+        let ceiling = db_to_gain(self.ceiling);
+        let peak = self.true_peak();
+        let needed = if peak > ceiling { ceiling / peak } else { 1. };
+
+        let len = self.required.len();
+        self.required[self.window_idx] = needed;
+        let held = self.required.iter().cloned().fold(1., f32::min);
+
+        self.envelope = if held < self.envelope {
+            held
+        } else {
+            held + self.release_coeff * (self.envelope - held)
+        };
+
+        self.window_sum += self.envelope - self.smoothing[self.window_idx];
+        self.smoothing[self.window_idx] = self.envelope;
+        self.window_idx = (self.window_idx + 1) % len;
+
+        (self.window_sum / len as f32).min(1.)
+    }
+
+    fn print_meter(&mut self) {
+        println!("{}: gain reduction {:.1} dB (max {:.1} dB)",
+            self.name(), self.reduction, self.max_reduction);
+        self.max_reduction = 0.;
+    }
+}
+
+impl Effect for Limiter {
+
+    fn new(sample_rate: usize, _frame_size: u32) -> Self {
+        let sample_rate = sample_rate as f32;
+        let window = (LOOKAHEAD * sample_rate) as usize + 1;
+        // the peak estimate trails the newest sample by one sample
+        let mut lookahead = Lookahead::new(window + 1);
+        lookahead.set_len(window + 1);
+        let mut limiter = Limiter {
+            bypassing: false,
+            sample_rate,
+            ceiling: -1.,
+            release: 0.,
+            release_coeff: 0.,
+            history: [0.; 4],
+            lookahead,
+            required: vec![1.; window],
+            smoothing: vec![1.; window],
+            window_idx: 0,
+            window_sum: window as f32,
+            envelope: 1.,
+            reduction: 0.,
+            max_reduction: 0.,
+        };
+        limiter.set_release(0.05);
+        limiter
+    }
+
+    fn name(&self) -> &str {
+        "limiter"
+    }
+
+    fn process_samples(&mut self, input: &[f32], output_l: &mut [f32], output_r: &mut [f32]) {
+
+        if self.bypassing {
+            output_l.clone_from_slice(input);
+            output_r.clone_from_slice(input);
+            return;
+        }
+
+        let ceiling = db_to_gain(self.ceiling);
+        for (i, x) in input.iter().enumerate() {
+            let gain = self.next_gain(*x);
+            self.reduction = gain_to_db(gain);
+            self.max_reduction = self.max_reduction.min(self.reduction);
+
+            let y = (self.lookahead.process(*x) * gain).max(-ceiling).min(ceiling);
+            output_l[i] = y;
+            output_r[i] = y;
+        }
+    }
+
+    /// The lookahead window depends on the sample rate, so this starts
+    /// over with the same settings
+    fn sample_rate_changed(&mut self, sample_rate: usize) {
+        let mut fresh = Limiter::new(sample_rate, 0);
+        fresh.bypassing = self.bypassing;
+        fresh.ceiling = self.ceiling;
+        fresh.set_release(self.release);
+        *self = fresh;
+    }
+
+    fn params(&self) -> Vec<(String, Param)> {
+        use self::Param::*;
+        params(&[
+            ("ceiling", Float(self.ceiling)),
+            ("release", Float(self.release)),
+        ])
+    }
+
+    fn bypass(&mut self) {
+        self.bypassing = !self.bypassing;
+    }
+
+    fn is_bypassing(&self) -> bool {
+        self.bypassing
+    }
+
+    fn set(&mut self, param: &str, val: f32) {
+        match param {
+            "ceiling" => self.set_ceiling(val),
+            "release" => self.set_release(val),
+            _ => (),
+        }
+    }
+
+    fn ctrl(&mut self, msg: CtrlMsg) {
+        use self::CtrlMsg::*;
+        match msg {
+            Bypass => self.bypass(),
+            Set(_pedal_name, conf_name, val) => self.set(&conf_name, val),
+            Info(_) => self.print_meter(),
+            _ => (),
+        }
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Limiter;
+    use effects::Effect;
+    use effects::dynamics::db_to_gain;
+
+    #[test]
+    fn test_limiter_ceiling() {
+        let mut limiter = Limiter::new(48000, 256);
+        limiter.set_ceiling(-6.);
+
+        let input: Vec<f32> = (0..4800).map(|i| (i as f32 * 0.3).sin()).collect();
+        let mut l = vec![0.; 4800];
+        let mut r = vec![0.; 4800];
+        limiter.process_samples(&input, &mut l, &mut r);
+
+        let ceiling = db_to_gain(-6.);
+        assert!(l.iter().all(|y| y.abs() <= ceiling));
+        // the gain came down before the peaks instead of clipping them
+        let peak = l[2400..].iter().cloned().fold(0., f32::max);
+        assert!(peak > 0.9 * ceiling);
+        assert!(limiter.gain_reduction() < -5.);
+    }
+}
@@ -0,0 +1,560 @@
+use effects::{Effect, CtrlMsg, Param, params};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::thread;
+use wav::write_wav;
+
+/// Longest loop in seconds
+const MAX_LOOP: f32 = 30.;
+/// The recording plus this many overdubs can be undone separately;
+/// older overdubs are merged into the recording
+const MAX_LAYERS: usize = 4;
+/// Samples of the loop copied for an export per block, so a long loop
+/// does not hold up the audio thread
+const EXPORT_CHUNK: usize = 8192;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum State {
+    Empty,
+    Recording,
+    Playing,
+    Overdubbing,
+    Stopped,
+}
+
+/// A loop on its way to a WAV file. The audio thread copies the loop
+/// into the buffer a chunk per block, then hands it to a worker thread
+/// that writes the file and sends the buffer back.
+struct Export {
+    /// `MAX_LOOP` long, None while the worker has it
+    buffer: Option<Vec<f32>>,
+    /// file being copied for, samples copied and the loop length
+    pending: Option<(String, usize, usize)>,
+    to_worker: SyncSender<(String, Vec<f32>, usize, u32)>,
+    from_worker: Receiver<Vec<f32>>,
+}
+
+fn export_worker(jobs: Receiver<(String, Vec<f32>, usize, u32)>, done: SyncSender<Vec<f32>>) {
+    for (path, buffer, len, sample_rate) in jobs {
+        match write_wav(&path, sample_rate, &buffer[..len]) {
+            Ok(()) => println!("Loop written to {}", path),
+            Err(e) => println!("Could not write {}: {}", path, e),
+        }
+        if done.send(buffer).is_err() {
+            break;
+        }
+    }
+}
+
+/// Looper with overdub layers.
+///
+/// Every layer, and the copy an export is written from, is allocated for
+/// `MAX_LOOP` seconds up front so that recording and exporting never
+/// allocate on the audio thread. The loop is the sum
+/// of the active layers: undo deactivates the newest one, redo brings it
+/// back until a new overdub starts. Playback can run reversed and at
+/// half speed. With a tempo set, the recorded length is rounded to
+/// whole beats.
+pub struct Looper {
+    pub bypassing: bool,
+    sample_rate: usize,
+    state: State,
+    layers: Vec<Vec<f32>>,
+    /// layers that are heard
+    active: usize,
+    /// layers that can be brought back with redo
+    recorded: usize,
+    len: usize,
+    pos: f32,
+    /// last index written by the overdub, so half speed writes once
+    last_written: usize,
+
+    level: f32,
+    reverse: bool,
+    half_speed: bool,
+    quantize: bool,
+    tempo: Option<f32>,
+    /// value of the footswitch parameters at the last message
+    switches: [f32; 5],
+    export: Export,
+}
+
+impl Looper {
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    /// length of the loop in samples
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn record(&mut self) {
+        match self.state {
+            State::Recording => self.play(),
+            _ => {
+                self.clear();
+                self.active = 1;
+                self.recorded = 1;
+                self.state = State::Recording;
+            }
+        }
+    }
+
+    pub fn play(&mut self) {
+        match self.state {
+            State::Empty => (),
+            State::Recording => {
+                if self.finish_recording() {
+                    self.state = State::Playing;
+                }
+            },
+            State::Stopped => {
+                self.pos = self.start();
+                self.state = State::Playing;
+            },
+            _ => self.state = State::Playing,
+        }
+    }
+
+    pub fn overdub(&mut self) {
+        match self.state {
+            State::Empty => (),
+            State::Overdubbing => self.state = State::Playing,
+            _ => {
+                if self.state == State::Recording && !self.finish_recording() {
+                    return;
+                }
+                if self.active == MAX_LAYERS {
+                    self.merge_oldest_overdub();
+                }
+                // clears what an undone overdub left in the layer
+                for x in self.layers[self.active][..self.len].iter_mut() {
+                    *x = 0.;
+                }
+                self.active += 1;
+                self.recorded = self.active;
+                self.last_written = usize::max_value();
+                self.state = State::Overdubbing;
+            }
+        }
+    }
+
+    pub fn stop(&mut self) {
+        match self.state {
+            State::Empty => (),
+            State::Recording => {
+                if self.finish_recording() {
+                    self.state = State::Stopped;
+                }
+            },
+            _ => self.state = State::Stopped,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.state = State::Empty;
+        self.active = 0;
+        self.recorded = 0;
+        self.len = 0;
+        self.pos = 0.;
+    }
+
+    pub fn undo(&mut self) {
+        if self.state == State::Overdubbing {
+            self.state = State::Playing;
+        }
+        if self.active > 1 {
+            self.active -= 1;
+        }
+    }
+
+    pub fn redo(&mut self) {
+        if self.state != State::Overdubbing && self.active < self.recorded {
+            self.active += 1;
+        }
+    }
+
+    /// footswitch cycle: record, play, overdub, play, overdub...
+    pub fn footswitch(&mut self) {
+        match self.state {
+            State::Empty => self.record(),
+            State::Recording | State::Overdubbing | State::Stopped => self.play(),
+            State::Playing => self.overdub(),
+        }
+    }
+
+    pub fn set_reverse(&mut self, on: bool) {
+        self.reverse = on;
+    }
+
+    pub fn set_half_speed(&mut self, on: bool) {
+        self.half_speed = on;
+    }
+
+    pub fn set_level(&mut self, level: f32) {
+        self.level = level.max(0.).min(2.);
+    }
+
+    /// Writes the loop as heard to a WAV file, copied over the next
+    /// blocks and written by the export worker
+    pub fn export(&mut self, path: String) {
+        if self.len == 0 {
+            println!("Nothing to export");
+            return;
+        }
+        if self.export.buffer.is_none() {
+            self.export.buffer = self.export.from_worker.try_recv().ok();
+        }
+        if self.export.buffer.is_none() || self.export.pending.is_some() {
+            println!("Still exporting");
+            return;
+        }
+        self.export.pending = Some((path, 0, self.len));
+    }
+
+    /// Copies the next chunk of a pending export, and hands the copy
+    /// over once it is complete
+    fn copy_export(&mut self) {
+        let complete = match (self.export.pending.as_mut(), self.export.buffer.as_mut()) {
+            (Some(&mut (_, ref mut copied, len)), Some(buffer)) => {
+                let end = (*copied + EXPORT_CHUNK).min(len);
+                for i in *copied..end {
+                    buffer[i] = self.layers[..self.active].iter().map(|layer| layer[i]).sum();
+                }
+                *copied = end;
+                end == len
+            },
+            _ => false,
+        };
+        if complete {
+            let (path, _, len) = self.export.pending.take().unwrap();
+            let buffer = self.export.buffer.take().unwrap();
+            if self.export.to_worker.try_send((path, buffer, len, self.sample_rate as u32)).is_err() {
+                println!("Export worker gone");
+            }
+        }
+    }
+
+    fn start(&self) -> f32 {
+        if self.reverse { self.len as f32 - 1. } else { 0. }
+    }
+
+    /// Sets the loop length; false, and the looper empty, if nothing
+    /// was recorded
+    fn finish_recording(&mut self) -> bool {
+        let recorded = self.len;
+        if recorded == 0 {
+            self.clear();
+            return false;
+        }
+        if let Some(bpm) = self.tempo {
+            if self.quantize {
+                let beat = 60. / bpm * self.sample_rate as f32;
+                let beats = (recorded as f32 / beat).round().max(1.);
+                self.len = ((beats * beat) as usize).min(self.layers[0].len());
+            }
+        }
+        // silence the padding of a loop rounded up to the beat
+        for x in self.layers[0][recorded.min(self.len)..self.len].iter_mut() {
+            *x = 0.;
+        }
+        self.pos = self.start();
+        true
+    }
+
+    /// folds the first overdub into the recording to free a layer
+    fn merge_oldest_overdub(&mut self) {
+        let overdub = self.layers.remove(1);
+        for (x, y) in self.layers[0].iter_mut().zip(overdub[..self.len].iter()) {
+            *x += *y;
+        }
+        self.layers.push(overdub);
+        self.active -= 1;
+    }
+
+    fn mixed(&self, i: usize) -> f32 {
+        self.layers[..self.active].iter().map(|layer| layer[i]).sum()
+    }
+
+    fn play_sample(&self) -> f32 {
+        let i = self.pos.floor() as usize % self.len;
+        let frac = self.pos - self.pos.floor();
+        let next = (i + 1) % self.len;
+        let a = self.mixed(i);
+        a + frac * (self.mixed(next) - a)
+    }
+
+    fn advance(&mut self) {
+        let step = if self.half_speed { 0.5 } else { 1. };
+        let len = self.len as f32;
+        self.pos += if self.reverse { -step } else { step };
+        if self.pos >= len {
+            self.pos -= len;
+        } else if self.pos < 0. {
+            self.pos += len;
+        }
+    }
+
+    fn process(&mut self, x: f32) -> f32 {
+        match self.state {
+            State::Empty | State::Stopped => x,
+            State::Recording => {
+                self.layers[0][self.len] = x;
+                self.len += 1;
+                if self.len == self.layers[0].len() {
+                    println!("Loop memory full, playing");
+                    self.play();
+                }
+                x
+            },
+            State::Playing => {
+                let y = self.play_sample();
+                self.advance();
+                x + self.level * y
+            },
+            State::Overdubbing => {
+                let y = self.play_sample();
+                let i = self.pos.floor() as usize % self.len;
+                if i != self.last_written {
+                    self.layers[self.active - 1][i] += x;
+                    self.last_written = i;
+                }
+                self.advance();
+                x + self.level * y
+            },
+        }
+    }
+
+    /// Acts on the rising edge of a momentary footswitch parameter
+    fn switch(&mut self, idx: usize, val: f32) -> bool {
+        let pressed = val >= 0.5 && self.switches[idx] < 0.5;
+        self.switches[idx] = val;
+        pressed
+    }
+
+    fn print_state(&self) {
+        println!("{}: {:?}, {:.2} s, {} of {} layers{}{}",
+            self.name(), self.state,
+            self.len as f32 / self.sample_rate as f32,
+            self.active, self.recorded,
+            if self.reverse { ", reversed" } else { "" },
+            if self.half_speed { ", half speed" } else { "" });
+    }
+}
+
+impl Effect for Looper {
+
+    fn new(sample_rate: usize, _frame_size: u32) -> Self {
+        let max = (MAX_LOOP * sample_rate as f32) as usize;
+        let mut layers = Vec::with_capacity(MAX_LAYERS);
+        for _ in 0..MAX_LAYERS {
+            layers.push(vec![0.; max]);
+        }
+        let (to_worker, jobs) = sync_channel(1);
+        let (done, from_worker) = sync_channel(1);
+        thread::spawn(move || export_worker(jobs, done));
+        Looper {
+            bypassing: false,
+            sample_rate,
+            state: State::Empty,
+            layers,
+            active: 0,
+            recorded: 0,
+            len: 0,
+            pos: 0.,
+            last_written: 0,
+            level: 1.,
+            reverse: false,
+            half_speed: false,
+            quantize: true,
+            tempo: None,
+            switches: [0.; 5],
+            export: Export {
+                buffer: Some(vec![0.; max]),
+                pending: None,
+                to_worker,
+                from_worker,
+            },
+        }
+    }
+
+    fn name(&self) -> &str {
+        "looper"
+    }
+
+    fn process_samples(&mut self, input: &[f32], output_l: &mut [f32], output_r: &mut [f32]) {
+
+        self.copy_export();
+
+        if self.bypassing {
+            output_l.clone_from_slice(input);
+            output_r.clone_from_slice(input);
+            return;
+        }
+
+        for (i, x) in input.iter().enumerate() {
+            let y = self.process(*x);
+            output_l[i] = y;
+            output_r[i] = y;
+        }
+    }
+
+    /// The loop would play back at the wrong pitch, so it is dropped
+    fn sample_rate_changed(&mut self, sample_rate: usize) {
+        let max = (MAX_LOOP * sample_rate as f32) as usize;
+        for layer in self.layers.iter_mut() {
+            *layer = vec![0.; max];
+        }
+        // waits for an export being written so only one buffer is around
+        if self.export.buffer.is_none() {
+            self.export.from_worker.recv().ok();
+        }
+        self.export.buffer = Some(vec![0.; max]);
+        self.export.pending = None;
+        self.sample_rate = sample_rate;
+        self.clear();
+    }
+
+    fn params(&self) -> Vec<(String, Param)> {
+        use self::Param::*;
+        params(&[
+            ("level", Float(self.level)),
+            ("reverse", Int(self.reverse as i32)),
+            ("half", Int(self.half_speed as i32)),
+            ("quantize", Int(self.quantize as i32)),
+        ])
+    }
+
+    fn bypass(&mut self) {
+        self.bypassing = !self.bypassing;
+    }
+
+    fn is_bypassing(&self) -> bool {
+        self.bypassing
+    }
+
+    fn set(&mut self, param: &str, val: f32) {
+        match param {
+            "level" => self.set_level(val),
+            "reverse" => self.set_reverse(val >= 0.5),
+            "half" => self.set_half_speed(val >= 0.5),
+            "quantize" => self.quantize = val >= 0.5,
+            // momentary switches for MIDI footswitches
+            "foot" => if self.switch(0, val) { self.footswitch() },
+            "stop" => if self.switch(1, val) { self.stop() },
+            "clear" => if self.switch(2, val) { self.clear() },
+            "undo" => if self.switch(3, val) { self.undo() },
+            "redo" => if self.switch(4, val) { self.redo() },
+            _ => (),
+        }
+    }
+
+    fn ctrl(&mut self, msg: CtrlMsg) {
+        use self::CtrlMsg::*;
+        match msg {
+            Bypass => self.bypass(),
+            Set(_pedal_name, conf_name, val) => self.set(&conf_name, val),
+            Select(_pedal_name, conf_name, val) => {
+                match (conf_name.as_str(), val.as_str()) {
+                    ("action", "rec") => self.record(),
+                    ("action", "play") => self.play(),
+                    ("action", "dub") => self.overdub(),
+                    ("action", "stop") => self.stop(),
+                    ("action", "clear") => self.clear(),
+                    ("action", "undo") => self.undo(),
+                    ("action", "redo") => self.redo(),
+                    ("export", path) => self.export(path.to_owned()),
+                    _ => println!("Unknown {}: {}", conf_name, val),
+                }
+            },
+            Tempo(bpm) => self.tempo = if bpm > 0. { Some(bpm) } else { None },
+            Info(_) => self.print_state(),
+            _ => (),
+        }
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Looper, State};
+    use effects::Effect;
+    use std::env;
+    use wav::read_wav;
+
+    fn run(looper: &mut Looper, input: &[f32]) -> Vec<f32> {
+        let mut l = vec![0.; input.len()];
+        let mut r = vec![0.; input.len()];
+        looper.process_samples(input, &mut l, &mut r);
+        l
+    }
+
+    #[test]
+    fn test_record_and_play() {
+        let mut looper = Looper::new(1000, 64);
+        let riff: Vec<f32> = (0..100).map(|i| i as f32 / 100.).collect();
+
+        looper.footswitch();
+        run(&mut looper, &riff);
+        looper.footswitch();
+        assert_eq!(looper.state(), State::Playing);
+        assert_eq!(looper.len(), 100);
+
+        let out = run(&mut looper, &vec![0.; 200]);
+        assert_eq!(&out[..100], &riff[..]);
+        assert_eq!(&out[100..], &riff[..]);
+    }
+
+    #[test]
+    fn test_overdub_undo_redo() {
+        let mut looper = Looper::new(1000, 64);
+        looper.record();
+        run(&mut looper, &vec![0.25; 100]);
+        looper.overdub();
+        run(&mut looper, &vec![0.5; 100]);
+        looper.play();
+
+        assert_eq!(run(&mut looper, &vec![0.; 100]), vec![0.75; 100]);
+        looper.undo();
+        assert_eq!(run(&mut looper, &vec![0.; 100]), vec![0.25; 100]);
+        looper.redo();
+        assert_eq!(run(&mut looper, &vec![0.; 100]), vec![0.75; 100]);
+    }
+
+    #[test]
+    fn test_reverse_and_quantize() {
+        let mut looper = Looper::new(1000, 64);
+        // 120 bpm at 1000 Hz is 500 samples per beat
+        looper.ctrl(::effects::CtrlMsg::Tempo(120.));
+        looper.record();
+        let riff: Vec<f32> = (0..480).map(|i| i as f32).collect();
+        run(&mut looper, &riff);
+        looper.set_reverse(true);
+        looper.play();
+        assert_eq!(looper.len(), 500);
+
+        let out = run(&mut looper, &vec![0.; 500]);
+        assert_eq!(&out[..20], &vec![0.; 20][..]);
+        assert_eq!(out[20], 479.);
+        assert_eq!(out[499], 0.);
+    }
+
+    #[test]
+    fn test_export() {
+        let path = env::temp_dir().join("rasta_test_loop.wav");
+        let mut looper = Looper::new(1000, 64);
+        looper.record();
+        let riff: Vec<f32> = (0..100).map(|i| i as f32 / 200.).collect();
+        run(&mut looper, &riff);
+        looper.play();
+
+        looper.export(path.to_str().unwrap().to_owned());
+        run(&mut looper, &vec![0.; 64]);
+        // back from the worker once the file is written
+        looper.export.from_worker.recv().unwrap();
+        let (rate, samples) = read_wav(&path).unwrap();
+        assert_eq!((rate, samples.len()), (1000, 100));
+        assert!(samples.iter().zip(riff.iter()).all(|(a, b)| (a - b).abs() < 1e-4));
+    }
+}
@@ -2,9 +2,36 @@ pub mod overdrive;
 pub mod delay;
 pub mod autowah;
 pub mod tuner;
+pub mod tuning;
 pub mod tremelo;
 pub mod pedals;
+pub mod dynamics;
+pub mod gate;
+pub mod compressor;
+pub mod limiter;
+pub mod biquad;
+pub mod eq;
+pub mod lfo;
+pub mod delay_line;
+pub mod chorus;
+pub mod flanger;
+pub mod vibrato;
+pub mod phaser;
+pub mod stereo_delay;
+pub mod looper;
+pub mod pitch;
+pub mod pitch_shift;
+pub mod octaver;
+pub mod harmonizer;
+pub mod pitch_to_midi;
+pub mod snapshot;
 pub use self::pedals::Pedals;
+use midi::MidiEvent;
+use self::tuning::Tuning;
+use setlist::Setlist;
+use automation::Automation;
+use std::fmt;
+use std::sync::Arc;
 
 pub trait Effect: Send {
     fn new(sample_rate: usize, frame_size: u32) -> Self
@@ -17,14 +44,133 @@ pub trait Effect: Send {
         output_r.clone_from_slice(input);
     }
 
+    /// Receives the signal tapped at another point of the chain,
+    /// called right before `process_samples`
+    fn sidechain(&mut self, _key: &[f32]) {}
+
+    /// Hands over the MIDI produced by the last `process_samples`, with
+    /// times as sample offsets into that block
+    fn midi_out(&mut self, _events: &mut Vec<MidiEvent>) {}
+
+    /// Adapts to a new sample rate. Never called from the audio thread,
+    /// so buffers may be reallocated here
+    fn sample_rate_changed(&mut self, _sample_rate: usize) {}
+
+    /// Largest block `process_samples` gets from now on, also called off
+    /// the audio thread. Blocks can always be shorter.
+    fn frame_size_changed(&mut self, _frame_size: u32) {}
+
+    /// Current value of every parameter, in an order that sets them back
+    /// with `Set` and `Select`
+    fn params(&self) -> Vec<(String, Param)> {
+        vec![]
+    }
+
+    /// A live status line, like the note the tuner hears, for the TUI
+    fn readout(&self) -> Option<String> {
+        None
+    }
+
     fn bypass(&mut self);
 
     fn is_bypassing(&self) -> bool;
 
+    /// What `Set` does, with a borrowed name so a morph or a MIDI
+    /// controller can drive a parameter without allocating. Effects with
+    /// numeric parameters handle `Set` here.
+    fn set(&mut self, param: &str, val: f32) {
+        self.ctrl(CtrlMsg::Set(String::new(), param.to_owned(), val));
+    }
+
     fn ctrl(&mut self, msg: CtrlMsg);
 
 }
 
+/// Value of a pedal parameter
+#[derive(Clone, Debug, PartialEq)]
+pub enum Param {
+    /// continuous, set with `Set`
+    Float(f32),
+    /// a count or a switch, set with `Set` but never in between
+    Int(i32),
+    /// set with `Select`
+    Name(String),
+}
+
+impl Param {
+
+    /// The message that sets `param` of `pedal` to this value
+    pub fn message(&self, pedal: &str, param: &str) -> CtrlMsg {
+        let (pedal, param) = (pedal.to_owned(), param.to_owned());
+        match *self {
+            Param::Float(v) => CtrlMsg::Set(pedal, param, v),
+            Param::Int(n) => CtrlMsg::Set(pedal, param, n as f32),
+            Param::Name(ref name) => CtrlMsg::Select(pedal, param, name.clone()),
+        }
+    }
+}
+
+impl fmt::Display for Param {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Param::Float(v) => write!(f, "{}", v),
+            Param::Int(n) => write!(f, "{}", n),
+            Param::Name(ref name) => write!(f, "{}", name),
+        }
+    }
+}
+
+/// `(name, value)` pairs for `Effect::params`
+pub fn params(list: &[(&str, Param)]) -> Vec<(String, Param)> {
+    list.iter().map(|&(name, ref val)| (name.to_owned(), val.clone())).collect()
+}
+
+/// Type names `create` knows
+pub const TYPES: &[&str] = &[
+    "delay", "overdrive", "tuner", "autowah", "tremelo", "gate", "compressor",
+    "limiter", "eq", "geq", "chorus", "flanger", "vibrato", "phaser",
+    "stereodelay", "looper", "pitchshift", "octaver", "harmonizer", "pitch2midi",
+];
+
+/// Creates an effect from its type name as used by `a <name> <type>`
+pub fn create(eff_type: &str, sample_rate: usize, frame_size: u32) -> Option<Box<Effect>> {
+    let eff : Box<Effect> = match eff_type {
+        "delay" =>      box delay::Delay::new(sample_rate, frame_size),
+        "overdrive" =>  box overdrive::Overdrive::new(sample_rate, frame_size),
+        "tuner" =>      box tuner::Tuner::new(sample_rate, frame_size),
+        "autowah" =>    box autowah::AutoWah::new(sample_rate, frame_size),
+        "tremelo" =>    box tremelo::Tremelo::new(sample_rate, frame_size),
+        "gate" =>       box gate::NoiseGate::new(sample_rate, frame_size),
+        "compressor" => box compressor::Compressor::new(sample_rate, frame_size),
+        "limiter" =>    box limiter::Limiter::new(sample_rate, frame_size),
+        "eq" =>         box eq::Equalizer::new(sample_rate, frame_size),
+        "geq" =>        box eq::Equalizer::graphic(sample_rate, frame_size),
+        "chorus" =>     box chorus::Chorus::new(sample_rate, frame_size),
+        "flanger" =>    box flanger::Flanger::new(sample_rate, frame_size),
+        "vibrato" =>    box vibrato::Vibrato::new(sample_rate, frame_size),
+        "phaser" =>     box phaser::Phaser::new(sample_rate, frame_size),
+        "stereodelay" => box stereo_delay::StereoDelay::new(sample_rate, frame_size),
+        "looper" =>     box looper::Looper::new(sample_rate, frame_size),
+        "pitchshift" => box pitch_shift::PitchShifter::new(sample_rate, frame_size),
+        "octaver" =>    box octaver::Octaver::new(sample_rate, frame_size),
+        "harmonizer" => box harmonizer::Harmonizer::new(sample_rate, frame_size),
+        "pitch2midi" => box pitch_to_midi::PitchToMidi::new(sample_rate, frame_size),
+        &_ => return None,
+    };
+    Some(eff)
+}
+
+/// Settings a type takes besides the parameters it lists
+const SETTINGS: &[(&str, &str)] = &[("tuner", "note"), ("tuner", "stream")];
+
+/// The names a pedal of `eff_type` is set by, read off a small instance
+pub fn param_names(eff_type: &str) -> Vec<String> {
+    let mut names: Vec<String> = create(eff_type, 8000, 64)
+        .map_or(vec![], |eff| eff.params().into_iter().map(|(name, _)| name).collect());
+    names.extend(SETTINGS.iter().filter(|s| s.0 == eff_type).map(|s| s.1.to_owned()));
+    names
+}
+
 type PedalName = String;
 type ConfName = String;
 type Val = f32;
@@ -37,6 +183,63 @@ pub enum CtrlMsg {
     Chain(Vec<CtrlMsg>),
     Disconnect(String),
     Connections,
+    /// add a pedal of a type, created by the control thread
     Add(String, String),
+    /// a pedal the control thread created for `Add`
+    Insert(PedalName, Box<Effect>),
     Set(PedalName, ConfName, Val),
+    /// set a parameter that takes a name rather than a number
+    Select(PedalName, ConfName, String),
+    /// print the pedal's meters
+    Info(PedalName),
+    /// drive a parameter from a MIDI controller: cc, pedal, param, min, max
+    MidiMap(u8, PedalName, ConfName, Val, Val),
+    MidiUnmap(u8),
+    /// global tempo in beats per minute, 0 clears it
+    Tempo(f32),
+    /// feed the output of a chain node ("in" or a pedal) to a pedal's key input
+    Sidechain(PedalName, String),
+    /// print the DSP load, xruns and time spent in every pedal
+    Stats,
+    /// print the statistics every so many seconds, 0 stops
+    StatsLog(f32),
+    /// switch a pedal on (true) or bypass it, unlike `BypassPedal` which toggles
+    Enable(PedalName, bool),
+    /// load a song or a setlist by name, answered by the control thread
+    LoadSong(String),
+    LoadSetlist(String),
+    /// start the loaded setlist at its first scene
+    Setlist(Setlist),
+    /// move through the setlist by so many scenes, or songs
+    Step(i32),
+    StepSong(i32),
+    /// go to a scene of the current song, an empty name prints where we are
+    Scene(String),
+    /// step through the setlist from a MIDI footswitch: cc, step
+    MidiStep(u8, i32),
+    /// switch to A/B slot 0 or 1, None toggles
+    Ab(Option<usize>),
+    /// morph between the A/B slots to a position (0 is A, 1 is B) over
+    /// so many seconds
+    Morph(f32, f32),
+    /// start (true) or stop recording the parameter changes
+    Record(bool),
+    /// save the last take to a file, answered by the control thread
+    SaveTake(String),
+    /// load a take from a file and play it, answered by the control thread
+    LoadTake(String),
+    /// play a take, None replays the last one recorded
+    Play(Option<Arc<Automation>>),
+    StopPlay,
+    /// print the levels at the taps, answered by the control thread
+    Meters,
+    /// also tap behind every pedal (true) or only `in` and `out`
+    MeterPedals(bool),
+    /// clear the held peaks and the clip counts
+    MeterReset,
+    /// tunings for a tuner, read from the file of `s <tuner> load <file>`
+    /// by the control thread
+    Tunings(PedalName, Vec<Tuning>),
+    /// a command that could not be parsed, with its usage
+    Invalid(String),
 }
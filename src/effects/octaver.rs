@@ -0,0 +1,274 @@
+use effects::{Effect, CtrlMsg, Param, params};
+use effects::biquad::{Biquad, Coeffs};
+use effects::dynamics::time_coeff;
+use effects::lfo::Lfo;
+use effects::pitch::PitchTracker;
+
+/// Envelope follower times in seconds
+const ATTACK: f32 = 0.002;
+const RELEASE: f32 = 0.05;
+/// Fraction of the envelope a zero crossing has to swing past,
+/// so noise around zero does not flip the dividers
+const HYSTERESIS: f32 = 0.1;
+/// Tracked pitches below this confidence keep the previous note
+const MIN_CONFIDENCE: f32 = 0.8;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+    /// flip-flop frequency dividers, like the classic analog boxes
+    Analog,
+    /// sine oscillators following the detected pitch
+    Tracked,
+}
+
+impl Mode {
+    fn from_name(name: &str) -> Option<Mode> {
+        match name {
+            "analog" => Some(Mode::Analog),
+            "tracked" => Some(Mode::Tracked),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match *self {
+            Mode::Analog => "analog",
+            Mode::Tracked => "tracked",
+        }
+    }
+}
+
+/// Octaver adding one and two octaves down and one octave up.
+///
+/// The analog mode divides the zero crossings of the lowpassed input
+/// with flip-flops and shapes the square waves with the input envelope.
+/// It reacts instantly but glitches on chords. The tracked mode runs
+/// sine oscillators at the detected pitch, which is smoother but lags a
+/// few milliseconds behind the attack. The octave up is the rectified
+/// input in both modes.
+pub struct Octaver {
+    pub bypassing: bool,
+    mode: Mode,
+    dry: f32,
+    sub1: f32,
+    sub2: f32,
+    up: f32,
+
+    /// isolates the fundamental before the dividers
+    input_lp: Biquad,
+    /// rounds off the edges of the divided square waves
+    sub_lp: Biquad,
+    /// removes the DC of the rectified octave up
+    up_hp: Biquad,
+
+    env: f32,
+    attack: f32,
+    release: f32,
+
+    positive: bool,
+    /// divide by two and by four
+    ff1: bool,
+    ff2: bool,
+
+    tracker: PitchTracker,
+    osc1: Lfo,
+    osc2: Lfo,
+}
+
+impl Octaver {
+
+    pub fn set_mode(&mut self, mode: Mode) {
+        self.mode = mode;
+    }
+
+    fn snap_filters(&mut self, sr: f32) {
+        self.input_lp.snap_coeffs(Coeffs::lowpass(sr, 400., 0.707));
+        self.sub_lp.snap_coeffs(Coeffs::lowpass(sr, 800., 0.707));
+        self.up_hp.snap_coeffs(Coeffs::highpass(sr, 60., 0.707));
+    }
+
+    fn follow(&mut self, x: f32) -> f32 {
+        let level = x.abs();
+        let coeff = if level > self.env { self.attack } else { self.release };
+        self.env = level + coeff * (self.env - level);
+        self.env
+    }
+
+    fn analog(&mut self, x: f32, env: f32) -> (f32, f32) {
+        let fundamental = self.input_lp.process(x);
+        let threshold = HYSTERESIS * env;
+        if !self.positive && fundamental > threshold {
+            self.positive = true;
+            self.ff1 = !self.ff1;
+            if self.ff1 {
+                self.ff2 = !self.ff2;
+            }
+        } else if self.positive && fundamental < -threshold {
+            self.positive = false;
+        }
+        let square = |on: bool| if on { 1. } else { -1. };
+        (square(self.ff1) * env, square(self.ff2) * env)
+    }
+
+    fn tracked(&mut self, x: f32, env: f32) -> (f32, f32) {
+        if let Some((freq, confidence)) = self.tracker.process(x) {
+            if confidence >= MIN_CONFIDENCE {
+                self.osc1.set_rate(freq / 2.);
+                self.osc2.set_rate(freq / 4.);
+            }
+        }
+        (self.osc1.next() * env, self.osc2.next() * env)
+    }
+}
+
+impl Effect for Octaver {
+
+    fn new(sample_rate: usize, _frame_size: u32) -> Self {
+        let sr = sample_rate as f32;
+        let mut octaver = Octaver {
+            bypassing: false,
+            mode: Mode::Analog,
+            dry: 1.,
+            sub1: 0.7,
+            sub2: 0.,
+            up: 0.,
+            input_lp: Biquad::new(sr),
+            sub_lp: Biquad::new(sr),
+            up_hp: Biquad::new(sr),
+            env: 0.,
+            attack: time_coeff(ATTACK, sr),
+            release: time_coeff(RELEASE, sr),
+            positive: false,
+            ff1: false,
+            ff2: false,
+            tracker: PitchTracker::new(sr),
+            osc1: Lfo::new(sr, 0.),
+            osc2: Lfo::new(sr, 0.),
+        };
+        octaver.snap_filters(sr);
+        octaver
+    }
+
+    fn name(&self) -> &str {
+        "octaver"
+    }
+
+    fn process_samples(&mut self, input: &[f32], output_l: &mut [f32], output_r: &mut [f32]) {
+
+        if self.bypassing {
+            output_l.clone_from_slice(input);
+            output_r.clone_from_slice(input);
+            return;
+        }
+
+        for (i, x) in input.iter().enumerate() {
+            let env = self.follow(*x);
+            let (sub1, sub2) = match self.mode {
+                Mode::Analog => self.analog(*x, env),
+                Mode::Tracked => self.tracked(*x, env),
+            };
+            let sub = self.sub_lp.process(self.sub1 * sub1 + self.sub2 * sub2);
+            let up = self.up_hp.process(x.abs());
+
+            let y = self.dry * x + sub + self.up * up;
+            output_l[i] = y;
+            output_r[i] = y;
+        }
+    }
+
+    fn sample_rate_changed(&mut self, sample_rate: usize) {
+        let sr = sample_rate as f32;
+        for filter in [&mut self.input_lp, &mut self.sub_lp, &mut self.up_hp].iter_mut() {
+            filter.set_sample_rate(sr);
+        }
+        self.snap_filters(sr);
+        self.attack = time_coeff(ATTACK, sr);
+        self.release = time_coeff(RELEASE, sr);
+        self.tracker = PitchTracker::new(sr);
+        self.osc1.set_sample_rate(sr);
+        self.osc2.set_sample_rate(sr);
+    }
+
+    fn params(&self) -> Vec<(String, Param)> {
+        use self::Param::*;
+        params(&[
+            ("mode", Name(self.mode.name().to_owned())),
+            ("dry", Float(self.dry)),
+            ("sub1", Float(self.sub1)),
+            ("sub2", Float(self.sub2)),
+            ("up", Float(self.up)),
+        ])
+    }
+
+    fn bypass(&mut self) {
+        self.bypassing = !self.bypassing;
+    }
+
+    fn is_bypassing(&self) -> bool {
+        self.bypassing
+    }
+
+    fn set(&mut self, param: &str, val: f32) {
+        let val = val.max(0.).min(1.);
+        match param {
+            "dry" => self.dry = val,
+            "sub1" => self.sub1 = val,
+            "sub2" => self.sub2 = val,
+            "up" => self.up = val,
+            _ => (),
+        }
+    }
+
+    fn ctrl(&mut self, msg: CtrlMsg) {
+        use self::CtrlMsg::*;
+        match msg {
+            Bypass => self.bypass(),
+            Set(_pedal_name, conf_name, val) => self.set(&conf_name, val),
+            Select(_pedal_name, conf_name, val) => {
+                if &conf_name == "mode" {
+                    match Mode::from_name(&val) {
+                        Some(mode) => self.set_mode(mode),
+                        None => println!("Unknown mode: {} (analog, tracked)", val),
+                    }
+                }
+            },
+            _ => (),
+        }
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Octaver, Mode};
+    use effects::{Effect, CtrlMsg};
+    use std::f32::consts::PI;
+
+    /// rising zero crossings per second of the second half of `buf`
+    fn frequency(buf: &[f32], sample_rate: f32) -> f32 {
+        let half = &buf[buf.len() / 2..];
+        let crossings = half.windows(2).filter(|w| w[0] < 0. && w[1] >= 0.).count();
+        crossings as f32 * sample_rate / half.len() as f32
+    }
+
+    fn sub_octave(mode: Mode) -> f32 {
+        let mut octaver = Octaver::new(48000, 256);
+        octaver.set_mode(mode);
+        octaver.ctrl(CtrlMsg::Set("oct".into(), "dry".into(), 0.));
+        octaver.ctrl(CtrlMsg::Set("oct".into(), "sub1".into(), 1.));
+
+        let input: Vec<f32> = (0..48000)
+            .map(|i| 0.5 * (2. * PI * 196. * i as f32 / 48000.).sin())
+            .collect();
+        let mut l = vec![0.; 48000];
+        let mut r = vec![0.; 48000];
+        octaver.process_samples(&input, &mut l, &mut r);
+        frequency(&l, 48000.)
+    }
+
+    #[test]
+    fn test_sub_octave() {
+        assert!((sub_octave(Mode::Analog) - 98.).abs() < 2.);
+        assert!((sub_octave(Mode::Tracked) - 98.).abs() < 2.);
+    }
+}
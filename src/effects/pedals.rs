@@ -1,13 +1,61 @@
 use effects::*;
+use midi::{Control, MidiMap, MidiMapping, MidiEvent};
+use stats::Recorder;
+use meters::Taps;
+use tui::{View, PedalView, ViewFeed};
+use setlist::Setlist;
+use effects::snapshot::{Morph, PedalState, Snapshot};
+use automation::{Automation, Player};
+use std::sync::Arc;
+use std::sync::mpsc::SyncSender;
 use std::collections::HashMap;
+use std::time::Instant;
+use std::mem;
 
 pub struct Pedals {
     sample_rate: usize,
     frame_size: u32,
     pub pedals: HashMap<String, Box<Effect>>,
     pub bypassing: bool,
+    midi_map: MidiMap,
+    /// beats per minute shared by the tempo-synced pedals
+    tempo: f32,
     /// in -> eff1 -> eff2 -> out
     chain: HashMap<String, String>,
+    /// pedal -> node whose output keys it
+    sidechains: HashMap<String, String>,
+    /// latest output of every node used as a sidechain source, allocated
+    /// at the frame size, and how much of it the last block filled
+    taps: HashMap<String, (Vec<f32>, usize)>,
+    /// the signal between two pedals
+    scratch: Vec<f32>,
+    /// MIDI sent by the pedals during the current block
+    midi_out: Vec<MidiEvent>,
+    /// times every pedal when set
+    pub stats: Option<Recorder>,
+    /// measures the levels when set
+    pub meters: Option<Taps>,
+    /// hands views to the TUI when set
+    pub views: Option<ViewFeed>,
+    /// songs and scenes stepped through with `next` and `prev`
+    setlist: Option<Setlist>,
+    /// A/B slots, `slot` is the one in use
+    slots: [Option<Snapshot>; 2],
+    slot: usize,
+    morph: Option<Morph>,
+    /// set while the morph's own changes go out, the take gets its
+    /// position instead
+    morphing: bool,
+    /// samples processed so far, and the one changes take effect on
+    clock: u64,
+    now: u64,
+    /// the take being recorded and the sample it started on
+    recording: Option<(u64, Automation)>,
+    /// shared with the player and the control thread, never copied
+    take: Option<Arc<Automation>>,
+    player: Option<Player>,
+    /// where finished takes go for saving
+    pub takes: Option<SyncSender<Arc<Automation>>>,
 }
 
 impl Effect for Pedals {
@@ -18,7 +66,27 @@ impl Effect for Pedals {
             frame_size,
             pedals: HashMap::new(),
             bypassing: false,
+            midi_map: MidiMap::new(),
+            tempo: 0.,
             chain: HashMap::new(),
+            sidechains: HashMap::new(),
+            taps: HashMap::new(),
+            scratch: vec![0.; frame_size as usize],
+            midi_out: Vec::with_capacity(256),
+            stats: None,
+            meters: None,
+            views: None,
+            setlist: None,
+            slots: [None, None],
+            slot: 0,
+            morph: None,
+            morphing: false,
+            clock: 0,
+            now: 0,
+            recording: None,
+            take: None,
+            player: None,
+            takes: None,
         }
     }
 
@@ -26,49 +94,78 @@ impl Effect for Pedals {
         "effects"
     }
 
-    fn process_samples(&mut self, input: &[f32], output_l: &mut [f32], output_r: &mut [f32]) {
-
-        if self.bypassing {
-            output_l.clone_from_slice(input);
-            output_r.clone_from_slice(input);
+    fn sample_rate_changed(&mut self, sample_rate: usize) {
+        if sample_rate == self.sample_rate {
             return;
         }
+        self.sample_rate = sample_rate;
+        for eff in self.pedals.values_mut() {
+            eff.sample_rate_changed(sample_rate);
+        }
+        if let Some(ref mut stats) = self.stats {
+            stats.set_sample_rate(sample_rate);
+        }
+        if let Some(ref mut meters) = self.meters {
+            meters.set_sample_rate(sample_rate);
+        }
+        if let Some(ref mut views) = self.views {
+            views.set_sample_rate(sample_rate);
+        }
+    }
 
-        let mut next_node = {
-            let temp = self.chain.get("in");
-            if temp.is_none() {
-                return;
-            } else {
-                temp.unwrap()
-            }
-        };
-
-        let mut temp_buf = input.to_owned();
-
-        while *next_node != "out" {
-            let eff = self.pedals.get_mut(next_node);
-            let eff = if eff.is_none() {
-                break
-            } else {
-                eff.unwrap()
-            };
-            // if eff.is_bypassing() { continue; }
-
-            eff.process_samples(&temp_buf, output_l, output_r);
-            temp_buf = output_l.to_owned();
+    fn frame_size_changed(&mut self, frame_size: u32) {
+        if frame_size == self.frame_size {
+            return;
+        }
+        self.frame_size = frame_size;
+        for eff in self.pedals.values_mut() {
+            eff.frame_size_changed(frame_size);
+        }
+        for stored in self.taps.values_mut() {
+            *stored = (vec![0.; frame_size as usize], 0);
+        }
+        self.scratch = vec![0.; frame_size as usize];
+    }
 
-            let next = self.chain.get(next_node);
+    /// Events of all pedals in time order, JACK wants them sorted
+    fn midi_out(&mut self, events: &mut Vec<MidiEvent>) {
+        self.midi_out.sort_by_key(|e| e.time);
+        events.extend(self.midi_out.drain(..));
+    }
 
-            next_node = if next.is_none() {
-                break
-            } else {
-                next.unwrap()
+    /// Splits the block wherever automation is due, so every change
+    /// lands on its sample
+    fn process_samples(&mut self, input: &[f32], output_l: &mut [f32], output_r: &mut [f32]) {
+        let len = input.len();
+        if let Some(ref mut meters) = self.meters {
+            meters.tap("in", input, None);
+        }
+        let mut from = 0;
+        while from < len {
+            self.now = self.clock;
+            let to = match self.play_due() {
+                Some(wait) => from + (wait.min((len - from) as u64) as usize),
+                None => len,
             };
+            let before = self.midi_out.len();
+            self.process_block(&input[from..to], &mut output_l[from..to], &mut output_r[from..to]);
+            for event in self.midi_out[before..].iter_mut() {
+                event.time += from as u32;
+            }
+            self.clock += (to - from) as u64;
+            from = to;
+        }
+        self.now = self.clock;
+        if let Some(ref mut meters) = self.meters {
+            meters.tap("out", output_l, Some(output_r));
+            meters.block(len);
+        }
+        if self.views.as_mut().map_or(false, |views| views.due(len)) {
+            let view = self.view();
+            if let Some(ref views) = self.views {
+                views.send(view);
+            }
         }
-
-        output_l.clone_from_slice(&temp_buf);
-        output_r.clone_from_slice(&temp_buf);
-
     }
 
     fn bypass(&mut self) {
@@ -85,12 +182,20 @@ impl Effect for Pedals {
         match msg {
             Bypass => self.bypass(),
             BypassPedal(name) => {
-                let mut pedal = self.pedals.get_mut(&name).unwrap();
-                (*pedal).ctrl(Bypass);
+                let on = match self.pedals.get_mut(&name) {
+                    Some(pedal) => {
+                        pedal.ctrl(Bypass);
+                        !pedal.is_bypassing()
+                    },
+                    None => return println!("No pedal named {}", name),
+                };
+                self.record(&name, "on", Param::Int(on as i32));
             }
             Tuner => {
-                let mut tuner = self.pedals.get_mut("tuner").unwrap();
-                (*tuner).ctrl(msg);
+                match self.pedals.get_mut("tuner") {
+                    Some(tuner) => tuner.ctrl(msg),
+                    None => println!("No pedal named tuner"),
+                }
             },
             Connect(from, to) => {
                 self.connect(&from, &to)
@@ -99,20 +204,108 @@ impl Effect for Pedals {
                 self.disconnect(&from)
             },
             Connections => self.print_conn(),
-            Add(name, eff_type) => {
-                let eff : Box<Effect> = match eff_type.as_str() {
-                    "delay" =>      box delay::Delay::new(self.sample_rate, self.frame_size),
-                    "overdrive" =>  box overdrive::Overdrive::new(self.sample_rate, self.frame_size),
-                    "tuner" =>      box tuner::Tuner::new(self.sample_rate, self.frame_size),
-                    "autowah" =>    box autowah::AutoWah::new(self.sample_rate, self.frame_size),
-                    &_ => unimplemented!()
+            Insert(name, eff) => self.add(&name, eff),
+            Set(name, conf, val) => self.set_param(&name, &conf, val),
+            Select(name, conf, val) => {
+                if !self.pedals.contains_key(&name) {
+                    return println!("No pedal named {}", name);
+                }
+                self.record(&name, &conf, Param::Name(val.clone()));
+                if let Some(pedal) = self.pedals.get_mut(&name) {
+                    pedal.ctrl(Select(name, conf, val));
+                }
+            },
+            Tunings(name, tunings) => {
+                match self.pedals.get_mut(&name) {
+                    Some(pedal) => pedal.ctrl(Tunings(name, tunings)),
+                    None => println!("No pedal named {}", name),
+                }
+            },
+            Info(name) => {
+                match self.pedals.get_mut(&name) {
+                    Some(pedal) => pedal.ctrl(Info(name)),
+                    None => println!("No pedal named {}", name),
+                }
+            },
+            MidiMap(cc, pedal, param, min, max) => {
+                self.midi_map.map(cc, MidiMapping { pedal, param, min, max });
+            },
+            MidiUnmap(cc) => self.midi_map.unmap(cc),
+            Tempo(bpm) => {
+                self.tempo = bpm.max(0.);
+                for pedal in self.pedals.values_mut() {
+                    pedal.ctrl(Tempo(self.tempo));
+                }
+            },
+            Sidechain(name, source) => {
+                self.set_sidechain(&name, &source);
+            },
+            Enable(name, on) => self.enable(&name, on),
+            Setlist(setlist) => {
+                self.setlist = Some(setlist);
+                self.scene();
+            },
+            Step(n) => {
+                if self.setlist.as_mut().map_or(false, |s| s.step(n)) {
+                    self.scene();
+                }
+            },
+            StepSong(n) => {
+                if self.setlist.as_mut().map_or(false, |s| s.step_song(n)) {
+                    self.scene();
+                }
+            },
+            Scene(name) => {
+                let found = match self.setlist {
+                    Some(ref mut setlist) if name.is_empty() => {
+                        println!("{}", setlist.describe());
+                        false
+                    },
+                    Some(ref mut setlist) => setlist.goto(&name),
+                    None => {
+                        println!("No setlist loaded");
+                        return;
+                    },
                 };
-                self.add(&name, eff);
+                if found {
+                    self.scene();
+                } else if !name.is_empty() {
+                    println!("No scene named {}", name);
+                }
+            },
+            MidiStep(cc, step) => self.midi_map.map_step(cc, step),
+            Ab(slot) => {
+                let slot = slot.unwrap_or(1 - self.slot);
+                self.switch_slot(slot);
+            },
+            Morph(position, seconds) => self.start_morph(position, seconds),
+            Record(true) => {
+                self.recording = Some((self.now, Automation::new(self.sample_rate)));
+                println!("Recording");
             },
-            Set(name, conf, val) => {
-                let mut pedal = self.pedals.get_mut(&name).unwrap();
-                (*pedal).ctrl(Set(name, conf, val));
+            Record(false) => match self.recording.take() {
+                Some((_, take)) => {
+                    println!("Recorded {} changes over {:.1} s", take.events.len(), take.duration());
+                    let take = Arc::new(take);
+                    if let Some(ref takes) = self.takes {
+                        takes.try_send(take.clone()).ok();
+                    }
+                    self.take = Some(take);
+                },
+                None => println!("Not recording"),
             },
+            Play(take) => match take.or_else(|| self.take.clone()) {
+                Some(take) => {
+                    println!("Playing {} changes", take.events.len());
+                    self.player = Some(Player::new(take, self.now));
+                },
+                None => println!("Nothing recorded"),
+            },
+            StopPlay => self.player = None,
+            // answered by the control thread
+            Add(..) | Stats | StatsLog(_) | LoadSong(_) | LoadSetlist(_) | SaveTake(_) | LoadTake(_) |
+            Meters | MeterPedals(_) | MeterReset => (),
+            Invalid(usage) => println!("{}", usage),
             Chain(v) => {
                 for i in v.into_iter() {
                     self.ctrl(i);
@@ -125,7 +318,119 @@ impl Effect for Pedals {
 
 impl Pedals {
 
-    pub fn add(&mut self, name: &str, eff: Box<Effect>) {
+    /// Runs a block through the chain
+    fn process_block(&mut self, input: &[f32], output_l: &mut [f32], output_r: &mut [f32]) {
+
+        if self.morph.as_ref().map_or(false, |m| m.is_gliding()) {
+            self.morph.as_mut().unwrap().advance(input.len());
+            self.morph_moved();
+        }
+
+        if self.bypassing {
+            output_l.clone_from_slice(input);
+            output_r.clone_from_slice(input);
+            return;
+        }
+
+        let mut next_node = {
+            let temp = self.chain.get("in");
+            if temp.is_none() {
+                return;
+            } else {
+                temp.unwrap()
+            }
+        };
+
+        tap(&mut self.taps, "in", input);
+
+        let len = input.len();
+        if len > self.scratch.len() {
+            // only when called with more than the frame size
+            self.scratch.resize(len, 0.);
+        }
+        let temp_buf = &mut self.scratch[..len];
+        temp_buf.clone_from_slice(input);
+        let mut processed = false;
+
+        while *next_node != "out" {
+            let eff = self.pedals.get_mut(next_node);
+            let eff = if eff.is_none() {
+                break
+            } else {
+                eff.unwrap()
+            };
+            // if eff.is_bypassing() { continue; }
+
+            if let Some(src) = self.sidechains.get(next_node) {
+                if let Some(&(ref key, used)) = self.taps.get(src) {
+                    eff.sidechain(&key[..used]);
+                }
+            }
+
+            let start = Instant::now();
+            eff.process_samples(temp_buf, output_l, output_r);
+            if let Some(ref mut stats) = self.stats {
+                stats.pedal(next_node, start.elapsed());
+            }
+            if let Some(ref mut meters) = self.meters {
+                if meters.pedals() {
+                    meters.tap(next_node, output_l, None);
+                }
+            }
+            eff.midi_out(&mut self.midi_out);
+            temp_buf.clone_from_slice(output_l);
+            processed = true;
+            tap(&mut self.taps, next_node, temp_buf);
+
+            let next = self.chain.get(next_node);
+
+            next_node = if next.is_none() {
+                break
+            } else {
+                next.unwrap()
+            };
+        }
+
+        // pedals feed each other the left channel, the last one
+        // in the chain sets the stereo image
+        if !processed {
+            output_l.clone_from_slice(temp_buf);
+            output_r.clone_from_slice(temp_buf);
+        }
+
+    }
+
+
+    /// Plays the automation due now, returns the samples until the next
+    /// change
+    fn play_due(&mut self) -> Option<u64> {
+        let mut player = match self.player.take() {
+            Some(player) => player,
+            None => return None,
+        };
+        player.due(self.clock, |e| self.change(&e.pedal, &e.param, &e.value));
+        let wait = player.until_next(self.clock);
+        match wait {
+            Some(_) => self.player = Some(player),
+            None => println!("Automation played"),
+        }
+        wait
+    }
+
+    /// Stamps a parameter change into the take being recorded
+    fn record(&mut self, pedal: &str, param: &str, value: Param) {
+        if self.morphing {
+            return;
+        }
+        if let Some((start, ref mut take)) = self.recording {
+            take.record(self.now - start, pedal, param, value);
+        }
+    }
+
+    pub fn add(&mut self, name: &str, mut eff: Box<Effect>) {
+        if self.tempo > 0. {
+            eff.ctrl(CtrlMsg::Tempo(self.tempo));
+        }
         self.pedals.insert(name.to_owned(), eff);
     }
 
@@ -137,19 +442,397 @@ impl Pedals {
         self.chain.remove(from);
     }
 
+    /// Applies the parameter changes mapped to a raw MIDI message. They
+    /// take effect at the start of the block, so a take records them
+    /// there rather than at `_time`, where they came in.
+    pub fn midi(&mut self, _time: u32, bytes: &[u8]) {
+        self.now = self.clock;
+        // out of `self` while it is walked, `new` does not allocate
+        let map = mem::replace(&mut self.midi_map, MidiMap::new());
+        map.translate(bytes, |control| match control {
+            Control::Set(pedal, param, val) => self.set_param(pedal, param, val),
+            Control::Step(n) => self.ctrl(CtrlMsg::Step(n)),
+        });
+        self.midi_map = map;
+    }
+
+    /// Keys `name` from the output of `source`, "in" being the dry input.
+    /// "off" removes the sidechain.
+    pub fn set_sidechain(&mut self, name: &str, source: &str) {
+        if !self.pedals.contains_key(name) {
+            println!("No pedal named {}", name);
+            return;
+        }
+        if source == "off" {
+            self.sidechains.remove(name);
+            let pedal = self.pedals.get_mut(name).unwrap();
+            pedal.ctrl(CtrlMsg::Sidechain(name.to_owned(), source.to_owned()));
+        } else if source == "in" || self.pedals.contains_key(source) {
+            self.sidechains.insert(name.to_owned(), source.to_owned());
+            let frame_size = self.frame_size as usize;
+            self.taps.entry(source.to_owned()).or_insert_with(|| (vec![0.; frame_size], 0));
+        } else {
+            println!("No node named {}", source);
+            return;
+        }
+        let sidechains = &self.sidechains;
+        self.taps.retain(|node, _| sidechains.values().any(|src| src == node));
+    }
+
+    /// Sets up the current scene of the setlist
+    fn scene(&mut self) {
+        let msgs = match self.setlist {
+            Some(ref setlist) => {
+                println!("{}", setlist.describe());
+                setlist.messages()
+            },
+            None => return,
+        };
+        self.apply(msgs);
+    }
+
+    /// Whether every pedal is on and the values of its parameters
+    pub fn snapshot(&self) -> Snapshot {
+        let mut names: Vec<_> = self.pedals.keys().collect();
+        names.sort();
+        Snapshot {
+            pedals: names.into_iter()
+                .map(|name| {
+                    let pedal = &self.pedals[name];
+                    PedalState { name: name.clone(), on: !pedal.is_bypassing(), params: pedal.params() }
+                })
+                .collect(),
+        }
+    }
+
+    /// Keeps the settings in the slot in use and recalls `slot`. An empty
+    /// slot starts as a copy of the other one.
+    fn switch_slot(&mut self, slot: usize) {
+        let current = self.snapshot();
+        // a half-way morph belongs to neither slot
+        if self.morph.is_none() {
+            self.slots[self.slot] = Some(current.clone());
+        }
+        self.morph = None;
+        let msgs = match self.slots[slot] {
+            Some(ref snapshot) => snapshot.changes(&current),
+            None => vec![],
+        };
+        if self.slots[slot].is_none() {
+            self.slots[slot] = Some(current);
+        }
+        self.slot = slot;
+        println!("Slot {}", if slot == 0 { "A" } else { "B" });
+        self.apply(msgs);
+    }
+
+    /// Morphs from where the pedals are to `position` between the slots,
+    /// over `seconds` or at once. Starting one takes a snapshot, which
+    /// allocates once per command, the glide itself does not.
+    fn start_morph(&mut self, position: f32, seconds: f32) {
+        if self.morph.is_none() {
+            self.slots[self.slot] = Some(self.snapshot());
+            let morph = match self.slots {
+                [Some(ref a), Some(ref b)] => Morph::new(a, b, self.slot as f32),
+                _ => {
+                    println!("Set up both slots with `ab` first");
+                    return;
+                },
+            };
+            self.morph = Some(morph);
+        }
+        if seconds > 0. {
+            let samples = seconds * self.sample_rate as f32;
+            self.morph.as_mut().unwrap().glide(position, samples);
+        } else {
+            self.morph.as_mut().unwrap().jump(position);
+            self.morph_moved();
+        }
+    }
+
+    /// Sends what the last move of the morph changed and records where
+    /// the morph is, rather than every parameter it moved
+    fn morph_moved(&mut self) {
+        let morph = match self.morph.take() {
+            Some(morph) => morph,
+            None => return,
+        };
+        self.morphing = true;
+        morph.changes(|pedal, param, value| self.change(pedal, param, value));
+        self.morphing = false;
+        self.record("ab", "morph", Param::Float(morph.position));
+        self.morph = Some(morph);
+        self.morph_landed();
+    }
+
+    /// Sets a parameter from borrowed names, `on` switching the pedal on
+    /// or off. Only names are sent as an owned `Select`.
+    fn change(&mut self, pedal: &str, param: &str, value: &Param) {
+        match *value {
+            Param::Float(v) if param == "on" => self.enable(pedal, v != 0.),
+            Param::Int(n) if param == "on" => self.enable(pedal, n != 0),
+            Param::Float(v) => self.set_param(pedal, param, v),
+            Param::Int(n) => self.set_param(pedal, param, n as f32),
+            Param::Name(_) => self.ctrl(value.message(pedal, param)),
+        }
+    }
+
+    fn set_param(&mut self, name: &str, param: &str, val: f32) {
+        // `m 11 ab morph` sweeps from A to B with an expression pedal
+        if name == "ab" && param == "morph" {
+            return self.start_morph(val, 0.);
+        }
+        match self.pedals.get_mut(name) {
+            Some(pedal) => pedal.set(param, val),
+            None => return println!("No pedal named {}", name),
+        }
+        self.record(name, param, Param::Float(val));
+    }
+
+    /// Switches a pedal on (true) or off, unlike `bypass` which toggles
+    fn enable(&mut self, name: &str, on: bool) {
+        match self.pedals.get_mut(name) {
+            Some(pedal) => if pedal.is_bypassing() == on { pedal.ctrl(CtrlMsg::Bypass) },
+            None => return println!("No pedal named {}", name),
+        }
+        self.record(name, "on", Param::Int(on as i32));
+    }
+
+    /// Ends a morph that reached either slot, which becomes the one in use
+    fn morph_landed(&mut self) {
+        let end = match self.morph {
+            Some(ref morph) if morph.position == 0. || morph.position == 1. => morph.position as usize,
+            _ => return,
+        };
+        self.morph = None;
+        self.slot = end;
+    }
+
+    /// Sends control messages to the pedals, skipping missing ones
+    fn apply(&mut self, msgs: Vec<CtrlMsg>) {
+        use self::CtrlMsg::*;
+        for msg in msgs {
+            match msg {
+                // a scene or a mapping may name pedals this rig lacks, `ab`
+                // is the morph
+                Enable(ref name, _) | Set(ref name, _, _) | Select(ref name, _, _)
+                    if name != "ab" && !self.pedals.contains_key(name) => println!("No pedal named {}", name),
+                msg => self.ctrl(msg),
+            }
+        }
+    }
+
+    /// Pedals from `in` on as far as the chain goes
+    fn chain_order(&self) -> Vec<&str> {
+        let mut order = vec![];
+        let mut node = self.chain.get("in");
+        while let Some(name) = node {
+            if name == "out" || order.contains(&name.as_str()) || !self.pedals.contains_key(name) {
+                break;
+            }
+            order.push(name.as_str());
+            node = self.chain.get(name);
+        }
+        order
+    }
+
+    /// The chain with every pedal's state, then the pedals off the chain
+    /// What the TUI and `p` show
+    pub fn view(&self) -> View {
+        let chain = self.chain_order();
+        let mut others: Vec<&str> = self.pedals.keys()
+            .map(|name| name.as_str())
+            .filter(|name| !chain.contains(name))
+            .collect();
+        others.sort();
+        let pedal = |name: &str| {
+            let eff = &self.pedals[name];
+            PedalView {
+                name: name.to_owned(),
+                kind: eff.name().to_owned(),
+                on: !eff.is_bypassing(),
+                params: eff.params(),
+                readout: eff.readout(),
+            }
+        };
+        View {
+            chain: chain.iter().map(|&name| pedal(name)).collect(),
+            others: others.iter().map(|&name| pedal(name)).collect(),
+            bypassing: self.bypassing,
+            scene: self.setlist.as_ref().map(|s| s.describe()),
+        }
+    }
+
     pub fn print_conn(&self) {
+        let view = self.view();
+        println!("Chain: {}", view.chain_line());
+        for pedal in view.chain.iter() {
+            println!("  {}", pedal);
+        }
+        if !view.others.is_empty() {
+            println!("Off the chain:");
+            for pedal in view.others.iter() {
+                println!("  {}", pedal);
+            }
+        }
 
-        print!("Chain: ");
-        let mut node = "in";
-        while node != "out" && self.chain.contains_key(node) {
-            print!("{} -> ", node);
-            node = self.chain.get(node).unwrap();
+        let mut sidechains: Vec<_> = self.sidechains.iter().collect();
+        sidechains.sort();
+        for (name, source) in sidechains {
+            println!("Sidechain: {} <- {}", name, source);
         }
-        println!("out");
 
-        println!("Graph: {:?}", self.chain);
+        self.midi_map.print();
+    }
+
+}
+
+/// Records the output of `node` if a pedal is keyed from it
+fn tap(taps: &mut HashMap<String, (Vec<f32>, usize)>, node: &str, buf: &[f32]) {
+    if let Some(&mut (ref mut stored, ref mut used)) = taps.get_mut(node) {
+        *used = buf.len().min(stored.len());
+        stored[..*used].clone_from_slice(&buf[..*used]);
+    }
+}
 
-        println!("Pedals: {:?}", self.pedals.keys().collect::<Vec<_>>())
+#[cfg(test)]
+mod tests {
+    use super::Pedals;
+    use automation::Automation;
+    use effects::{CtrlMsg, Effect, Param, params};
+    use std::sync::Arc;
+
+    /// Plays its `level` on every sample
+    struct Level {
+        level: f32,
+        bypassing: bool,
     }
 
-}
\ No newline at end of file
+    impl Effect for Level {
+        fn new(_sample_rate: usize, _frame_size: u32) -> Self {
+            Level { level: 0., bypassing: false }
+        }
+
+        fn name(&self) -> &str {
+            "level"
+        }
+
+        fn process_samples(&mut self, _input: &[f32], output_l: &mut [f32], output_r: &mut [f32]) {
+            for (l, r) in output_l.iter_mut().zip(output_r.iter_mut()) {
+                *l = self.level;
+                *r = self.level;
+            }
+        }
+
+        fn params(&self) -> Vec<(String, Param)> {
+            params(&[("level", Param::Float(self.level))])
+        }
+
+        fn bypass(&mut self) {
+            self.bypassing = !self.bypassing;
+        }
+
+        fn is_bypassing(&self) -> bool {
+            self.bypassing
+        }
+
+        fn ctrl(&mut self, msg: CtrlMsg) {
+            if let CtrlMsg::Set(_, _, level) = msg {
+                self.level = level;
+            }
+        }
+    }
+
+    #[test]
+    fn test_missing_pedals() {
+        let mut pedals = Pedals::new(48000, 64);
+        pedals.ctrl(CtrlMsg::MidiMap(11, "typo".to_owned(), "pedal".to_owned(), 0., 1.));
+        pedals.midi(0, &[0xb0, 11, 64]);
+        pedals.ctrl(CtrlMsg::Set("typo".to_owned(), "drive".to_owned(), 0.5));
+        pedals.ctrl(CtrlMsg::Select("typo".to_owned(), "mode".to_owned(), "fast".to_owned()));
+        pedals.ctrl(CtrlMsg::BypassPedal("x".to_owned()));
+        pedals.ctrl(CtrlMsg::Tuner);
+        assert!(pedals.pedals.is_empty());
+    }
+
+    #[test]
+    fn test_midi_mappings() {
+        let mut pedals = Pedals::new(48000, 64);
+        pedals.add("lvl", box Level::new(48000, 64));
+        pedals.ctrl(CtrlMsg::Connect("in".to_owned(), "lvl".to_owned()));
+        pedals.ctrl(CtrlMsg::Connect("lvl".to_owned(), "out".to_owned()));
+        pedals.ctrl(CtrlMsg::MidiMap(11, "lvl".to_owned(), "level".to_owned(), 0.5, 1.));
+        pedals.midi(0, &[0xb0, 11, 127]);
+        pedals.midi(0, &[0x90, 11, 0]);
+        let (mut l, mut r) = (vec![0.; 64], vec![0.; 64]);
+        pedals.process_samples(&[0.; 64], &mut l, &mut r);
+        assert_eq!(l[0], 1.);
+
+        // applied at the start of the block, and recorded there
+        pedals.ctrl(CtrlMsg::Record(true));
+        pedals.midi(40, &[0xb3, 11, 0]);
+        pedals.process_samples(&[0.; 64], &mut l, &mut r);
+        assert_eq!(l[0], 0.5);
+        pedals.ctrl(CtrlMsg::Record(false));
+        assert_eq!(pedals.take.as_ref().unwrap().events[0].time, 0);
+    }
+
+    #[test]
+    fn test_automation_is_sample_accurate() {
+        let mut pedals = Pedals::new(48000, 64);
+        pedals.add("lvl", box Level::new(48000, 64));
+        pedals.ctrl(CtrlMsg::Connect("in".to_owned(), "lvl".to_owned()));
+        pedals.ctrl(CtrlMsg::Connect("lvl".to_owned(), "out".to_owned()));
+        let mut take = Automation::new(48000);
+        take.record(100, "lvl", "level", Param::Float(0.5));
+        take.record(101, "lvl", "level", Param::Float(1.));
+        pedals.ctrl(CtrlMsg::Play(Some(Arc::new(take))));
+
+        let mut out = vec![];
+        for _ in 0..3 {
+            let (mut l, mut r) = (vec![0.; 64], vec![0.; 64]);
+            pedals.process_samples(&[0.; 64], &mut l, &mut r);
+            out.extend(l);
+        }
+        assert!(out[..100].iter().all(|&x| x == 0.));
+        assert_eq!(out[100], 0.5);
+        assert!(out[101..].iter().all(|&x| x == 1.));
+    }
+
+    #[test]
+    fn test_morph_records_its_position() {
+        let mut pedals = Pedals::new(48000, 64);
+        pedals.add("lvl", box Level::new(48000, 64));
+        pedals.ctrl(CtrlMsg::Connect("in".to_owned(), "lvl".to_owned()));
+        pedals.ctrl(CtrlMsg::Connect("lvl".to_owned(), "out".to_owned()));
+        pedals.ctrl(CtrlMsg::Ab(Some(1)));
+        pedals.ctrl(CtrlMsg::Set("lvl".to_owned(), "level".to_owned(), 1.));
+
+        // back to A over 4 blocks
+        pedals.ctrl(CtrlMsg::Record(true));
+        pedals.ctrl(CtrlMsg::Morph(0., 256. / 48000.));
+        let mut out = vec![];
+        for _ in 0..5 {
+            let (mut l, mut r) = (vec![0.; 64], vec![0.; 64]);
+            pedals.process_samples(&[0.; 64], &mut l, &mut r);
+            out.push(l[0]);
+        }
+        pedals.ctrl(CtrlMsg::Record(false));
+        assert_eq!(out, vec![0.75, 0.5, 0.25, 0., 0.]);
+        assert!(pedals.morph.is_none());
+        assert_eq!(pedals.slot, 0);
+
+        let take = pedals.take.clone().unwrap();
+        let positions: Vec<String> = take.events.iter()
+            .map(|e| format!("{} {} {} {}", e.time, e.pedal, e.param, e.value))
+            .collect();
+        assert_eq!(positions, vec!["0 ab morph 0.75", "64 ab morph 0.5", "128 ab morph 0.25", "192 ab morph 0"]);
+
+        // playing it back from B moves the morph the same way
+        pedals.ctrl(CtrlMsg::Ab(Some(1)));
+        pedals.ctrl(CtrlMsg::Play(Some(take)));
+        let (mut l, mut r) = (vec![0.; 64], vec![0.; 64]);
+        pedals.process_samples(&[0.; 64], &mut l, &mut r);
+        assert_eq!(l[0], 0.75);
+    }
+}
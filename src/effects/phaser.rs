@@ -0,0 +1,216 @@
+use effects::{Effect, CtrlMsg, Param, params};
+use effects::lfo::{Lfo, Waveform};
+use std::f32::consts::PI;
+
+const MAX_STAGES: usize = 12;
+
+/// State of one channel's allpass cascade
+#[derive(Default)]
+struct Cascade {
+    x1: [f32; MAX_STAGES],
+    y1: [f32; MAX_STAGES],
+    fed_back: f32,
+}
+
+/// Phaser made of N first-order allpass stages whose break frequency
+/// is swept exponentially between `min` and `max` Hz. Mixing the
+/// result with the dry signal puts N/2 notches in the spectrum.
+pub struct Phaser {
+    pub bypassing: bool,
+    sample_rate: f32,
+    lfo: Lfo,
+    channels: [Cascade; 2],
+    stages: usize,
+    min_freq: f32,
+    max_freq: f32,
+    feedback: f32,
+    mix: f32,
+    phase: f32,
+}
+
+impl Phaser {
+
+    /// an even number of stages, 2 to 12
+    pub fn set_stages(&mut self, stages: usize) {
+        let stages = (stages / 2 * 2).max(2).min(MAX_STAGES);
+        if stages != self.stages {
+            self.stages = stages;
+            self.channels = Default::default();
+        }
+    }
+
+    /// the sweep range in Hz, a minimum above the maximum pushes it up
+    /// and the other way round
+    pub fn set_min_freq(&mut self, freq: f32) {
+        self.min_freq = freq.max(20.).min(0.45 * self.sample_rate);
+        self.max_freq = self.max_freq.max(self.min_freq);
+    }
+
+    pub fn set_max_freq(&mut self, freq: f32) {
+        self.max_freq = freq.max(20.).min(0.45 * self.sample_rate);
+        self.min_freq = self.min_freq.min(self.max_freq);
+    }
+
+    pub fn set_feedback(&mut self, fb: f32) {
+        self.feedback = fb.max(-0.95).min(0.95);
+    }
+
+    pub fn set_mix(&mut self, mix: f32) {
+        self.mix = mix.max(0.).min(1.);
+    }
+
+    /// offset of the right channel's sweep in degrees
+    pub fn set_phase(&mut self, degrees: f32) {
+        self.phase = degrees / 360.;
+    }
+
+    fn channel(&mut self, ch: usize, x: f32) -> f32 {
+        let lfo = (1. + self.lfo.value_at(ch as f32 * self.phase)) / 2.;
+        let freq = self.min_freq * (self.max_freq / self.min_freq).powf(lfo);
+        let t = (PI * freq / self.sample_rate).tan();
+        let a = (t - 1.) / (t + 1.);
+
+        let cascade = &mut self.channels[ch];
+        let mut y = x + self.feedback * cascade.fed_back;
+        for k in 0..self.stages {
+            let out = a * y + cascade.x1[k] - a * cascade.y1[k];
+            cascade.x1[k] = y;
+            cascade.y1[k] = out;
+            y = out;
+        }
+        cascade.fed_back = y;
+
+        (1. - self.mix) * x + self.mix * y
+    }
+}
+
+impl Effect for Phaser {
+
+    fn new(sample_rate: usize, _frame_size: u32) -> Self {
+        let sample_rate = sample_rate as f32;
+        Phaser {
+            bypassing: false,
+            sample_rate,
+            lfo: Lfo::new(sample_rate, 0.5),
+            channels: Default::default(),
+            stages: 4,
+            min_freq: 200.,
+            max_freq: 2000.,
+            feedback: 0.5,
+            mix: 0.5,
+            phase: 0.,
+        }
+    }
+
+    fn name(&self) -> &str {
+        "phaser"
+    }
+
+    fn process_samples(&mut self, input: &[f32], output_l: &mut [f32], output_r: &mut [f32]) {
+
+        if self.bypassing {
+            output_l.clone_from_slice(input);
+            output_r.clone_from_slice(input);
+            return;
+        }
+
+        for (i, x) in input.iter().enumerate() {
+            output_l[i] = self.channel(0, *x);
+            output_r[i] = self.channel(1, *x);
+            self.lfo.advance();
+        }
+    }
+
+    fn sample_rate_changed(&mut self, sample_rate: usize) {
+        self.sample_rate = sample_rate as f32;
+        self.lfo.set_sample_rate(self.sample_rate);
+        let max = self.max_freq;
+        self.set_max_freq(max);
+    }
+
+    fn params(&self) -> Vec<(String, Param)> {
+        use self::Param::*;
+        params(&[
+            ("rate", Float(self.lfo.rate())),
+            ("stages", Int(self.stages as i32)),
+            // min first, it may push max up
+            ("min", Float(self.min_freq)),
+            ("max", Float(self.max_freq)),
+            ("feedback", Float(self.feedback)),
+            ("mix", Float(self.mix)),
+            ("phase", Float(self.phase * 360.)),
+            ("wave", Name(self.lfo.waveform.name().to_owned())),
+        ])
+    }
+
+    fn bypass(&mut self) {
+        self.bypassing = !self.bypassing;
+    }
+
+    fn is_bypassing(&self) -> bool {
+        self.bypassing
+    }
+
+    fn set(&mut self, param: &str, val: f32) {
+        match param {
+            "rate" => self.lfo.set_rate(val),
+            "stages" => self.set_stages(val as usize),
+            "min" => self.set_min_freq(val),
+            "max" => self.set_max_freq(val),
+            "feedback" => self.set_feedback(val),
+            "mix" => self.set_mix(val),
+            "phase" => self.set_phase(val),
+            _ => (),
+        }
+    }
+
+    fn ctrl(&mut self, msg: CtrlMsg) {
+        use self::CtrlMsg::*;
+        match msg {
+            Bypass => self.bypass(),
+            Set(_pedal_name, conf_name, val) => self.set(&conf_name, val),
+            Select(_pedal_name, conf_name, val) => {
+                if &conf_name == "wave" {
+                    match Waveform::from_name(&val) {
+                        Some(w) => self.lfo.waveform = w,
+                        None => println!("Unknown waveform: {}", val),
+                    }
+                }
+            },
+            _ => (),
+        }
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Phaser;
+    use effects::Effect;
+    use std::f32::consts::PI;
+
+    /// Level of a sine at `freq` through two stages frozen at `position`
+    /// in the cycle
+    fn level(position: f32, freq: f32) -> f32 {
+        let mut phaser = Phaser::new(48000, 4800);
+        phaser.set_stages(2);
+        phaser.set_feedback(0.);
+        phaser.lfo.set_rate(0.);
+        phaser.lfo.set_phase(position);
+
+        let input: Vec<f32> = (0..4800).map(|i| (2. * PI * freq * i as f32 / 48000.).sin()).collect();
+        let (mut l, mut r) = (vec![0.; 4800], vec![0.; 4800]);
+        phaser.process_samples(&input, &mut l, &mut r);
+        l[2400..].iter().fold(0., |max: f32, y| max.max(y.abs()))
+    }
+
+    #[test]
+    fn test_notch_sweeps_from_min_to_max() {
+        // the bottom of the LFO sets the stages to `min`, 200 Hz
+        assert!(level(0.75, 200.) < 0.01);
+        assert!(level(0.75, 2000.) > 0.5);
+        // and the top to `max`, 2 kHz
+        assert!(level(0.25, 2000.) < 0.01);
+        assert!(level(0.25, 200.) > 0.5);
+    }
+}
@@ -0,0 +1,233 @@
+//! Monophonic pitch detection shared by the pitch-aware effects.
+
+/// Lowest and highest fundamentals tracked, covering 5-string bass
+/// to the top frets of a guitar
+pub const MIN_FREQ: f32 = 30.;
+pub const MAX_FREQ: f32 = 1500.;
+/// YIN aperiodicity below which a dip counts as the period
+const YIN_THRESHOLD: f32 = 0.15;
+
+/// Estimates the fundamental of `buf` with the YIN algorithm.
+///
+/// Returns the frequency in Hz and a confidence in 0..1. `scratch` holds
+/// the difference function and is resized only when the lag range grows.
+pub fn yin(buf: &[f32], sample_rate: f32, scratch: &mut Vec<f32>) -> Option<(f32, f32)> {
+    let min_tau = ((sample_rate / MAX_FREQ) as usize).max(1);
+    let max_tau = ((sample_rate / MIN_FREQ) as usize).min(buf.len() / 2);
+    if max_tau <= min_tau + 1 {
+        return None;
+    }
+    let window = buf.len() - max_tau;
+
+    if scratch.len() < max_tau + 1 {
+        scratch.resize(max_tau + 1, 0.);
+    }
+    let d = &mut scratch[..max_tau + 1];
+
+    // difference function, normalized by its running mean
+    d[0] = 1.;
+    let mut running = 0.;
+    for tau in 1..max_tau + 1 {
+        let mut sum = 0.;
+        for j in 0..window {
+            let diff = buf[j] - buf[j + tau];
+            sum += diff * diff;
+        }
+        running += sum;
+        d[tau] = if running > 0. { sum * tau as f32 / running } else { 1. };
+    }
+
+    // first dip under the threshold, followed down to its minimum
+    let mut tau = min_tau;
+    while tau < max_tau {
+        if d[tau] < YIN_THRESHOLD {
+            while tau + 1 < max_tau && d[tau + 1] < d[tau] {
+                tau += 1;
+            }
+            let period = tau as f32 + parabolic_peak(d[tau - 1], d[tau], d[tau + 1]);
+            return Some((sample_rate / period, 1. - d[tau]));
+        }
+        tau += 1;
+    }
+    None
+}
+
+/// Offset in -0.5..0.5 of the extremum of the parabola through three
+/// equally spaced points, relative to the middle one
+pub fn parabolic_peak(left: f32, mid: f32, right: f32) -> f32 {
+    let curvature = left - 2. * mid + right;
+    if curvature == 0. {
+        return 0.;
+    }
+    (0.5 * (left - right) / curvature).max(-0.5).min(0.5)
+}
+
+/// Follows the pitch of a live signal.
+///
+/// The input is decimated before analysis so that running YIN every
+/// `HOP` samples stays cheap enough for the audio thread; the buffers
+/// are allocated in `new`.
+pub struct PitchTracker {
+    sample_rate: f32,
+    decimation: usize,
+    acc: f32,
+    count: usize,
+    buffer: Vec<f32>,
+    ordered: Vec<f32>,
+    scratch: Vec<f32>,
+    idx: usize,
+    since_analysis: usize,
+    pitch: Option<(f32, f32)>,
+}
+
+/// Analysis rate after decimation, in Hz
+const ANALYSIS_RATE: f32 = 12000.;
+/// Length of the analysis window and the hop between analyses,
+/// in decimated samples
+const WINDOW: usize = 1024;
+const HOP: usize = 256;
+
+impl PitchTracker {
+
+    pub fn new(sample_rate: f32) -> Self {
+        let decimation = ((sample_rate / ANALYSIS_RATE).round() as usize).max(1);
+        let rate = sample_rate / decimation as f32;
+        PitchTracker {
+            sample_rate: rate,
+            decimation,
+            acc: 0.,
+            count: 0,
+            buffer: vec![0.; WINDOW],
+            ordered: vec![0.; WINDOW],
+            scratch: vec![0.; (rate / MIN_FREQ) as usize + 1],
+            idx: 0,
+            since_analysis: 0,
+            pitch: None,
+        }
+    }
+
+    /// Feeds one sample, returns the latest estimate as (Hz, confidence)
+    pub fn process(&mut self, x: f32) -> Option<(f32, f32)> {
+        // boxcar average as a crude anti-aliasing filter
+        self.acc += x;
+        self.count += 1;
+        if self.count < self.decimation {
+            return self.pitch;
+        }
+        self.buffer[self.idx] = self.acc / self.decimation as f32;
+        self.idx = (self.idx + 1) % WINDOW;
+        self.acc = 0.;
+        self.count = 0;
+
+        self.since_analysis += 1;
+        if self.since_analysis >= HOP {
+            self.since_analysis = 0;
+            for i in 0..WINDOW {
+                self.ordered[i] = self.buffer[(self.idx + i) % WINDOW];
+            }
+            self.pitch = yin(&self.ordered, self.sample_rate, &mut self.scratch);
+        }
+        self.pitch
+    }
+
+    pub fn pitch(&self) -> Option<(f32, f32)> {
+        self.pitch
+    }
+}
+
+/// Frequency in Hz to fractional MIDI note number, A4 = 440 Hz = 69
+pub fn freq_to_midi(freq: f32) -> f32 {
+    69. + 12. * (freq / 440.).log2()
+}
+
+/// Semitones above C of a note letter with an optional accidental,
+/// such as `C`, `f#` or `Bb`; `Cb` is -1 and `B#` is 12
+fn letter_semitones(name: &str) -> Option<i32> {
+    let mut chars = name.chars();
+    let base = match chars.next()?.to_ascii_uppercase() {
+        'C' => 0,
+        'D' => 2,
+        'E' => 4,
+        'F' => 5,
+        'G' => 7,
+        'A' => 9,
+        'B' => 11,
+        _ => return None,
+    };
+    let accidental = match chars.as_str() {
+        "" => 0,
+        "#" => 1,
+        "b" => -1,
+        _ => return None,
+    };
+    Some(base + accidental)
+}
+
+/// Pitch class of a key name such as `C`, `f#` or `Bb`
+pub fn parse_key(name: &str) -> Option<i32> {
+    letter_semitones(name).map(|n| (n + 12) % 12)
+}
+
+/// MIDI note number of a note name with octave such as `E2` or `Bb1`,
+/// C4 is middle C
+pub fn parse_note(name: &str) -> Option<i32> {
+    let split = name.char_indices()
+        .skip(1)
+        .find(|&(_, c)| c.is_ascii_digit() || c == '-')
+        .map(|(i, _)| i)?;
+    let octave = name[split..].parse::<i32>().ok()?;
+    Some(12 * (octave + 1) + letter_semitones(&name[..split])?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{yin, parse_key, parse_note, PitchTracker};
+    use std::f32::consts::PI;
+
+    fn tone(freq: f32, sample_rate: f32, len: usize) -> Vec<f32> {
+        (0..len)
+            .map(|i| (2. * PI * freq * i as f32 / sample_rate).sin()
+                + 0.5 * (4. * PI * freq * i as f32 / sample_rate).sin())
+            .collect()
+    }
+
+    #[test]
+    fn test_yin() {
+        let mut scratch = vec![];
+        let (freq, confidence) = yin(&tone(220., 48000., 4096), 48000., &mut scratch).unwrap();
+        assert!((freq - 220.).abs() < 0.5);
+        assert!(confidence > 0.9);
+
+        assert!(yin(&vec![0.; 4096], 48000., &mut scratch).is_none());
+    }
+
+    #[test]
+    fn test_tracker() {
+        let mut tracker = PitchTracker::new(48000.);
+        for x in tone(110., 48000., 48000) {
+            tracker.process(x);
+        }
+        let (freq, _) = tracker.pitch().unwrap();
+        assert!((freq - 110.).abs() < 0.5);
+    }
+
+    #[test]
+    fn test_parse_key() {
+        assert_eq!(parse_key("C"), Some(0));
+        assert_eq!(parse_key("f#"), Some(6));
+        assert_eq!(parse_key("Bb"), Some(10));
+        assert_eq!(parse_key("Cb"), Some(11));
+        assert_eq!(parse_key("H"), None);
+    }
+
+    #[test]
+    fn test_parse_note() {
+        assert_eq!(parse_note("A4"), Some(69));
+        assert_eq!(parse_note("E2"), Some(40));
+        assert_eq!(parse_note("B0"), Some(23));
+        assert_eq!(parse_note("Cb4"), Some(59));
+        assert_eq!(parse_note("C#-1"), Some(1));
+        assert_eq!(parse_note("E"), None);
+        assert_eq!(parse_note("X2"), None);
+    }
+}
@@ -0,0 +1,200 @@
+use effects::{Effect, CtrlMsg, Param, params};
+use effects::delay_line::DelayLine;
+use std::f32::consts::PI;
+
+/// Longest grain in seconds
+const MAX_WINDOW: f32 = 0.1;
+
+/// Granular pitch shifter: two read heads sweep a delay line at a
+/// speed set by the pitch ratio, half a grain apart, and crossfade with
+/// a Hann window so one is always silent when it jumps back.
+pub struct Shifter {
+    line: DelayLine,
+    sample_rate: f32,
+    /// grain length in samples
+    window: f32,
+    ratio: f32,
+    phase: f32,
+}
+
+impl Shifter {
+
+    pub fn new(sample_rate: f32) -> Self {
+        Shifter {
+            line: DelayLine::new((MAX_WINDOW * sample_rate) as usize + 2),
+            sample_rate,
+            window: 0.05 * sample_rate,
+            ratio: 1.,
+            phase: 0.,
+        }
+    }
+
+    /// keeps the grain length in seconds
+    pub fn set_sample_rate(&mut self, sample_rate: f32) {
+        let window = self.window / self.sample_rate;
+        self.line = DelayLine::new((MAX_WINDOW * sample_rate) as usize + 2);
+        self.sample_rate = sample_rate;
+        self.set_window(window);
+    }
+
+    /// shift in semitones, fractions are cents
+    pub fn set_semitones(&mut self, semitones: f32) {
+        self.ratio = 2f32.powf(semitones / 12.);
+    }
+
+    /// grain length in seconds; longer is smoother for low notes,
+    /// shorter has less latency and echo
+    pub fn set_window(&mut self, t: f32) {
+        self.window = t.max(0.01).min(MAX_WINDOW) * self.sample_rate;
+    }
+
+    /// grain length in seconds
+    pub fn window(&self) -> f32 {
+        self.window / self.sample_rate
+    }
+
+    pub fn process(&mut self, x: f32) -> f32 {
+        self.line.push(x);
+
+        // the delay grows when pitching down and shrinks when pitching up
+        self.phase += (1. - self.ratio) / self.window;
+        self.phase -= self.phase.floor();
+
+        let mut y = 0.;
+        for &offset in [0., 0.5].iter() {
+            let p = self.phase + offset;
+            let p = p - p.floor();
+            let gain = (PI * p).sin().powi(2);
+            y += gain * self.line.read_cubic(1. + p * self.window);
+        }
+        y
+    }
+}
+
+/// Pitch shifter with an expression controlled whammy.
+///
+/// The shift is `semitones + cents / 100`, plus `whammy` semitones
+/// times the `pedal` position in whammy mode.
+pub struct PitchShifter {
+    pub bypassing: bool,
+    shifter: Shifter,
+    semitones: f32,
+    cents: f32,
+    whammy: f32,
+    pedal: f32,
+    mix: f32,
+}
+
+impl PitchShifter {
+
+    fn update(&mut self) {
+        let shift = self.semitones + self.cents / 100. + self.whammy * self.pedal;
+        self.shifter.set_semitones(shift);
+    }
+}
+
+impl Effect for PitchShifter {
+
+    fn new(sample_rate: usize, _frame_size: u32) -> Self {
+        let mut ps = PitchShifter {
+            bypassing: false,
+            shifter: Shifter::new(sample_rate as f32),
+            semitones: 12.,
+            cents: 0.,
+            whammy: 0.,
+            pedal: 0.,
+            mix: 1.,
+        };
+        ps.update();
+        ps
+    }
+
+    fn name(&self) -> &str {
+        "pitchshift"
+    }
+
+    fn process_samples(&mut self, input: &[f32], output_l: &mut [f32], output_r: &mut [f32]) {
+
+        if self.bypassing {
+            output_l.clone_from_slice(input);
+            output_r.clone_from_slice(input);
+            return;
+        }
+
+        for (i, x) in input.iter().enumerate() {
+            let y = (1. - self.mix) * x + self.mix * self.shifter.process(*x);
+            output_l[i] = y;
+            output_r[i] = y;
+        }
+    }
+
+    fn sample_rate_changed(&mut self, sample_rate: usize) {
+        self.shifter.set_sample_rate(sample_rate as f32);
+    }
+
+    fn params(&self) -> Vec<(String, Param)> {
+        use self::Param::*;
+        params(&[
+            ("semitones", Float(self.semitones)),
+            ("cents", Float(self.cents)),
+            ("whammy", Float(self.whammy)),
+            ("pedal", Float(self.pedal)),
+            ("mix", Float(self.mix)),
+            ("window", Float(self.shifter.window() * 1000.)),
+        ])
+    }
+
+    fn bypass(&mut self) {
+        self.bypassing = !self.bypassing;
+    }
+
+    fn is_bypassing(&self) -> bool {
+        self.bypassing
+    }
+
+    fn set(&mut self, param: &str, val: f32) {
+        match param {
+            "semitones" => self.semitones = val.max(-24.).min(24.),
+            "cents" => self.cents = val.max(-100.).min(100.),
+            "whammy" => self.whammy = val.max(-24.).min(24.),
+            "pedal" => self.pedal = val.max(0.).min(1.),
+            "mix" => self.mix = val.max(0.).min(1.),
+            "window" => self.shifter.set_window(val / 1000.),
+            _ => (),
+        }
+        self.update();
+    }
+
+    fn ctrl(&mut self, msg: CtrlMsg) {
+        use self::CtrlMsg::*;
+        match msg {
+            Bypass => self.bypass(),
+            Set(_pedal_name, conf_name, val) => self.set(&conf_name, val),
+            _ => (),
+        }
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Shifter;
+    use effects::pitch::yin;
+    use std::f32::consts::PI;
+
+    #[test]
+    fn test_octave_up_and_down() {
+        let sr = 48000.;
+        let input: Vec<f32> = (0..48000)
+            .map(|i| (2. * PI * 220. * i as f32 / sr).sin())
+            .collect();
+
+        for &(semitones, expected) in [(12., 440.), (-12., 110.), (7., 329.6)].iter() {
+            let mut shifter = Shifter::new(sr);
+            shifter.set_semitones(semitones);
+            let out: Vec<f32> = input.iter().map(|&x| shifter.process(x)).collect();
+            let (f, _) = yin(&out[40000..], sr, &mut vec![]).unwrap();
+            assert!((f - expected).abs() / expected < 0.03, "{} != {}", f, expected);
+        }
+    }
+}
@@ -0,0 +1,418 @@
+use effects::{Effect, CtrlMsg, Param, params};
+use effects::dynamics::{time_coeff, db_to_gain, gain_to_db};
+use effects::pitch::{PitchTracker, freq_to_midi};
+use midi::MidiEvent;
+
+/// Envelope follower times in seconds; the fast one catches the pick
+/// attack, the slow one is what a new attack has to stand out from
+const FAST_ATTACK: f32 = 0.001;
+const FAST_RELEASE: f32 = 0.03;
+const SLOW_ATTACK: f32 = 0.05;
+const SLOW_RELEASE: f32 = 0.3;
+/// A repick is an attack this many times louder than the slow envelope
+const ONSET_RATIO: f32 = 2.;
+/// Shortest time between two onsets, in seconds
+const REFRACTORY: f32 = 0.05;
+/// Time after an onset before the pitch is read, so the tracker has
+/// mostly forgotten the previous note, and how long to wait at most
+const ONSET_DELAY: f32 = 0.04;
+const MAX_WAIT: f32 = 0.2;
+/// The note ends when the level falls this far below the threshold
+const RELEASE_HYSTERESIS: f32 = 6.;
+/// Pitches below this confidence are ignored
+const MIN_CONFIDENCE: f32 = 0.85;
+/// Smallest change of the bend worth sending, out of 16384
+const BEND_STEP: i32 = 32;
+const BEND_CENTER: u16 = 8192;
+/// Events buffered per block, allocated up front
+const MAX_EVENTS: usize = 64;
+
+/// Converts the monophonic pitch of the input to MIDI notes.
+///
+/// An attack above the onset `threshold` starts a note once the pitch is
+/// known, with a velocity set by how loud the attack peaked. Bends and
+/// slides within `bend` semitones are sent as pitch bend, further moves
+/// start a new note. The note ends when the level drops below the
+/// threshold. Audio passes through unchanged.
+pub struct PitchToMidi {
+    pub bypassing: bool,
+    sample_rate: f32,
+    tracker: PitchTracker,
+
+    fast: f32,
+    slow: f32,
+    fast_attack: f32,
+    fast_release: f32,
+    slow_attack: f32,
+    slow_release: f32,
+
+    /// onset threshold in dBFS
+    threshold: f32,
+    /// bend range in semitones, 0 sends plain notes
+    bend_range: f32,
+    /// 0..15
+    channel: u8,
+
+    gate_open: bool,
+    /// samples since the last onset
+    since_onset: usize,
+    /// waiting for the pitch of a new attack
+    pending: bool,
+    /// loudest level since the onset
+    peak: f32,
+    note: Option<u8>,
+    velocity: u8,
+    bend: u16,
+    events: Vec<MidiEvent>,
+}
+
+impl PitchToMidi {
+
+    pub fn set_threshold(&mut self, db: f32) {
+        self.threshold = db.max(-80.).min(0.);
+    }
+
+    pub fn set_bend_range(&mut self, semitones: f32) {
+        self.bend_range = semitones.max(0.).min(24.);
+    }
+
+    /// 1..16 like on the synth. A sounding note is ended, and the bend
+    /// re-centered, on the old channel first so none hangs.
+    pub fn set_channel(&mut self, channel: f32) {
+        let channel = (channel.round().max(1.).min(16.) - 1.) as u8;
+        if channel != self.channel {
+            self.stop_note(0);
+            self.send_bend(0, BEND_CENTER);
+            self.channel = channel;
+        }
+    }
+
+    pub fn note(&self) -> Option<u8> {
+        self.note
+    }
+
+    fn follow(level: f32, env: f32, attack: f32, release: f32) -> f32 {
+        let coeff = if level > env { attack } else { release };
+        level + coeff * (env - level)
+    }
+
+    fn push(&mut self, event: MidiEvent) {
+        // never grow the buffer on the audio thread
+        if self.events.len() < MAX_EVENTS {
+            self.events.push(event);
+        }
+    }
+
+    fn velocity(&self) -> u8 {
+        let range = -self.threshold;
+        let above = (gain_to_db(self.peak) - self.threshold) / range;
+        (1. + 126. * above.max(0.).min(1.)).round() as u8
+    }
+
+    fn start_note(&mut self, time: u32, note: u8, velocity: u8) {
+        self.stop_note(time);
+        self.note = Some(note);
+        self.velocity = velocity;
+        let channel = self.channel;
+        self.push(MidiEvent::note_on(time, channel, note, velocity));
+    }
+
+    fn stop_note(&mut self, time: u32) {
+        if let Some(note) = self.note.take() {
+            let channel = self.channel;
+            self.push(MidiEvent::note_off(time, channel, note));
+        }
+    }
+
+    fn send_bend(&mut self, time: u32, value: u16) {
+        if (value as i32 - self.bend as i32).abs() >= BEND_STEP || (value == BEND_CENTER && self.bend != value) {
+            self.bend = value;
+            let channel = self.channel;
+            self.push(MidiEvent::pitch_bend(time, channel, value));
+        }
+    }
+
+    /// Bend for `pitch` relative to the sounding note
+    fn bend_value(&self, pitch: f32, note: u8) -> u16 {
+        if self.bend_range == 0. {
+            return BEND_CENTER;
+        }
+        let amount = ((pitch - note as f32) / self.bend_range).max(-1.).min(1.);
+        (BEND_CENTER as f32 + amount * 8191.).round() as u16
+    }
+
+    fn process_sample(&mut self, time: u32, x: f32) {
+        let level = x.abs();
+        self.fast = Self::follow(level, self.fast, self.fast_attack, self.fast_release);
+        self.slow = Self::follow(level, self.slow, self.slow_attack, self.slow_release);
+        let pitch = self.tracker.process(x)
+            .and_then(|(freq, confidence)| if confidence >= MIN_CONFIDENCE { Some(freq_to_midi(freq)) } else { None });
+
+        let threshold = db_to_gain(self.threshold);
+        self.since_onset += 1;
+        let refractory = self.since_onset as f32 > REFRACTORY * self.sample_rate;
+        let onset = if !self.gate_open {
+            self.fast > threshold
+        } else {
+            refractory && self.fast > ONSET_RATIO * self.slow
+        };
+        if onset {
+            self.gate_open = true;
+            self.pending = true;
+            self.since_onset = 0;
+            self.peak = 0.;
+        }
+
+        if self.gate_open && self.fast < threshold * db_to_gain(-RELEASE_HYSTERESIS) {
+            self.gate_open = false;
+            self.pending = false;
+            self.stop_note(time);
+            self.send_bend(time, BEND_CENTER);
+            return;
+        }
+
+        if self.pending {
+            self.peak = self.peak.max(self.fast);
+            let waited = self.since_onset as f32 / self.sample_rate;
+            if waited >= ONSET_DELAY {
+                if let Some(pitch) = pitch {
+                    let note = pitch.round().max(0.).min(127.) as u8;
+                    let velocity = self.velocity();
+                    self.pending = false;
+                    self.send_bend(time, BEND_CENTER);
+                    self.start_note(time, note, velocity);
+                } else if waited > MAX_WAIT {
+                    self.pending = false;
+                }
+            }
+            return;
+        }
+
+        if let (Some(note), Some(pitch)) = (self.note, pitch) {
+            // a slide closer to another note than the bend range
+            // reaches continues as that note
+            if (pitch - note as f32).abs() > self.bend_range + 0.5 {
+                let velocity = self.velocity;
+                let next = pitch.round().max(0.).min(127.) as u8;
+                self.send_bend(time, BEND_CENTER);
+                self.start_note(time, next, velocity);
+            } else {
+                let bend = self.bend_value(pitch, note);
+                self.send_bend(time, bend);
+            }
+        }
+    }
+}
+
+impl Effect for PitchToMidi {
+
+    fn new(sample_rate: usize, _frame_size: u32) -> Self {
+        let sr = sample_rate as f32;
+        PitchToMidi {
+            bypassing: false,
+            sample_rate: sr,
+            tracker: PitchTracker::new(sr),
+            fast: 0.,
+            slow: 0.,
+            fast_attack: time_coeff(FAST_ATTACK, sr),
+            fast_release: time_coeff(FAST_RELEASE, sr),
+            slow_attack: time_coeff(SLOW_ATTACK, sr),
+            slow_release: time_coeff(SLOW_RELEASE, sr),
+            threshold: -40.,
+            bend_range: 2.,
+            channel: 0,
+            gate_open: false,
+            since_onset: 0,
+            pending: false,
+            peak: 0.,
+            note: None,
+            velocity: 0,
+            bend: BEND_CENTER,
+            events: Vec::with_capacity(MAX_EVENTS),
+        }
+    }
+
+    fn name(&self) -> &str {
+        "pitch2midi"
+    }
+
+    fn process_samples(&mut self, input: &[f32], output_l: &mut [f32], output_r: &mut [f32]) {
+        output_l.clone_from_slice(input);
+        output_r.clone_from_slice(input);
+
+        if self.bypassing {
+            return;
+        }
+
+        for (i, x) in input.iter().enumerate() {
+            self.process_sample(i as u32, *x);
+        }
+    }
+
+    fn midi_out(&mut self, events: &mut Vec<MidiEvent>) {
+        events.extend(self.events.drain(..));
+    }
+
+    fn sample_rate_changed(&mut self, sample_rate: usize) {
+        let sr = sample_rate as f32;
+        self.sample_rate = sr;
+        self.tracker = PitchTracker::new(sr);
+        self.fast_attack = time_coeff(FAST_ATTACK, sr);
+        self.fast_release = time_coeff(FAST_RELEASE, sr);
+        self.slow_attack = time_coeff(SLOW_ATTACK, sr);
+        self.slow_release = time_coeff(SLOW_RELEASE, sr);
+    }
+
+    fn params(&self) -> Vec<(String, Param)> {
+        use self::Param::*;
+        params(&[
+            ("threshold", Float(self.threshold)),
+            ("bend", Float(self.bend_range)),
+            ("channel", Int(self.channel as i32 + 1)),
+        ])
+    }
+
+    fn bypass(&mut self) {
+        self.bypassing = !self.bypassing;
+        if self.bypassing {
+            self.pending = false;
+            self.gate_open = false;
+            self.stop_note(0);
+        }
+    }
+
+    fn is_bypassing(&self) -> bool {
+        self.bypassing
+    }
+
+    fn set(&mut self, param: &str, val: f32) {
+        match param {
+            "threshold" => self.set_threshold(val),
+            "bend" => self.set_bend_range(val),
+            "channel" => self.set_channel(val),
+            _ => (),
+        }
+    }
+
+    fn ctrl(&mut self, msg: CtrlMsg) {
+        use self::CtrlMsg::*;
+        match msg {
+            Bypass => self.bypass(),
+            Set(_pedal_name, conf_name, val) => self.set(&conf_name, val),
+            Info(_pedal_name) => {
+                match self.note {
+                    Some(note) => println!("Note {} velocity {} bend {}", note, self.velocity, self.bend),
+                    None => println!("No note"),
+                }
+            },
+            _ => (),
+        }
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PitchToMidi;
+    use effects::Effect;
+    use midi::MidiEvent;
+    use std::f32::consts::PI;
+
+    /// a plucked string from `start` seconds on, as a phase accumulator
+    /// so the frequency may glide
+    fn pluck(out: &mut [f32], start: f32, len: f32, freq: &Fn(f32) -> f32, amp: f32) {
+        let sr = 48000.;
+        let mut phase = 0.;
+        let first = (start * sr) as usize;
+        for i in first..(first + (len * sr) as usize).min(out.len()) {
+            let t = (i - first) as f32 / sr;
+            phase += freq(t) / sr;
+            out[i] = amp * (-t * 3.).exp()
+                * ((2. * PI * phase).sin() + 0.3 * (4. * PI * phase).sin());
+        }
+    }
+
+    fn run(input: &[f32]) -> Vec<(usize, MidiEvent)> {
+        let mut p2m = PitchToMidi::new(48000, 256);
+        let mut l = vec![0.; 256];
+        let mut r = vec![0.; 256];
+        let mut events = vec![];
+        let mut out = vec![];
+        for (block, frame) in input.chunks(256).enumerate() {
+            let n = frame.len();
+            p2m.process_samples(frame, &mut l[..n], &mut r[..n]);
+            p2m.midi_out(&mut events);
+            for e in events.drain(..) {
+                out.push((block * 256 + e.time as usize, e));
+            }
+        }
+        out
+    }
+
+    fn notes(events: &[(usize, MidiEvent)]) -> Vec<(u8, u8, u8)> {
+        events.iter()
+            .filter(|&&(_, e)| e.bytes[0] & 0xE0 == 0x80)
+            .map(|&(_, e)| (e.bytes[0], e.bytes[1], e.bytes[2]))
+            .collect()
+    }
+
+    #[test]
+    fn test_notes_and_velocity() {
+        let mut input = vec![0.; 96000];
+        pluck(&mut input, 0.1, 0.6, &|_| 110., 0.5);
+        pluck(&mut input, 1.0, 0.6, &|_| 146.83, 0.05);
+        let events = run(&input);
+        let notes = notes(&events);
+
+        assert_eq!(notes.len(), 4, "{:?}", notes);
+        assert_eq!((notes[0].0, notes[0].1), (0x90, 45));
+        assert_eq!(notes[1], (0x80, 45, 0));
+        assert_eq!((notes[2].0, notes[2].1), (0x90, 50));
+        assert_eq!(notes[3], (0x80, 50, 0));
+        // the softer pluck has a lower velocity
+        assert!(notes[0].2 > notes[2].2 + 20);
+
+        // the onset delay plus at most one analysis hop
+        let latency = events[0].0 as f32 / 48000. - 0.1;
+        assert!(latency < 0.1, "{}", latency);
+    }
+
+    #[test]
+    fn test_pitch_bend() {
+        // a bend up a whole step over 300 ms is one bent note
+        let mut input = vec![0.; 48000];
+        pluck(&mut input, 0.05, 0.8, &|t| 110. * 2f32.powf((t - 0.1).max(0.).min(0.3) / 0.3 * 2. / 12.), 0.5);
+        let events = run(&input);
+
+        let notes = notes(&events);
+        assert_eq!((notes[0].0, notes[0].1), (0x90, 45));
+        let bends: Vec<u16> = events.iter()
+            .filter(|&&(_, e)| e.bytes[0] == 0xE0)
+            .map(|&(_, e)| e.bytes[1] as u16 | (e.bytes[2] as u16) << 7)
+            .collect();
+        let top = *bends.iter().max().unwrap();
+        // two semitones is the full bend range
+        assert!(top > 16000, "{:?}", bends);
+        assert_eq!(notes.iter().filter(|n| n.0 == 0x90).count(), 1);
+    }
+
+    #[test]
+    fn test_channel_change() {
+        let mut p2m = PitchToMidi::new(48000, 256);
+        p2m.start_note(0, 45, 100);
+        p2m.send_bend(0, 10000);
+        p2m.set_channel(3.);
+        p2m.start_note(10, 47, 90);
+        let mut events = vec![];
+        p2m.midi_out(&mut events);
+        let bytes: Vec<[u8; 3]> = events.iter().map(|e| e.bytes).collect();
+        assert_eq!(bytes, vec![
+            [0x90, 45, 100],
+            [0xE0, (10000 & 0x7F) as u8, (10000 >> 7) as u8],
+            // off and centered where the synth is playing
+            [0x80, 45, 0],
+            [0xE0, 0, 64],
+            [0x92, 47, 90],
+        ]);
+    }
+}
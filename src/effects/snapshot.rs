@@ -0,0 +1,219 @@
+//! A/B snapshots of the pedal settings and morphs between them.
+//!
+//! A snapshot holds whether every pedal is on and the values of all its
+//! parameters. Recalling one only sends what differs from the current
+//! settings, so pedals are not disturbed by values they already have.
+//! A morph moves every continuous parameter from its value in A to its
+//! value in B; counts, switches, names and bypass states flip at the
+//! midpoint.
+
+use effects::{CtrlMsg, Param};
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct PedalState {
+    pub name: String,
+    pub on: bool,
+    pub params: Vec<(String, Param)>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Snapshot {
+    pub pedals: Vec<PedalState>,
+}
+
+impl Snapshot {
+
+    pub fn pedal(&self, name: &str) -> Option<&PedalState> {
+        self.pedals.iter().find(|p| p.name == name)
+    }
+
+    /// The messages that take the pedals from `current` to this snapshot.
+    /// Pedals added since it was taken are left alone.
+    pub fn changes(&self, current: &Snapshot) -> Vec<CtrlMsg> {
+        let mut msgs = vec![];
+        for pedal in self.pedals.iter() {
+            let now = match current.pedal(&pedal.name) {
+                Some(now) => now,
+                None => continue,
+            };
+            for &(ref param, ref val) in pedal.params.iter() {
+                if !now.params.iter().any(|p| p.0 == *param && p.1 == *val) {
+                    msgs.push(val.message(&pedal.name, param));
+                }
+            }
+            if now.on != pedal.on {
+                msgs.push(CtrlMsg::Enable(pedal.name.clone(), pedal.on));
+            }
+        }
+        msgs
+    }
+}
+
+/// Position between two snapshots, 0 is A and 1 is B
+pub struct Morph {
+    /// pedal, parameter and its values in A and B, for those that differ
+    params: Vec<(String, String, Param, Param)>,
+    /// pedal and whether it is on in A and B, for those that differ
+    enables: Vec<(String, bool, bool)>,
+    pub position: f32,
+    /// where the last move started
+    from: f32,
+    /// where a glide goes and how far it moves per sample
+    target: f32,
+    speed: f32,
+}
+
+impl Morph {
+
+    /// Starts at `position` without sending anything, the pedals are
+    /// supposed to be there already
+    pub fn new(a: &Snapshot, b: &Snapshot, position: f32) -> Morph {
+        let mut params = vec![];
+        let mut enables = vec![];
+        for pa in a.pedals.iter() {
+            let pb = match b.pedal(&pa.name) {
+                Some(pb) => pb,
+                None => continue,
+            };
+            if pa.on != pb.on {
+                enables.push((pa.name.clone(), pa.on, pb.on));
+            }
+            for &(ref param, ref va) in pa.params.iter() {
+                if let Some(&(_, ref vb)) = pb.params.iter().find(|p| p.0 == *param) {
+                    if va != vb {
+                        params.push((pa.name.clone(), param.clone(), va.clone(), vb.clone()));
+                    }
+                }
+            }
+        }
+        Morph { params, enables, position, from: position, target: position, speed: 0. }
+    }
+
+    pub fn is_gliding(&self) -> bool {
+        self.position != self.target
+    }
+
+    /// Moves straight to `position`
+    pub fn jump(&mut self, position: f32) {
+        self.from = self.position;
+        self.position = position.max(0.).min(1.);
+        self.target = self.position;
+    }
+
+    /// Moves to `target` over `samples` from now on, see `advance`
+    pub fn glide(&mut self, target: f32, samples: f32) {
+        self.target = target.max(0.).min(1.);
+        self.speed = (self.target - self.position).abs() / samples.max(1.);
+    }
+
+    /// Moves a glide on by `frames` samples
+    pub fn advance(&mut self, frames: usize) {
+        let from = self.position;
+        let step = self.speed * frames as f32;
+        self.position = if self.target > from {
+            (from + step).min(self.target)
+        } else {
+            (from - step).max(self.target)
+        };
+        self.from = from;
+    }
+
+    /// Calls `change` with the pedal, parameter and value of everything
+    /// the last move changed, `on` for switching a pedal on or off like
+    /// in a take. Names are borrowed, so a glide does not allocate.
+    pub fn changes<F: FnMut(&str, &str, &Param)>(&self, mut change: F) {
+        let (from, t) = (self.from, self.position);
+        if t == from {
+            return;
+        }
+        let flipped = (from < 0.5) != (t < 0.5);
+        for &(ref pedal, ref param, ref a, ref b) in self.params.iter() {
+            match (a, b) {
+                (&Param::Float(a), &Param::Float(b)) => change(pedal, param, &Param::Float(a + t * (b - a))),
+                _ if flipped => change(pedal, param, if t < 0.5 { a } else { b }),
+                _ => (),
+            }
+        }
+        if flipped {
+            for &(ref pedal, a, b) in self.enables.iter() {
+                change(pedal, "on", &Param::Int((if t < 0.5 { a } else { b }) as i32));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Morph, PedalState, Snapshot};
+    use effects::{CtrlMsg, Param};
+
+    fn moved(morph: &Morph) -> Vec<String> {
+        let mut changes = vec![];
+        morph.changes(|pedal, param, value| changes.push(if param == "on" {
+            format!("{} {}", pedal, value)
+        } else {
+            format!("{} {}", param, value)
+        }));
+        changes
+    }
+
+    fn snapshot(on: bool, drive: f32, mode: &str, stages: i32) -> Snapshot {
+        Snapshot {
+            pedals: vec![PedalState {
+                name: "ph".to_owned(),
+                on,
+                params: vec![
+                    ("drive".to_owned(), Param::Float(drive)),
+                    ("mode".to_owned(), Param::Name(mode.to_owned())),
+                    ("stages".to_owned(), Param::Int(stages)),
+                    ("mix".to_owned(), Param::Float(0.5)),
+                ],
+            }],
+        }
+    }
+
+    fn describe(msgs: &[CtrlMsg]) -> Vec<String> {
+        msgs.iter()
+            .map(|msg| match *msg {
+                CtrlMsg::Set(_, ref param, val) => format!("{} {}", param, val),
+                CtrlMsg::Select(_, ref param, ref val) => format!("{} {}", param, val),
+                CtrlMsg::Enable(ref pedal, on) => format!("{} {}", pedal, on),
+                _ => "other".to_owned(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_changes() {
+        let a = snapshot(true, 0.2, "tape", 4);
+        let b = snapshot(false, 0.2, "digital", 4);
+        assert_eq!(describe(&b.changes(&a)), vec!["mode digital", "ph false"]);
+        assert!(a.changes(&a).is_empty());
+        assert!(a.changes(&Snapshot::default()).is_empty());
+    }
+
+    #[test]
+    fn test_morph() {
+        let a = snapshot(true, 0.25, "tape", 4);
+        let b = snapshot(false, 0.75, "digital", 8);
+        let mut morph = Morph::new(&a, &b, 0.);
+        morph.jump(0.25);
+        assert_eq!(moved(&morph), vec!["drive 0.375"]);
+        morph.jump(0.5);
+        assert_eq!(moved(&morph), vec!["drive 0.5", "mode digital", "stages 8", "ph 0"]);
+        morph.jump(0.375);
+        assert_eq!(moved(&morph), vec!["drive 0.4375", "mode tape", "stages 4", "ph 1"]);
+
+        // from A to B over 1024 samples in blocks of 128
+        morph.jump(0.);
+        morph.glide(1., 1024.);
+        let mut blocks = 0;
+        while morph.is_gliding() {
+            morph.advance(128);
+            blocks += 1;
+        }
+        assert_eq!((blocks, morph.position), (8, 1.));
+        morph.advance(128);
+        assert!(moved(&morph).is_empty());
+    }
+}
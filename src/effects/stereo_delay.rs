@@ -0,0 +1,349 @@
+use effects::{Effect, CtrlMsg, Param, params};
+use effects::delay_line::DelayLine;
+
+/// Longest delay time in seconds, allocated up front
+const MAX_DELAY: f32 = 4.;
+const MAX_TAPS: usize = 4;
+/// Length of the fades at the edges of reversed chunks, in seconds
+const REVERSE_FADE: f32 = 0.005;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+    /// repeats bounce from left to right
+    PingPong,
+    /// independent left and right delay times
+    Dual,
+    /// up to four taps with their own time, level, pan and feedback
+    MultiTap,
+    /// plays every `left` seconds of input backwards
+    Reverse,
+}
+
+impl Mode {
+    fn from_name(name: &str) -> Option<Mode> {
+        match name {
+            "pingpong" => Some(Mode::PingPong),
+            "dual" => Some(Mode::Dual),
+            "multitap" => Some(Mode::MultiTap),
+            "reverse" => Some(Mode::Reverse),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match *self {
+            Mode::PingPong => "pingpong",
+            Mode::Dual => "dual",
+            Mode::MultiTap => "multitap",
+            Mode::Reverse => "reverse",
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Tap {
+    /// seconds
+    time: f32,
+    level: f32,
+    /// -1 left to 1 right
+    pan: f32,
+    feedback: f32,
+}
+
+/// Stereo delay with ping-pong, dual, multi-tap and reverse modes.
+/// Taps are set with `s sd t1.time 0.25`, `t1.level`, `t1.pan` and
+/// `t1.feedback`; a tap at level 0 is off.
+pub struct StereoDelay {
+    pub bypassing: bool,
+    sample_rate: f32,
+    mode: Mode,
+    lines: [DelayLine; 2],
+    left: f32,
+    right: f32,
+    feedback: f32,
+    mix: f32,
+    taps: [Tap; MAX_TAPS],
+    /// position in the current reversed chunk, in samples
+    chunk_pos: usize,
+}
+
+impl StereoDelay {
+
+    pub fn set_mode(&mut self, mode: Mode) {
+        self.mode = mode;
+        for line in self.lines.iter_mut() {
+            line.clear();
+        }
+        self.chunk_pos = 0;
+    }
+
+    /// times are in seconds
+    pub fn set_left(&mut self, t: f32) {
+        self.left = clamp_time(t);
+    }
+
+    pub fn set_right(&mut self, t: f32) {
+        self.right = clamp_time(t);
+    }
+
+    pub fn set_feedback(&mut self, f: f32) {
+        self.feedback = f.max(0.).min(0.95);
+    }
+
+    pub fn set_mix(&mut self, mix: f32) {
+        self.mix = mix.max(0.).min(1.);
+    }
+
+    pub fn set_tap(&mut self, tap: usize, param: &str, val: f32) {
+        let tap = match self.taps.get_mut(tap) {
+            Some(tap) => tap,
+            None => {
+                println!("No tap {}", tap + 1);
+                return;
+            }
+        };
+        match param {
+            "time" => tap.time = clamp_time(val),
+            "level" => tap.level = val.max(0.).min(1.),
+            "pan" => tap.pan = val.max(-1.).min(1.),
+            "feedback" => tap.feedback = val.max(0.).min(0.95),
+            _ => println!("Unknown tap parameter: {}", param),
+        }
+    }
+
+    fn samples(&self, t: f32) -> f32 {
+        // read before writing, so one sample of the delay has passed
+        t * self.sample_rate - 1.
+    }
+
+    fn ping_pong(&mut self, x: f32) -> (f32, f32) {
+        let t = self.samples(self.left);
+        let wet_l = self.lines[0].read_cubic(t);
+        let wet_r = self.lines[1].read_cubic(t);
+        self.lines[0].push(x + self.feedback * wet_r);
+        self.lines[1].push(self.feedback * wet_l);
+        (wet_l, wet_r)
+    }
+
+    fn dual(&mut self, x: f32) -> (f32, f32) {
+        let wet_l = self.lines[0].read_cubic(self.samples(self.left));
+        let wet_r = self.lines[1].read_cubic(self.samples(self.right));
+        self.lines[0].push(x + self.feedback * wet_l);
+        self.lines[1].push(x + self.feedback * wet_r);
+        (wet_l, wet_r)
+    }
+
+    fn multi_tap(&mut self, x: f32) -> (f32, f32) {
+        let (mut wet_l, mut wet_r, mut fed_back) = (0., 0., 0.);
+        for tap in self.taps.iter().filter(|tap| tap.level > 0.) {
+            let y = self.lines[0].read_cubic(tap.time * self.sample_rate - 1.);
+            wet_l += y * tap.level * (1. - tap.pan) / 2.;
+            wet_r += y * tap.level * (1. + tap.pan) / 2.;
+            fed_back += y * tap.feedback;
+        }
+        self.lines[0].push(x + fed_back);
+        (wet_l, wet_r)
+    }
+
+    fn reverse(&mut self, x: f32) -> (f32, f32) {
+        let chunk = (self.left * self.sample_rate) as usize;
+        if self.chunk_pos >= chunk {
+            self.chunk_pos = 0;
+        }
+        let pos = self.chunk_pos;
+        self.chunk_pos += 1;
+
+        // walks backwards through the previous chunk
+        let y = self.lines[0].tap(2 * pos);
+        let fade = (REVERSE_FADE * self.sample_rate).max(1.);
+        let edge = pos.min(chunk - 1 - pos) as f32;
+        let wet = y * (edge / fade).min(1.);
+
+        self.lines[0].push(x + self.feedback * wet);
+        (wet, wet)
+    }
+}
+
+fn clamp_time(t: f32) -> f32 {
+    let clamped = t.max(0.001).min(MAX_DELAY / 2.);
+    if clamped != t {
+        println!("Delay time {} is out of range, using {}", t, clamped);
+    }
+    clamped
+}
+
+/// Splits `t2.time` into the tap index and parameter name
+fn parse_tap(conf_name: &str) -> Option<(usize, &str)> {
+    if !conf_name.starts_with("t") {
+        return None;
+    }
+    let mut parts = conf_name[1..].splitn(2, '.');
+    let idx = parts.next()?.parse::<usize>().ok()?;
+    let param = parts.next()?;
+    if idx == 0 {
+        return None;
+    }
+    Some((idx - 1, param))
+}
+
+impl Effect for StereoDelay {
+
+    fn new(sample_rate: usize, _frame_size: u32) -> Self {
+        let sr = sample_rate as f32;
+        let max = (MAX_DELAY * sr) as usize + 2;
+        let tap = |time, level, pan| Tap { time, level, pan, feedback: 0. };
+        StereoDelay {
+            bypassing: false,
+            sample_rate: sr,
+            mode: Mode::PingPong,
+            lines: [DelayLine::new(max), DelayLine::new(max)],
+            left: 0.3,
+            right: 0.45,
+            feedback: 0.4,
+            mix: 0.35,
+            taps: [
+                tap(0.125, 0.8, -0.7),
+                tap(0.25, 0.6, 0.7),
+                tap(0.375, 0.4, -0.3),
+                tap(0.5, 0., 0.3),
+            ],
+            chunk_pos: 0,
+        }
+    }
+
+    fn name(&self) -> &str {
+        "stereodelay"
+    }
+
+    fn process_samples(&mut self, input: &[f32], output_l: &mut [f32], output_r: &mut [f32]) {
+
+        if self.bypassing {
+            output_l.clone_from_slice(input);
+            output_r.clone_from_slice(input);
+            return;
+        }
+
+        for (i, x) in input.iter().enumerate() {
+            let (wet_l, wet_r) = match self.mode {
+                Mode::PingPong => self.ping_pong(*x),
+                Mode::Dual => self.dual(*x),
+                Mode::MultiTap => self.multi_tap(*x),
+                Mode::Reverse => self.reverse(*x),
+            };
+            output_l[i] = (1. - self.mix) * x + self.mix * wet_l;
+            output_r[i] = (1. - self.mix) * x + self.mix * wet_r;
+        }
+    }
+
+    fn sample_rate_changed(&mut self, sample_rate: usize) {
+        self.sample_rate = sample_rate as f32;
+        let max = (MAX_DELAY * self.sample_rate) as usize + 2;
+        self.lines = [DelayLine::new(max), DelayLine::new(max)];
+        self.chunk_pos = 0;
+    }
+
+    fn params(&self) -> Vec<(String, Param)> {
+        use self::Param::*;
+        let mut list = params(&[
+            ("mode", Name(self.mode.name().to_owned())),
+            ("left", Float(self.left)),
+            ("right", Float(self.right)),
+            ("feedback", Float(self.feedback)),
+            ("mix", Float(self.mix)),
+        ]);
+        for (i, tap) in self.taps.iter().enumerate() {
+            let name = |param: &str| format!("t{}.{}", i + 1, param);
+            list.push((name("time"), Float(tap.time)));
+            list.push((name("level"), Float(tap.level)));
+            list.push((name("pan"), Float(tap.pan)));
+            list.push((name("feedback"), Float(tap.feedback)));
+        }
+        list
+    }
+
+    fn bypass(&mut self) {
+        self.bypassing = !self.bypassing;
+    }
+
+    fn is_bypassing(&self) -> bool {
+        self.bypassing
+    }
+
+    fn set(&mut self, param: &str, val: f32) {
+        match param {
+            "left" => self.set_left(val),
+            "right" => self.set_right(val),
+            "feedback" => self.set_feedback(val),
+            "mix" => self.set_mix(val),
+            _ => {
+                if let Some((tap, param)) = parse_tap(param) {
+                    self.set_tap(tap, param, val);
+                }
+            }
+        }
+    }
+
+    fn ctrl(&mut self, msg: CtrlMsg) {
+        use self::CtrlMsg::*;
+        match msg {
+            Bypass => self.bypass(),
+            Set(_pedal_name, conf_name, val) => self.set(&conf_name, val),
+            Select(_pedal_name, conf_name, val) => {
+                if &conf_name == "mode" {
+                    match Mode::from_name(&val) {
+                        Some(mode) => self.set_mode(mode),
+                        None => println!("Unknown mode: {} (pingpong, dual, multitap, reverse)", val),
+                    }
+                }
+            },
+            _ => (),
+        }
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{StereoDelay, Mode};
+    use effects::Effect;
+
+    fn impulse_response(sd: &mut StereoDelay, len: usize) -> (Vec<f32>, Vec<f32>) {
+        let mut input = vec![0.; len];
+        input[0] = 1.;
+        let mut l = vec![0.; len];
+        let mut r = vec![0.; len];
+        sd.process_samples(&input, &mut l, &mut r);
+        (l, r)
+    }
+
+    #[test]
+    fn test_ping_pong() {
+        let mut sd = StereoDelay::new(48000, 256);
+        sd.set_mix(1.);
+        sd.set_feedback(0.5);
+        sd.set_left(0.1);
+
+        let (l, r) = impulse_response(&mut sd, 20000);
+        assert!((l[4800] - 1.).abs() < 1e-4 && r[4800].abs() < 1e-4);
+        assert!((r[9600] - 0.5).abs() < 1e-4 && l[9600].abs() < 1e-4);
+        assert!((l[14400] - 0.25).abs() < 1e-4);
+    }
+
+    #[test]
+    fn test_reverse_plays_chunk_backwards() {
+        let mut sd = StereoDelay::new(48000, 256);
+        sd.set_mode(Mode::Reverse);
+        sd.set_mix(1.);
+        sd.set_feedback(0.);
+        sd.set_left(0.1);
+
+        // an impulse early in the first chunk comes out late in the second
+        let mut input = vec![0.; 9600];
+        input[1000] = 1.;
+        let mut l = vec![0.; 9600];
+        let mut r = vec![0.; 9600];
+        sd.process_samples(&input, &mut l, &mut r);
+        let at = l.iter().position(|&y| y > 0.5).unwrap();
+        assert_eq!(at, 4800 + 4800 - 1 - 1000);
+    }
+}
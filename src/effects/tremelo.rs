@@ -1,30 +1,145 @@
-use effects::{Effect, CtrlMsg};
+use effects::{Effect, CtrlMsg, Param, params};
+use effects::biquad::{Biquad, Coeffs};
+use effects::dynamics::time_coeff;
+use effects::lfo::{Lfo, Waveform};
 
+/// Q of the Butterworth sections making up the crossover
+const BUTTERWORTH_Q: f32 = 0.707;
+
+/// Tremolo with a per-sample LFO.
+///
+/// The gain swings between `1 - depth` and 1. The right channel's LFO
+/// is shifted by `phase` degrees, 180 turning it into an auto-panner.
+/// In harmonic mode the signal is split at `xover` Hz and the two bands
+/// are modulated in opposite phase, like the brown-face amps. `mix`
+/// blends the dry signal back in.
 pub struct Tremelo {
     pub bypassing: bool,
-    pub depth: f32,
-    pub control: i16,
-    pub modulo: i16,
-    pub counter_limit: i16,
-    pub offset: f32,
-    pub alpha_mix: f32,
-    pub beta_mix: f32,
+    sample_rate: f32,
+    lfo: Lfo,
+    rate: f32,
+    /// length of one cycle in beats when synced to the tempo, 0 is free
+    sync: f32,
+    tempo: Option<f32>,
+    depth: f32,
+    /// wet share of the output
+    mix: f32,
+    phase: f32,
+    smoothing_ms: f32,
+    smoothing: f32,
+    gains: [f32; 2],
+
+    harmonic: bool,
+    crossover: f32,
+    // Linkwitz-Riley crossover: two Butterworth sections per band
+    lows: [Biquad; 2],
+    highs: [Biquad; 2],
+}
+
+impl Tremelo {
+
+    /// rate in Hz, used unless synced to the tempo
+    pub fn set_rate(&mut self, rate: f32) {
+        self.rate = rate.max(0.);
+        self.update_rate();
+    }
+
+    /// cycle length in beats, e.g. 0.5 for eighth notes; 0 to unsync
+    pub fn set_sync(&mut self, beats: f32) {
+        self.sync = beats.max(0.);
+        self.update_rate();
+    }
+
+    pub fn set_tempo(&mut self, bpm: f32) {
+        self.tempo = if bpm > 0. { Some(bpm) } else { None };
+        self.update_rate();
+    }
+
+    pub fn set_depth(&mut self, depth: f32) {
+        self.depth = depth.max(0.).min(1.);
+    }
+
+    pub fn set_mix(&mut self, mix: f32) {
+        self.mix = mix.max(0.).min(1.);
+    }
+
+    /// offset of the right channel in degrees
+    pub fn set_phase(&mut self, degrees: f32) {
+        self.phase = degrees / 360.;
+    }
+
+    /// time in milliseconds to round off the edges of the square wave
+    pub fn set_smoothing(&mut self, ms: f32) {
+        self.smoothing_ms = ms;
+        self.smoothing = time_coeff(ms.max(0.) / 1000., self.sample_rate);
+    }
+
+    pub fn set_harmonic(&mut self, on: bool) {
+        self.harmonic = on;
+    }
+
+    /// glides to the new crossover while the bands are playing
+    pub fn set_crossover(&mut self, freq: f32) {
+        let glide = self.harmonic;
+        self.update_crossover(freq, glide);
+    }
+
+    /// the filters start from the identity, so a glide from `new` would
+    /// play both bands nearly unfiltered at first
+    fn update_crossover(&mut self, freq: f32, glide: bool) {
+        self.crossover = freq.max(20.).min(0.45 * self.sample_rate);
+        let low = Coeffs::lowpass(self.sample_rate, self.crossover, BUTTERWORTH_Q);
+        let high = Coeffs::highpass(self.sample_rate, self.crossover, BUTTERWORTH_Q);
+        for stage in self.lows.iter_mut() {
+            if glide { stage.set_coeffs(low) } else { stage.snap_coeffs(low) }
+        }
+        for stage in self.highs.iter_mut() {
+            if glide { stage.set_coeffs(high) } else { stage.snap_coeffs(high) }
+        }
+    }
+
+    fn update_rate(&mut self) {
+        match self.tempo {
+            Some(bpm) if self.sync > 0. => self.lfo.set_rate(bpm / 60. / self.sync),
+            _ => self.lfo.set_rate(self.rate),
+        }
+    }
+
+    /// gain of channel `ch`, from the LFO shifted by `offset` cycles
+    fn gain(&mut self, ch: usize, offset: f32) -> f32 {
+        let unipolar = (1. + self.lfo.value_at(offset)) / 2.;
+        let target = 1. - self.depth * (1. - unipolar);
+        let g = &mut self.gains[ch];
+        *g = target + self.smoothing * (*g - target);
+        *g
+    }
 }
 
 impl Effect for Tremelo {
 
-    fn new(_sample_rate: usize, _frame_size: u32) -> Self {
-        let depth = 1.;
-        Tremelo {
+    fn new(sample_rate: usize, _frame_size: u32) -> Self {
+        let sample_rate = sample_rate as f32;
+        let mut trem = Tremelo {
             bypassing: false,
-            counter_limit: 50,
-            depth,
-            control: 1,
-            modulo: 0,
-            offset: 1. - depth,
-            alpha_mix: 0.8,
-            beta_mix: 0.2,
-        }
+            sample_rate,
+            lfo: Lfo::new(sample_rate, 5.),
+            rate: 5.,
+            sync: 0.,
+            tempo: None,
+            depth: 0.5,
+            mix: 1.,
+            phase: 0.,
+            smoothing_ms: 0.,
+            smoothing: 0.,
+            gains: [1.; 2],
+            harmonic: false,
+            crossover: 0.,
+            lows: [Biquad::new(sample_rate), Biquad::new(sample_rate)],
+            highs: [Biquad::new(sample_rate), Biquad::new(sample_rate)],
+        };
+        trem.set_smoothing(2.);
+        trem.update_crossover(800., false);
+        trem
     }
 
     fn name(&self) -> &str {
@@ -38,19 +153,48 @@ impl Effect for Tremelo {
             return;
         }
 
-        let m = self.modulo as f32 * self.depth / self.counter_limit as f32;
+        let dry = 1. - self.mix;
         for (i, x) in input.iter().enumerate() {
-            let y_ = (m + self.offset) * x;
-            let y = self.mixer(*x, y_);
-            output_r[i] = y;
-            output_l[i] = y;
+            let offset = self.phase;
+            let (l, r) = if self.harmonic {
+                let low = self.lows.iter_mut().fold(*x, |y, s| s.process(y));
+                let high = self.highs.iter_mut().fold(*x, |y, s| s.process(y));
+                let y = low * self.gain(0, 0.) + high * self.gain(1, 0.5);
+                (y, y)
+            } else {
+                (x * self.gain(0, 0.), x * self.gain(1, offset))
+            };
+            output_l[i] = self.mix * l + dry * x;
+            output_r[i] = self.mix * r + dry * x;
+            self.lfo.advance();
         }
-
-        self.sweep();
     }
 
+    fn sample_rate_changed(&mut self, sample_rate: usize) {
+        self.sample_rate = sample_rate as f32;
+        self.lfo.set_sample_rate(self.sample_rate);
+        for stage in self.lows.iter_mut().chain(self.highs.iter_mut()) {
+            stage.set_sample_rate(self.sample_rate);
+        }
+        let (ms, freq) = (self.smoothing_ms, self.crossover);
+        self.set_smoothing(ms);
+        self.update_crossover(freq, false);
+    }
 
-
+    fn params(&self) -> Vec<(String, Param)> {
+        use self::Param::*;
+        params(&[
+            ("rate", Float(self.rate)),
+            ("sync", Float(self.sync)),
+            ("depth", Float(self.depth)),
+            ("mix", Float(self.mix)),
+            ("phase", Float(self.phase * 360.)),
+            ("smooth", Float(self.smoothing_ms)),
+            ("harmonic", Int(self.harmonic as i32)),
+            ("xover", Float(self.crossover)),
+            ("wave", Name(self.lfo.waveform.name().to_owned())),
+        ])
+    }
 
     fn bypass(&mut self) {
         self.bypassing = !self.bypassing;
@@ -60,18 +204,34 @@ impl Effect for Tremelo {
         self.bypassing
     }
 
+    fn set(&mut self, param: &str, val: f32) {
+        match param {
+            "rate" => self.set_rate(val),
+            "sync" => self.set_sync(val),
+            "depth" => self.set_depth(val),
+            "mix" => self.set_mix(val),
+            "phase" => self.set_phase(val),
+            "smooth" => self.set_smoothing(val),
+            "harmonic" => self.set_harmonic(val >= 0.5),
+            "xover" => self.set_crossover(val),
+            _ => (),
+        }
+    }
+
     fn ctrl(&mut self, msg: CtrlMsg) {
         use self::CtrlMsg::*;
         match msg {
             Bypass => self.bypass(),
-            Set(_pedal_name, conf_name, val) => {
-                if &conf_name == "limit" {
-                    self.set_limit(val as i16);
-                }
-                if &conf_name == "mix" {
-                    self.set_mixing(val);
+            Set(_pedal_name, conf_name, val) => self.set(&conf_name, val),
+            Select(_pedal_name, conf_name, val) => {
+                if &conf_name == "wave" {
+                    match Waveform::from_name(&val) {
+                        Some(w) => self.lfo.waveform = w,
+                        None => println!("Unknown waveform: {} (sine, triangle, square)", val),
+                    }
                 }
             },
+            Tempo(bpm) => self.set_tempo(bpm),
             _ => (),
         }
 
@@ -79,23 +239,64 @@ impl Effect for Tremelo {
 
 }
 
-impl Tremelo {
-    fn sweep(&mut self) {
-        self.modulo += self.control;
-        if self.modulo > self.counter_limit {
-            self.control = -1;
-        } else if self.modulo == 0 {
-            self.control = 1;
+#[cfg(test)]
+mod tests {
+    use super::Tremelo;
+    use effects::Effect;
+    use std::f32::consts::PI;
+
+    fn run(block: usize) -> Vec<f32> {
+        run_mixed(block, 1.)
+    }
+
+    fn run_mixed(block: usize, mix: f32) -> Vec<f32> {
+        let mut trem = Tremelo::new(48000, block as u32);
+        trem.set_mix(mix);
+        let input = vec![1.; 9600];
+        let mut out = vec![];
+        for chunk in input.chunks(block) {
+            let mut l = vec![0.; chunk.len()];
+            let mut r = vec![0.; chunk.len()];
+            trem.process_samples(chunk, &mut l, &mut r);
+            out.extend(l);
         }
+        out
+    }
+
+    #[test]
+    fn test_independent_of_block_size() {
+        assert_eq!(run(64), run(1024));
     }
-    fn set_limit(&mut self, val: i16) {
-        self.counter_limit = val;
+
+    #[test]
+    fn test_rate_and_depth() {
+        // 5 Hz, depth 0.5: the gain dips to 0.5 every 9600 samples
+        let out = run(256);
+        let min = out.iter().cloned().fold(1., f32::min);
+        assert!((min - 0.5).abs() < 0.01);
+        let dip = out.iter().position(|&y| y == min).unwrap();
+        assert!((dip as i32 - 7200).abs() < 150);
     }
-    fn mixer(&self, x: f32, y: f32) -> f32 {
-        self.alpha_mix * y + self.beta_mix * x
+
+    #[test]
+    fn test_mix() {
+        // half wet, the dip of 0.5 only reaches 0.75
+        let min = run_mixed(256, 0.5).iter().cloned().fold(1., f32::min);
+        assert!((min - 0.75).abs() < 0.01);
+        assert!(run_mixed(256, 0.).iter().all(|&y| y == 1.));
     }
-    pub fn set_mixing(&mut self, alpha_mix: f32) {
-        self.alpha_mix = alpha_mix;
-        self.beta_mix = 1. - alpha_mix;
+
+    #[test]
+    fn test_harmonic_starts_filtered() {
+        // without modulation the two bands add up to the input level,
+        // right from the first sample
+        let mut trem = Tremelo::new(48000, 480);
+        trem.set_depth(0.);
+        trem.set_harmonic(true);
+        let input: Vec<f32> = (0..480).map(|i| (2. * PI * 800. * i as f32 / 48000.).sin()).collect();
+        let (mut l, mut r) = (vec![0.; 480], vec![0.; 480]);
+        trem.process_samples(&input, &mut l, &mut r);
+        let peak = l.iter().fold(0f32, |m, y| m.max(y.abs()));
+        assert!(peak < 1.05, "{}", peak);
     }
 }
@@ -1,25 +1,225 @@
 static TUNER_BUFFER_SIZE : usize = 10240;
-
-use std::time::Instant;
+/// Readings with a lower YIN confidence are discarded
+const MIN_CONFIDENCE: f32 = 0.85;
+/// Buffers quieter than this RMS level are not analysed
+const MIN_LEVEL: f32 = 0.001;
+/// Readings per second in streaming mode
+const STREAM_RATE: usize = 10;
+/// Buffers passed between the audio thread and the tuner worker
+const STREAM_BUFFERS: usize = 2;
+/// Range accepted for the A4 reference pitch, in Hz
+const MIN_REFERENCE: f32 = 432.;
+const MAX_REFERENCE: f32 = 446.;
+/// Width of the needle and strobe displays in characters
+const DISPLAY_WIDTH: usize = 41;
+/// Strobe pattern movement in characters per second per cent off
+const STROBE_SPEED: f32 = 0.5;
+/// Partials per string examined by the strum tuner
+const STRUM_HARMONICS: usize = 8;
+/// How far off a string may be and still be found, in cents
+const STRUM_RANGE: f32 = 50.;
+/// Seconds of input kept for the strum tuner, long enough to separate
+/// partials a few Hz apart
+const STRUM_LENGTH: f32 = 0.5;
+/// Search range around the coarse estimate when refining, in cents
+const STRUM_REFINE: f32 = 5.;
+/// Partials quieter than this fraction of the loudest one are ignored
+const STRUM_FLOOR: f32 = 0.01;
+
+use std::fmt;
+use std::f32::consts::PI;
+use std::io::{self, Write};
 use std::thread;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{sync_channel, SyncSender, Receiver};
 
 extern crate rustfft;
-use effects::{CtrlMsg, Effect};
+extern crate pitch_calc;
+use effects::{CtrlMsg, Effect, Param, params};
+use effects::pitch::{yin, parse_note, parabolic_peak};
+use effects::tuning::Tuning;
 use self::rustfft::FFTplanner;
 use self::rustfft::num_complex::Complex;
 use self::rustfft::num_traits::Zero;
+use self::pitch_calc::{Letter, Octave};
+
+/// Continuously tunes in a worker thread while `t on` is active.
+/// `s tuner stream quiet` only keeps the latest line for `readout`.
+///
+/// The worker is started with the tuner and waits while nothing is
+/// streamed, switching the stream on and off only sets a flag. Every
+/// `1 / STREAM_RATE` seconds the audio thread copies its buffer into a
+/// snapshot the worker handed back earlier and sends it over a bounded
+/// channel, so there is no allocation, blocking or thread spawning on
+/// the audio thread. Dropping the sender stops the worker.
+struct Stream {
+    to_worker: SyncSender<ToWorker>,
+    from_worker: Receiver<Snapshot>,
+    since_publish: usize,
+}
+
+enum ToWorker {
+    Tune(Snapshot),
+    /// the stream was switched off
+    Stop,
+}
+
+struct Snapshot {
+    samples: Vec<f32>,
+    reference: f32,
+    mode: Mode,
+    tuning: Arc<Tuning>,
+    display: Display,
+    quiet: bool,
+}
+
+/// What the cents deviation is measured against
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+    /// the nearest note of the chromatic scale
+    Chromatic,
+    /// the nearest open string of the selected tuning
+    Strings,
+    /// a fixed MIDI note, for setting intonation
+    Note(i32),
+    /// all strings of the selected tuning at once, from a strummed chord
+    Strum,
+}
+
+impl Mode {
+
+    /// Label of the target and the deviation from it in cents
+    pub fn target(&self, reading: &Reading, tuning: &Tuning) -> (String, f32) {
+        match *self {
+            Mode::Chromatic => {
+                (format!("{}{}", reading.note_name(), reading.octave), reading.cents)
+            },
+            Mode::Strings => {
+                let idx = tuning.nearest(reading.step);
+                let string = tuning.strings[idx];
+                (format!("{}:{}", tuning.string_number(idx), note_name(string)),
+                    100. * (reading.step - string as f32))
+            },
+            Mode::Note(note) => (note_name(note), 100. * (reading.step - note as f32)),
+            // strummed chords are not reduced to a single reading
+            Mode::Strum => (String::new(), 0.),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Display {
+    /// a needle swinging around the center
+    Needle,
+    /// a pattern that scrolls left when flat, right when sharp and
+    /// stands still when in tune
+    Strobe,
+}
+
+impl Display {
+    fn from_name(name: &str) -> Option<Display> {
+        match name {
+            "needle" => Some(Display::Needle),
+            "strobe" => Some(Display::Strobe),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match *self {
+            Display::Needle => "needle",
+            Display::Strobe => "strobe",
+        }
+    }
+}
 
 pub struct Tuner {
     tuner_buffer: Vec<f32>,
     i_idx: usize,
     bypassing: bool,
     sample_rate: usize,
-    frame_size: u32,
+    /// A4 in Hz
+    reference: f32,
+    /// silence the output while streaming
+    mute: bool,
+    display: Display,
+    stream: Stream,
+    streaming: bool,
+    mode: Mode,
+    tunings: Vec<Arc<Tuning>>,
+    tuning: Arc<Tuning>,
+    /// stream without printing
+    quiet: bool,
+    /// latest line of the stream
+    line: Arc<Mutex<String>>,
 }
 
-pub fn calculate_spectrum(samples: &[f32]) -> Vec<f32> {
-    let now = Instant::now();
+/// A detected pitch, relative to the nearest equal tempered note
+#[derive(Clone, Copy, Debug)]
+pub struct Reading {
+    pub freq: f32,
+    pub letter: Letter,
+    pub octave: Octave,
+    /// deviation from the note, -50..50
+    pub cents: f32,
+    /// fractional MIDI note number
+    pub step: f32,
+    pub confidence: f32,
+}
+
+impl Reading {
+
+    /// Names `freq` relative to equal temperament with A4 at `reference` Hz
+    pub fn from_freq(freq: f32, confidence: f32, reference: f32) -> Self {
+        let step = pitch_calc::step_from_hz(freq * 440. / reference);
+        let (letter, octave) = pitch_calc::letter_octave_from_step(step);
+        Reading {
+            freq,
+            letter,
+            octave,
+            cents: 100. * (step - step.round()),
+            step,
+            confidence,
+        }
+    }
+
+    pub fn note_name(&self) -> &'static str {
+        letter_name(self.letter)
+    }
+}
+
+impl fmt::Display for Reading {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}{} {:+.1} cents ({:.2} Hz)",
+            self.note_name(), self.octave, self.cents, self.freq)
+    }
+}
 
+/// Name with octave of a MIDI note number, such as `E2`
+pub fn note_name(note: i32) -> String {
+    let (letter, octave) = pitch_calc::letter_octave_from_step(note as f32);
+    format!("{}{}", letter_name(letter), octave)
+}
+
+pub fn letter_name(letter: Letter) -> &'static str {
+    use self::Letter::*;
+    match letter {
+        C => "C",
+        Csh | Db => "C#",
+        D => "D",
+        Dsh | Eb => "D#",
+        E => "E",
+        F => "F",
+        Fsh | Gb => "F#",
+        G => "G",
+        Gsh | Ab => "G#",
+        A => "A",
+        Ash | Bb => "A#",
+        B => "B",
+    }
+}
+
+pub fn calculate_spectrum(samples: &[f32]) -> Vec<f32> {
     let mut input: Vec<Complex<f32>> = samples.iter()
         .map(|&x| Complex::new(x, 0.0))
         .collect();
@@ -30,57 +230,373 @@ pub fn calculate_spectrum(samples: &[f32]) -> Vec<f32> {
     let fft = planner.plan_fft(input.len());
     fft.process(&mut input, &mut output);
 
-    println!("{:?}", now.elapsed());
-
     output.iter()
         .map(|&c| c.norm_sqr())
         .collect()
 }
 
-pub fn tune(input: &[f32], sample_rate: usize) -> Option<f32> {
+/// Estimates how many cents each string of `tuning` is off in a
+/// strummed open chord, `None` for strings that cannot be heard.
+///
+/// The windowed input is zero-padded for a finely sampled spectrum. For
+/// every string the peaks near its partials are located with parabolic
+/// interpolation and divided by their harmonic number, and the estimates
+/// are averaged weighted by their magnitude. Partials that land close to
+/// a partial of another string, like the third harmonic of the low E and
+/// the open B, are skipped. A first pass over the two lowest partials
+/// finds roughly where each string is, so the second pass can check the
+/// collisions against the detuned strings and search narrow windows.
+///
+/// A string an octave above another one, like the D3 in drop D, has no
+/// partial of its own. It is read from the shared peaks, which is only
+/// accurate while both strings are within a few cents of each other.
+pub fn strum(input: &[f32], sample_rate: usize, reference: f32, tuning: &Tuning) -> Vec<Option<f32>> {
+    let len = input.len();
+    let fft_size = (4 * len).next_power_of_two();
+    let mut padded = vec![0.; fft_size];
+    for (i, x) in input.iter().enumerate() {
+        let hann = 0.5 - 0.5 * (2. * PI * i as f32 / len as f32).cos();
+        padded[i] = x * hann;
+    }
+    let magnitude: Vec<f32> = calculate_spectrum(&padded)[..fft_size / 2]
+        .iter()
+        .map(|p| p.sqrt())
+        .collect();
+    let loudest = magnitude.iter().cloned().fold(0., f32::max);
+    if loudest == 0. {
+        return vec![None; tuning.strings.len()];
+    }
 
-    let input_len = input.len();
-    let freqs = calculate_spectrum(input);
+    let bin_hz = sample_rate as f32 / fft_size as f32;
+    // half the width of the main lobe of the window
+    let lobe_hz = 2. * sample_rate as f32 / len as f32;
+    let nominal: Vec<f32> = tuning.strings.iter()
+        .map(|&note| reference * 2f32.powf((note - 69) as f32 / 12.))
+        .collect();
 
-    let buckets: Vec<_> =
-        (0 .. 1 + input_len / 2) // has Hermitian symmetry to f=0
-        .filter_map(|i| {
-            let norm = freqs[i];
-            let noise_threshold = 1.0;
-            if norm > noise_threshold {
-                let f = i as f32 / input_len as f32 * sample_rate as f32;
-                Some((f, norm))
-            } else {
-                None
+    let lobe_bins = (lobe_hz / bin_hz).ceil() as usize;
+
+    // how far a partial at `freq` may be from where it is expected
+    let reach = |freq: f32, slack: f32| (freq * (2f32.powf(slack / 1200.) - 1.)).max(lobe_hz / 2.);
+
+    // fundamental of string `s` from its first `harmonics` partials, each
+    // searched within `slacks[s]` cents of the multiple of `centers[s]`
+    // and skipped when the window of a partial of another string comes
+    // closer than `clearance` Hz
+    let estimate = |s: usize, centers: &[f32], slacks: &[f32], harmonics: usize, clearance: f32| {
+        let (mut sum, mut weight) = (0., 0.);
+        for k in 1..harmonics + 1 {
+            let expected = k as f32 * centers[s];
+            if expected * 1.1 > sample_rate as f32 / 2. {
+                break;
+            }
+            let window = reach(expected, slacks[s]);
+            let collides = (0..centers.len())
+                .filter(|&other| other != s)
+                .any(|other| (1..STRUM_HARMONICS + 1).any(|m| {
+                    let partial = m as f32 * centers[other];
+                    let distance = (partial - expected).abs();
+                    distance < clearance + window + reach(partial, slacks[other])
+                }));
+            if collides {
+                continue;
+            }
+
+            let lo = (((expected - window) / bin_hz).floor() as usize).max(lobe_bins);
+            let hi = (((expected + window) / bin_hz).ceil() as usize)
+                .min(magnitude.len() - 1 - lobe_bins);
+            if lo > hi {
+                continue;
+            }
+            let peak = (lo..hi + 1)
+                .max_by(|&a, &b| magnitude[a].partial_cmp(&magnitude[b]).unwrap())
+                .unwrap();
+            let m = magnitude[peak];
+            // a sidelobe of a louder partial nearby is not a partial
+            let main_lobe = magnitude[peak - lobe_bins..peak + lobe_bins + 1]
+                .iter()
+                .all(|&x| x <= m);
+            if m < STRUM_FLOOR * loudest || !main_lobe {
+                continue;
+            }
+            // the log magnitude of a Hann window peak is close to a parabola
+            let ln = |i: usize| magnitude[i].max(1e-20).ln();
+            let offset = parabolic_peak(ln(peak - 1), ln(peak), ln(peak + 1));
+            sum += m * (peak as f32 + offset) * bin_hz / k as f32;
+            weight += m;
+        }
+        if weight > 0. { Some(sum / weight) } else { None }
+    };
+
+    // the low partials narrow the strings down, the cleanly separated
+    // partials around the found frequencies then refine them
+    let slacks = vec![STRUM_RANGE; nominal.len()];
+    let coarse: Vec<_> = (0..nominal.len())
+        .map(|s| estimate(s, &nominal, &slacks, 2, 0.))
+        .collect();
+    let centers: Vec<f32> = coarse.iter().zip(nominal.iter())
+        .map(|(f, &n)| f.unwrap_or(n))
+        .collect();
+    let slacks: Vec<f32> = coarse.iter()
+        .map(|f| if f.is_some() { STRUM_REFINE } else { STRUM_RANGE })
+        .collect();
+    (0..nominal.len())
+        .map(|s| {
+            let f = estimate(s, &centers, &slacks, STRUM_HARMONICS, lobe_hz)
+                .or(coarse[s])
+                .or_else(|| estimate(s, &nominal, &slacks, 2, -f32::INFINITY))?;
+            Some(1200. * (f / nominal[s]).log2())
+        })
+        .collect()
+}
+
+/// Detects the pitch of `input` with YIN, `None` when the signal is too
+/// quiet or not periodic enough to trust
+pub fn tune(input: &[f32], sample_rate: usize, reference: f32) -> Option<Reading> {
+    // the latest samples are plenty for a single note
+    let input = &input[input.len().saturating_sub(TUNER_BUFFER_SIZE)..];
+    let rms = (input.iter().map(|x| x * x).sum::<f32>() / input.len() as f32).sqrt();
+    if rms < MIN_LEVEL {
+        return None;
+    }
+
+    let mut scratch = vec![];
+    let (freq, confidence) = yin(input, sample_rate as f32, &mut scratch)?;
+    if confidence < MIN_CONFIDENCE {
+        return None;
+    }
+    Some(Reading::from_freq(freq, confidence, reference))
+}
+
+/// Deviation of every string, highest first like a tab
+pub fn strum_line(tuning: &Tuning, cents: &[Option<f32>]) -> String {
+    let strings: Vec<_> = tuning.strings.iter().zip(cents.iter()).enumerate().rev()
+        .map(|(idx, (&note, c))| {
+            let label = format!("{}:{}", tuning.string_number(idx), note_name(note));
+            match *c {
+                Some(c) => format!("{} {:+.1}", label, c),
+                None => format!("{} --", label),
             }
         })
         .collect();
+    strings.join("  ")
+}
 
-    if buckets.is_empty() {
-        return None
+/// One line of the needle display, `|` marks the target and `^` the
+/// pitch `cents` away from it
+pub fn needle(cents: f32) -> String {
+    let center = DISPLAY_WIDTH / 2;
+    let pos = (center as f32 * (1. + cents / 50.)).round().max(0.) as usize;
+    (0..DISPLAY_WIDTH)
+        .map(|i| if i == pos.min(DISPLAY_WIDTH - 1) { '^' } else if i == center { '|' } else { '-' })
+        .collect()
+}
+
+/// One line of the strobe display with the pattern shifted by `phase`
+/// characters
+pub fn strobe(phase: f32) -> String {
+    (0..DISPLAY_WIDTH)
+        .map(|i| {
+            let cell = ((i as f32 - phase) / 4.).floor() as i32;
+            if cell % 2 == 0 { '#' } else { ' ' }
+        })
+        .collect()
+}
+
+/// Worker side of the streaming tuner: analyses the snapshots the audio
+/// thread sends and redraws the reading in place
+fn stream_worker(from_audio: Receiver<ToWorker>, to_audio: SyncSender<Snapshot>,
+                 len: usize, sample_rate: usize, latest: Arc<Mutex<String>>) {
+    for _ in 0..STREAM_BUFFERS {
+        let snapshot = Snapshot {
+            samples: vec![0.; len],
+            reference: 440.,
+            mode: Mode::Chromatic,
+            tuning: Arc::new(Tuning::builtin().remove(0)),
+            display: Display::Needle,
+            quiet: false,
+        };
+        if to_audio.send(snapshot).is_err() {
+            return;
+        }
+    }
+
+    let show = |line: String, quiet: bool| {
+        if !quiet {
+            print!("\r{}", line);
+            io::stdout().flush().ok();
+        }
+        *latest.lock().unwrap() = line;
+    };
+    let dt = 1. / STREAM_RATE as f32;
+    let mut phase = 0.;
+    // a line is left on the terminal
+    let mut printed = false;
+    for msg in from_audio.iter() {
+        let snapshot = match msg {
+            ToWorker::Tune(snapshot) => snapshot,
+            ToWorker::Stop => {
+                if printed {
+                    println!();
+                }
+                printed = false;
+                phase = 0.;
+                latest.lock().unwrap().clear();
+                continue;
+            },
+        };
+        printed = printed || !snapshot.quiet;
+        if snapshot.mode == Mode::Strum {
+            let cents = strum(&snapshot.samples, sample_rate, snapshot.reference, &snapshot.tuning);
+            show(strum_line(&snapshot.tuning, &cents), snapshot.quiet);
+            let _ = to_audio.try_send(snapshot);
+            continue;
+        }
+        let line = match tune(&snapshot.samples, sample_rate, snapshot.reference) {
+            Some(reading) => {
+                let (label, cents) = snapshot.mode.target(&reading, &snapshot.tuning);
+                let meter = match snapshot.display {
+                    Display::Needle => needle(cents),
+                    Display::Strobe => {
+                        phase += cents * STROBE_SPEED * dt;
+                        phase %= 8.;
+                        strobe(phase)
+                    },
+                };
+                format!("{:>6} {:+7.1}c [{}] {:.0}%",
+                    label, cents, meter, 100. * reading.confidence)
+            },
+            None => format!("    --          [{}]", " ".repeat(DISPLAY_WIDTH)),
+        };
+        show(line, snapshot.quiet);
+        let _ = to_audio.try_send(snapshot);
+    }
+    if printed {
+        println!();
     }
+}
 
-    let &(max_f, _max_val) =
-        buckets.iter()
-        .max_by(|&&(_f1, ref val1), &&(_f2, ref val2)| val1.partial_cmp(val2).unwrap())
-        .unwrap();
+/// Starts a worker for buffers of `len` samples
+fn spawn_worker(len: usize, sample_rate: usize, latest: Arc<Mutex<String>>) -> Stream {
+    // room for a stop behind every snapshot in flight
+    let (to_worker, from_audio) = sync_channel(STREAM_BUFFERS + 1);
+    let (to_audio, from_worker) = sync_channel(STREAM_BUFFERS);
+    thread::spawn(move || stream_worker(from_audio, to_audio, len, sample_rate, latest));
+    Stream { to_worker, from_worker, since_publish: 0 }
+}
 
+impl Tuner {
 
-    println!("Max index is {}", max_f);
-    // println!("Max value is {}", max_val);
+    /// The buffered input, oldest sample first
+    fn ordered_buffer(&self) -> Vec<f32> {
+        let mut input = vec![0.; self.tuner_buffer.len()];
+        self.copy_ordered(&mut input);
+        input
+    }
+
+    fn copy_ordered(&self, out: &mut [f32]) {
+        let split = self.tuner_buffer.len() - self.i_idx;
+        out[..split].copy_from_slice(&self.tuner_buffer[self.i_idx..]);
+        out[split..].copy_from_slice(&self.tuner_buffer[..self.i_idx]);
+    }
+
+    pub fn set_reference(&mut self, freq: f32) {
+        let clamped = freq.max(MIN_REFERENCE).min(MAX_REFERENCE);
+        if clamped != freq {
+            println!("tuner: reference {} is out of range, using {}", freq, clamped);
+        }
+        self.reference = clamped;
+    }
 
-    Some(max_f)
+    pub fn set_tuning(&mut self, name: &str) {
+        match self.tunings.iter().find(|t| t.name == name) {
+            Some(tuning) => {
+                self.tuning = tuning.clone();
+                println!("{}", tuning.describe());
+            },
+            None => {
+                let names: Vec<_> = self.tunings.iter().map(|t| t.name.as_str()).collect();
+                println!("Unknown tuning: {} ({})", name, names.join(", "));
+            }
+        }
+    }
+
+    /// Adds `loaded`, replacing the tunings with the same name
+    pub fn add_tunings(&mut self, loaded: Vec<Tuning>) {
+        for tuning in loaded {
+            println!("{}", tuning.describe());
+            self.tunings.retain(|t| t.name != tuning.name);
+            self.tunings.push(Arc::new(tuning));
+        }
+    }
+
+    pub fn is_streaming(&self) -> bool {
+        self.streaming
+    }
+
+    pub fn start_stream(&mut self) {
+        if !self.streaming {
+            self.streaming = true;
+            self.stream.since_publish = 0;
+        }
+    }
+
+    pub fn stop_stream(&mut self) {
+        if self.streaming {
+            self.streaming = false;
+            let _ = self.stream.to_worker.try_send(ToWorker::Stop);
+        }
+    }
+
+    /// Hands the worker a fresh snapshot when one is due and a buffer is free
+    fn publish(&mut self, samples: usize) {
+        if !self.streaming {
+            return;
+        }
+        self.stream.since_publish += samples;
+        if self.stream.since_publish < self.sample_rate / STREAM_RATE {
+            return;
+        }
+        let mut snapshot = match self.stream.from_worker.try_recv() {
+            Ok(snapshot) => snapshot,
+            Err(_) => return,
+        };
+        self.stream.since_publish = 0;
+        self.copy_ordered(&mut snapshot.samples);
+        snapshot.reference = self.reference;
+        snapshot.mode = self.mode;
+        snapshot.tuning = self.tuning.clone();
+        snapshot.display = self.display;
+        snapshot.quiet = self.quiet;
+        let _ = self.stream.to_worker.try_send(ToWorker::Tune(snapshot));
+    }
+}
+
+/// Samples kept for analysis, enough for the strum tuner
+fn buffer_len(sample_rate: usize) -> usize {
+    TUNER_BUFFER_SIZE.max((STRUM_LENGTH * sample_rate as f32) as usize)
 }
 
 impl Effect for Tuner {
 
-    fn new(sample_rate: usize, frame_size: u32) -> Self {
+    fn new(sample_rate: usize, _frame_size: u32) -> Self {
+        let tunings: Vec<_> = Tuning::builtin().into_iter().map(Arc::new).collect();
+        let line = Arc::new(Mutex::new(String::new()));
         Self {
-            tuner_buffer: vec![0.; TUNER_BUFFER_SIZE],
+            tuner_buffer: vec![0.; buffer_len(sample_rate)],
             i_idx: 0,
             bypassing: true,
             sample_rate,
-            frame_size
+            reference: 440.,
+            mute: false,
+            display: Display::Needle,
+            stream: spawn_worker(buffer_len(sample_rate), sample_rate, line.clone()),
+            streaming: false,
+            mode: Mode::Chromatic,
+            tunings: tunings.clone(),
+            tuning: tunings[0].clone(),
+            quiet: false,
+            line,
         }
     }
 
@@ -90,8 +606,8 @@ impl Effect for Tuner {
 
     fn process_samples(&mut self, input: &[f32], output_l: &mut [f32], output_r: &mut [f32]) {
 
-        for bufptr in 0..self.frame_size as usize {
-            if self.i_idx >= TUNER_BUFFER_SIZE {
+        for bufptr in 0..input.len() {
+            if self.i_idx >= self.tuner_buffer.len() {
                 self.i_idx = 0;
             }
             self.tuner_buffer[self.i_idx] = input[bufptr];
@@ -101,6 +617,48 @@ impl Effect for Tuner {
             output_r[bufptr] = input[bufptr];
         }
 
+        self.publish(input.len());
+        if self.mute && self.is_streaming() {
+            for (l, r) in output_l.iter_mut().zip(output_r.iter_mut()) {
+                *l = 0.;
+                *r = 0.;
+            }
+        }
+    }
+
+    fn sample_rate_changed(&mut self, sample_rate: usize) {
+        self.sample_rate = sample_rate;
+        self.tuner_buffer = vec![0.; buffer_len(sample_rate)];
+        self.i_idx = 0;
+        // the worker's snapshots fit the old buffer, not called on the
+        // audio thread
+        self.stream = spawn_worker(self.tuner_buffer.len(), sample_rate, self.line.clone());
+    }
+
+    fn params(&self) -> Vec<(String, Param)> {
+        use self::Param::*;
+        let mut list = params(&[
+            ("ref", Float(self.reference)),
+            ("mute", Int(self.mute as i32)),
+            ("display", Name(self.display.name().to_owned())),
+            ("tuning", Name(self.tuning.name.clone())),
+        ]);
+        list.push(match self.mode {
+            Mode::Note(note) => ("note".to_owned(), Name(note_name(note))),
+            Mode::Chromatic => ("mode".to_owned(), Name("chromatic".to_owned())),
+            Mode::Strings => ("mode".to_owned(), Name("strings".to_owned())),
+            Mode::Strum => ("mode".to_owned(), Name("strum".to_owned())),
+        });
+        list
+    }
+
+    fn readout(&self) -> Option<String> {
+        if !self.is_streaming() {
+            return None;
+        }
+        // the worker is writing, the next call gets it. Still Some, the
+        // TUI takes a readout for a stream.
+        Some(self.line.try_lock().map(|line| line.clone()).unwrap_or_default())
     }
 
     fn bypass(&mut self) {
@@ -111,17 +669,69 @@ impl Effect for Tuner {
         self.bypassing
     }
 
+    fn set(&mut self, param: &str, val: f32) {
+        match param {
+            "ref" => self.set_reference(val),
+            "mute" => self.mute = val > 0.5,
+            _ => (),
+        }
+    }
+
     fn ctrl(&mut self, msg: CtrlMsg) {
         use self::CtrlMsg::*;
         match msg {
             Bypass => self.bypass(),
             Tuner => {
-                let input = self.tuner_buffer.to_owned();
+                let input = self.ordered_buffer();
                 let sample_rate = self.sample_rate;
+                let reference = self.reference;
+                let mode = self.mode;
+                let tuning = self.tuning.clone();
                 thread::spawn(move || {
-                    tune(&input, sample_rate);
+                    if mode == Mode::Strum {
+                        let cents = strum(&input, sample_rate, reference, &tuning);
+                        println!("{}", strum_line(&tuning, &cents));
+                        return;
+                    }
+                    match tune(&input, sample_rate, reference) {
+                        Some(reading) => {
+                            let (label, cents) = mode.target(&reading, &tuning);
+                            println!("{} {:+.1} cents ({:.2} Hz)", label, cents, reading.freq);
+                        },
+                        None => println!("No clear pitch"),
+                    }
                 });
             },
+            Set(_pedal_name, conf_name, val) => self.set(&conf_name, val),
+            Select(_pedal_name, conf_name, val) => {
+                match conf_name.as_str() {
+                    "stream" => match val.as_str() {
+                        "on" | "quiet" => {
+                            self.quiet = val == "quiet";
+                            self.start_stream();
+                        },
+                        "off" => self.stop_stream(),
+                        _ => println!("Unknown tuner stream setting: {} (on, quiet, off)", val),
+                    },
+                    "display" => match Display::from_name(&val) {
+                        Some(display) => self.display = display,
+                        None => println!("Unknown display: {} (needle, strobe)", val),
+                    },
+                    "mode" => match val.as_str() {
+                        "chromatic" => self.mode = Mode::Chromatic,
+                        "strings" => self.mode = Mode::Strings,
+                        "strum" => self.mode = Mode::Strum,
+                        _ => println!("Unknown tuner mode: {} (chromatic, strings, strum)", val),
+                    },
+                    "note" => match parse_note(&val) {
+                        Some(note) => self.mode = Mode::Note(note),
+                        None => println!("Unknown note: {}", val),
+                    },
+                    "tuning" => self.set_tuning(&val),
+                    _ => (),
+                }
+            },
+            Tunings(_, loaded) => self.add_tunings(loaded),
             _ => (),
         }
     }
@@ -131,8 +741,11 @@ impl Effect for Tuner {
 #[cfg(test)]
 mod tests {
     use std::f32::consts::PI;
-    use super::{calculate_spectrum, Tuner};
-    use effects::Effect;
+    use std::thread;
+    use std::time::Duration;
+    use super::{calculate_spectrum, tune, strum, strobe, Mode, Tuner};
+    use effects::tuning::Tuning;
+    use effects::{CtrlMsg, Effect};
 
     #[test]
     fn test_fft() {
@@ -163,30 +776,234 @@ mod tests {
         println!("spectrum = {:?}", spectrum);
     }
 
+    /// a tone with decaying harmonics, like a plucked string
+    fn string_tone(freq: f32, sample_rate: f32, len: usize) -> Vec<f32> {
+        (0..len)
+            .map(|i| {
+                let t = i as f32 / sample_rate;
+                (1..6).map(|h| (2. * PI * freq * h as f32 * t).sin() / h as f32).sum::<f32>() * 0.3
+            }).collect()
+    }
+
     #[test]
     fn test_tune() {
-        // okay... this has some "numerical stability" issues surrounding
-        // discret fourier transformation when sampling rate >> length of sample
-        // there will only be 128 bins if one sample
-        // contains 128 data points
-        // this means I will have to use a circular buffer
-        // to extend the length of the input to fft
-
-        let sampling_rate = 41100.;
-        let length = 1280;
-        let freq = 420.0;
-
-        let sin_vec: Vec<f32> = (0..length)
-            .map(|i| {
-                let t = (i as f32) / sampling_rate;
-                ((2. * PI) * freq * t).sin() 
-            }).collect();
+        let sampling_rate = 41100;
+        let frame_size = 256;
+
+        let sin_vec = string_tone(420., sampling_rate as f32, 40 * frame_size);
+
+        let mut tuner = Tuner::new(sampling_rate, frame_size as u32);
+        let mut l = vec![0.; frame_size];
+        let mut r = vec![0.; frame_size];
+        for frame in sin_vec.chunks(frame_size) {
+            tuner.process_samples(frame, &mut l, &mut r);
+        }
+        let note = tune(&tuner.ordered_buffer(), sampling_rate, 440.).unwrap();
+
+        assert!((note.freq - 420.).abs() < 0.2);
+        assert_eq!(note.note_name(), "G#");
+        assert_eq!(note.octave, 4);
+    }
 
-        let tuner = Tuner::new();
-        tuner.process_samples(&sin_vec, &mut vec![], &mut vec![]);
-        let note = tuner.tune();
+    #[test]
+    fn test_any_block_size_and_rate() {
+        let mut tuner = Tuner::new(48000, 256);
+        tuner.sample_rate_changed(96000);
+        tuner.frame_size_changed(1024);
+
+        // more than fills the buffer, in blocks that vary in length
+        let tone = string_tone(420., 96000., 2 * 96000);
+        let mut l = vec![0.; 1024];
+        let mut r = vec![0.; 1024];
+        let mut pos = 0;
+        for &len in [1024, 300, 1, 777].iter().cycle() {
+            if pos + len > tone.len() {
+                break;
+            }
+            tuner.process_samples(&tone[pos..pos + len], &mut l[..len], &mut r[..len]);
+            pos += len;
+        }
+        let note = tune(&tuner.ordered_buffer(), 96000, 440.).unwrap();
+        assert!((note.freq - 420.).abs() < 0.2, "{}", note.freq);
+    }
+
+    #[test]
+    fn test_bass_and_guitar_range() {
+        // low B of a 5-string bass, bass E, guitar strings and a high fret
+        let notes = [
+            (30.87, "B", 0),
+            (41.20, "E", 1),
+            (82.41, "E", 2),
+            (110.00, "A", 2),
+            (146.83, "D", 3),
+            (196.00, "G", 3),
+            (246.94, "B", 3),
+            (329.63, "E", 4),
+            (1046.50, "C", 6),
+        ];
+        for &(freq, name, octave) in notes.iter() {
+            let note = tune(&string_tone(freq, 48000., 10240), 48000, 440.).unwrap();
+            assert_eq!((note.note_name(), note.octave), (name, octave));
+            assert!(note.cents.abs() < 1., "{} is {} cents off", freq, note.cents);
+        }
+    }
 
-        println!("NOTE : {:?}", note);
+    #[test]
+    fn test_cents() {
+        // a quarter tone sharp of A2
+        let freq = 110. * 2f32.powf(0.25 / 12.);
+        let note = tune(&string_tone(freq, 48000., 10240), 48000, 440.).unwrap();
+        assert_eq!(note.note_name(), "A");
+        assert!((note.cents - 25.).abs() < 1.);
+    }
 
+    #[test]
+    fn test_confidence_gating() {
+        assert!(tune(&vec![0.; 10240], 48000, 440.).is_none());
+
+        // white noise from a linear congruential generator
+        let mut seed = 12345u32;
+        let noise: Vec<f32> = (0..10240).map(|_| {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            (seed >> 8) as f32 / (1 << 23) as f32 - 1.
+        }).collect();
+        assert!(tune(&noise, 48000, 440.).is_none());
+    }
+
+    #[test]
+    fn test_reference_pitch() {
+        // A at 432 Hz is in tune against a 432 Hz reference
+        let tone = string_tone(432., 48000., 10240);
+        let note = tune(&tone, 48000, 432.).unwrap();
+        assert_eq!(note.note_name(), "A");
+        assert!(note.cents.abs() < 1.);
+        let note = tune(&tone, 48000, 440.).unwrap();
+        assert!((note.cents + 31.8).abs() < 1.);
+    }
+
+    #[test]
+    fn test_stream_mutes_output() {
+        let mut tuner = Tuner::new(48000, 256);
+        tuner.ctrl(CtrlMsg::Set("tuner".into(), "mute".into(), 1.));
+        let input = vec![0.5; 256];
+        let mut l = vec![0.; 256];
+        let mut r = vec![0.; 256];
+        tuner.process_samples(&input, &mut l, &mut r);
+        assert_eq!(l[0], 0.5);
+
+        tuner.ctrl(CtrlMsg::Select("tuner".into(), "stream".into(), "on".into()));
+        tuner.process_samples(&input, &mut l, &mut r);
+        assert!(l.iter().chain(r.iter()).all(|&y| y == 0.));
+
+        tuner.ctrl(CtrlMsg::Select("tuner".into(), "stream".into(), "off".into()));
+        tuner.process_samples(&input, &mut l, &mut r);
+        assert_eq!(r[0], 0.5);
+    }
+
+    #[test]
+    fn test_stream_worker_outlives_the_stream() {
+        // 44 whole cycles, so the blocks join up
+        let mut tuner = Tuner::new(48000, 4800);
+        let tone: Vec<f32> = (0..4800).map(|i| 0.5 * (2. * PI * 440. * i as f32 / 48000.).sin()).collect();
+        let (mut l, mut r) = (vec![0.; 4800], vec![0.; 4800]);
+        let mut play = |tuner: &mut Tuner| {
+            for _ in 0..200 {
+                tuner.process_samples(&tone, &mut l, &mut r);
+                if tuner.readout().map_or(false, |line| line.contains("A4")) {
+                    return true;
+                }
+                thread::sleep(Duration::from_millis(10));
+            }
+            false
+        };
+        // streamed twice by the same worker, the display switched in between
+        for &display in ["needle", "strobe"].iter() {
+            tuner.ctrl(CtrlMsg::Select("tuner".into(), "display".into(), display.into()));
+            tuner.ctrl(CtrlMsg::Select("tuner".into(), "stream".into(), "quiet".into()));
+            assert!(play(&mut tuner), "no reading with the {}", display);
+            tuner.ctrl(CtrlMsg::Select("tuner".into(), "stream".into(), "off".into()));
+            assert_eq!(tuner.readout(), None);
+        }
+    }
+
+    #[test]
+    fn test_strobe_stands_still_in_tune() {
+        assert_eq!(strobe(0.), strobe(8.));
+        assert!(strobe(0.) != strobe(1.));
+    }
+
+    #[test]
+    fn test_string_and_note_targets() {
+        let tuning = Tuning::builtin().into_iter().find(|t| t.name == "dropd").unwrap();
+        // a slightly sharp low D on the 6th string
+        let freq = 73.42 * 2f32.powf(0.1 / 12.);
+        let reading = tune(&string_tone(freq, 48000., 10240), 48000, 440.).unwrap();
+        let (label, cents) = Mode::Strings.target(&reading, &tuning);
+        assert_eq!(label, "6:D2");
+        assert!((cents - 10.).abs() < 1.);
+
+        // the same note against the octave above, as at the 12th fret
+        let (label, cents) = Mode::Note(50).target(&reading, &tuning);
+        assert_eq!(label, "D3");
+        assert!((cents + 1190.).abs() < 1.);
+    }
+
+    /// strings plucked together, each with its own detune in cents and
+    /// decaying harmonics
+    fn chord(tuning: &Tuning, detune: &[f32], sample_rate: f32, len: usize) -> Vec<f32> {
+        let mut out = vec![0.; len];
+        for (&note, &cents) in tuning.strings.iter().zip(detune.iter()) {
+            let freq = 440. * 2f32.powf((note as f32 - 69. + cents / 100.) / 12.);
+            for (i, y) in out.iter_mut().enumerate() {
+                let t = i as f32 / sample_rate;
+                for h in 1..8 {
+                    let decay = (-t * 2. * h as f32).exp();
+                    *y += 0.1 * decay * (2. * PI * freq * h as f32 * t).sin() / h as f32;
+                }
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn test_strum() {
+        let tunings = Tuning::builtin();
+        for &(name, ref detune) in [
+            ("standard", vec![12., -7., 0., 25., -18., 4.]),
+            ("dropd", vec![-6., 5., -4., -3., 0., 15.]),
+            ("dadgad", vec![3., -10., 2., 8., -12., 4.]),
+            ("bass5", vec![8., -12., 20., 0., -5.]),
+        ].iter() {
+            let tuning = tunings.iter().find(|t| t.name == name).unwrap();
+            let input = chord(tuning, detune, 48000., 24000);
+            let cents = strum(&input, 48000, 440., tuning);
+            for (c, d) in cents.iter().zip(detune.iter()) {
+                let c = c.unwrap();
+                assert!((c - d).abs() < 2., "{}: {} != {}", name, c, d);
+            }
+        }
+    }
+
+    #[test]
+    fn test_strum_missing_string() {
+        let tuning = Tuning::builtin().remove(0);
+        // the low E is muted
+        let mut input = chord(&tuning, &[0.; 6], 48000., 10240);
+        let low_e = chord(&Tuning { name: "e".into(), strings: vec![40] }, &[0.], 48000., 10240);
+        for (x, e) in input.iter_mut().zip(low_e.iter()) {
+            *x -= e;
+        }
+        let cents = strum(&input, 48000, 440., &tuning);
+        assert!(cents[0].is_none());
+        assert!(cents[1..].iter().all(|c| c.unwrap().abs() < 2.));
+    }
+
+    #[test]
+    fn test_add_tunings() {
+        let mut tuner = Tuner::new(48000, 256);
+        let open_g = Tuning::parse("openg", "D2 G2 D3 G3 B3 D4").unwrap();
+        tuner.ctrl(CtrlMsg::Tunings("tuner".to_owned(), vec![open_g]));
+        tuner.ctrl(CtrlMsg::Select("tuner".to_owned(), "tuning".to_owned(), "openg".to_owned()));
+        assert_eq!(tuner.tuning.name, "openg");
     }
 }
@@ -0,0 +1,137 @@
+//! Instrument tunings for the tuner's string mode.
+//!
+//! User tunings are read from a text file with one tuning per line,
+//! the name followed by the open strings from lowest to highest:
+//!
+//! ```text
+//! # baritone a fourth down
+//! baritone: B1 E2 A2 D3 F#3 B3
+//! ```
+
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use effects::pitch::parse_note;
+use effects::tuner::note_name;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Tuning {
+    pub name: String,
+    /// MIDI note numbers of the open strings, lowest first
+    pub strings: Vec<i32>,
+}
+
+const BUILTIN: &[(&str, &str)] = &[
+    ("standard", "E2 A2 D3 G3 B3 E4"),
+    ("dropd", "D2 A2 D3 G3 B3 E4"),
+    ("dadgad", "D2 A2 D3 G3 A3 D4"),
+    ("openg", "D2 G2 D3 G3 B3 D4"),
+    ("bass4", "E1 A1 D2 G2"),
+    ("bass5", "B0 E1 A1 D2 G2"),
+    ("bass6", "B0 E1 A1 D2 G2 C3"),
+];
+
+impl Tuning {
+
+    /// Standard, drop D, DADGAD, open G and 4, 5 and 6-string bass
+    pub fn builtin() -> Vec<Tuning> {
+        BUILTIN.iter()
+            .map(|&(name, notes)| Tuning::parse(name, notes).unwrap())
+            .collect()
+    }
+
+    /// Parses space separated note names such as `D2 A2 D3`
+    pub fn parse(name: &str, notes: &str) -> Result<Tuning, String> {
+        let mut strings = vec![];
+        for note in notes.split_whitespace() {
+            match parse_note(note) {
+                Some(n) => strings.push(n),
+                None => return Err(format!("unknown note {}", note)),
+            }
+        }
+        if strings.is_empty() {
+            return Err("no strings".to_owned());
+        }
+        strings.sort();
+        Ok(Tuning { name: name.to_owned(), strings })
+    }
+
+    /// Reads the tunings in `path`, see the module documentation
+    pub fn load(path: &str) -> Result<Vec<Tuning>, String> {
+        let file = File::open(path).map_err(|e| format!("{}: {}", path, e))?;
+        let mut tunings = vec![];
+        for (i, line) in BufReader::new(file).lines().enumerate() {
+            let line = line.map_err(|e| format!("{}: {}", path, e))?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut parts = line.splitn(2, ':');
+            let name = parts.next().unwrap().trim();
+            let tuning = parts.next()
+                .ok_or_else(|| "expected `name: notes`".to_owned())
+                .and_then(|notes| Tuning::parse(name, notes))
+                .map_err(|e| format!("{}:{}: {}", path, i + 1, e))?;
+            tunings.push(tuning);
+        }
+        Ok(tunings)
+    }
+
+    /// Index of the string whose open note is closest to `step`, a
+    /// fractional MIDI note number
+    pub fn nearest(&self, step: f32) -> usize {
+        let mut best = 0;
+        for (i, &s) in self.strings.iter().enumerate() {
+            if (step - s as f32).abs() < (step - self.strings[best] as f32).abs() {
+                best = i;
+            }
+        }
+        best
+    }
+
+    /// Number of the string at `idx` the way players count them,
+    /// 1 being the highest
+    pub fn string_number(&self, idx: usize) -> usize {
+        self.strings.len() - idx
+    }
+
+    pub fn describe(&self) -> String {
+        let notes: Vec<_> = self.strings.iter().map(|&n| note_name(n)).collect();
+        format!("{}: {}", self.name, notes.join(" "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Tuning;
+    use std::env;
+    use std::fs::File;
+    use std::io::Write;
+
+    #[test]
+    fn test_nearest_string() {
+        let tunings = Tuning::builtin();
+        let dropd = tunings.iter().find(|t| t.name == "dropd").unwrap();
+        assert_eq!(dropd.strings[0], 38);
+        // a low E on a drop D guitar is the sharp 6th string
+        let idx = dropd.nearest(40.);
+        assert_eq!((idx, dropd.string_number(idx)), (0, 6));
+        assert_eq!(dropd.string_number(dropd.nearest(45.3)), 5);
+        assert_eq!(dropd.describe(), "dropd: D2 A2 D3 G3 B3 E4");
+    }
+
+    #[test]
+    fn test_load() {
+        let path = env::temp_dir().join("rasta_test_tunings.txt");
+        let mut f = File::create(&path).unwrap();
+        writeln!(f, "# comment\n\nbaritone: B1 E2 A2 D3 F#3 B3\nopend: D2 A2 D3 F#3 A3 D4").unwrap();
+        let tunings = Tuning::load(path.to_str().unwrap()).unwrap();
+        assert_eq!(tunings.len(), 2);
+        assert_eq!(tunings[0].name, "baritone");
+        assert_eq!(tunings[0].strings, vec![35, 40, 45, 50, 54, 59]);
+
+        let mut f = File::create(&path).unwrap();
+        writeln!(f, "bad: E2 Q3").unwrap();
+        let err = Tuning::load(path.to_str().unwrap()).unwrap_err();
+        assert!(err.ends_with(":1: unknown note Q3"), "{}", err);
+    }
+}
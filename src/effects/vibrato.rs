@@ -0,0 +1,139 @@
+use effects::{Effect, CtrlMsg, Param, params};
+use effects::delay_line::DelayLine;
+use effects::lfo::{Lfo, Waveform};
+
+/// Longest sweep in seconds
+const MAX_DEPTH: f32 = 0.01;
+
+/// Pitch vibrato: the fully wet output of a delay line whose length
+/// is swept by the LFO.
+pub struct Vibrato {
+    pub bypassing: bool,
+    sample_rate: f32,
+    line: DelayLine,
+    lfo: Lfo,
+    /// sweep in seconds
+    depth: f32,
+}
+
+impl Vibrato {
+
+    /// depth in milliseconds
+    pub fn set_depth(&mut self, ms: f32) {
+        self.depth = (ms / 1000.).max(0.).min(MAX_DEPTH);
+    }
+}
+
+impl Effect for Vibrato {
+
+    fn new(sample_rate: usize, _frame_size: u32) -> Self {
+        let sample_rate = sample_rate as f32;
+        Vibrato {
+            bypassing: false,
+            sample_rate,
+            line: DelayLine::new((2. * MAX_DEPTH * sample_rate) as usize + 2),
+            lfo: Lfo::new(sample_rate, 5.),
+            depth: 0.002,
+        }
+    }
+
+    fn name(&self) -> &str {
+        "vibrato"
+    }
+
+    fn process_samples(&mut self, input: &[f32], output_l: &mut [f32], output_r: &mut [f32]) {
+
+        if self.bypassing {
+            output_l.clone_from_slice(input);
+            output_r.clone_from_slice(input);
+            return;
+        }
+
+        for (i, x) in input.iter().enumerate() {
+            self.line.push(*x);
+            let delay = self.depth * (1. + self.lfo.next()) * self.sample_rate;
+            let y = self.line.read_cubic(delay + 1.);
+            output_l[i] = y;
+            output_r[i] = y;
+        }
+    }
+
+    fn sample_rate_changed(&mut self, sample_rate: usize) {
+        self.sample_rate = sample_rate as f32;
+        self.line = DelayLine::new((2. * MAX_DEPTH * self.sample_rate) as usize + 2);
+        self.lfo.set_sample_rate(self.sample_rate);
+    }
+
+    fn params(&self) -> Vec<(String, Param)> {
+        use self::Param::*;
+        params(&[
+            ("rate", Float(self.lfo.rate())),
+            ("depth", Float(self.depth * 1000.)),
+            ("wave", Name(self.lfo.waveform.name().to_owned())),
+        ])
+    }
+
+    fn bypass(&mut self) {
+        self.bypassing = !self.bypassing;
+    }
+
+    fn is_bypassing(&self) -> bool {
+        self.bypassing
+    }
+
+    fn set(&mut self, param: &str, val: f32) {
+        match param {
+            "rate" => self.lfo.set_rate(val),
+            "depth" => self.set_depth(val),
+            _ => (),
+        }
+    }
+
+    fn ctrl(&mut self, msg: CtrlMsg) {
+        use self::CtrlMsg::*;
+        match msg {
+            Bypass => self.bypass(),
+            Set(_pedal_name, conf_name, val) => self.set(&conf_name, val),
+            Select(_pedal_name, conf_name, val) => {
+                if &conf_name == "wave" {
+                    match Waveform::from_name(&val) {
+                        Some(w) => self.lfo.waveform = w,
+                        None => println!("Unknown waveform: {}", val),
+                    }
+                }
+            },
+            _ => (),
+        }
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Vibrato;
+    use effects::Effect;
+
+    fn impulse_response(vibrato: &mut Vibrato) -> Vec<f32> {
+        let mut input = vec![0.; 512];
+        input[0] = 1.;
+        let (mut l, mut r) = (vec![0.; 512], vec![0.; 512]);
+        vibrato.process_samples(&input, &mut l, &mut r);
+        l
+    }
+
+    #[test]
+    fn test_no_dry_signal() {
+        // without a sweep the output is the input a sample late
+        let mut vibrato = Vibrato::new(48000, 512);
+        vibrato.set_depth(0.);
+        let out = impulse_response(&mut vibrato);
+        assert_eq!((out[0], out[1]), (0., 1.));
+
+        // 2 ms sweep: nothing until the delay, about 97 samples
+        let mut vibrato = Vibrato::new(48000, 512);
+        let out = impulse_response(&mut vibrato);
+        assert!(out[..90].iter().all(|&y| y == 0.));
+        let peak = (0..out.len()).max_by(|&a, &b| out[a].partial_cmp(&out[b]).unwrap()).unwrap();
+        assert!(peak > 95 && peak < 110, "{}", peak);
+    }
+}
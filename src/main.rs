@@ -1,4 +1,4 @@
-//! Takes 2 audio inputs and outputs them to 2 audio outputs.
+//! Takes the audio inputs through the pedal chain to 2 audio outputs.
 //! All JACK notifications are also printed out.
 
 #![feature(box_syntax)]
@@ -8,64 +8,307 @@ extern crate jack;
 mod notifications;
 mod effects;
 mod parser;
+mod midi;
+mod wav;
+mod stats;
+mod options;
+mod connect;
+mod config;
+mod script;
+mod setlist;
+mod automation;
+mod meters;
+mod tui;
 
 use parser::parse_input;
-use effects::{Effect};
+use effects::{Effect, CtrlMsg};
 use std::io::{self, Write};
 use jack::{Control, Client, ProcessScope};
-use std::sync::mpsc::channel;
+use std::sync::mpsc::{channel, sync_channel};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use notifications::Notifications;
+use options::Options;
+use connect::Rule;
+use config::Config;
+use script::Script;
+use setlist::{Setlist, Song};
+use automation::Automation;
+use effects::tuning::Tuning;
+use effects::Pedals;
+use std::env;
+use std::process;
+
+/// The options and the config they point to, the config found on the
+/// XDG path or else the supplied one
+fn load_config(args: Vec<String>) -> Result<(Options, Config), String> {
+    let opts = Options::parse(args.iter().cloned())?;
+    let config = match opts.config {
+        Some(ref path) => Config::load(path)?,
+        None => match config::find() {
+            Some(path) => Config::load(&path.to_string_lossy())?,
+            None => Config::builtin(),
+        },
+    };
+    let opts = Options::parse_over(config.options(), args.into_iter())?;
+    Ok((opts, config))
+}
+
+/// Block size of an offline render
+const RENDER_FRAMES: usize = 256;
+
+/// Loads the files a message names and creates the pedals it adds, for
+/// the messages the control thread answers before the pedals see them
+fn resolve(msg: CtrlMsg, config: &Config, sample_rate: usize, frame_size: u32) -> Result<CtrlMsg, String> {
+    Ok(match msg {
+        // a looper takes megabytes and a thread, never made on the audio thread
+        CtrlMsg::Add(name, eff_type) => match effects::create(&eff_type, sample_rate, frame_size) {
+            Some(eff) => CtrlMsg::Insert(name, eff),
+            None => return Err(format!("Unknown effect type: {}", eff_type)),
+        },
+        CtrlMsg::LoadSong(name) => CtrlMsg::Setlist(Setlist::song(Song::load(&name, None, config)?)),
+        CtrlMsg::LoadSetlist(name) => CtrlMsg::Setlist(Setlist::load(&name, config)?),
+        CtrlMsg::LoadTake(path) => CtrlMsg::Play(Some(Arc::new(Automation::load(&path, sample_rate)?))),
+        CtrlMsg::Select(pedal, ref conf, ref path) if conf == "load" => {
+            let tunings = Tuning::load(path).map_err(|e| format!("Could not load tunings: {}", e))?;
+            CtrlMsg::Tunings(pedal, tunings)
+        },
+        msg => msg,
+    })
+}
+
+/// Runs a WAV file through the pedals set up by the config, the script
+/// and the automation, and writes the stereo result
+fn render(opts: &Options, config: &Config, input: &str, output: &str) -> Result<(), String> {
+    let (sample_rate, samples) = wav::read_wav(input).map_err(|e| format!("{}: {}", input, e))?;
+    let mut pedals = Pedals::new(sample_rate as usize, RENDER_FRAMES as u32);
+    for msg in config.commands() {
+        pedals.ctrl(resolve(msg, config, sample_rate as usize, RENDER_FRAMES as u32)?);
+    }
+
+    let mut cmds = vec![];
+    if let Some(ref path) = opts.script {
+        cmds = Script::new().source(path)?;
+    }
+    if let Some(ref path) = opts.automation {
+        cmds.push(format!("play {}", path));
+    }
+    for cmd in cmds {
+        pedals.ctrl(resolve(parse_input(&cmd), config, sample_rate as usize, RENDER_FRAMES as u32)?);
+    }
+
+    let mut left = vec![0.; samples.len()];
+    let mut right = vec![0.; samples.len()];
+    let mut midi_events = vec![];
+    for ((input, l), r) in samples.chunks(RENDER_FRAMES)
+        .zip(left.chunks_mut(RENDER_FRAMES))
+        .zip(right.chunks_mut(RENDER_FRAMES)) {
+        pedals.process_samples(input, l, r);
+        pedals.midi_out(&mut midi_events);
+        midi_events.clear();
+    }
+    wav::write_wav_stereo(output, sample_rate, &left, &right).map_err(|e| format!("{}: {}", output, e))?;
+    println!("Rendered {:.1} s into {}", samples.len() as f32 / sample_rate as f32, output);
+    Ok(())
+}
 
 fn main() {
+    let (opts, config) = match load_config(env::args().skip(1).collect()) {
+        Ok(loaded) => loaded,
+        Err(msg) => {
+            println!("{}", msg);
+            process::exit(1);
+        }
+    };
+    println!("Using config {}", config.path);
+
+    if let Some((ref input, ref output)) = opts.render {
+        if let Err(e) = render(&opts, &config, input, output) {
+            println!("{}", e);
+            process::exit(1);
+        }
+        return;
+    }
+
     // Create client
     let (client, _status) =
-        jack::Client::new("rasta", jack::ClientOptions::NO_START_SERVER).unwrap();
+        jack::Client::new(&opts.client_name, jack::ClientOptions::NO_START_SERVER).unwrap();
 
     let sample_rate = client.sample_rate();
     let frame_size = client.buffer_size();
 
     // Create ports
-    let in_b = client
-        .register_port("guitar_in", jack::AudioIn::default())
+    let midi_in = client
+        .register_port("midi_in", jack::MidiIn::default())
         .unwrap();
+    let mut midi_out = client
+        .register_port("midi_out", jack::MidiOut::default())
+        .unwrap();
+    let ins: Vec<_> = opts.inputs.iter()
+        .map(|name| client.register_port(name, jack::AudioIn::default()).unwrap())
+        .collect();
     let mut out_a = client
-        .register_port("rasta_out_l", jack::AudioOut::default())
+        .register_port(&opts.outputs[0], jack::AudioOut::default())
         .unwrap();
     let mut out_b = client
-        .register_port("rasta_out_r", jack::AudioOut::default())
+        .register_port(&opts.outputs[1], jack::AudioOut::default())
         .unwrap();
 
-    let mut pedals = effects::Pedals::new(sample_rate, frame_size);
-    pedals.add("overdrive", box effects::overdrive::Overdrive::new(sample_rate, frame_size));
-    pedals.add("delay", box effects::delay::Delay::new(sample_rate, frame_size));
-    pedals.add("tuner", box effects::tuner::Tuner::new(sample_rate, frame_size));
-    pedals.add("aw", box effects::autowah::AutoWah::new(sample_rate, frame_size));
-    pedals.add("trem", box effects::tremelo::Tremelo::new(sample_rate, frame_size));
+    // capture -> inputs, outputs -> playback and the patchbay's cables
+    let in_names: Vec<_> = ins.iter().map(|p| p.name().unwrap().to_owned()).collect();
+    let in_names = connect::exact(&in_names);
+    let out_names = connect::exact(&[out_a.name().unwrap().to_owned(), out_b.name().unwrap().to_owned()]);
+    let mut rules: Vec<_> = opts.capture.iter()
+        .map(|pattern| Rule { from: pattern.clone(), to: in_names.clone() })
+        .chain(opts.playback.iter().map(|pattern| Rule { from: out_names.clone(), to: pattern.clone() }))
+        .collect();
+    if let Some(ref path) = opts.patchbay {
+        match connect::load_patchbay(path, &opts.client_name, client.name()) {
+            Ok(patchbay) => rules.extend(patchbay),
+            Err(e) => println!("Could not load patchbay {}", e),
+        }
+    }
+
+    let mut pedals = Pedals::new(sample_rate, frame_size);
+    for msg in config.commands() {
+        match resolve(msg, &config, sample_rate as usize, frame_size) {
+            Ok(msg) => pedals.ctrl(msg),
+            Err(e) => println!("{}", e),
+        }
+    }
+
+    let (recorder, monitor) = stats::start(sample_rate);
+    monitor.set_log(config.stats_log);
+    pedals.stats = Some(recorder);
+    let (taps, readout) = meters::start(sample_rate);
+    pedals.meters = Some(taps);
+    let (feed, views) = tui::start(sample_rate);
+    pedals.views = Some(feed);
+    let (takes_tx, takes) = sync_channel(4);
+    pedals.takes = Some(takes_tx);
+
+    let pedals = Arc::new(Mutex::new(pedals));
+    let notifications = Notifications::new(pedals.clone(), monitor.xruns.clone());
 
     let (tx, rx) = channel();
+    let mut midi_events = Vec::with_capacity(256);
+    let mut mix = vec![0.; frame_size as usize];
 
-    let process_callback = move |_: &Client, ps: &ProcessScope| -> Control {
+    let process_callback = move |client: &Client, ps: &ProcessScope| -> Control {
 
         let mut out_a_p = out_a.as_mut_slice(ps);
         let mut out_b_p = out_b.as_mut_slice(ps);
-        let in_b_p = in_b.as_slice(ps);
+        let in_b_p = if ins.len() == 1 {
+            ins[0].as_slice(ps)
+        } else {
+            // only grows after a buffer size change
+            mix.resize(ps.n_frames() as usize, 0.);
+            for x in mix.iter_mut() {
+                *x = 0.;
+            }
+            for port in ins.iter() {
+                for (m, x) in mix.iter_mut().zip(port.as_slice(ps)) {
+                    *m += *x;
+                }
+            }
+            &mix[..]
+        };
+
+        let mut pedals = match pedals.try_lock() {
+            Ok(pedals) => pedals,
+            Err(_) => {
+                // being reconfigured, pass the guitar through meanwhile
+                out_a_p.clone_from_slice(in_b_p);
+                out_b_p.clone_from_slice(in_b_p);
+                return Control::Continue;
+            }
+        };
 
         if let Ok(msg) = rx.try_recv() {
             pedals.ctrl(msg);
         }
+        for event in midi_in.iter(ps) {
+            pedals.midi(event.time, event.bytes);
+        }
+        let start = Instant::now();
         pedals.process_samples(in_b_p, &mut out_a_p, &mut out_b_p);
+        if let Some(ref mut stats) = pedals.stats {
+            stats.block(in_b_p.len(), start.elapsed(), client.cpu_load());
+        }
+
+        pedals.midi_out(&mut midi_events);
+        let mut writer = midi_out.writer(ps);
+        for event in midi_events.drain(..) {
+            writer.write(&jack::RawMidi { time: event.time, bytes: &event.bytes }).ok();
+        }
         Control::Continue
     };
 
     let process = jack::ClosureProcessHandler::new(process_callback);
-    let active_client = client.activate_async((), process).unwrap();
+    let active_client = client.activate_async(notifications, process).unwrap();
+    connect::apply(active_client.as_client(), &rules);
+
+    let client = active_client.as_client();
+    let mut take: Option<Arc<Automation>> = None;
+    let mut run = |cmds: Vec<String>| {
+        for cmd in cmds {
+            match parse_input(&cmd) {
+                CtrlMsg::Stats => monitor.print(),
+                CtrlMsg::StatsLog(seconds) => monitor.set_log(seconds),
+                CtrlMsg::Meters => readout.print(),
+                CtrlMsg::MeterPedals(on) => readout.set_pedals(on),
+                CtrlMsg::MeterReset => readout.reset(),
+                CtrlMsg::Invalid(usage) => println!("{}", usage),
+                CtrlMsg::SaveTake(path) => {
+                    while let Ok(latest) = takes.try_recv() {
+                        take = Some(latest);
+                    }
+                    match take {
+                        Some(ref take) => match take.save(&path) {
+                            Ok(()) => println!("Saved {}", path),
+                            Err(e) => println!("{}", e),
+                        },
+                        None => println!("Nothing recorded, `rec` and `rec stop` first"),
+                    }
+                },
+                // JACK may have changed them since the start
+                msg => match resolve(msg, &config, client.sample_rate(), client.buffer_size()) {
+                    Ok(msg) => tx.send(msg).unwrap(),
+                    Err(e) => println!("{}", e),
+                },
+            }
+        }
+    };
+
+    let mut script = Script::new();
+    if let Some(ref path) = opts.script {
+        match script.source(path) {
+            Ok(cmds) => run(cmds),
+            Err(e) => println!("{}", e),
+        }
+    }
+    if let Some(ref path) = opts.automation {
+        run(vec![format!("play {}", path)]);
+    }
+
+    let mut command = |line: &str| match script.line(line) {
+        Ok(cmds) => run(cmds),
+        Err(e) => println!("{}", e),
+    };
+    if opts.tui {
+        tui::run(&mut command, &views, &readout);
+    }
 
     // Wait for user input to quit
     let mut user_input = String::new();
     print!(">>> ");
     io::stdout().flush().ok().expect("Could not flush stdout");
     while let Ok(_) = io::stdin().read_line(&mut user_input) {
-        let msg = parse_input(&user_input[0..user_input.len()-1]);
-        tx.send(msg).unwrap();
+        if user_input.trim() == "tui" {
+            tui::run(&mut command, &views, &readout);
+        } else {
+            command(&user_input);
+        }
         user_input.clear();
         print!(">>> ");
         io::stdout().flush().ok().expect("Could not flush stdout");
@@ -0,0 +1,467 @@
+//! Level meters.
+//!
+//! The audio thread measures the signal at `in`, `out` and, after
+//! `meters pedals`, behind every pedal: the sample peak, RMS over 300 ms,
+//! short-term loudness over 3 s in LUFS (ITU-R BS.1770 K-weighting) and
+//! the samples at or beyond full scale. Every 100 ms it hands the
+//! readings to a worker thread over bounded channels, like the
+//! statistics, so the audio thread never allocates or waits.
+
+use effects::biquad::{Biquad, Coeffs};
+use std::f64::consts::PI;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::thread;
+
+/// Seconds between readings, also the loudness block
+const INTERVAL: f32 = 0.1;
+/// Intervals in the RMS and in the short-term loudness
+const RMS_INTERVALS: usize = 3;
+const SHORT_TERM: usize = 30;
+/// `in`, `out` and 32 pedals
+const MAX_TAPS: usize = 34;
+const NAME_CAPACITY: usize = 32;
+/// Readings in flight between the audio thread and the worker
+const BUFFERS: usize = 3;
+/// Level reported for silence
+const FLOOR_DB: f32 = -120.;
+
+/// dB of a mean square, `offset` added
+fn db(power: f64, offset: f32) -> f32 {
+    if power <= 0. {
+        FLOOR_DB
+    } else {
+        (10. * power.log10() as f32 + offset).max(FLOOR_DB)
+    }
+}
+
+fn level(db: f32) -> String {
+    if db <= FLOOR_DB {
+        "  -inf".to_owned()
+    } else {
+        format!("{:6.1}", db)
+    }
+}
+
+/// Shelf and highpass of the K-weighting for one channel. The
+/// cookbook shelf is off by a few tenths of a dB, these are the
+/// BS.1770 filters for any sample rate.
+fn k_weighting(sample_rate: usize) -> (Biquad, Biquad) {
+    let rate = sample_rate as f64;
+
+    let k = (PI * 1681.974450955533 / rate).tan();
+    let q = 0.7071752369554196;
+    let vh = 10f64.powf(3.999843853973347 / 20.);
+    let vb = vh.powf(0.4996667741545416);
+    let a0 = 1. + k / q + k * k;
+    let mut shelf = Biquad::new(rate as f32);
+    shelf.snap_coeffs(Coeffs {
+        b0: ((vh + vb * k / q + k * k) / a0) as f32,
+        b1: (2. * (k * k - vh) / a0) as f32,
+        b2: ((vh - vb * k / q + k * k) / a0) as f32,
+        a1: (2. * (k * k - 1.) / a0) as f32,
+        a2: ((1. - k / q + k * k) / a0) as f32,
+    });
+
+    let k = (PI * 38.13547087602444 / rate).tan();
+    let q = 0.5003270373238773;
+    let a0 = 1. + k / q + k * k;
+    let mut highpass = Biquad::new(rate as f32);
+    highpass.snap_coeffs(Coeffs {
+        b0: 1.,
+        b1: -2.,
+        b2: 1.,
+        a1: (2. * (k * k - 1.) / a0) as f32,
+        a2: ((1. - k / q + k * k) / a0) as f32,
+    });
+    (shelf, highpass)
+}
+
+/// Latest levels of one tap
+#[derive(Clone, Debug, PartialEq)]
+pub struct Reading {
+    pub name: String,
+    /// sample peak of the last interval and since the last reset, dBFS
+    pub peak: f32,
+    pub max: f32,
+    pub rms: f32,
+    /// short-term loudness
+    pub lufs: f32,
+    /// samples at or beyond full scale since the last reset
+    pub clips: u64,
+}
+
+impl fmt::Display for Reading {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:<12} peak {} max {} rms {} dBFS {} LUFS {:6} clips",
+            self.name, level(self.peak), level(self.max), level(self.rms), level(self.lufs), self.clips)
+    }
+}
+
+/// Everything measured at one tap
+struct Meter {
+    name: String,
+    k: [(Biquad, Biquad); 2],
+    channels: usize,
+    peak: f32,
+    max: f32,
+    clips: u64,
+    /// sums of squares in the current interval, plain and K-weighted
+    sum: f64,
+    k_sum: f64,
+    frames: usize,
+    /// mean squares of the last intervals, plain and K-weighted
+    history: [(f64, f64); SHORT_TERM],
+    filled: usize,
+    pos: usize,
+    /// whether the tap got any audio this interval
+    fed: bool,
+}
+
+impl Meter {
+
+    fn new(sample_rate: usize) -> Self {
+        Meter {
+            name: String::with_capacity(NAME_CAPACITY),
+            k: [k_weighting(sample_rate), k_weighting(sample_rate)],
+            channels: 1,
+            peak: 0.,
+            max: 0.,
+            clips: 0,
+            sum: 0.,
+            k_sum: 0.,
+            frames: 0,
+            history: [(0., 0.); SHORT_TERM],
+            filled: 0,
+            pos: 0,
+            fed: false,
+        }
+    }
+
+    /// Starts over for the tap called `name`, already `cut`
+    fn claim(&mut self, name: &str, sample_rate: usize) {
+        self.name.clear();
+        self.name.push_str(name);
+        self.k = [k_weighting(sample_rate), k_weighting(sample_rate)];
+        self.max = 0.;
+        self.clips = 0;
+        self.filled = 0;
+    }
+
+    fn feed(&mut self, left: &[f32], right: Option<&[f32]>) {
+        self.fed = true;
+        self.feed_channel(0, left);
+        if let Some(right) = right {
+            self.feed_channel(1, right);
+        }
+        self.channels = if right.is_some() { 2 } else { 1 };
+        self.frames += left.len();
+    }
+
+    fn feed_channel(&mut self, channel: usize, buf: &[f32]) {
+        let (ref mut shelf, ref mut highpass) = self.k[channel];
+        for &x in buf.iter() {
+            let a = x.abs();
+            if a > self.peak {
+                self.peak = a;
+            }
+            if a >= 1. {
+                self.clips += 1;
+            }
+            self.sum += (x * x) as f64;
+            let k = highpass.process(shelf.process(x));
+            self.k_sum += (k * k) as f64;
+        }
+    }
+
+    /// Ends the interval and writes the levels to `reading` if there is
+    /// one to hand over
+    fn close(&mut self, reading: Option<&mut Reading>) {
+        let frames = self.frames.max(1) as f64;
+        // loudness sums the channels, RMS averages them
+        self.history[self.pos] = (self.sum / frames / self.channels as f64, self.k_sum / frames);
+        self.pos = (self.pos + 1) % SHORT_TERM;
+        self.filled = (self.filled + 1).min(SHORT_TERM);
+        self.max = self.max.max(self.peak);
+
+        if let Some(reading) = reading {
+            let recent = |count: usize, pick: fn(&(f64, f64)) -> f64| {
+                let count = count.min(self.filled);
+                let sum: f64 = (1..count + 1).map(|i| pick(&self.history[(self.pos + SHORT_TERM - i) % SHORT_TERM])).sum();
+                sum / count as f64
+            };
+            reading.name.clear();
+            reading.name.push_str(&self.name);
+            reading.peak = db((self.peak * self.peak) as f64, 0.);
+            reading.max = db((self.max * self.max) as f64, 0.);
+            reading.rms = db(recent(RMS_INTERVALS, |h| h.0), 0.);
+            reading.lufs = db(recent(SHORT_TERM, |h| h.1), -0.691);
+            reading.clips = self.clips;
+        }
+
+        self.peak = 0.;
+        self.sum = 0.;
+        self.k_sum = 0.;
+        self.frames = 0;
+        self.fed = false;
+    }
+}
+
+/// Readings of the taps in use, `MAX_TAPS` allocated up front
+struct Readings {
+    taps: Vec<Reading>,
+    used: usize,
+}
+
+impl Readings {
+
+    fn new() -> Self {
+        Readings {
+            taps: (0..MAX_TAPS)
+                .map(|_| Reading {
+                    name: String::with_capacity(NAME_CAPACITY),
+                    peak: FLOOR_DB,
+                    max: FLOOR_DB,
+                    rms: FLOOR_DB,
+                    lufs: FLOOR_DB,
+                    clips: 0,
+                })
+                .collect(),
+            used: 0,
+        }
+    }
+}
+
+/// Audio thread side, see the module documentation
+pub struct Taps {
+    /// `MAX_TAPS` meters, the first `used` are taps
+    meters: Vec<Meter>,
+    used: usize,
+    sample_rate: usize,
+    frames: usize,
+    pedals: Arc<AtomicBool>,
+    reset: Arc<AtomicBool>,
+    to_worker: SyncSender<Readings>,
+    from_worker: Receiver<Readings>,
+}
+
+impl Taps {
+
+    /// Not called from the audio thread
+    pub fn set_sample_rate(&mut self, sample_rate: usize) {
+        self.sample_rate = sample_rate;
+        for meter in self.meters.iter_mut() {
+            meter.k = [k_weighting(sample_rate), k_weighting(sample_rate)];
+        }
+    }
+
+    /// Whether to tap behind every pedal
+    pub fn pedals(&self) -> bool {
+        self.pedals.load(Ordering::Relaxed)
+    }
+
+    /// Measures the block of the tap called `name`, `right` for stereo
+    pub fn tap(&mut self, name: &str, left: &[f32], right: Option<&[f32]>) {
+        let name = cut(name);
+        let idx = match self.meters[..self.used].iter().position(|m| m.name == name) {
+            Some(idx) => idx,
+            None if self.used < MAX_TAPS => {
+                self.meters[self.used].claim(name, self.sample_rate);
+                self.used += 1;
+                self.used - 1
+            },
+            None => return,
+        };
+        self.meters[idx].feed(left, right);
+    }
+
+    /// Ends a block of `frames`, handing over the readings every interval
+    pub fn block(&mut self, frames: usize) {
+        self.frames += frames;
+        if (self.frames as f32) < INTERVAL * self.sample_rate as f32 {
+            return;
+        }
+        self.frames = 0;
+
+        // taps that got nothing, like removed pedals, are dropped
+        let mut i = 0;
+        while i < self.used {
+            if self.meters[i].fed {
+                i += 1;
+            } else {
+                self.used -= 1;
+                self.meters.swap(i, self.used);
+            }
+        }
+        if self.reset.swap(false, Ordering::Relaxed) {
+            for meter in self.meters[..self.used].iter_mut() {
+                meter.max = 0.;
+                meter.clips = 0;
+            }
+        }
+
+        let mut readings = self.from_worker.try_recv().ok();
+        // without a free buffer the readings of this interval are skipped
+        for (i, meter) in self.meters[..self.used].iter_mut().enumerate() {
+            meter.close(readings.as_mut().map(|r| &mut r.taps[i]));
+        }
+        if let Some(mut readings) = readings {
+            readings.used = self.used;
+            let _ = self.to_worker.try_send(readings);
+        }
+    }
+}
+
+/// Control side: prints the latest readings
+pub struct Readout {
+    readings: Arc<Mutex<Vec<Reading>>>,
+    pedals: Arc<AtomicBool>,
+    reset: Arc<AtomicBool>,
+}
+
+impl Readout {
+
+    /// `in` first, `out` last and the pedals in between
+    pub fn latest(&self) -> Vec<Reading> {
+        let mut readings = self.readings.lock().unwrap().clone();
+        readings.sort_by_key(|r| if r.name == "in" { 0 } else if r.name == "out" { 2 } else { 1 });
+        readings
+    }
+
+    pub fn print(&self) {
+        let readings = self.latest();
+        if readings.is_empty() {
+            println!("No levels yet");
+        }
+        for reading in readings.iter() {
+            println!("{}", reading);
+        }
+    }
+
+    /// Taps behind every pedal as well as at `in` and `out`
+    pub fn set_pedals(&self, on: bool) {
+        self.pedals.store(on, Ordering::Relaxed);
+    }
+
+    /// Clears the held peaks and the clip counts
+    pub fn reset(&self) {
+        self.reset.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Starts the worker thread and returns both ends
+/// `name` as a `Meter` keeps it, longer names are cut rather than
+/// reallocated
+fn cut(name: &str) -> &str {
+    let mut end = name.len().min(NAME_CAPACITY);
+    while !name.is_char_boundary(end) {
+        end -= 1;
+    }
+    &name[..end]
+}
+
+pub fn start(sample_rate: usize) -> (Taps, Readout) {
+    let (to_worker, from_audio) = sync_channel::<Readings>(BUFFERS);
+    let (to_audio, from_worker) = sync_channel(BUFFERS);
+    for _ in 0..BUFFERS {
+        to_audio.send(Readings::new()).unwrap();
+    }
+
+    let readout = Readout {
+        readings: Arc::new(Mutex::new(vec![])),
+        pedals: Arc::new(AtomicBool::new(false)),
+        reset: Arc::new(AtomicBool::new(false)),
+    };
+    let latest = readout.readings.clone();
+    thread::spawn(move || {
+        for readings in from_audio.iter() {
+            *latest.lock().unwrap() = readings.taps[..readings.used].to_vec();
+            // the audio thread may be gone
+            let _ = to_audio.try_send(readings);
+        }
+    });
+
+    let taps = Taps {
+        meters: (0..MAX_TAPS).map(|_| Meter::new(sample_rate)).collect(),
+        used: 0,
+        sample_rate,
+        frames: 0,
+        pedals: readout.pedals.clone(),
+        reset: readout.reset.clone(),
+        to_worker,
+        from_worker,
+    };
+    (taps, readout)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{start, Meter, Readings, MAX_TAPS};
+    use std::f32::consts::PI;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_levels() {
+        let mut mono = Meter::new(48000);
+        let mut stereo = Meter::new(48000);
+        let mut readings = Readings::new();
+        // 3 s of a 997 Hz sine at half scale, in 100 ms intervals
+        let sine: Vec<f32> = (0..48000 * 3).map(|i| 0.5 * (2. * PI * 997. * i as f32 / 48000.).sin()).collect();
+        for block in sine.chunks(4800) {
+            mono.feed(block, None);
+            stereo.feed(block, Some(block));
+            mono.close(Some(&mut readings.taps[0]));
+            stereo.close(Some(&mut readings.taps[1]));
+        }
+        let (mono, stereo) = (&readings.taps[0], &readings.taps[1]);
+        // a full scale sine is -3.01 dBFS RMS and -3.01 LUFS
+        assert!((mono.peak + 6.02).abs() < 0.01, "{}", mono);
+        assert!((mono.rms + 9.03).abs() < 0.01, "{}", mono);
+        assert!((mono.lufs + 9.03).abs() < 0.1, "{}", mono);
+        // both channels count for loudness, not for RMS
+        assert!((stereo.rms + 9.03).abs() < 0.01, "{}", stereo);
+        assert!((stereo.lufs + 6.02).abs() < 0.1, "{}", stereo);
+        assert_eq!(mono.clips, 0);
+    }
+
+    #[test]
+    fn test_taps_reach_the_readout() {
+        let (mut taps, readout) = start(1000);
+        // an interval is 100 frames, ten blocks
+        for i in 0..30 {
+            let block = if i == 5 { [1.5; 10] } else { [0.25; 10] };
+            taps.tap("out", &block, Some(&block));
+            taps.tap("in", &[0.; 10], None);
+            taps.block(10);
+        }
+
+        let mut readings = vec![];
+        for _ in 0..100 {
+            readings = readout.latest();
+            if !readings.is_empty() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(readings.len(), 2);
+        let (input, output) = (&readings[0], &readings[1]);
+        assert_eq!((input.name.as_str(), output.name.as_str()), ("in", "out"));
+        assert_eq!((input.peak, input.clips), (-120., 0));
+        assert_eq!(output.clips, 20);
+        assert!((output.max - 3.52).abs() < 0.01, "{}", output);
+        assert!(format!("{}", input).starts_with("in           peak   -inf"));
+    }
+    #[test]
+    fn test_long_names_keep_their_tap() {
+        let (mut taps, _readout) = start(48000);
+        let name = "a_pedal_with_a_name_well_over_32_bytes";
+        for _ in 0..MAX_TAPS + 1 {
+            taps.tap(name, &[0.5; 64], None);
+        }
+        assert_eq!(taps.used, 1);
+        assert_eq!(taps.meters[0].name, &name[..32]);
+    }
+}
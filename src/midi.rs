@@ -0,0 +1,114 @@
+//! Maps incoming MIDI control changes to pedal parameters and carries
+//! the MIDI messages pedals send out.
+
+use std::collections::HashMap;
+
+/// Status nibbles of the channel messages used here
+const NOTE_OFF: u8 = 0x80;
+const NOTE_ON: u8 = 0x90;
+const CONTROL_CHANGE: u8 = 0xB0;
+const PITCH_BEND: u8 = 0xE0;
+
+/// A channel message produced by a pedal, `time` is the frame offset
+/// into the current block
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MidiEvent {
+    pub time: u32,
+    pub bytes: [u8; 3],
+}
+
+impl MidiEvent {
+
+    /// `channel` is 0..15
+    pub fn note_on(time: u32, channel: u8, note: u8, velocity: u8) -> Self {
+        MidiEvent { time, bytes: [NOTE_ON | channel, note & 0x7F, velocity.max(1) & 0x7F] }
+    }
+
+    pub fn note_off(time: u32, channel: u8, note: u8) -> Self {
+        MidiEvent { time, bytes: [NOTE_OFF | channel, note & 0x7F, 0] }
+    }
+
+    /// `value` is 0..16383, 8192 is centered
+    pub fn pitch_bend(time: u32, channel: u8, value: u16) -> Self {
+        let value = value.min(16383);
+        MidiEvent { time, bytes: [PITCH_BEND | channel, (value & 0x7F) as u8, (value >> 7) as u8] }
+    }
+}
+
+/// What a controller asks for, names borrowed from the mapping
+pub enum Control<'a> {
+    /// pedal, parameter and value
+    Set(&'a str, &'a str, f32),
+    /// move through the setlist by so many scenes
+    Step(i32),
+}
+
+/// Parameter driven by a controller, scaled from 0..127 to min..max
+pub struct MidiMapping {
+    pub pedal: String,
+    pub param: String,
+    pub min: f32,
+    pub max: f32,
+}
+
+pub struct MidiMap {
+    /// controller number -> parameters it drives, on any channel
+    mappings: HashMap<u8, Vec<MidiMapping>>,
+    /// footswitches moving through the setlist by so many scenes
+    steps: HashMap<u8, i32>,
+}
+
+impl MidiMap {
+
+    pub fn new() -> Self {
+        MidiMap {
+            mappings: HashMap::new(),
+            steps: HashMap::new(),
+        }
+    }
+
+    pub fn map(&mut self, cc: u8, mapping: MidiMapping) {
+        self.mappings.entry(cc).or_insert_with(Vec::new).push(mapping);
+    }
+
+    pub fn map_step(&mut self, cc: u8, step: i32) {
+        self.steps.insert(cc, step);
+    }
+
+    pub fn unmap(&mut self, cc: u8) {
+        self.mappings.remove(&cc);
+        self.steps.remove(&cc);
+    }
+
+    /// Calls `control` with what a raw MIDI message asks for. The
+    /// mappings are walked in place, an expression pedal sends hundreds
+    /// of these a second on the audio thread.
+    pub fn translate<F: FnMut(Control)>(&self, bytes: &[u8], mut control: F) {
+        if bytes.len() < 3 || bytes[0] & 0xF0 != CONTROL_CHANGE {
+            return;
+        }
+        let value = bytes[2] as f32 / 127.;
+        if let Some(targets) = self.mappings.get(&bytes[1]) {
+            for m in targets.iter() {
+                control(Control::Set(&m.pedal, &m.param, m.min + value * (m.max - m.min)));
+            }
+        }
+        // footswitches step when pressed, not when released
+        if let Some(&step) = self.steps.get(&bytes[1]) {
+            if bytes[2] >= 64 {
+                control(Control::Step(step));
+            }
+        }
+    }
+
+    pub fn print(&self) {
+        for (cc, targets) in self.mappings.iter() {
+            for m in targets.iter() {
+                println!("CC {} -> {} {} [{}, {}]", cc, m.pedal, m.param, m.min, m.max);
+            }
+        }
+        for (cc, step) in self.steps.iter() {
+            println!("CC {} -> {}", cc, if *step > 0 { "next" } else { "prev" });
+        }
+    }
+}
@@ -1,6 +1,22 @@
 use jack;
+use effects::{Effect, Pedals};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
 
-pub struct Notifications;
+/// Prints the JACK notifications and reconfigures the pedals when the
+/// sample rate or buffer size changes. JACK calls these outside of the
+/// process callback, so the pedals can reallocate here while the audio
+/// thread passes the input through. Xruns are counted in `xruns`.
+pub struct Notifications {
+    pedals: Arc<Mutex<Pedals>>,
+    xruns: Arc<AtomicUsize>,
+}
+
+impl Notifications {
+    pub fn new(pedals: Arc<Mutex<Pedals>>, xruns: Arc<AtomicUsize>) -> Self {
+        Notifications { pedals, xruns }
+    }
+}
 
 impl jack::NotificationHandler for Notifications {
     fn thread_init(&self, _: &jack::Client) {
@@ -23,11 +39,13 @@ impl jack::NotificationHandler for Notifications {
 
     fn buffer_size(&mut self, _: &jack::Client, sz: jack::Frames) -> jack::Control {
         println!("JACK: buffer size changed to {}", sz);
+        self.pedals.lock().unwrap().frame_size_changed(sz);
         jack::Control::Continue
     }
 
     fn sample_rate(&mut self, _: &jack::Client, srate: jack::Frames) -> jack::Control {
         println!("JACK: sample rate changed to {}", srate);
+        self.pedals.lock().unwrap().sample_rate_changed(srate as usize);
         jack::Control::Continue
     }
 
@@ -86,7 +104,8 @@ impl jack::NotificationHandler for Notifications {
     }
 
     fn xrun(&mut self, _: &jack::Client) -> jack::Control {
-        println!("JACK: xrun occurred");
+        let count = self.xruns.fetch_add(1, Ordering::Relaxed) + 1;
+        println!("JACK: xrun occurred ({} so far)", count);
         jack::Control::Continue
     }
 
@@ -0,0 +1,180 @@
+//! Command line options for the JACK client.
+
+const USAGE: &str = "\
+usage: rasta [options]
+
+  --config FILE        read the pedals and these options from FILE instead
+                       of $XDG_CONFIG_HOME/rasta/config.toml
+  --script FILE        run the commands in FILE once the pedals are up
+  --automation FILE    play the parameter changes recorded in FILE
+  --render IN OUT      run the WAV file IN through the pedals into OUT
+                       instead of starting a JACK client
+  --tui                start in the full-screen UI instead of the prompt
+  --name NAME          JACK client name (rasta)
+  --inputs N           number of audio inputs, mixed into the chain (1)
+  --in NAME,...        input port names (guitar_in, guitar_in_2, ...)
+  --out LEFT,RIGHT     output port names (rasta_out_l,rasta_out_r)
+  --capture PATTERN    connect the ports matching PATTERN to the inputs
+  --playback PATTERN   connect the outputs to the ports matching PATTERN
+  --patchbay FILE      connect like a QjackCtl patchbay, e.g. patchbay.xml
+  --help               print this help";
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Options {
+    pub client_name: String,
+    pub inputs: Vec<String>,
+    pub outputs: Vec<String>,
+    /// patterns of the ports feeding the inputs, connected in order
+    pub capture: Vec<String>,
+    /// patterns of the ports the outputs feed
+    pub playback: Vec<String>,
+    pub patchbay: Option<String>,
+    /// config file given on the command line
+    pub config: Option<String>,
+    /// command file to run at startup
+    pub script: Option<String>,
+    /// take to play from the start
+    pub automation: Option<String>,
+    /// input and output WAV files of an offline render
+    pub render: Option<(String, String)>,
+    /// start in the full-screen UI
+    pub tui: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            client_name: "rasta".to_owned(),
+            inputs: input_names(1),
+            outputs: vec!["rasta_out_l".to_owned(), "rasta_out_r".to_owned()],
+            capture: vec![],
+            playback: vec![],
+            patchbay: None,
+            config: None,
+            script: None,
+            automation: None,
+            render: None,
+            tui: false,
+        }
+    }
+}
+
+/// guitar_in, guitar_in_2, guitar_in_3, ...
+pub fn input_names(count: usize) -> Vec<String> {
+    (0..count)
+        .map(|i| if i == 0 { "guitar_in".to_owned() } else { format!("guitar_in_{}", i + 1) })
+        .collect()
+}
+
+fn names(list: &str) -> Vec<String> {
+    list.split(',').map(|n| n.trim().to_owned()).filter(|n| !n.is_empty()).collect()
+}
+
+impl Options {
+
+    /// Parses the arguments after the program name
+    pub fn parse<I: Iterator<Item = String>>(args: I) -> Result<Options, String> {
+        Options::parse_over(Options::default(), args)
+    }
+
+    /// Parses the arguments on top of `base`, the options of a config
+    pub fn parse_over<I: Iterator<Item = String>>(base: Options, mut args: I) -> Result<Options, String> {
+        let mut opts = base;
+        let mut count = None;
+        let mut inputs = None;
+        while let Some(arg) = args.next() {
+            if arg == "--help" {
+                return Err(USAGE.to_owned());
+            }
+            let mut value = || args.next().ok_or_else(|| format!("{} needs a value", arg));
+            match arg.as_str() {
+                "--name" => opts.client_name = value()?,
+                "--inputs" => {
+                    let val = value()?;
+                    match val.parse::<usize>() {
+                        Ok(n) if n > 0 => count = Some(n),
+                        _ => return Err(format!("--inputs: {} is not a number of inputs", val)),
+                    }
+                },
+                "--in" => inputs = Some(names(&value()?)),
+                "--out" => {
+                    opts.outputs = names(&value()?);
+                    if opts.outputs.len() != 2 {
+                        return Err("--out takes two names, left and right".to_owned());
+                    }
+                },
+                "--capture" => opts.capture.push(value()?),
+                "--playback" => opts.playback.push(value()?),
+                "--patchbay" => opts.patchbay = Some(value()?),
+                "--config" => opts.config = Some(value()?),
+                "--script" => opts.script = Some(value()?),
+                "--automation" => opts.automation = Some(value()?),
+                "--render" => opts.render = Some((value()?, value()?)),
+                "--tui" => opts.tui = true,
+                _ => return Err(format!("unknown option {}\n\n{}", arg, USAGE)),
+            }
+        }
+
+        opts.inputs = match (inputs, count) {
+            (Some(names), None) => names,
+            (Some(names), Some(n)) if names.len() == n => names,
+            (Some(names), Some(n)) => {
+                return Err(format!("--in names {} ports but --inputs is {}", names.len(), n));
+            },
+            (None, Some(n)) => input_names(n),
+            (None, None) => opts.inputs,
+        };
+        if opts.inputs.is_empty() {
+            return Err("--in needs at least one name".to_owned());
+        }
+        Ok(opts)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Options;
+
+    fn parse(args: &str) -> Result<Options, String> {
+        Options::parse(args.split_whitespace().map(|s| s.to_owned()))
+    }
+
+    #[test]
+    fn test_defaults() {
+        let opts = parse("").unwrap();
+        assert_eq!(opts, Options::default());
+        assert_eq!(opts.inputs, vec!["guitar_in"]);
+    }
+
+    #[test]
+    fn test_options() {
+        let opts = parse("--name amp --inputs 2 --out l,r --capture system:capture_[12] \
+                          --playback system:playback_.* --patchbay patchbay.xml").unwrap();
+        assert_eq!(opts.client_name, "amp");
+        assert_eq!(opts.inputs, vec!["guitar_in", "guitar_in_2"]);
+        assert_eq!(opts.outputs, vec!["l", "r"]);
+        assert_eq!(opts.capture, vec!["system:capture_[12]"]);
+        assert_eq!(opts.playback, vec!["system:playback_.*"]);
+        assert_eq!(opts.patchbay, Some("patchbay.xml".to_owned()));
+
+        assert_eq!(parse("--in gtr,mic").unwrap().inputs, vec!["gtr", "mic"]);
+        assert!(parse("--in gtr,mic --inputs 3").is_err());
+        assert!(parse("--out mono").is_err());
+        assert!(parse("--inputs").unwrap_err().contains("needs a value"));
+        assert!(parse("--bogus").unwrap_err().starts_with("unknown option --bogus"));
+
+        let base = Options { client_name: "amp".to_owned(), inputs: vec!["a".to_owned(), "b".to_owned()], ..Options::default() };
+        let args = "--config rig.toml --script song.txt --out l,r".split_whitespace().map(|s| s.to_owned());
+        let opts = Options::parse_over(base, args).unwrap();
+        assert_eq!(opts.config, Some("rig.toml".to_owned()));
+        assert_eq!(opts.script, Some("song.txt".to_owned()));
+        assert_eq!((opts.client_name.as_str(), opts.inputs.len()), ("amp", 2));
+        assert_eq!(opts.outputs, vec!["l", "r"]);
+
+        let opts = parse("--render di.wav out.wav --automation take.txt").unwrap();
+        assert_eq!(opts.render, Some(("di.wav".to_owned(), "out.wav".to_owned())));
+        assert_eq!(opts.automation, Some("take.txt".to_owned()));
+        assert!(parse("--tui").unwrap().tui);
+        assert!(parse("--render di.wav").unwrap_err().contains("needs a value"));
+    }
+}
@@ -7,13 +7,24 @@ pub fn parse_input(cmd: &str) -> CtrlMsg {
         Tuner
     } else
 
+    if cmd.starts_with("t ") {
+        Select("tuner".to_owned(), "stream".to_owned(), cmd[2..].to_owned())
+    } else
+
+    if cmd.starts_with("bpm ") {
+        match cmd[4..].trim().parse::<f32>() {
+            Ok(bpm) => Tempo(bpm),
+            Err(_) => Invalid("usage: bpm <beats per minute>".to_owned()),
+        }
+    } else
+
     if cmd == "b" {
         Bypass
     } else
 
-    if cmd.starts_with("b") {
+    if cmd.starts_with("b ") {
         let tokens = cmd[2..]
-            .split(" ")
+            .split_whitespace()
             .collect::<Vec<&str>>();
         let mut chain = vec![];
         for token in tokens.into_iter() {
@@ -22,14 +33,21 @@ pub fn parse_input(cmd: &str) -> CtrlMsg {
         Chain(chain)
     } else
 
+    if cmd.starts_with("i ") {
+        Info(cmd[2..].to_owned())
+    } else
+
     if cmd == "p" {
         Connections
     } else
 
-    if cmd.starts_with("d") {
-        let tokens = cmd[2..]
-            .split(" ")
+    if cmd == "d" || cmd.starts_with("d ") {
+        let tokens = cmd[1..]
+            .split_whitespace()
             .collect::<Vec<&str>>();
+        if tokens.is_empty() {
+            return Invalid("usage: d <node>...".to_owned());
+        }
         let mut chain = vec![];
         for a in tokens.into_iter() {
             chain.push(Disconnect(a.to_owned()));
@@ -37,23 +55,138 @@ pub fn parse_input(cmd: &str) -> CtrlMsg {
         Chain(chain)
     } else
 
-    if cmd.starts_with("s") {
-        let tokens = cmd[2..]
-            .split(" ")
+    if cmd == "next" || cmd == "prev" {
+        Step(if cmd == "next" { 1 } else { -1 })
+    } else
+
+    if cmd == "next song" || cmd == "prev song" {
+        StepSong(if cmd == "next song" { 1 } else { -1 })
+    } else
+
+    if cmd == "scene" || cmd.starts_with("scene ") {
+        // scene solo
+        Scene(cmd[5..].trim().to_owned())
+    } else
+
+    if cmd == "ab" || cmd.starts_with("ab ") {
+        // ab
+        // ab b
+        match cmd[2..].trim() {
+            "a" => Ab(Some(0)),
+            "b" => Ab(Some(1)),
+            _ => Ab(None),
+        }
+    } else
+
+    if cmd.starts_with("morph ") {
+        // morph 0.5
+        // morph b 4
+        let tokens = cmd[6..].split_whitespace().collect::<Vec<&str>>();
+        let position = match tokens.get(0) {
+            Some(&"a") => Some(0.),
+            Some(&"b") => Some(1.),
+            pos => pos.and_then(|pos| pos.parse::<f32>().ok()),
+        };
+        let seconds = match tokens.get(1) {
+            Some(s) => s.parse::<f32>().ok(),
+            None => Some(0.),
+        };
+        match (position, seconds) {
+            (Some(position), Some(seconds)) if tokens.len() <= 2 => Morph(position, seconds),
+            _ => Invalid("usage: morph a|b|<position> [seconds]".to_owned()),
+        }
+    } else
+
+    if cmd == "rec" || cmd.starts_with("rec ") {
+        // rec
+        // rec stop
+        // rec save take.txt
+        match cmd[3..].trim() {
+            "stop" => Record(false),
+            rest if rest.starts_with("save ") => SaveTake(rest[5..].trim().to_owned()),
+            _ => Record(true),
+        }
+    } else
+
+    if cmd == "play" || cmd.starts_with("play ") {
+        // play
+        // play take.txt
+        // play stop
+        match cmd[4..].trim() {
+            "" => Play(None),
+            "stop" => StopPlay,
+            path => LoadTake(path.to_owned()),
+        }
+    } else
+
+    if cmd.starts_with("song ") {
+        LoadSong(cmd[5..].to_owned())
+    } else
+
+    if cmd.starts_with("setlist ") {
+        LoadSetlist(cmd[8..].to_owned())
+    } else
+
+    if cmd == "stats" {
+        Stats
+    } else
+
+    if cmd == "meters" || cmd.starts_with("meters ") {
+        // meters
+        // meters pedals
+        // meters pedals off
+        // meters reset
+        match cmd[6..].trim() {
+            "pedals" => MeterPedals(true),
+            "pedals off" => MeterPedals(false),
+            "reset" => MeterReset,
+            _ => Meters,
+        }
+    } else
+
+    if cmd.starts_with("stats log ") {
+        // stats log 10
+        // stats log off
+        StatsLog(cmd[10..].parse::<f32>().unwrap_or(0.))
+    } else
+
+    if cmd.starts_with("sc ") {
+        // key a pedal from another node:
+        // sc gate in
+        let tokens = cmd[3..]
+            .split_whitespace()
             .collect::<Vec<&str>>();
-        let pedal_name = tokens[0].to_owned();
-        let conf_name = tokens[1].to_owned();
-        let val = tokens[2].parse::<f32>().unwrap();
-        Set(pedal_name, conf_name, val)
+        match (tokens.get(0), tokens.get(1)) {
+            (Some(pedal_name), Some(source)) => Sidechain(pedal_name.to_string(), source.to_string()),
+            _ => Invalid("usage: sc <pedal> <source>".to_owned()),
+        }
+
+    } else
+
+    if cmd == "s" || cmd.starts_with("s ") {
+        let tokens = cmd[1..]
+            .split_whitespace()
+            .collect::<Vec<&str>>();
+        match tokens.as_slice() {
+            [pedal_name, conf_name, val] => match val.parse::<f32>() {
+                Ok(val) => Set(pedal_name.to_string(), conf_name.to_string(), val),
+                // s comp detect rms
+                Err(_) => Select(pedal_name.to_string(), conf_name.to_string(), val.to_string()),
+            },
+            _ => Invalid("usage: s <pedal> <param> <value>".to_owned()),
+        }
 
     } else
     
-    if cmd.starts_with("c") {
+    if cmd == "c" || cmd.starts_with("c ") {
         // allow daisy chaining:
         // c in delay overdrive out
-        let tokens = cmd[2..]
-            .split(" ")
+        let tokens = cmd[1..]
+            .split_whitespace()
             .collect::<Vec<&str>>();
+        if tokens.len() < 2 {
+            return Invalid("usage: c <from> <to>...".to_owned());
+        }
 
         let mut chain = vec![];
         for (a, b) in tokens.iter().zip(tokens[1..].into_iter()) {
@@ -66,14 +199,59 @@ pub fn parse_input(cmd: &str) -> CtrlMsg {
         Chain(chain)
     } else
     
-    if cmd.starts_with("a") {
-        let tokens = cmd.split(" ").collect::<Vec<&str>>();
-        let name = tokens[1].to_owned();
-        let eff_type = tokens[2].to_owned();
+    if cmd.starts_with("l ") {
+        // looper transport:
+        // l lp rec|play|dub|stop|clear|undo|redo
+        // l lp export loop.wav
+        let tokens = cmd[2..]
+            .split_whitespace()
+            .collect::<Vec<&str>>();
+        match tokens.as_slice() {
+            [pedal_name, "export", path] => Select(pedal_name.to_string(), "export".to_owned(), path.to_string()),
+            [pedal_name, action] if *action != "export" => Select(pedal_name.to_string(), "action".to_owned(), action.to_string()),
+            _ => Invalid("usage: l <looper> rec|play|dub|stop|clear|undo|redo or l <looper> export <file>".to_owned()),
+        }
+    } else
 
-        Add(name, eff_type)
+    if cmd.starts_with("m ") {
+        // map a MIDI controller to a parameter:
+        // m 11 aw pedal [min max]
+        // m 11 off
+        let tokens = cmd[2..]
+            .split_whitespace()
+            .collect::<Vec<&str>>();
+        let usage = || Invalid("usage: m <cc> <pedal> <param> [min max], m <cc> next|prev or m <cc> off".to_owned());
+        let cc = match tokens.get(0).and_then(|t| t.parse::<u8>().ok()) {
+            Some(cc) => cc,
+            None => return usage(),
+        };
+        match tokens[1..] {
+            ["off"] => MidiUnmap(cc),
+            // m 80 next
+            ["next"] => MidiStep(cc, 1),
+            ["prev"] => MidiStep(cc, -1),
+            [pedal_name, conf_name] => MidiMap(cc, pedal_name.to_owned(), conf_name.to_owned(), 0., 1.),
+            [pedal_name, conf_name, min, max] => match (min.parse::<f32>(), max.parse::<f32>()) {
+                (Ok(min), Ok(max)) => MidiMap(cc, pedal_name.to_owned(), conf_name.to_owned(), min, max),
+                _ => usage(),
+            },
+            _ => usage(),
+        }
+    } else
+
+    if cmd == "a" || cmd.starts_with("a ") {
+        let tokens = cmd.split_whitespace().collect::<Vec<&str>>();
+        match tokens[1..] {
+            [name, eff_type] => Add(name.to_owned(), eff_type.to_owned()),
+            _ => Invalid("usage: a <name> <type>".to_owned()),
+        }
 
+    } else
+
+    if cmd == "tui" {
+        // the prompt opens it before the command gets here
+        Invalid("tui only works at the prompt".to_owned())
     } else {
-        Bypass
+        Invalid(format!("unknown command: {}", cmd))
     }
 }
\ No newline at end of file
@@ -0,0 +1,292 @@
+//! Command files and macros.
+//!
+//! Every line typed or read from a file goes through `Script` before the
+//! parser. `#` starts a comment and `;` separates several commands on one
+//! line. A macro is defined with
+//!
+//! ```text
+//! def lead = b aw; s od drive 0.8
+//! def boost pedal amount = s $pedal drive $amount; i $pedal
+//! ```
+//!
+//! and invoked by its name followed by one word per parameter, e.g.
+//! `boost od 0.9`. `def` alone lists the macros and `source <file>` runs
+//! the commands in a file, which may define macros of its own.
+
+use effects::CtrlMsg;
+use parser::parse_input;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+
+/// How deep macros and sourced files may nest, stops runaway recursion
+const MAX_DEPTH: usize = 16;
+
+/// Command words a macro may not shadow
+const BUILTIN: &[&str] = &[
+    "a", "ab", "b", "bpm", "c", "d", "def", "i", "l", "m", "meters", "morph", "next", "p", "play",
+    "prev", "rec", "s", "sc", "scene", "setlist", "song", "source", "stats", "t", "tui",
+];
+
+#[derive(Clone, Debug, PartialEq)]
+struct Macro {
+    params: Vec<String>,
+    /// commands with `$param` words still in them
+    body: Vec<String>,
+}
+
+pub struct Script {
+    macros: HashMap<String, Macro>,
+    depth: usize,
+}
+
+/// Splits a line into its commands. Whatever follows a `def` belongs to
+/// the macro, `;` included.
+fn split(line: &str) -> Vec<String> {
+    let line = match line.find('#') {
+        Some(idx) => &line[..idx],
+        None => line,
+    };
+    let mut cmds = vec![];
+    let mut rest = line;
+    loop {
+        let trimmed = rest.trim_start();
+        if trimmed.starts_with("def ") {
+            cmds.push(trimmed.trim_end().to_owned());
+            break;
+        }
+        match rest.find(';') {
+            Some(idx) => {
+                cmds.push(rest[..idx].trim().to_owned());
+                rest = &rest[idx + 1..];
+            },
+            None => {
+                cmds.push(rest.trim().to_owned());
+                break;
+            },
+        }
+    }
+    cmds.retain(|cmd| !cmd.is_empty());
+    cmds
+}
+
+impl Script {
+
+    pub fn new() -> Self {
+        Script {
+            macros: HashMap::new(),
+            depth: 0,
+        }
+    }
+
+    /// The plain commands a line stands for, with macros expanded and
+    /// files sourced
+    pub fn line(&mut self, line: &str) -> Result<Vec<String>, String> {
+        let mut out = vec![];
+        for cmd in split(line) {
+            self.command(&cmd, &mut out)?;
+        }
+        Ok(out)
+    }
+
+    /// The plain commands of the file at `path`. Nothing of a file with
+    /// an error is run, the error names the line.
+    pub fn source(&mut self, path: &str) -> Result<Vec<String>, String> {
+        if self.depth >= MAX_DEPTH {
+            return Err(format!("{}: sourced too deep", path));
+        }
+        let file = File::open(path).map_err(|e| format!("{}: {}", path, e))?;
+        self.depth += 1;
+        let mut out = vec![];
+        let mut result = Ok(());
+        for (i, line) in BufReader::new(file).lines().enumerate() {
+            result = line
+                .map_err(|e| format!("{}: {}", path, e))
+                .and_then(|line| self.line(&line).map_err(|e| format!("{}:{}: {}", path, i + 1, e)))
+                .map(|cmds| out.extend(cmds));
+            if result.is_err() {
+                break;
+            }
+        }
+        self.depth -= 1;
+        result.map(|_| out)
+    }
+
+    fn command(&mut self, cmd: &str, out: &mut Vec<String>) -> Result<(), String> {
+        let words: Vec<&str> = cmd.split_whitespace().collect();
+        match words[0] {
+            "def" if words.len() == 1 => self.print(),
+            "def" => self.define(&cmd[3..])?,
+            "source" if words.len() == 2 => out.extend(self.source(words[1])?),
+            "source" => return Err("usage: source <file>".to_owned()),
+            name if self.macros.contains_key(name) => {
+                if self.depth >= MAX_DEPTH {
+                    return Err(format!("{}: macros nested too deep", name));
+                }
+                let body = self.expand(name, &words[1..])?;
+                self.depth += 1;
+                let mut result = Ok(());
+                for cmd in body.iter() {
+                    result = self.command(cmd, out);
+                    if result.is_err() {
+                        break;
+                    }
+                }
+                self.depth -= 1;
+                result?;
+            },
+            _ => match parse_input(cmd) {
+                CtrlMsg::Invalid(usage) => return Err(usage),
+                _ => out.push(cmd.to_owned()),
+            },
+        }
+        Ok(())
+    }
+
+    /// `name params... = body`
+    fn define(&mut self, def: &str) -> Result<(), String> {
+        let mut parts = def.splitn(2, '=');
+        let head: Vec<&str> = parts.next().unwrap().split_whitespace().collect();
+        let body = parts.next().ok_or("usage: def <name> [params...] = <commands>")?;
+        let name = *head.first().ok_or("def needs a name")?;
+        if BUILTIN.contains(&name) {
+            return Err(format!("{} is a command", name));
+        }
+        let params: Vec<String> = head[1..].iter().map(|p| p.to_string()).collect();
+        let body = split(body);
+        if body.is_empty() {
+            return Err(format!("{} does nothing", name));
+        }
+        for word in body.iter().flat_map(|cmd| cmd.split_whitespace()) {
+            if word.starts_with('$') && !params.iter().any(|p| *p == word[1..]) {
+                return Err(format!("{}: no parameter {}", name, &word[1..]));
+            }
+        }
+        self.macros.insert(name.to_owned(), Macro { params, body });
+        Ok(())
+    }
+
+    /// The body of the macro with `args` in place of its parameters
+    fn expand(&self, name: &str, args: &[&str]) -> Result<Vec<String>, String> {
+        let m = &self.macros[name];
+        if args.len() != m.params.len() {
+            return Err(format!("{} takes {} arguments: {}", name, m.params.len(), m.params.join(" ")));
+        }
+        let body = m.body.iter()
+            .map(|cmd| {
+                let words: Vec<&str> = cmd.split_whitespace()
+                    .map(|word| match m.params.iter().position(|p| word.starts_with('$') && *p == word[1..]) {
+                        Some(idx) => args[idx],
+                        None => word,
+                    })
+                    .collect();
+                words.join(" ")
+            })
+            .collect();
+        Ok(body)
+    }
+
+    fn print(&self) {
+        if self.macros.is_empty() {
+            println!("No macros");
+        }
+        let mut names: Vec<_> = self.macros.keys().collect();
+        names.sort();
+        for name in names {
+            let m = &self.macros[name];
+            let params: String = m.params.iter().map(|p| format!(" {}", p)).collect();
+            println!("def {}{} = {}", name, params, m.body.join("; "));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{split, Script};
+    use std::env;
+    use std::fs::File;
+    use std::io::Write;
+
+    #[test]
+    fn test_split() {
+        assert_eq!(split("c in od out; s od drive 0.5 # warm"), vec!["c in od out", "s od drive 0.5"]);
+        assert_eq!(split("  # only a comment"), Vec::<String>::new());
+        assert_eq!(split("b; def lead = b aw; s od drive 0.8"), vec!["b", "def lead = b aw; s od drive 0.8"]);
+    }
+
+    #[test]
+    fn test_macros() {
+        let mut script = Script::new();
+        assert_eq!(script.line("def lead = b aw; s od drive 0.8").unwrap(), Vec::<String>::new());
+        assert_eq!(script.line("lead").unwrap(), vec!["b aw", "s od drive 0.8"]);
+
+        script.line("def boost pedal amount = s $pedal drive $amount; i $pedal").unwrap();
+        script.line("def solo = lead; boost od2 1").unwrap();
+        assert_eq!(script.line("solo; p").unwrap(),
+            vec!["b aw", "s od drive 0.8", "s od2 drive 1", "i od2", "p"]);
+
+        assert!(script.line("boost od").unwrap_err().starts_with("boost takes 2 arguments"));
+        assert_eq!(script.line("def x = s od drive $level").unwrap_err(), "x: no parameter level");
+        assert_eq!(script.line("def s = b").unwrap_err(), "s is a command");
+        assert_eq!(script.line("def song = b").unwrap_err(), "song is a command");
+        assert_eq!(script.line("def next = b").unwrap_err(), "next is a command");
+        script.line("def loop = loop").unwrap();
+        assert_eq!(script.line("loop").unwrap_err(), "loop: macros nested too deep");
+    }
+
+    #[test]
+    fn test_source() {
+        let dir = env::temp_dir();
+        let inner = dir.join("rasta_test_inner.txt");
+        let outer = dir.join("rasta_test_outer.txt");
+        let mut f = File::create(&inner).unwrap();
+        writeln!(f, "# song one\ndef verse = b od\n\nc in od out; verse").unwrap();
+        let mut f = File::create(&outer).unwrap();
+        writeln!(f, "a od overdrive\nsource {}\nverse", inner.to_str().unwrap()).unwrap();
+
+        let mut script = Script::new();
+        let cmds = script.line(&format!("source {}", outer.to_str().unwrap())).unwrap();
+        assert_eq!(cmds, vec!["a od overdrive", "c in od out", "b od", "b od"]);
+
+        let mut f = File::create(&inner).unwrap();
+        writeln!(f, "def verse = b od\nverse 1").unwrap();
+        let err = Script::new().source(inner.to_str().unwrap()).unwrap_err();
+        assert!(err.ends_with("rasta_test_inner.txt:2: verse takes 0 arguments: "), "{}", err);
+
+        let mut f = File::create(&inner).unwrap();
+        writeln!(f, "c in gate out\nsc gate").unwrap();
+        let err = Script::new().source(inner.to_str().unwrap()).unwrap_err();
+        assert!(err.ends_with("rasta_test_inner.txt:2: usage: sc <pedal> <source>"), "{}", err);
+        assert_eq!(Script::new().line("bpm 120; bpm fast").unwrap_err(), "usage: bpm <beats per minute>");
+        let usage = "usage: m <cc> <pedal> <param> [min max], m <cc> next|prev or m <cc> off";
+        assert_eq!(Script::new().line("m 11 aw pedal 0 1; m 11 off; m 80 next").unwrap().len(), 3);
+        assert_eq!(Script::new().line("m x aw pedal").unwrap_err(), usage);
+        assert_eq!(Script::new().line("m 11 aw").unwrap_err(), usage);
+        assert_eq!(Script::new().line("m 11 aw pedal 0 high").unwrap_err(), usage);
+        let usage = "usage: l <looper> rec|play|dub|stop|clear|undo|redo or l <looper> export <file>";
+        assert_eq!(Script::new().line("l lp rec; l lp export loop.wav").unwrap().len(), 2);
+        assert_eq!(Script::new().line("l lp").unwrap_err(), usage);
+        assert_eq!(Script::new().line("l lp export").unwrap_err(), usage);
+        let usage = "usage: morph a|b|<position> [seconds]";
+        assert_eq!(Script::new().line("morph 0.5; morph b 4").unwrap().len(), 2);
+        assert_eq!(Script::new().line("morph x").unwrap_err(), usage);
+        assert_eq!(Script::new().line("morph b slowly").unwrap_err(), usage);
+
+        // a typo is reported with its line instead of panicking or
+        // toggling the bypass
+        let mut f = File::create(&inner).unwrap();
+        writeln!(f, "a od overdrive\ns od drive").unwrap();
+        let err = Script::new().source(inner.to_str().unwrap()).unwrap_err();
+        assert!(err.ends_with("rasta_test_inner.txt:2: usage: s <pedal> <param> <value>"), "{}", err);
+        assert_eq!(Script::new().line("s od").unwrap_err(), "usage: s <pedal> <param> <value>");
+        assert_eq!(Script::new().line("a od").unwrap_err(), "usage: a <name> <type>");
+        assert_eq!(Script::new().line("d").unwrap_err(), "usage: d <node>...");
+        assert_eq!(Script::new().line("c in").unwrap_err(), "usage: c <from> <to>...");
+        assert_eq!(Script::new().line("stats foo").unwrap_err(), "unknown command: stats foo");
+        assert_eq!(Script::new().line("help").unwrap_err(), "unknown command: help");
+        let mut script = Script::new();
+        script.line("def ui = tui").unwrap();
+        assert_eq!(script.line("ui").unwrap_err(), "tui only works at the prompt");
+        assert_eq!(Script::new().line("s comp detect rms; d od; b od aw; stats; stats log 10").unwrap().len(), 5);
+    }
+}
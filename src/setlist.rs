@@ -0,0 +1,411 @@
+//! Scenes, songs and setlists.
+//!
+//! A scene switches pedals on or off and overrides parameters, layered on
+//! a preset: the startup config, or a config file in `presets/`. A song
+//! lists its scenes in order and a setlist its songs. They are TOML files
+//! kept next to the config, found by name:
+//!
+//! ```text
+//! ~/.config/rasta/presets/clean.toml
+//! ~/.config/rasta/songs/blues.toml
+//! ~/.config/rasta/setlists/friday.toml
+//! ```
+//!
+//! ```toml
+//! # songs/blues.toml
+//! preset = "clean"
+//! tempo = 96
+//!
+//! [scenes.verse]
+//! bypass = ["od", "delay"]
+//!
+//! [scenes.solo]
+//! on = ["od", "delay"]
+//!
+//! [scenes.solo.delay]
+//! mix = 0.4
+//! ```
+//!
+//! ```toml
+//! # setlists/friday.toml
+//! songs = ["intro", "blues", "encore.toml"]
+//! ```
+//!
+//! A name ending in `.toml` is a path instead, relative to the file that
+//! refers to it.
+
+use config::{self, Config, Value, number, parse_tables, string, strings};
+use effects::CtrlMsg;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+/// Pedals switched on or off and parameters set by one part of a song,
+/// with the preset folded in
+#[derive(Clone, Debug, PartialEq)]
+pub struct Scene {
+    pub name: String,
+    /// pedal and whether it is on
+    pub enable: Vec<(String, bool)>,
+    /// pedal, parameter and value
+    pub params: Vec<(String, String, Value)>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Song {
+    pub name: String,
+    /// beats per minute, 0 keeps the current tempo
+    pub tempo: f32,
+    pub scenes: Vec<Scene>,
+}
+
+pub struct Setlist {
+    pub name: String,
+    pub songs: Vec<Song>,
+    song: usize,
+    scene: usize,
+}
+
+/// Where the file called `name` of a `kind` (presets, songs, setlists) is
+fn resolve(name: &str, kind: &str, base: Option<&Path>) -> PathBuf {
+    if name.ends_with(".toml") {
+        match base {
+            Some(dir) => dir.join(name),
+            None => PathBuf::from(name),
+        }
+    } else {
+        config::dir()
+            .unwrap_or_else(PathBuf::new)
+            .join(kind)
+            .join(format!("{}.toml", name))
+    }
+}
+
+fn read(path: &Path) -> Result<String, String> {
+    let mut text = String::new();
+    File::open(path)
+        .and_then(|mut f| f.read_to_string(&mut text))
+        .map_err(|e| format!("{}: {}", path.display(), e))?;
+    Ok(text)
+}
+
+/// Name of a file without directory and extension
+fn stem(path: &Path) -> String {
+    path.file_stem().map(|s| s.to_string_lossy().into_owned()).unwrap_or_default()
+}
+
+fn set_param(params: &mut Vec<(String, String, Value)>, pedal: &str, param: &str, value: Value) {
+    match params.iter().position(|p| p.0 == pedal && p.1 == param) {
+        Some(idx) => params[idx].2 = value,
+        None => params.push((pedal.to_owned(), param.to_owned(), value)),
+    }
+}
+
+impl Song {
+
+    /// Reads the song called `name`, `preset` being the config it layers
+    /// on unless it names its own
+    pub fn load(name: &str, base: Option<&Path>, preset: &Config) -> Result<Song, String> {
+        let path = resolve(name, "songs", base);
+        let text = read(&path)?;
+        let dir = path.parent().map(|d| d.to_path_buf());
+        Song::parse(&text, &path.to_string_lossy(), &stem(&path), |name| {
+            let path = resolve(name, "presets", dir.as_ref().map(|d| d.as_path()));
+            Config::load(&path.to_string_lossy())
+        }, preset)
+    }
+
+    /// Reads the text of a song, `load_preset` fetches a preset it names
+    pub fn parse<F>(text: &str, path: &str, name: &str, load_preset: F, preset: &Config) -> Result<Song, String>
+        where F: Fn(&str) -> Result<Config, String>
+    {
+        let tables = parse_tables(text)
+            .map_err(|(line, e)| format!("{}:{}: {}", path, line, e))?;
+        let mut song = Song { name: name.to_owned(), tempo: 0., scenes: vec![] };
+        let mut own_preset = None;
+        let mut errors = vec![];
+        let mut scenes: Vec<(String, Vec<String>, Vec<String>, Vec<(String, String, Value)>)> = vec![];
+
+        for table in tables.iter() {
+            let mut parts = table.name.splitn(3, '.');
+            let (kind, scene, pedal) = (parts.next().unwrap(), parts.next(), parts.next());
+            let scene = match (kind, scene) {
+                ("", _) => None,
+                ("scenes", Some(scene)) => {
+                    if !scenes.iter().any(|s| s.0 == scene) {
+                        scenes.push((scene.to_owned(), vec![], vec![], vec![]));
+                    }
+                    scenes.iter().position(|s| s.0 == scene)
+                },
+                _ => {
+                    errors.push(format!("{}:{}: unknown table [{}]", path, table.line, table.name));
+                    continue;
+                },
+            };
+            for entry in table.entries.iter() {
+                let value = &entry.value;
+                let result = match (scene, pedal, entry.key.as_str()) {
+                    (None, _, "name") => string(value).map(|n| song.name = n),
+                    (None, _, "tempo") => number(value).map(|t| song.tempo = t),
+                    (None, _, "preset") => string(value)
+                        .and_then(|p| load_preset(&p))
+                        .map(|p| own_preset = Some(p)),
+                    (Some(idx), None, "bypass") => strings(value).map(|p| scenes[idx].1 = p),
+                    (Some(idx), None, "on") => strings(value).map(|p| scenes[idx].2 = p),
+                    (Some(idx), Some(pedal), param) => match *value {
+                        Value::Array(_) => Err("expected a number, string or boolean".to_owned()),
+                        _ => {
+                            scenes[idx].3.push((pedal.to_owned(), param.to_owned(), value.clone()));
+                            Ok(())
+                        },
+                    },
+                    (_, _, key) => Err(format!("unknown setting {}", key)),
+                };
+                if let Err(e) = result {
+                    errors.push(format!("{}:{}: {}: {}", path, entry.line, entry.key, e));
+                }
+            }
+        }
+        if scenes.is_empty() {
+            errors.push(format!("{}: no scenes", path));
+        }
+        if !errors.is_empty() {
+            return Err(errors.join("\n"));
+        }
+
+        let preset = own_preset.as_ref().unwrap_or(preset);
+        for (name, bypass, on, params) in scenes {
+            let mut enable: Vec<(String, bool)> = preset.pedals.iter()
+                .map(|p| (p.0.clone(), !preset.bypass.contains(&p.0)))
+                .collect();
+            for (pedal, state) in bypass.into_iter().map(|p| (p, false)).chain(on.into_iter().map(|p| (p, true))) {
+                match enable.iter().position(|e| e.0 == pedal) {
+                    Some(idx) => enable[idx].1 = state,
+                    None => enable.push((pedal, state)),
+                }
+            }
+            let mut layered = preset.params.clone();
+            for (pedal, param, value) in params {
+                set_param(&mut layered, &pedal, &param, value);
+            }
+            song.scenes.push(Scene { name, enable, params: layered });
+        }
+        Ok(song)
+    }
+}
+
+impl Setlist {
+
+    /// A setlist of one song
+    pub fn song(song: Song) -> Setlist {
+        Setlist { name: song.name.clone(), songs: vec![song], song: 0, scene: 0 }
+    }
+
+    /// Reads the setlist called `name` and all its songs
+    pub fn load(name: &str, preset: &Config) -> Result<Setlist, String> {
+        let path = resolve(name, "setlists", None);
+        let text = read(&path)?;
+        let label = path.to_string_lossy().into_owned();
+        let tables = parse_tables(&text)
+            .map_err(|(line, e)| format!("{}:{}: {}", label, line, e))?;
+
+        let mut setlist = Setlist { name: stem(&path), songs: vec![], song: 0, scene: 0 };
+        let mut names = vec![];
+        for table in tables.iter() {
+            if !table.name.is_empty() {
+                return Err(format!("{}:{}: unknown table [{}]", label, table.line, table.name));
+            }
+            for entry in table.entries.iter() {
+                let result = match entry.key.as_str() {
+                    "name" => string(&entry.value).map(|n| setlist.name = n),
+                    "songs" => strings(&entry.value).map(|s| names = s),
+                    key => Err(format!("unknown setting {}", key)),
+                };
+                result.map_err(|e| format!("{}:{}: {}: {}", label, entry.line, entry.key, e))?;
+            }
+        }
+        if names.is_empty() {
+            return Err(format!("{}: no songs", label));
+        }
+        let dir = path.parent();
+        for name in names.iter() {
+            setlist.songs.push(Song::load(name, dir, preset)?);
+        }
+        Ok(setlist)
+    }
+
+    pub fn current(&self) -> (&Song, &Scene) {
+        let song = &self.songs[self.song];
+        (song, &song.scenes[self.scene])
+    }
+
+    /// Moves `n` scenes on, or back when negative, into the next or
+    /// previous songs. Stops at either end, false when it did not move.
+    pub fn step(&mut self, n: i32) -> bool {
+        let before: usize = self.songs[..self.song].iter().map(|s| s.scenes.len()).sum();
+        let total: usize = self.songs.iter().map(|s| s.scenes.len()).sum();
+        let pos = (before + self.scene) as i32;
+        let mut target = (pos + n).max(0).min(total as i32 - 1) as usize;
+        if target as i32 == pos {
+            return false;
+        }
+        for (i, song) in self.songs.iter().enumerate() {
+            if target < song.scenes.len() {
+                self.song = i;
+                self.scene = target;
+                break;
+            }
+            target -= song.scenes.len();
+        }
+        true
+    }
+
+    /// Moves to the first scene of the song `n` songs on
+    pub fn step_song(&mut self, n: i32) -> bool {
+        let target = (self.song as i32 + n).max(0).min(self.songs.len() as i32 - 1) as usize;
+        if target == self.song && self.scene == 0 {
+            return false;
+        }
+        self.song = target;
+        self.scene = 0;
+        true
+    }
+
+    /// Moves to the scene called `name` of the current song
+    pub fn goto(&mut self, name: &str) -> bool {
+        match self.songs[self.song].scenes.iter().position(|s| s.name == name) {
+            Some(idx) => {
+                self.scene = idx;
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// `blues: solo (3/3), song 2/4 of friday`
+    pub fn describe(&self) -> String {
+        let (song, scene) = self.current();
+        format!("{}: {} ({}/{}), song {}/{} of {}",
+            song.name, scene.name, self.scene + 1, song.scenes.len(),
+            self.song + 1, self.songs.len(), self.name)
+    }
+
+    /// The control messages that set up the current scene
+    pub fn messages(&self) -> Vec<CtrlMsg> {
+        use self::CtrlMsg::*;
+        let (song, scene) = self.current();
+        let mut msgs = vec![];
+        if song.tempo > 0. {
+            msgs.push(Tempo(song.tempo));
+        }
+        for &(ref pedal, on) in scene.enable.iter() {
+            msgs.push(Enable(pedal.clone(), on));
+        }
+        for &(ref pedal, ref param, ref value) in scene.params.iter() {
+            let (pedal, param) = (pedal.clone(), param.clone());
+            msgs.push(match *value {
+                Value::Num(n) => Set(pedal, param, n as f32),
+                Value::Bool(b) => Set(pedal, param, if b { 1. } else { 0. }),
+                Value::Str(ref s) => Select(pedal, param, s.clone()),
+                Value::Array(_) => continue,
+            });
+        }
+        msgs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Setlist, Song};
+    use config::{Config, Value};
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::Write;
+
+    const PRESET: &str = "
+        bypass = [\"delay\"]
+        [pedals]
+        od = \"overdrive\"
+        delay = \"delay\"
+        [params.delay]
+        mix = 0.2
+        feedback = 0.3
+    ";
+
+    const SONG: &str = "
+        tempo = 96
+        [scenes.verse]
+        bypass = [\"od\"]
+        [scenes.solo]
+        on = [\"delay\"]
+        [scenes.solo.delay]
+        mix = 0.4
+        [scenes.solo.od]
+        mode = \"fuzz\"
+    ";
+
+    fn song(name: &str) -> Song {
+        let preset = Config::parse(PRESET, "preset.toml").unwrap();
+        Song::parse(SONG, "song.toml", name, |_| Err("no presets".to_owned()), &preset).unwrap()
+    }
+
+    #[test]
+    fn test_layering() {
+        let song = song("blues");
+        assert_eq!(song.tempo, 96.);
+        let verse = &song.scenes[0];
+        assert_eq!(verse.enable, vec![("od".to_owned(), false), ("delay".to_owned(), false)]);
+        assert_eq!(verse.params.len(), 2);
+
+        let solo = &song.scenes[1];
+        assert_eq!(solo.name, "solo");
+        assert_eq!(solo.enable, vec![("od".to_owned(), true), ("delay".to_owned(), true)]);
+        assert_eq!(solo.params, vec![
+            ("delay".to_owned(), "mix".to_owned(), Value::Num(0.4)),
+            ("delay".to_owned(), "feedback".to_owned(), Value::Num(0.3)),
+            ("od".to_owned(), "mode".to_owned(), Value::Str("fuzz".to_owned())),
+        ]);
+
+        let err = Song::parse("[scenes.a]\nvolume = 1\n[lfo]", "s.toml", "s", |_| Err("x".to_owned()),
+            &Config::default()).unwrap_err();
+        assert_eq!(err, "s.toml:2: volume: unknown setting volume\ns.toml:3: unknown table [lfo]");
+    }
+
+    #[test]
+    fn test_navigation() {
+        let mut setlist = Setlist::song(song("intro"));
+        setlist.songs.push(song("blues"));
+        setlist.name = "friday".to_owned();
+        assert_eq!(setlist.describe(), "intro: verse (1/2), song 1/2 of friday");
+        assert!(!setlist.step(-1));
+        assert!(setlist.step(2));
+        assert_eq!(setlist.describe(), "blues: verse (1/2), song 2/2 of friday");
+        assert!(setlist.step(5));
+        assert_eq!(setlist.current().1.name, "solo");
+        assert!(setlist.step_song(-1));
+        assert_eq!(setlist.describe(), "intro: verse (1/2), song 1/2 of friday");
+        assert!(setlist.goto("solo"));
+        assert!(!setlist.goto("bridge"));
+        // tempo, 2 pedals, 3 parameters
+        assert_eq!(setlist.messages().len(), 6);
+    }
+
+    #[test]
+    fn test_load_setlist() {
+        let dir = env::temp_dir().join("rasta_test_setlist");
+        fs::create_dir_all(&dir).unwrap();
+        let write = |name: &str, text: &str| {
+            let mut f = File::create(dir.join(name)).unwrap();
+            f.write_all(text.as_bytes()).unwrap();
+        };
+        write("clean.toml", PRESET);
+        write("blues.toml", &format!("preset = \"clean.toml\"\n{}", SONG));
+        write("gig.toml", "name = \"gig\"\nsongs = [\"blues.toml\", \"blues.toml\"]");
+
+        let setlist = Setlist::load(dir.join("gig.toml").to_str().unwrap(), &Config::default()).unwrap();
+        assert_eq!(setlist.name, "gig");
+        assert_eq!(setlist.songs.len(), 2);
+        assert_eq!(setlist.songs[0].name, "blues");
+        assert_eq!(setlist.songs[0].scenes[0].params.len(), 2);
+    }
+}
@@ -0,0 +1,383 @@
+//! DSP timing statistics.
+//!
+//! The audio thread records how long each pedal and the whole chain take
+//! into preallocated windows and hands every full window to a worker
+//! thread, which summarizes it for the `stats` command and the periodic
+//! log. Windows travel back and forth over bounded channels, so the
+//! audio thread never allocates or waits; when the worker falls behind
+//! a window is simply skipped.
+
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::thread;
+use std::time::Duration;
+
+/// Length of a statistics window in seconds
+const WINDOW: f32 = 1.;
+/// Windows in flight between the audio thread and the worker
+const BUFFERS: usize = 3;
+/// Most pedals and blocks a window keeps times for
+const MAX_PEDALS: usize = 32;
+const MAX_BLOCKS: usize = 8192;
+const NAME_CAPACITY: usize = 32;
+
+/// Processing times of one pedal in nanoseconds, one per block
+struct PedalTimes {
+    name: String,
+    nanos: Vec<u32>,
+    /// time so far in the current block, which automation may split
+    block: Option<u32>,
+}
+
+/// Everything recorded during one window
+struct Window {
+    /// `MAX_PEDALS` entries allocated up front, the first `used` are filled
+    pedals: Vec<PedalTimes>,
+    used: usize,
+    /// time of the whole chain per block
+    total: Vec<u32>,
+    /// JACK DSP load in percent per block
+    load: Vec<f32>,
+    frames: usize,
+    sample_rate: usize,
+}
+
+impl Window {
+
+    fn new() -> Self {
+        Window {
+            pedals: (0..MAX_PEDALS)
+                .map(|_| PedalTimes {
+                    name: String::with_capacity(NAME_CAPACITY),
+                    nanos: Vec::with_capacity(MAX_BLOCKS),
+                    block: None,
+                })
+                .collect(),
+            used: 0,
+            total: Vec::with_capacity(MAX_BLOCKS),
+            load: Vec::with_capacity(MAX_BLOCKS),
+            frames: 0,
+            sample_rate: 0,
+        }
+    }
+
+    fn clear(&mut self) {
+        for pedal in self.pedals[..self.used].iter_mut() {
+            pedal.nanos.clear();
+            pedal.block = None;
+        }
+        self.used = 0;
+        self.total.clear();
+        self.load.clear();
+        self.frames = 0;
+    }
+
+    /// Times of the pedal called `name`, claiming a free entry for a
+    /// pedal not seen in this window yet
+    fn pedal(&mut self, name: &str) -> Option<&mut PedalTimes> {
+        let name = cut(name);
+        let idx = match self.pedals[..self.used].iter().position(|p| p.name == name) {
+            Some(idx) => idx,
+            None if self.used < MAX_PEDALS => {
+                let pedal = &mut self.pedals[self.used];
+                pedal.name.clear();
+                pedal.name.push_str(name);
+                self.used += 1;
+                self.used - 1
+            },
+            None => return None,
+        };
+        Some(&mut self.pedals[idx])
+    }
+}
+
+/// `name` as a `PedalTimes` keeps it, longer names are cut rather than
+/// reallocated
+fn cut(name: &str) -> &str {
+    let mut end = name.len().min(NAME_CAPACITY);
+    while !name.is_char_boundary(end) {
+        end -= 1;
+    }
+    &name[..end]
+}
+
+fn nanos(elapsed: Duration) -> u32 {
+    let nanos = elapsed.as_secs() * 1_000_000_000 + elapsed.subsec_nanos() as u64;
+    nanos.min(u32::max_value() as u64) as u32
+}
+
+fn push<T>(buf: &mut Vec<T>, val: T) {
+    if buf.len() < buf.capacity() {
+        buf.push(val);
+    }
+}
+
+/// Audio thread side, see the module documentation
+pub struct Recorder {
+    window: Option<Window>,
+    sample_rate: usize,
+    to_worker: SyncSender<Window>,
+    from_worker: Receiver<Window>,
+}
+
+impl Recorder {
+
+    pub fn set_sample_rate(&mut self, sample_rate: usize) {
+        self.sample_rate = sample_rate;
+    }
+
+    /// The window being filled, if the worker has handed one back
+    fn window(&mut self) -> Option<&mut Window> {
+        if self.window.is_none() {
+            self.window = self.from_worker.try_recv().ok();
+        }
+        self.window.as_mut()
+    }
+
+    /// Time `name` took to process (part of) the current block
+    pub fn pedal(&mut self, name: &str, elapsed: Duration) {
+        if let Some(pedal) = self.window().and_then(|window| window.pedal(name)) {
+            pedal.block = Some(pedal.block.unwrap_or(0).saturating_add(nanos(elapsed)));
+        }
+    }
+
+    /// Ends a block of `frames` that took `elapsed` in total while JACK
+    /// reported a DSP load of `load` percent
+    pub fn block(&mut self, frames: usize, elapsed: Duration, load: f32) {
+        let len = WINDOW * self.sample_rate as f32;
+        let full = match self.window() {
+            Some(window) => {
+                for pedal in window.pedals[..window.used].iter_mut() {
+                    if let Some(nanos) = pedal.block.take() {
+                        push(&mut pedal.nanos, nanos);
+                    }
+                }
+                push(&mut window.total, nanos(elapsed));
+                push(&mut window.load, load);
+                window.frames += frames;
+                window.frames as f32 >= len
+            },
+            None => false,
+        };
+        if full {
+            let mut window = self.window.take().unwrap();
+            window.sample_rate = self.sample_rate;
+            let _ = self.to_worker.try_send(window);
+        }
+    }
+}
+
+/// Mean, maximum and percentiles of a set of times, in microseconds
+#[derive(Clone, Debug, PartialEq)]
+pub struct Summary {
+    pub mean: f32,
+    pub max: f32,
+    pub p50: f32,
+    pub p95: f32,
+    pub p99: f32,
+}
+
+impl Summary {
+
+    pub fn new(nanos: &[u32]) -> Summary {
+        if nanos.is_empty() {
+            return Summary { mean: 0., max: 0., p50: 0., p95: 0., p99: 0. };
+        }
+        let mut sorted = nanos.to_vec();
+        sorted.sort();
+        let us = |n: u32| n as f32 / 1000.;
+        let percentile = |p: f32| us(sorted[((sorted.len() - 1) as f32 * p).round() as usize]);
+        let sum: f64 = sorted.iter().map(|&n| n as f64).sum();
+        Summary {
+            mean: (sum / sorted.len() as f64 / 1000.) as f32,
+            max: us(sorted[sorted.len() - 1]),
+            p50: percentile(0.5),
+            p95: percentile(0.95),
+            p99: percentile(0.99),
+        }
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "mean {:7.1} max {:7.1} p50 {:7.1} p95 {:7.1} p99 {:7.1} us",
+            self.mean, self.max, self.p50, self.p95, self.p99)
+    }
+}
+
+/// Summary of the latest window
+#[derive(Clone, Debug)]
+pub struct Report {
+    /// average length of a block in microseconds, the time budget
+    pub budget: f32,
+    pub load_mean: f32,
+    pub load_max: f32,
+    pub chain: Summary,
+    pub pedals: Vec<(String, Summary)>,
+    pub xruns: usize,
+}
+
+impl Report {
+
+    fn new(window: &Window, xruns: usize) -> Report {
+        let blocks = window.total.len().max(1);
+        let load_sum: f32 = window.load.iter().sum();
+        let mut pedals: Vec<_> = window.pedals[..window.used].iter()
+            .map(|p| (p.name.clone(), Summary::new(&p.nanos)))
+            .collect();
+        // most expensive first
+        pedals.sort_by(|a, b| b.1.mean.partial_cmp(&a.1.mean).unwrap());
+        Report {
+            budget: window.frames as f32 / blocks as f32 / window.sample_rate.max(1) as f32 * 1e6,
+            load_mean: load_sum / window.load.len().max(1) as f32,
+            load_max: window.load.iter().cloned().fold(0., f32::max),
+            chain: Summary::new(&window.total),
+            pedals,
+            xruns,
+        }
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "DSP load {:.1}% (max {:.1}%), {} xruns, {:.0} us per block",
+            self.load_mean, self.load_max, self.xruns, self.budget)?;
+        write!(f, "{:<12} {}", "chain", self.chain)?;
+        for &(ref name, ref summary) in self.pedals.iter() {
+            write!(f, "\n{:<12} {}", name, summary)?;
+        }
+        Ok(())
+    }
+}
+
+/// Control side: prints the statistics and counts the xruns that
+/// `Notifications` reports
+pub struct Monitor {
+    report: Arc<Mutex<Option<Report>>>,
+    /// seconds between log lines, 0 is off
+    log_every: Arc<AtomicUsize>,
+    pub xruns: Arc<AtomicUsize>,
+}
+
+impl Monitor {
+
+    pub fn print(&self) {
+        match *self.report.lock().unwrap() {
+            Some(ref report) => println!("{}", report),
+            None => println!("No statistics yet"),
+        }
+    }
+
+    /// Prints the statistics every `seconds`, 0 stops
+    pub fn set_log(&self, seconds: f32) {
+        let windows = (seconds.max(0.) / WINDOW).round() as usize;
+        self.log_every.store(windows, Ordering::Relaxed);
+    }
+
+    pub fn report(&self) -> Option<Report> {
+        self.report.lock().unwrap().clone()
+    }
+}
+
+/// Starts the worker thread and returns both ends
+pub fn start(sample_rate: usize) -> (Recorder, Monitor) {
+    let (to_worker, from_audio) = sync_channel(BUFFERS);
+    let (to_audio, from_worker) = sync_channel(BUFFERS);
+    for _ in 0..BUFFERS {
+        to_audio.send(Window::new()).unwrap();
+    }
+
+    let monitor = Monitor {
+        report: Arc::new(Mutex::new(None)),
+        log_every: Arc::new(AtomicUsize::new(0)),
+        xruns: Arc::new(AtomicUsize::new(0)),
+    };
+    let report = monitor.report.clone();
+    let log_every = monitor.log_every.clone();
+    let xruns = monitor.xruns.clone();
+    thread::spawn(move || {
+        let mut count = 0;
+        for mut window in from_audio.iter() {
+            let summary = Report::new(&window, xruns.load(Ordering::Relaxed));
+            window.clear();
+            // the audio thread may be gone
+            let _ = to_audio.try_send(window);
+
+            count += 1;
+            let every = log_every.load(Ordering::Relaxed);
+            if every > 0 && count % every == 0 {
+                println!("{}", summary);
+            }
+            *report.lock().unwrap() = Some(summary);
+        }
+    });
+
+    let recorder = Recorder {
+        window: None,
+        sample_rate,
+        to_worker,
+        from_worker,
+    };
+    (recorder, monitor)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{start, Summary, Window, MAX_PEDALS};
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_summary() {
+        let nanos: Vec<u32> = (1..101).map(|i| i * 1000).collect();
+        let summary = Summary::new(&nanos);
+        assert_eq!(summary.mean, 50.5);
+        assert_eq!(summary.max, 100.);
+        assert_eq!(summary.p50, 51.);
+        assert_eq!(summary.p95, 95.);
+        assert_eq!(summary.p99, 99.);
+    }
+
+    #[test]
+    fn test_windows_reach_the_monitor() {
+        let (mut recorder, monitor) = start(1000);
+        // a window is 1000 frames, ten blocks
+        for i in 0..30 {
+            // a block split in two by automation still counts once
+            recorder.pedal("delay", Duration::new(0, 5_000));
+            recorder.pedal("delay", Duration::new(0, 15_000));
+            recorder.pedal("od", Duration::new(0, if i % 3 == 0 { 6_000 } else { 5_000 }));
+            recorder.block(100, Duration::new(0, 30_000), 12.);
+        }
+
+        let mut report = None;
+        for _ in 0..100 {
+            report = monitor.report();
+            if report.is_some() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        let report = report.unwrap();
+        assert_eq!(report.budget, 100_000.);
+        assert_eq!(report.load_mean, 12.);
+        assert_eq!(report.chain.mean, 30.);
+        assert_eq!(report.pedals[0].0, "delay");
+        assert_eq!(report.pedals[0].1.max, 20.);
+        assert_eq!((report.pedals[1].1.p50, report.pedals[1].1.max), (5., 6.));
+    }
+    #[test]
+    fn test_long_names_keep_their_entry() {
+        let mut window = Window::new();
+        let name = "a_pedal_with_a_name_well_over_32_bytes";
+        for _ in 0..MAX_PEDALS + 1 {
+            assert!(window.pedal(name).is_some());
+        }
+        assert_eq!(window.used, 1);
+        assert_eq!(window.pedals[0].name, &name[..32]);
+        // cut on a character boundary
+        assert_eq!(window.pedal(&"é".repeat(20)).unwrap().name, "é".repeat(16));
+    }
+}
@@ -0,0 +1,549 @@
+//! Full-screen terminal UI.
+//!
+//! `tui` at the prompt, or `--tui` at startup, takes over the terminal:
+//! the chain as pedals with their bypass LEDs, the parameters of the
+//! selected pedal, the tuner and the meters, redrawn ten times a second.
+//! Every key turns into one of the text commands and goes the same way
+//! as a typed one, `:` types any command and `q` goes back to the prompt.
+//!
+//! ```text
+//! left/right  select a pedal          space  switch it on or off
+//! up/down     select a parameter      + -    nudge the value
+//! enter       type a value            b      bypass everything
+//! a           toggle the A/B slots    n p    next and previous scene
+//! r           reset the meters        :      any command
+//! ```
+//!
+//! The TUI never locks the pedals. While it is open the audio thread
+//! builds a view every 100 ms and hands it over a bounded channel, like
+//! the meters hand over their readings, skipping it while the last one
+//! has not been taken.
+
+use effects::Param;
+use meters::{Readout, Reading};
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::process::Command;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+
+/// Seconds between the views handed to the TUI
+const INTERVAL: f32 = 0.1;
+
+/// A pedal as the TUI and `p` show it
+#[derive(Clone, Debug, PartialEq)]
+pub struct PedalView {
+    pub name: String,
+    /// effect type, such as `delay`
+    pub kind: String,
+    pub on: bool,
+    pub params: Vec<(String, Param)>,
+    pub readout: Option<String>,
+}
+
+impl fmt::Display for PedalView {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:<10} {:<3}", self.name, if self.on { "on" } else { "off" })?;
+        for &(ref param, ref value) in self.params.iter() {
+            write!(f, "  {} {}", param, value)?;
+        }
+        Ok(())
+    }
+}
+
+/// What the pedals look like, built by `Pedals::view`
+#[derive(Clone, Debug, PartialEq)]
+pub struct View {
+    /// in chain order
+    pub chain: Vec<PedalView>,
+    /// pedals the chain does not reach, by name
+    pub others: Vec<PedalView>,
+    pub bypassing: bool,
+    /// where we are in the setlist
+    pub scene: Option<String>,
+}
+
+impl View {
+
+    /// `in -> od -> delay -> out`
+    pub fn chain_line(&self) -> String {
+        let mut line = "in".to_owned();
+        for pedal in self.chain.iter() {
+            line.push_str(" -> ");
+            line.push_str(&pedal.name);
+        }
+        line + " -> out"
+    }
+
+    /// The chain, then the rest
+    fn pedal(&self, idx: usize) -> Option<&PedalView> {
+        self.chain.iter().chain(self.others.iter()).nth(idx)
+    }
+
+    fn len(&self) -> usize {
+        self.chain.len() + self.others.len()
+    }
+}
+
+/// Audio thread side of the hand-over, see the module documentation
+pub struct ViewFeed {
+    open: Arc<AtomicBool>,
+    sample_rate: usize,
+    frames: usize,
+    to_tui: SyncSender<View>,
+}
+
+impl ViewFeed {
+
+    /// Not called from the audio thread
+    pub fn set_sample_rate(&mut self, sample_rate: usize) {
+        self.sample_rate = sample_rate;
+    }
+
+    /// Counts a block of `frames`, true when the TUI is open and a view
+    /// is due
+    pub fn due(&mut self, frames: usize) -> bool {
+        if !self.open.load(Ordering::Relaxed) {
+            self.frames = 0;
+            return false;
+        }
+        self.frames += frames;
+        if (self.frames as f32) < INTERVAL * self.sample_rate as f32 {
+            return false;
+        }
+        self.frames = 0;
+        true
+    }
+
+    pub fn send(&self, view: View) {
+        // the TUI has not taken the last one yet
+        let _ = self.to_tui.try_send(view);
+    }
+}
+
+/// Control side of the hand-over
+pub struct Views {
+    open: Arc<AtomicBool>,
+    from_audio: Receiver<View>,
+}
+
+impl Views {
+
+    /// The newest view handed over since the last call
+    fn latest(&self) -> Option<View> {
+        self.from_audio.try_iter().last()
+    }
+}
+
+/// Returns both ends of the hand-over
+pub fn start(sample_rate: usize) -> (ViewFeed, Views) {
+    let (to_tui, from_audio) = sync_channel(1);
+    let open = Arc::new(AtomicBool::new(false));
+    let feed = ViewFeed { open: open.clone(), sample_rate, frames: 0, to_tui };
+    (feed, Views { open, from_audio })
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Key {
+    Up,
+    Down,
+    Left,
+    Right,
+    Enter,
+    Backspace,
+    Esc,
+    Char(char),
+}
+
+/// Keys in what the terminal sent, arrows come as escape sequences
+fn keys(bytes: &[u8]) -> Vec<Key> {
+    let text = String::from_utf8_lossy(bytes);
+    let mut keys = vec![];
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        keys.push(match c {
+            '\x1b' if chars.peek() == Some(&'[') => {
+                chars.next();
+                match chars.next() {
+                    Some('A') => Key::Up,
+                    Some('B') => Key::Down,
+                    Some('C') => Key::Right,
+                    Some('D') => Key::Left,
+                    _ => continue,
+                }
+            },
+            '\x1b' => Key::Esc,
+            '\r' | '\n' => Key::Enter,
+            '\x7f' | '\x08' => Key::Backspace,
+            c => Key::Char(c),
+        });
+    }
+    keys
+}
+
+/// The value one step up (`dir` 1) or down (-1). Numbers move by a
+/// tenth of their order of magnitude, names are typed instead.
+fn nudge(value: &Param, dir: i32) -> Option<String> {
+    match *value {
+        Param::Float(v) => {
+            let step = if v == 0. { 0.01 } else { 10f32.powf(v.abs().log10().floor() - 1.) };
+            let stepped = ((v / step).round() + dir as f32) * step;
+            // drop the float noise of the multiplication
+            let digits = (-step.log10()).max(0.) as usize;
+            let text = format!("{:.*}", digits, stepped);
+            Some(text.parse::<f32>().map(|v| v.to_string()).unwrap_or(text))
+        },
+        Param::Int(n) => Some((n + dir).to_string()),
+        Param::Name(_) => None,
+    }
+}
+
+/// Level from -60 dBFS to full scale as a bar
+fn bar(db: f32) -> String {
+    const WIDTH: usize = 24;
+    let filled = (((db + 60.) / 60.).max(0.).min(1.) * WIDTH as f32).round() as usize;
+    format!("[{}{}]", "#".repeat(filled), " ".repeat(WIDTH - filled))
+}
+
+enum Edit {
+    Value,
+    Command,
+}
+
+struct Screen {
+    pedal: usize,
+    param: usize,
+    edit: Option<(Edit, String)>,
+    /// the last command sent
+    status: String,
+    /// tuners seen and whether the TUI started their stream for the
+    /// readout, the others were streaming already
+    tuners: Vec<(String, bool)>,
+}
+
+impl Screen {
+
+    /// Handles a key, false to leave
+    fn key<F: FnMut(&str)>(&mut self, key: Key, view: &View, command: &mut F) -> bool {
+        let mut send = |status: &mut String, cmd: String| {
+            command(&cmd);
+            *status = cmd;
+        };
+        if let Some((edit, mut text)) = self.edit.take() {
+            match key {
+                Key::Enter => {
+                    let cmd = match (edit, view.pedal(self.pedal)) {
+                        (Edit::Command, _) => text,
+                        (Edit::Value, Some(pedal)) => match pedal.params.get(self.param) {
+                            Some(&(ref param, _)) => format!("s {} {} {}", pedal.name, param, text.trim()),
+                            None => return true,
+                        },
+                        (Edit::Value, None) => return true,
+                    };
+                    if !cmd.trim().is_empty() && cmd.trim() != "tui" {
+                        send(&mut self.status, cmd);
+                    }
+                },
+                Key::Esc => (),
+                Key::Backspace => {
+                    text.pop();
+                    self.edit = Some((edit, text));
+                },
+                Key::Char(c) => {
+                    text.push(c);
+                    self.edit = Some((edit, text));
+                },
+                _ => self.edit = Some((edit, text)),
+            }
+            return true;
+        }
+
+        let pedal = view.pedal(self.pedal);
+        let param = pedal.and_then(|p| p.params.get(self.param));
+        match key {
+            Key::Char('q') | Key::Esc => return false,
+            Key::Left | Key::Char('h') => {
+                self.pedal = self.pedal.saturating_sub(1);
+                self.param = 0;
+            },
+            Key::Right | Key::Char('l') => {
+                self.pedal = (self.pedal + 1).min(view.len().saturating_sub(1));
+                self.param = 0;
+            },
+            Key::Up | Key::Char('k') => self.param = self.param.saturating_sub(1),
+            Key::Down | Key::Char('j') => {
+                let count = pedal.map_or(0, |p| p.params.len());
+                self.param = (self.param + 1).min(count.saturating_sub(1));
+            },
+            Key::Char(c @ '+') | Key::Char(c @ '=') | Key::Char(c @ '-') => {
+                let dir = if c == '-' { -1 } else { 1 };
+                if let (Some(pedal), Some(&(ref name, ref value))) = (pedal, param) {
+                    if let Some(value) = nudge(value, dir) {
+                        send(&mut self.status, format!("s {} {} {}", pedal.name, name, value));
+                    }
+                }
+            },
+            Key::Char(' ') => {
+                if let Some(pedal) = pedal {
+                    send(&mut self.status, format!("b {}", pedal.name));
+                }
+            },
+            Key::Enter => {
+                if param.is_some() {
+                    self.edit = Some((Edit::Value, String::new()));
+                }
+            },
+            Key::Char(':') => self.edit = Some((Edit::Command, String::new())),
+            Key::Char('b') => send(&mut self.status, "b".to_owned()),
+            Key::Char('a') => send(&mut self.status, "ab".to_owned()),
+            Key::Char('n') => send(&mut self.status, "next".to_owned()),
+            Key::Char('p') => send(&mut self.status, "prev".to_owned()),
+            Key::Char('r') => send(&mut self.status, "meters reset".to_owned()),
+            _ => (),
+        }
+        true
+    }
+
+    /// Streams the tuners not seen yet quietly, for their readout
+    fn stream_tuners<F: FnMut(&str)>(&mut self, view: &View, command: &mut F) {
+        for pedal in view.chain.iter().chain(view.others.iter()) {
+            if pedal.kind == "tuner" && !self.tuners.iter().any(|t| t.0 == pedal.name) {
+                // a tuner only has a readout while it streams
+                let start = pedal.readout.is_none();
+                if start {
+                    command(&format!("s {} stream quiet", pedal.name));
+                }
+                self.tuners.push((pedal.name.clone(), start));
+            }
+        }
+    }
+
+    /// Stops the streams `stream_tuners` started
+    fn stop_tuners<F: FnMut(&str)>(&self, command: &mut F) {
+        for &(ref name, started) in self.tuners.iter() {
+            if started {
+                command(&format!("s {} stream off", name));
+            }
+        }
+    }
+
+    /// The whole screen, drawn over the last one
+    fn draw(&self, view: &View, meters: &[Reading]) -> String {
+        let mut lines = vec![];
+        let mut title = "\x1b[1mrasta\x1b[0m".to_owned();
+        if view.bypassing {
+            title.push_str("  \x1b[7m BYPASSED \x1b[0m");
+        }
+        if let Some(ref scene) = view.scene {
+            title.push_str("  ");
+            title.push_str(scene);
+        }
+        lines.push(title);
+        lines.push(String::new());
+
+        let stomp = |idx: usize, pedal: &PedalView| {
+            let led = if pedal.on { "\x1b[32m●\x1b[0m" } else { "\x1b[2m○\x1b[0m" };
+            let name = if idx == self.pedal { format!("\x1b[7m{}\x1b[0m", pedal.name) } else { pedal.name.clone() };
+            format!("[{} {}]", led, name)
+        };
+        let mut chain = "in".to_owned();
+        for (i, pedal) in view.chain.iter().enumerate() {
+            chain.push_str(" ─ ");
+            chain.push_str(&stomp(i, pedal));
+        }
+        chain.push_str(" ─ out");
+        lines.push(chain);
+        if !view.others.is_empty() {
+            let others: Vec<_> = view.others.iter().enumerate()
+                .map(|(i, pedal)| stomp(view.chain.len() + i, pedal))
+                .collect();
+            lines.push(format!("off the chain: {}", others.join(" ")));
+        }
+        lines.push(String::new());
+
+        if let Some(pedal) = view.pedal(self.pedal) {
+            lines.push(format!("\x1b[1m{}\x1b[0m ({}) {}", pedal.name, pedal.kind, if pedal.on { "on" } else { "off" }));
+            for (i, &(ref param, ref value)) in pedal.params.iter().enumerate() {
+                let value = match self.edit {
+                    Some((Edit::Value, ref text)) if i == self.param => format!("\x1b[7m{}_\x1b[0m", text),
+                    _ => value.to_string(),
+                };
+                lines.push(format!("{} {:<12} {}", if i == self.param { ">" } else { " " }, param, value));
+            }
+            if pedal.params.is_empty() {
+                lines.push("  no parameters".to_owned());
+            }
+        }
+        lines.push(String::new());
+
+        for pedal in view.chain.iter().chain(view.others.iter()) {
+            if let Some(ref readout) = pedal.readout {
+                lines.push(format!("{:<12} {}", pedal.name, readout));
+            }
+        }
+        for reading in meters.iter() {
+            let clip = if reading.clips > 0 { " \x1b[31mCLIP\x1b[0m" } else { "" };
+            lines.push(format!("{} {}{}", reading, bar(reading.peak), clip));
+        }
+        lines.push(String::new());
+
+        lines.push("\x1b[2m←→ pedal  ↑↓ param  +- nudge  enter value  space on/off  \
+                    b bypass  a A/B  n p scene  r reset  : command  q quit\x1b[0m".to_owned());
+        lines.push(match self.edit {
+            Some((Edit::Command, ref text)) => format!(":{}_", text),
+            _ => self.status.clone(),
+        });
+
+        let mut screen = "\x1b[H".to_owned();
+        for line in lines {
+            screen.push_str(&line);
+            screen.push_str("\x1b[K\n");
+        }
+        screen + "\x1b[J"
+    }
+}
+
+/// Runs `stty` on the terminal, its output if it worked
+fn stty(args: &[&str]) -> Option<String> {
+    Command::new("stty").args(args).output().ok()
+        .and_then(|out| if out.status.success() { Some(String::from_utf8_lossy(&out.stdout).trim().to_owned()) } else { None })
+}
+
+/// Takes over the terminal until `q`. `command` runs a line like the
+/// prompt does, `views` brings what the pedals look like.
+pub fn run<F: FnMut(&str)>(command: &mut F, views: &Views, readout: &Readout) {
+    let saved = match stty(&["-g"]) {
+        Some(saved) => saved,
+        None => return println!("The TUI needs a terminal"),
+    };
+    // keys as they are typed, reads give up after a tenth of a second
+    stty(&["-icanon", "-echo", "min", "0", "time", "1"]);
+    print!("\x1b[?1049h\x1b[?25l\x1b[2J");
+    views.open.store(true, Ordering::Relaxed);
+
+    let mut screen = Screen { pedal: 0, param: 0, edit: None, status: String::new(), tuners: vec![] };
+    let mut view: Option<View> = None;
+    let mut buf = [0; 64];
+    loop {
+        if let Some(latest) = views.latest() {
+            view = Some(latest);
+        }
+        if let Some(ref view) = view {
+            screen.stream_tuners(view, command);
+            print!("{}", screen.draw(view, &readout.latest()));
+            io::stdout().flush().ok();
+        }
+
+        let n = io::stdin().read(&mut buf).unwrap_or(0);
+        let empty = View { chain: vec![], others: vec![], bypassing: false, scene: None };
+        let shown = view.as_ref().unwrap_or(&empty);
+        if !keys(&buf[..n]).into_iter().all(|key| screen.key(key, shown, command)) {
+            break;
+        }
+    }
+
+    views.open.store(false, Ordering::Relaxed);
+    screen.stop_tuners(command);
+    print!("\x1b[?25h\x1b[?1049l");
+    io::stdout().flush().ok();
+    stty(&[&saved]);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{keys, nudge, start, Key, PedalView, Screen, View};
+    use effects::Param;
+    use std::sync::atomic::Ordering;
+
+    fn view() -> View {
+        let pedal = |name: &str, on: bool| PedalView {
+            name: name.to_owned(),
+            kind: "delay".to_owned(),
+            on,
+            params: vec![("mix".to_owned(), Param::Float(0.3)), ("mode".to_owned(), Param::Name("tape".to_owned()))],
+            readout: None,
+        };
+        View { chain: vec![pedal("od", true), pedal("delay", false)], others: vec![pedal("aw", false)], bypassing: false, scene: None }
+    }
+
+    #[test]
+    fn test_keys() {
+        assert_eq!(keys(b"\x1b[A\x1b[Cq"), vec![Key::Up, Key::Right, Key::Char('q')]);
+        assert_eq!(keys(b"\x1b"), vec![Key::Esc]);
+        assert_eq!(keys(b"+\r\x7f"), vec![Key::Char('+'), Key::Enter, Key::Backspace]);
+    }
+
+    #[test]
+    fn test_nudge() {
+        assert_eq!(nudge(&Param::Float(0.3), 1), Some("0.31".to_owned()));
+        assert_eq!(nudge(&Param::Float(440.), -1), Some("430".to_owned()));
+        assert_eq!(nudge(&Param::Float(-45.), 1), Some("-44".to_owned()));
+        assert_eq!(nudge(&Param::Float(0.), 1), Some("0.01".to_owned()));
+        assert_eq!(nudge(&Param::Int(4), -1), Some("3".to_owned()));
+        assert_eq!(nudge(&Param::Name("tape".to_owned()), 1), None);
+    }
+
+    #[test]
+    fn test_keys_become_commands() {
+        let view = view();
+        let mut sent = vec![];
+        let mut screen = Screen { pedal: 0, param: 0, edit: None, status: String::new(), tuners: vec![] };
+        {
+            let mut command = |cmd: &str| sent.push(cmd.to_owned());
+            for key in keys(b"\x1b[C+ \x1b[B\rdigital\r:bpm 120\r") {
+                assert!(screen.key(key, &view, &mut command));
+            }
+            assert!(!screen.key(Key::Char('q'), &view, &mut command));
+        }
+        assert_eq!(sent, vec!["s delay mix 0.31", "b delay", "s delay mode digital", "bpm 120"]);
+
+        assert_eq!(view.chain_line(), "in -> od -> delay -> out");
+        let drawn = screen.draw(&view, &[]);
+        assert!(drawn.contains("[\x1b[32m●\x1b[0m od] ─ [\x1b[2m○\x1b[0m \x1b[7mdelay\x1b[0m]"));
+        assert!(drawn.contains("> mode         tape"));
+    }
+
+    #[test]
+    fn test_views_only_while_open() {
+        let (mut feed, views) = start(1000);
+        // a view is due every 100 frames, only while the TUI is open
+        assert!(!(0..20).any(|_| feed.due(10)));
+        views.open.store(true, Ordering::Relaxed);
+        let due: Vec<bool> = (0..20).map(|_| feed.due(10)).collect();
+        assert_eq!(due.iter().filter(|&&d| d).count(), 2);
+        assert!(due[9] && due[19]);
+
+        // one in flight at most, the TUI gets the newest
+        feed.send(view());
+        let mut other = view();
+        other.bypassing = true;
+        feed.send(other);
+        assert_eq!(views.latest(), Some(view()));
+        assert_eq!(views.latest(), None);
+    }
+
+    #[test]
+    fn test_only_stops_the_streams_it_started() {
+        let mut view = view();
+        let tuner = |name: &str, readout: Option<&str>| PedalView {
+            name: name.to_owned(),
+            kind: "tuner".to_owned(),
+            on: true,
+            params: vec![],
+            readout: readout.map(|r| r.to_owned()),
+        };
+        view.others.push(tuner("tu", None));
+        view.others.push(tuner("loud", Some("A4 +0.0c")));
+
+        let mut sent = vec![];
+        let mut screen = Screen { pedal: 0, param: 0, edit: None, status: String::new(), tuners: vec![] };
+        {
+            let mut command = |cmd: &str| sent.push(cmd.to_owned());
+            screen.stream_tuners(&view, &mut command);
+            screen.stream_tuners(&view, &mut command);
+            screen.stop_tuners(&mut command);
+        }
+        assert_eq!(sent, vec!["s tu stream quiet", "s tu stream off"]);
+    }
+}
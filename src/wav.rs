@@ -0,0 +1,126 @@
+//! Minimal reader and writer for PCM WAV files.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::path::Path;
+
+fn write_u32<W: Write>(w: &mut W, x: u32) -> io::Result<()> {
+    w.write_all(&[x as u8, (x >> 8) as u8, (x >> 16) as u8, (x >> 24) as u8])
+}
+
+fn write_u16<W: Write>(w: &mut W, x: u16) -> io::Result<()> {
+    w.write_all(&[x as u8, (x >> 8) as u8])
+}
+
+fn read_u32(b: &[u8]) -> u32 {
+    b[0] as u32 | (b[1] as u32) << 8 | (b[2] as u32) << 16 | (b[3] as u32) << 24
+}
+
+fn read_u16(b: &[u8]) -> u16 {
+    b[0] as u16 | (b[1] as u16) << 8
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Writes `samples` in -1..1 as mono 16-bit, clipping anything outside
+pub fn write_wav<P: AsRef<Path>>(path: P, sample_rate: u32, samples: &[f32]) -> io::Result<()> {
+    write_channels(path, sample_rate, &[samples])
+}
+
+/// Writes a stereo 16-bit file, `left` and `right` of the same length
+pub fn write_wav_stereo<P: AsRef<Path>>(path: P, sample_rate: u32, left: &[f32], right: &[f32]) -> io::Result<()> {
+    write_channels(path, sample_rate, &[left, right])
+}
+
+fn write_channels<P: AsRef<Path>>(path: P, sample_rate: u32, channels: &[&[f32]]) -> io::Result<()> {
+    let mut w = BufWriter::new(File::create(path)?);
+    let count = channels.len() as u32;
+    let data_len = channels[0].len() as u32 * 2 * count;
+
+    w.write_all(b"RIFF")?;
+    write_u32(&mut w, 36 + data_len)?;
+    w.write_all(b"WAVE")?;
+
+    w.write_all(b"fmt ")?;
+    write_u32(&mut w, 16)?;
+    write_u16(&mut w, 1)?; // PCM
+    write_u16(&mut w, count as u16)?;
+    write_u32(&mut w, sample_rate)?;
+    write_u32(&mut w, sample_rate * 2 * count)?; // byte rate
+    write_u16(&mut w, 2 * count as u16)?; // block align
+    write_u16(&mut w, 16)?; // bits per sample
+
+    w.write_all(b"data")?;
+    write_u32(&mut w, data_len)?;
+    for i in 0..channels[0].len() {
+        for channel in channels.iter() {
+            let s = (channel[i].max(-1.).min(1.) * 32767.) as i16;
+            write_u16(&mut w, s as u16)?;
+        }
+    }
+    w.flush()
+}
+
+/// Reads 16, 24 or 32-bit integer or 32-bit float PCM, channels mixed
+/// down to mono. Returns the sample rate and the samples.
+pub fn read_wav<P: AsRef<Path>>(path: P) -> io::Result<(u32, Vec<f32>)> {
+    let mut bytes = vec![];
+    File::open(path)?.read_to_end(&mut bytes)?;
+    if bytes.len() < 12 || &bytes[..4] != b"RIFF" || &bytes[8..12] != b"WAVE" {
+        return Err(invalid("not a WAV file"));
+    }
+
+    let mut format = None;
+    let mut pos = 12;
+    while pos + 8 <= bytes.len() {
+        let id = &bytes[pos..pos + 4];
+        let len = read_u32(&bytes[pos + 4..]) as usize;
+        let body = &bytes[pos + 8..(pos + 8 + len).min(bytes.len())];
+        if id == b"fmt " && body.len() >= 16 {
+            // tag, channels, rate, bits
+            format = Some((read_u16(body), read_u16(&body[2..]), read_u32(&body[4..]), read_u16(&body[14..])));
+        } else if id == b"data" {
+            let (tag, channels, rate, bits) = format.ok_or_else(|| invalid("data before fmt"))?;
+            let convert: fn(&[u8]) -> f32 = match (tag, bits) {
+                (1, 16) => |b| read_u16(b) as i16 as f32 / 32768.,
+                (1, 24) => |b| ((read_u32(&[0, b[0], b[1], b[2]]) as i32) >> 8) as f32 / 8388608.,
+                (1, 32) => |b| read_u32(b) as i32 as f32 / 2147483648.,
+                (3, 32) => |b| f32::from_bits(read_u32(b)),
+                _ => return Err(invalid("only 16, 24 and 32-bit PCM or 32-bit float")),
+            };
+            let size = bits as usize / 8;
+            let channels = channels.max(1) as usize;
+            let samples = body
+                .chunks(size * channels)
+                .filter(|frame| frame.len() == size * channels)
+                .map(|frame| frame.chunks(size).map(convert).sum::<f32>() / channels as f32)
+                .collect();
+            return Ok((rate, samples));
+        }
+        // chunks are padded to an even length
+        pos += 8 + len + len % 2;
+    }
+    Err(invalid("no data"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_wav, write_wav, write_wav_stereo};
+    use std::env;
+
+    #[test]
+    fn test_round_trip() {
+        let path = env::temp_dir().join("rasta_test.wav");
+        write_wav(&path, 44100, &[0., 0.5, -0.5, 2.]).unwrap();
+        let (rate, samples) = read_wav(&path).unwrap();
+        assert_eq!(rate, 44100);
+        let expected = [0., 16383. / 32768., -16383. / 32768., 32767. / 32768.];
+        assert_eq!(samples, expected);
+
+        write_wav_stereo(&path, 48000, &[0.5, 0.25], &[0.5, -0.25]).unwrap();
+        let (rate, samples) = read_wav(&path).unwrap();
+        assert_eq!((rate, samples), (48000, vec![16383. / 32768., 0.]));
+    }
+}